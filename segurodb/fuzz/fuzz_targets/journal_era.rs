@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate segurodb;
+
+fuzz_target!(|data: &[u8]| {
+	segurodb::fuzz::journal_era(data);
+});
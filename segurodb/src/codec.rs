@@ -0,0 +1,279 @@
+//! Encoding of typed keys and values used by `Table`.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use error::{ErrorKind, Result};
+
+/// Encodes keys of a `Table`.
+///
+/// Keys are stored in the order of their encoded bytes, so the encoding has to preserve
+/// the ordering of keys. Every encoded key has exactly `LEN` bytes.
+pub trait KeyCodec: Sized {
+	/// Length of the encoded key in bytes.
+	const LEN: usize;
+
+	/// Appends exactly `LEN` bytes of the encoded key to the buffer.
+	fn encode_key(&self, buf: &mut Vec<u8>);
+
+	/// Decodes the key from `LEN` bytes.
+	fn decode_key(data: &[u8]) -> Result<Self>;
+}
+
+/// Encodes values of a `Table`.
+pub trait ValueCodec: Sized {
+	/// Appends the encoded value to the buffer.
+	fn encode_value(&self, buf: &mut Vec<u8>);
+
+	/// Decodes the value from all the bytes of `data`.
+	fn decode_value(data: &[u8]) -> Result<Self>;
+}
+
+fn check_len(data: &[u8], len: usize) -> Result<()> {
+	if data.len() != len {
+		return Err(ErrorKind::InvalidEncoding(format!("expected {} bytes, got {}", len, data.len())).into());
+	}
+
+	Ok(())
+}
+
+macro_rules! impl_unsigned_codec {
+	($t: ty, $len: expr, $read: ident, $write: ident) => {
+		/// Big-endian encoding.
+		impl KeyCodec for $t {
+			const LEN: usize = $len;
+
+			fn encode_key(&self, buf: &mut Vec<u8>) {
+				let mut bytes = [0u8; $len];
+				BigEndian::$write(&mut bytes, *self);
+				buf.extend_from_slice(&bytes);
+			}
+
+			fn decode_key(data: &[u8]) -> Result<Self> {
+				check_len(data, $len)?;
+				Ok(BigEndian::$read(data))
+			}
+		}
+
+		/// Big-endian encoding.
+		impl ValueCodec for $t {
+			fn encode_value(&self, buf: &mut Vec<u8>) {
+				self.encode_key(buf);
+			}
+
+			fn decode_value(data: &[u8]) -> Result<Self> {
+				Self::decode_key(data)
+			}
+		}
+	}
+}
+
+impl_unsigned_codec!(u16, 2, read_u16, write_u16);
+impl_unsigned_codec!(u32, 4, read_u32, write_u32);
+impl_unsigned_codec!(u64, 8, read_u64, write_u64);
+
+macro_rules! impl_signed_codec {
+	($t: ty, $unsigned: ty, $len: expr, $sign: expr) => {
+		/// Big-endian encoding with the sign bit flipped, so negative numbers sort first.
+		impl KeyCodec for $t {
+			const LEN: usize = $len;
+
+			fn encode_key(&self, buf: &mut Vec<u8>) {
+				((*self as $unsigned) ^ $sign).encode_key(buf);
+			}
+
+			fn decode_key(data: &[u8]) -> Result<Self> {
+				<$unsigned>::decode_key(data).map(|value| (value ^ $sign) as $t)
+			}
+		}
+
+		/// Big-endian encoding with the sign bit flipped, so negative numbers sort first.
+		impl ValueCodec for $t {
+			fn encode_value(&self, buf: &mut Vec<u8>) {
+				self.encode_key(buf);
+			}
+
+			fn decode_value(data: &[u8]) -> Result<Self> {
+				Self::decode_key(data)
+			}
+		}
+	}
+}
+
+impl_signed_codec!(i16, u16, 2, 1 << 15);
+impl_signed_codec!(i32, u32, 4, 1 << 31);
+impl_signed_codec!(i64, u64, 8, 1 << 63);
+
+impl KeyCodec for u8 {
+	const LEN: usize = 1;
+
+	fn encode_key(&self, buf: &mut Vec<u8>) {
+		buf.push(*self);
+	}
+
+	fn decode_key(data: &[u8]) -> Result<Self> {
+		check_len(data, 1)?;
+		Ok(data[0])
+	}
+}
+
+impl ValueCodec for u8 {
+	fn encode_value(&self, buf: &mut Vec<u8>) {
+		self.encode_key(buf);
+	}
+
+	fn decode_value(data: &[u8]) -> Result<Self> {
+		Self::decode_key(data)
+	}
+}
+
+macro_rules! impl_array_codec {
+	($($len: expr),+) => {
+		$(
+			impl KeyCodec for [u8; $len] {
+				const LEN: usize = $len;
+
+				fn encode_key(&self, buf: &mut Vec<u8>) {
+					buf.extend_from_slice(self);
+				}
+
+				fn decode_key(data: &[u8]) -> Result<Self> {
+					check_len(data, $len)?;
+					let mut array = [0u8; $len];
+					array.copy_from_slice(data);
+					Ok(array)
+				}
+			}
+
+			impl ValueCodec for [u8; $len] {
+				fn encode_value(&self, buf: &mut Vec<u8>) {
+					self.encode_key(buf);
+				}
+
+				fn decode_value(data: &[u8]) -> Result<Self> {
+					Self::decode_key(data)
+				}
+			}
+		)+
+	}
+}
+
+impl_array_codec!(
+	1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+	17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+	48, 64
+);
+
+/// Raw bytes of any length.
+impl ValueCodec for Vec<u8> {
+	fn encode_value(&self, buf: &mut Vec<u8>) {
+		buf.extend_from_slice(self);
+	}
+
+	fn decode_value(data: &[u8]) -> Result<Self> {
+		Ok(data.to_vec())
+	}
+}
+
+macro_rules! impl_tuple_codec {
+	($($name: ident),+) => {
+		/// Concatenation of the encoded elements.
+		impl<$($name: KeyCodec),+> KeyCodec for ($($name,)+) {
+			const LEN: usize = 0 $(+ $name::LEN)+;
+
+			#[allow(non_snake_case)]
+			fn encode_key(&self, buf: &mut Vec<u8>) {
+				let ($(ref $name,)+) = *self;
+				$($name.encode_key(buf);)+
+			}
+
+			#[allow(unused_assignments)]
+			fn decode_key(data: &[u8]) -> Result<Self> {
+				check_len(data, Self::LEN)?;
+				let mut offset = 0;
+				Ok(($({
+					let element = $name::decode_key(&data[offset..offset + $name::LEN])?;
+					offset += $name::LEN;
+					element
+				},)+))
+			}
+		}
+
+		/// Concatenation of the encoded fixed width elements.
+		impl<$($name: KeyCodec),+> ValueCodec for ($($name,)+) {
+			fn encode_value(&self, buf: &mut Vec<u8>) {
+				self.encode_key(buf);
+			}
+
+			fn decode_value(data: &[u8]) -> Result<Self> {
+				Self::decode_key(data)
+			}
+		}
+	}
+}
+
+impl_tuple_codec!(A, B);
+impl_tuple_codec!(A, B, C);
+impl_tuple_codec!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+	use quickcheck::TestResult;
+	use error::ErrorKind;
+	use super::{KeyCodec, ValueCodec};
+
+	fn encode_key<K: KeyCodec>(key: &K) -> Vec<u8> {
+		let mut buf = Vec::new();
+		key.encode_key(&mut buf);
+		buf
+	}
+
+	fn encode_value<V: ValueCodec>(value: &V) -> Vec<u8> {
+		let mut buf = Vec::new();
+		value.encode_value(&mut buf);
+		buf
+	}
+
+	#[test]
+	fn test_integer_codecs() {
+		assert_eq!(encode_key(&0x0102u16), vec![1, 2]);
+		assert_eq!(encode_key(&0x01020304u32), vec![1, 2, 3, 4]);
+		assert_eq!(encode_key(&-1i32), vec![0x7f, 0xff, 0xff, 0xff]);
+		assert_eq!(encode_key(&0i32), vec![0x80, 0, 0, 0]);
+		assert_eq!(i64::decode_key(&encode_key(&i64::min_value())).unwrap(), i64::min_value());
+		assert_eq!(u64::decode_value(&encode_value(&42u64)).unwrap(), 42);
+		assert_eq!(
+			*u32::decode_key(&[1, 2, 3]).unwrap_err().kind(),
+			ErrorKind::InvalidEncoding("expected 4 bytes, got 3".into())
+		);
+	}
+
+	#[test]
+	fn test_array_and_tuple_codecs() {
+		assert_eq!(<(u8, [u8; 3], u16)>::LEN, 6);
+		let key = (7u8, *b"abc", 0x0102u16);
+		let encoded = encode_key(&key);
+		assert_eq!(encoded, vec![7, b'a', b'b', b'c', 1, 2]);
+		assert_eq!(<(u8, [u8; 3], u16)>::decode_key(&encoded).unwrap(), key);
+		assert_eq!(<(u8, [u8; 3], u16)>::decode_value(&encoded).unwrap(), key);
+		assert!(<(u8, [u8; 3], u16)>::decode_key(&encoded[1..]).is_err());
+		assert_eq!(Vec::<u8>::decode_value(b"value").unwrap(), b"value".to_vec());
+	}
+
+	quickcheck! {
+		fn quickcheck_u64_key_preserves_order(a: u64, b: u64) -> TestResult {
+			TestResult::from_bool(a.cmp(&b) == encode_key(&a).cmp(&encode_key(&b)))
+		}
+	}
+
+	quickcheck! {
+		fn quickcheck_i64_key_preserves_order(a: i64, b: i64) -> TestResult {
+			TestResult::from_bool(a.cmp(&b) == encode_key(&a).cmp(&encode_key(&b)))
+		}
+	}
+
+	quickcheck! {
+		fn quickcheck_tuple_key_preserves_order(a: (i32, u16), b: (i32, u16)) -> TestResult {
+			TestResult::from_bool(a.cmp(&b) == encode_key(&a).cmp(&encode_key(&b)))
+		}
+	}
+}
@@ -1,16 +1,16 @@
 use std::cmp::Ordering;
 use std::collections::btree_map;
 use std::collections::BTreeMap;
-use std::fs::{self, File};
 use std::io::{self, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::slice;
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
-use memmap::{Mmap, Protection};
 
-use error::Result;
+use durability::Durability;
+use error::{Corruption, Result};
 use transaction::Operation;
+use vfs::{LogFile, Mapping, Vfs};
 
 /// A data file representing all the data for a given prefix. All the data for this prefix exists in
 /// this file because there was a high threshold of collisions.
@@ -32,8 +32,10 @@ pub struct Collision {
 	index: BTreeMap<LogSlice, IndexEntry>,
 	prefix: u32,
 	path: PathBuf,
-	mmap: Mmap,
-	file: File,
+	mmap: Mapping,
+	file: LogFile,
+	vfs: Vfs,
+	durability: Durability,
 }
 
 #[derive(Debug)]
@@ -44,17 +46,55 @@ pub struct IndexEntry {
 }
 
 impl Collision {
-	fn collision_file_path<P: AsRef<Path>>(path: P, prefix: u32) -> PathBuf {
-		let collision_file_name = format!("collision-{}.log", prefix);
+	const FILE_PREFIX: &'static str = "collision-";
+	const FILE_EXTENSION: &'static str = ".log";
+
+	pub fn collision_file_path<P: AsRef<Path>>(path: P, prefix: u32) -> PathBuf {
+		let collision_file_name = format!("{}{}{}", Self::FILE_PREFIX, prefix, Self::FILE_EXTENSION);
 		path.as_ref().join(collision_file_name)
 	}
 
-	fn build_index(data: &[u8]) -> Result<BTreeMap<LogSlice, IndexEntry>> {
+	/// Returns the prefixes of all collision files found in the given directory.
+	pub fn file_prefixes<P: AsRef<Path>>(vfs: &Vfs, path: P) -> Result<Vec<u32>> {
+		let mut prefixes = Vec::new();
+
+		for entry in vfs.read_dir(path.as_ref())? {
+			let file_name = match entry.file_name() {
+				Some(file_name) => file_name.to_string_lossy().into_owned(),
+				None => continue,
+			};
+
+			if !file_name.starts_with(Self::FILE_PREFIX) || !file_name.ends_with(Self::FILE_EXTENSION) {
+				continue;
+			}
+
+			let prefix = &file_name[Self::FILE_PREFIX.len()..file_name.len() - Self::FILE_EXTENSION.len()];
+			if let Ok(prefix) = prefix.parse() {
+				prefixes.push(prefix);
+			}
+		}
+
+		prefixes.sort();
+		Ok(prefixes)
+	}
+
+	/// Removes the collision file for the given prefix if it exists.
+	/// Removal is not synced to the directory.
+	pub fn remove<P: AsRef<Path>>(vfs: &Vfs, path: P, prefix: u32) -> Result<()> {
+		match vfs.remove_file(&Self::collision_file_path(path, prefix)) {
+			Ok(_) => Ok(()),
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	fn build_index(data: &[u8], path: &Path) -> Result<BTreeMap<LogSlice, IndexEntry>> {
 		let log = LogIterator::new(data);
 
 		let mut index = BTreeMap::new();
 
-		for (position, entry) in log {
+		for entry in log {
+			let (position, entry) = entry.map_err(|corruption| corruption.in_file(path))?;
 			if let Some(value) = entry.value {
 				let position = position as u64;
 				let size = LogEntry::len(&entry.key, &value);
@@ -71,55 +111,61 @@ impl Collision {
 		Ok(index)
 	}
 
+	/// Reads the live entry at the position the index points to.
+	fn read_indexed<'a>(data: &'a [u8], path: &Path, position: u64, key: Option<&[u8]>) -> Result<(&'a [u8], &'a [u8])> {
+		let position = position as usize;
+		let corrupted = |msg: &str| Corruption::new(position, msg).in_file(path);
+
+		let entry = data.get(position..)
+			.ok_or_else(|| corrupted("Index points beyond the end of the file"))
+			.and_then(|data| LogEntry::read(data).map_err(|corruption| corruption.shifted(position).in_file(path)))?
+			.1;
+
+		if key.map_or(false, |key| key != entry.key) {
+			return Err(corrupted("Index points to an entry with a different key"));
+		}
+
+		match entry.value {
+			Some(value) => Ok((entry.key, value)),
+			None => Err(corrupted("Index points to a deleted entry")),
+		}
+	}
+
 	/// Create a new collision file for the given prefix.
-	pub fn create<P: AsRef<Path>>(path: P, prefix: u32) -> Result<Collision> {
+	pub fn create<P: AsRef<Path>>(vfs: &Vfs, path: P, prefix: u32, durability: Durability) -> Result<Collision> {
 		// Create directories if necessary.
-		fs::create_dir_all(&path)?;
+		vfs.create_dir_all(path.as_ref())?;
 
+		let dir = path.as_ref().to_path_buf();
 		let path = Self::collision_file_path(path, prefix);
-		let mut file = fs::OpenOptions::new()
-			.write(true)
-			.create_new(true)
-			.open(&path)?;
-
 		// TODO: grow file in chunks to avoid rebuilding index on every mutable operation
-		file.set_len(1)?;
-		file.flush()?;
-		let mmap = Mmap::open_path(&path, Protection::Read)?;
+		let file = vfs.create_log(&path, durability)?;
+		vfs.sync_dir(&dir, durability)?;
+		let mmap = vfs.map(&path)?;
 
 		let index = BTreeMap::new();
 
-		Ok(Collision { index, prefix, path, mmap, file })
+		Ok(Collision { index, prefix, path, mmap, file, vfs: vfs.clone(), durability })
 	}
 
 	/// Open collision file if it exists, returns `None` otherwise.
-	pub fn open<P: AsRef<Path>>(path: P, prefix: u32) -> Result<Option<Collision>> {
+	pub fn open<P: AsRef<Path>>(vfs: &Vfs, path: P, prefix: u32, durability: Durability) -> Result<Option<Collision>> {
 		let path = Self::collision_file_path(path, prefix);
-		let open_options = fs::OpenOptions::new()
-			.append(true)
-			.open(&path);
-
-		let file = match open_options {
+		let file = match vfs.open_log(&path) {
 			Ok(file) => file,
 			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
 			Err(err) => return Err(err.into()),
 		};
 
-		let mmap = Mmap::open_path(&path, Protection::Read)?;
-		let index = {
-			let data = unsafe { &mmap.as_slice() };
-			Collision::build_index(data)?
-		};
+		let mmap = vfs.map(&path)?;
+		let index = Collision::build_index(mmap.as_slice(), &path)?;
 
-		Ok(Some(Collision { index, prefix, path, mmap, file }))
+		Ok(Some(Collision { index, prefix, path, mmap, file, vfs: vfs.clone(), durability }))
 	}
 
 	fn rebuild_index(&mut self) -> Result<()> {
-		let mmap = Mmap::open_path(&self.path, Protection::Read)?;
-		let index = {
-			let data = unsafe { &mmap.as_slice() };
-			Collision::build_index(data)?
-		};
+		let mmap = self.vfs.map(&self.path)?;
+		let index = Collision::build_index(mmap.as_slice(), &self.path)?;
 
 		self.mmap = mmap;
 		self.index = index;
@@ -136,16 +182,12 @@ impl Collision {
 		// FIXME: have write return the `LogSlice` to avoid re-reading the entry
 		let position = LogEntry::write(&mut self.file, key, value)?;
 		let size = LogEntry::len(&key, &value);
+		self.file.sync(self.durability)?;
 
 		self.rebuild_index()?;
 
-		let data = unsafe { &self.mmap.as_slice()[position as usize..] };
-		let (_, entry) = LogEntry::read(data);
-
-		assert!(key == entry.key,
-				"found incorrect key after insertion into log");
-
-		self.index.insert(LogSlice::new(entry.key), IndexEntry { position, size });
+		let (key, _) = Collision::read_indexed(self.mmap.as_slice(), &self.path, position, Some(key))?;
+		self.index.insert(LogSlice::new(key), IndexEntry { position, size });
 
 		Ok(())
 	}
@@ -154,6 +196,7 @@ impl Collision {
 	pub fn delete(&mut self, key: &[u8]) -> Result<()> {
 		if let Some(_) = self.index.remove(&LogSlice::new(key)) {
 			LogEntry::write_deleted(&mut self.file, key)?;
+			self.file.sync(self.durability)?;
 			self.rebuild_index()?;
 		}
 
@@ -163,12 +206,8 @@ impl Collision {
 	/// Lookup a value associated with the given `key` in the collision file.
 	pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>> {
 		if let Some(entry) = self.index.get(&LogSlice::new(key)) {
-			let data = unsafe { &self.mmap.as_slice()[entry.position as usize..] };
-			let (_, entry) = LogEntry::read(data);
-			assert!(key == entry.key,
-					"index pointed to log entry with different key");
-
-			Ok(Some(entry.value.expect("index only points to live entries; qed")))
+			let (_, value) = Collision::read_indexed(self.mmap.as_slice(), &self.path, entry.position, Some(key))?;
+			Ok(Some(value))
 		} else {
 			Ok(None)
 		}
@@ -177,9 +216,27 @@ impl Collision {
 	/// Applies the given `Operation` by dispatching to the `insert` or `delete` methods.
 	pub fn apply(&mut self, op: Operation) -> Result<()> {
 		match op {
-			Operation::Delete(key) => self.delete(key),
-			Operation::Insert(key, value) => self.insert(key, value),
+			Operation::Delete(key) |
+			Operation::DeleteIfPresent(key) => self.delete(key),
+			Operation::Insert(key, value) |
+			Operation::InsertIfAbsent(key, value) |
+			Operation::UpdateIfEquals(key, _, value) => self.insert(key, value),
+			Operation::Merge(..) => unreachable!("merge operands are resolved before they are flushed; qed"),
+		}
+	}
+
+	/// Returns the number of live key-value pairs stored in the collision file.
+	pub fn len(&self) -> usize {
+		self.index.len()
+	}
+
+	/// Deletes the underlying collision file.
+	pub fn delete_file(self) -> Result<()> {
+		self.vfs.remove_file(&self.path)?;
+		if let Some(dir) = self.path.parent() {
+			self.vfs.sync_dir(dir, self.durability)?;
 		}
+		Ok(())
 	}
 
 	/// Return the `prefix` that this collision file refers to, i.e. all keys stored in this file
@@ -190,23 +247,25 @@ impl Collision {
 
 	/// Returns an iterator over all key-value pairs in the collision file ordered by key.
 	pub fn iter<'a>(&'a self) -> Result<CollisionLogIterator> {
-		let data = unsafe { &self.mmap.as_slice() };
+		let data = self.mmap.as_slice();
 
-		CollisionLogIterator::new(data, self.index.values())
+		CollisionLogIterator::new(data, &self.path, self.index.values())
 	}
 }
 
 pub struct CollisionLogIterator<'a> {
 	data: &'a [u8],
+	path: &'a Path,
 	index_iter: btree_map::Values<'a, LogSlice, IndexEntry>,
 }
 
 impl<'a> CollisionLogIterator<'a> {
 	fn new(
 		data: &'a [u8],
+		path: &'a Path,
 		index_iter: btree_map::Values<'a, LogSlice, IndexEntry>,
 	) -> Result<CollisionLogIterator<'a>> {
-		Ok(CollisionLogIterator { data, index_iter })
+		Ok(CollisionLogIterator { data, path, index_iter })
 	}
 }
 
@@ -214,20 +273,8 @@ impl<'a> Iterator for CollisionLogIterator<'a> {
 	type Item = Result<(&'a [u8], &'a [u8])>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		self.index_iter.next().and_then(|entry| {
-			let read_next = || {
-				let data = &self.data[entry.position as usize..];
-				let (_, entry) = LogEntry::read(data);
-
-				Ok((entry.key,
-					entry.value.expect("index only points to live entries; qed")))
-			};
-
-			match read_next() {
-				Err(err) => Some(Err(err)),
-				Ok(res) => Some(Ok(res)),
-			}
-		})
+		let (data, path) = (self.data, self.path);
+		self.index_iter.next().map(|entry| Collision::read_indexed(data, path, entry.position, None))
 	}
 }
 
@@ -268,6 +315,10 @@ impl PartialEq for LogSlice {
 
 impl Eq for LogSlice {}
 
+// `LogSlice` only points to the memory of the memmap owned by the same `Collision`.
+unsafe impl Send for LogSlice {}
+unsafe impl Sync for LogSlice {}
+
 impl LogSlice {
 	fn new(data: &[u8]) -> LogSlice {
 		LogSlice {
@@ -287,46 +338,58 @@ impl<'a> LogEntry<'a> {
 	// FIXME: validate max value size
 
 	fn write_deleted<W: Write + Seek>(writer: &mut W, key: &[u8]) -> Result<u64> {
-		let position = writer.seek(SeekFrom::Current(0))?;
-		writer.write_u32::<LittleEndian>(key.len() as u32)?;
-		writer.write_all(key)?;
-		writer.write_u32::<LittleEndian>(LogEntry::ENTRY_TOMBSTONE)?;
-		Ok(position)
+		let mut entry = Vec::with_capacity(key.len() + 8);
+		entry.write_u32::<LittleEndian>(key.len() as u32)?;
+		entry.extend_from_slice(key);
+		entry.write_u32::<LittleEndian>(LogEntry::ENTRY_TOMBSTONE)?;
+		LogEntry::append(writer, &entry)
 	}
 
 	fn write<W: Write + Seek>(writer: &mut W, key: &[u8], value: &[u8]) -> Result<u64> {
+		let mut entry = Vec::with_capacity(LogEntry::len(key, value));
+		entry.write_u32::<LittleEndian>(key.len() as u32)?;
+		entry.extend_from_slice(key);
+		entry.write_u32::<LittleEndian>(value.len() as u32)?;
+		entry.extend_from_slice(value);
+		LogEntry::append(writer, &entry)
+	}
+
+	/// Writes the serialized entry with a single write, so a crashed process never leaves
+	/// a partially written entry behind. Returns the position of the entry.
+	fn append<W: Write + Seek>(writer: &mut W, entry: &[u8]) -> Result<u64> {
 		let position = writer.seek(SeekFrom::Current(0))?;
-		writer.write_u32::<LittleEndian>(key.len() as u32)?;
-		writer.write_all(key)?;
-		writer.write_u32::<LittleEndian>(value.len() as u32)?;
-		writer.write_all(value)?;
+		writer.write_all(entry)?;
 		Ok(position)
 	}
 
-	// FIXME: should return Result
-	fn read(data: &[u8]) -> (usize, LogEntry) {
-		// FIXME: add sanity limits for key_size and value_size
-		//        this is to avoid issues if the file gets corruped
-		let mut offset = 4;
-		let key_size = LittleEndian::read_u32(&data[..offset]) as usize;
+	/// Reads the entry at the beginning of the data. Returns its length and the entry.
+	fn read(data: &[u8]) -> ::std::result::Result<(usize, LogEntry), Corruption> {
+		let read_size = |offset: usize| data.get(offset..offset + 4)
+			.map(|size| LittleEndian::read_u32(size))
+			.ok_or_else(|| Corruption::new(offset, "Truncated entry size"));
+		let read_slice = |offset: usize, size: usize| data.get(offset..offset + size)
+			.ok_or_else(|| Corruption::new(offset, format!("Entry of {} bytes is truncated", size)));
 
-		let key = &data[offset..offset + key_size];
+		let mut offset = 0;
+		let key_size = read_size(offset)? as usize;
+		offset += 4;
+
+		let key = read_slice(offset, key_size)?;
 		offset += key_size;
 
-		let value_size = LittleEndian::read_u32(&data[offset..]) as usize;
+		let value_size = read_size(offset)?;
 		offset += 4;
 
 		let value =
-			if value_size == LogEntry::ENTRY_TOMBSTONE as usize {
+			if value_size == LogEntry::ENTRY_TOMBSTONE {
 				None
 			} else {
-				let v = Some(&data[offset..offset + value_size]);
-				offset += value_size;
+				let v = Some(read_slice(offset, value_size as usize)?);
+				offset += value_size as usize;
 				v
 			};
 
-
-		(offset, LogEntry { key, value })
+		Ok((offset, LogEntry { key, value }))
 	}
 
 	fn len(key: &[u8], value: &[u8]) -> usize {
@@ -347,17 +410,23 @@ impl<'a> LogIterator<'a> {
 }
 
 impl<'a> Iterator for LogIterator<'a> {
-	type Item = (usize, LogEntry<'a>);
+	type Item = ::std::result::Result<(usize, LogEntry<'a>), Corruption>;
 
-	fn next(&mut self) -> Option<(usize, LogEntry<'a>)> {
+	fn next(&mut self) -> Option<Self::Item> {
 		if self.position >= self.data.len() { None }
 		else {
-			let (read, entry) = LogEntry::read(&self.data[self.position..]);
 			let position = self.position;
-
-			self.position += read;
-
-			Some((position, entry))
+			match LogEntry::read(&self.data[position..]) {
+				Ok((read, entry)) => {
+					self.position += read;
+					Some(Ok((position, entry)))
+				},
+				Err(corruption) => {
+					// the rest of the log can't be read
+					self.position = self.data.len();
+					Some(Err(corruption.shifted(position)))
+				},
+			}
 		}
 	}
 }
@@ -366,28 +435,51 @@ impl<'a> Iterator for LogIterator<'a> {
 mod tests {
 	extern crate tempdir;
 
-	use super::Collision;
+	use std::fs;
+	use std::io::Cursor;
+	use std::path::Path;
+	use std::sync::Arc;
+	use durability::Durability;
+	use error::ErrorKind;
+	use super::{Collision, LogEntry, LogIterator};
+	use vfs::{Fault, MemoryFs, Vfs};
 
 	#[test]
 	fn test_roundtrip() {
 		let temp = tempdir::TempDir::new("test_roundtrip").unwrap();
 
 		{
-			let mut collision = Collision::create(temp.path(), 0).unwrap();
+			let mut collision = Collision::create(&Vfs::Disk, temp.path(), 0, Durability::Full).unwrap();
 			collision.insert(b"hello", b"world").unwrap();
 			assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
 		}
 
-		let collision = Collision::open(temp.path(), 0).unwrap().unwrap();
+		let collision = Collision::open(&Vfs::Disk, temp.path(), 0, Durability::Full).unwrap().unwrap();
 		assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
 	}
 
+	#[test]
+	fn should_insert_after_reopen() {
+		let temp = tempdir::TempDir::new("should_insert_after_reopen").unwrap();
+
+		for vfs in &[Vfs::Disk, Vfs::memory()] {
+			vfs.create_dir_all(temp.path()).unwrap();
+			Collision::create(vfs, temp.path(), 0, Durability::Full).unwrap().insert(b"hello", b"world").unwrap();
+
+			let mut collision = Collision::open(vfs, temp.path(), 0, Durability::Full).unwrap().unwrap();
+			collision.insert(b"hi", b"there").unwrap();
+			assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
+			assert_eq!(collision.get(b"hi").unwrap().unwrap(), b"there");
+			collision.delete_file().unwrap();
+		}
+	}
+
 	#[test]
 	fn test_iter() {
 		let temp = tempdir::TempDir::new("test_roundtrip").unwrap();
 
 		{
-			let mut collision = Collision::create(temp.path(), 0).unwrap();
+			let mut collision = Collision::create(&Vfs::Disk, temp.path(), 0, Durability::Full).unwrap();
 			collision.insert(b"0", b"0").unwrap();
 			collision.insert(b"2", b"2").unwrap();
 			collision.insert(b"1", b"1").unwrap();
@@ -396,7 +488,7 @@ mod tests {
 			collision.delete(b"4").unwrap();
 		}
 
-		let collision = Collision::open(temp.path(), 0).unwrap().unwrap();
+		let collision = Collision::open(&Vfs::Disk, temp.path(), 0, Durability::Full).unwrap().unwrap();
 		let collision: Vec<_> = collision.iter().unwrap().flat_map(|entry| entry.ok()).collect();
 
 		let expected: Vec<(&[u8], &[u8])> =
@@ -404,4 +496,110 @@ mod tests {
 
 		assert_eq!(collision, expected);
 	}
+
+	#[test]
+	fn test_file_prefixes() {
+		let temp = tempdir::TempDir::new("test_file_prefixes").unwrap();
+
+		let mut collision = Collision::create(&Vfs::Disk, temp.path(), 97, Durability::Full).unwrap();
+		Collision::create(&Vfs::Disk, temp.path(), 3, Durability::Full).unwrap();
+		assert_eq!(Collision::file_prefixes(&Vfs::Disk, temp.path()).unwrap(), vec![3, 97]);
+
+		collision.insert(b"aaa", b"001").unwrap();
+		assert_eq!(collision.len(), 1);
+		collision.delete_file().unwrap();
+		Collision::remove(&Vfs::Disk, temp.path(), 3).unwrap();
+		Collision::remove(&Vfs::Disk, temp.path(), 3).unwrap();
+
+		assert!(Collision::file_prefixes(&Vfs::Disk, temp.path()).unwrap().is_empty());
+		assert!(Collision::open(&Vfs::Disk, temp.path(), 97, Durability::Full).unwrap().is_none());
+	}
+
+	/// Returns a collision file in memory holding a single entry.
+	fn collision_in_memory() -> (Arc<MemoryFs>, Vfs, Collision) {
+		let memory = Arc::new(MemoryFs::default());
+		let vfs = Vfs::Memory(memory.clone());
+		vfs.create_dir_all(Path::new("db")).unwrap();
+		let mut collision = Collision::create(&vfs, "db", 0, Durability::Full).unwrap();
+		collision.insert(b"hello", b"world").unwrap();
+		(memory, vfs, collision)
+	}
+
+	#[test]
+	fn should_recover_from_crash_during_insert() {
+		let (memory, _, mut collision) = collision_in_memory();
+		let start = memory.operations();
+		collision.insert(b"hi", b"there").unwrap();
+		let operations = memory.operations() - start;
+
+		for crash in 0..operations {
+			let (memory, vfs, mut collision) = collision_in_memory();
+			memory.inject(memory.operations() + crash, Fault::Crash);
+			assert!(collision.insert(b"hi", b"there").is_err());
+			drop(collision);
+			memory.restart();
+
+			let mut collision = Collision::open(&vfs, "db", 0, Durability::Full).unwrap().unwrap();
+			assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
+			let inserted = collision.get(b"hi").unwrap().map(|value| value.to_vec());
+			assert!(inserted.is_none() || inserted == Some(b"there".to_vec()), "crash at operation {}", crash);
+
+			collision.insert(b"hey", b"you").unwrap();
+			let collision = Collision::open(&vfs, "db", 0, Durability::Full).unwrap().unwrap();
+			assert_eq!(collision.get(b"hey").unwrap().unwrap(), b"you");
+		}
+	}
+
+	#[test]
+	fn should_reject_truncated_log() {
+		let temp = tempdir::TempDir::new("should_reject_truncated_log").unwrap();
+
+		{
+			let mut collision = Collision::create(&Vfs::Disk, temp.path(), 0, Durability::Full).unwrap();
+			collision.insert(b"hello", b"world").unwrap();
+			collision.insert(b"hi", b"there").unwrap();
+		}
+
+		let path = Collision::collision_file_path(temp.path(), 0);
+		let data = fs::read(&path).unwrap();
+		// the first entry ends after 4 + 5 + 4 + 5 bytes, so truncating there leaves a valid log
+		for len in (1..data.len()).filter(|&len| len != 18) {
+			fs::write(&path, &data[..len]).unwrap();
+			match *Collision::open(&Vfs::Disk, temp.path(), 0, Durability::Full).unwrap_err().kind() {
+				ErrorKind::Corrupted(ref corrupted, offset, _) => {
+					assert_eq!(corrupted, &path);
+					assert!(offset <= len as u64);
+					assert!((len < 18) == (offset < 18));
+				},
+				ref kind => panic!("unexpected error {:?}", kind),
+			}
+		}
+	}
+
+	#[test]
+	fn should_reject_invalid_index_entries() {
+		let path = Path::new("collision-0.log");
+		let mut cursor = Cursor::new(Vec::new());
+		LogEntry::write(&mut cursor, b"hello", b"world").unwrap();
+		let position = LogEntry::write_deleted(&mut cursor, b"hi").unwrap();
+		let data = cursor.into_inner();
+
+		assert_eq!(Collision::read_indexed(&data, path, 0, Some(b"hello")).unwrap(), (&b"hello"[..], &b"world"[..]));
+		assert_eq!(
+			*Collision::read_indexed(&data, path, 0, Some(b"jello")).unwrap_err().kind(),
+			ErrorKind::Corrupted(path.into(), 0, "Index points to an entry with a different key".into())
+		);
+		assert_eq!(
+			*Collision::read_indexed(&data, path, position, None).unwrap_err().kind(),
+			ErrorKind::Corrupted(path.into(), position, "Index points to a deleted entry".into())
+		);
+		assert!(Collision::read_indexed(&data, path, 1000, None).is_err());
+		assert!(Collision::read_indexed(&data, path, 3, None).is_err());
+	}
+
+	quickcheck! {
+		fn quickcheck_reading_garbled_log_never_panics(data: Vec<u8>) -> bool {
+			LogIterator::new(&data).all(|entry| entry.map(|(position, _)| position < data.len()).unwrap_or(true))
+		}
+	}
 }
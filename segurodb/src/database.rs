@@ -1,27 +1,30 @@
 use std::cmp::Ordering;
-use std::collections::{btree_set, BTreeMap};
-use std::collections::btree_map::Entry;
-use std::io::Write;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
 use std::path::{PathBuf, Path};
-use std::{cmp, fs};
+use std::{cmp, fs, str, vec};
 use std::fs::File;
+use std::time::Instant;
 
+use byteorder::{ByteOrder, LittleEndian};
 use fs2::FileExt;
-use memmap::{Mmap, Protection};
 use itertools::Itertools;
-use itertools::EitherOrBoth;
-
-use collision::Collision;
-use error::{ErrorKind, Result};
-use find;
-use find::RecordIterator;
-use flush::Flush;
-use journal::Journal;
-use key::Key;
-use metadata::{self, Metadata};
-use options::{Options, InternalOptions};
+use parking_lot::Mutex;
+
+use error::{Error, ErrorKind, Result};
+use event::{DatabaseOpened, Event, EventListener, FlushReplayed, JournalEraCorrupted, LockContended, RecoveryAction};
+use expiry::{self, EXPIRY_SIZE};
+use index::{Index, IndexIterator};
+use journal::{Changes, Journal, JournalEra, JournalOperation, MergeBase, PreparedTransaction};
+use keyspace::Keyspace;
+use metrics::{self, names};
+use migration;
+use options::{self, CompactionBudget, Options, InternalOptions, ValuesLen};
 use record::Record;
-use transaction::{Operation, Transaction};
+use subscription::{Change, KeyFilter, Subscriber, Subscription};
+use transaction::{Operation, Transaction, DEFAULT_KEYSPACE};
+use vfs::Vfs;
 
 /// A database record value.
 #[derive(Debug, PartialEq)]
@@ -30,6 +33,8 @@ pub enum Value<'a> {
 	Raw(&'a [u8]),
 	/// DB record
 	Record(Record<'a>),
+	/// Value resolved from merge operands
+	Merged(Vec<u8>),
 }
 
 impl<'a> Value<'a> {
@@ -43,6 +48,7 @@ impl<'a> Value<'a> {
 				record.read_value(&mut v);
 				v
 			},
+			Value::Merged(ref value) => value.clone(),
 		}
 	}
 
@@ -51,6 +57,27 @@ impl<'a> Value<'a> {
 		match *self {
 			Value::Raw(ref slice) => Some(slice),
 			Value::Record(ref record) => record.value_raw_slice(),
+			Value::Merged(ref value) => Some(value),
+		}
+	}
+
+	/// Splits the expiry time off a value stored with `Options::ttl`.
+	/// Returns `None` if the value is shorter than the expiry time.
+	pub(crate) fn split_expiry(self) -> Option<(u64, Value<'a>)> {
+		match self {
+			Value::Raw(slice) if slice.len() < EXPIRY_SIZE => None,
+			Value::Raw(slice) => Some((expiry::read(&slice[..EXPIRY_SIZE]), Value::Raw(&slice[EXPIRY_SIZE..]))),
+			Value::Record(record) => {
+				let (head, record) = record.split_value(EXPIRY_SIZE)?;
+				let mut expiry = [0; EXPIRY_SIZE];
+				head.copy_to_slice(&mut expiry);
+				Some((expiry::read(&expiry), Value::from(record)))
+			},
+			Value::Merged(ref value) if value.len() < EXPIRY_SIZE => None,
+			Value::Merged(mut value) => {
+				let rest = value.split_off(EXPIRY_SIZE);
+				Some((expiry::read(&value), Value::Merged(rest)))
+			},
 		}
 	}
 }
@@ -60,6 +87,7 @@ impl<'a, T: AsRef<[u8]>> PartialEq<T> for Value<'a> {
 		match *self {
 			Value::Raw(slice) => slice == other.as_ref(),
 			Value::Record(ref record) => record.value_is_equal(other.as_ref()),
+			Value::Merged(ref value) => value.as_slice() == other.as_ref(),
 		}
 	}
 }
@@ -73,35 +101,64 @@ impl<'a> From<Record<'a>> for Value<'a> {
 	}
 }
 
+/// Value of a key written by operations which are not committed yet, `None` if the key was deleted.
+/// Values with merged operands are owned.
+pub(crate) type PendingValue<'a> = Option<Cow<'a, [u8]>>;
+
 /// A top-level database API.
 #[derive(Debug)]
 pub struct Database {
 	path: PathBuf,
-	options: InternalOptions,
 	journal: Journal,
-	metadata: Metadata,
-	metadata_mmap: Mmap,
-	collisions: BTreeMap<u32, Collision>,
-	mmap: Mmap,
-	lock_file: File,
+	/// Keyspaces by name. Keys of the database itself are stored in the keyspace with an empty name.
+	keyspaces: BTreeMap<Vec<u8>, Keyspace>,
+	/// Sequence numbers of the first eras whose journaled entries are applied to the indexes
+	/// by keyspace name. Entries journaled before an index was built are already in its data file.
+	index_first_eras: HashMap<Vec<u8>, u64>,
+	/// Subscribers notified about committed changes.
+	subscribers: Mutex<Vec<Subscriber>>,
+	/// Lock held by databases on the disk.
+	lock_file: Option<File>,
 }
 
 impl Database {
-	const DB_FILE: &'static str = "data.db";
-	const META_FILE: &'static str = "meta.db";
 	const LOCK_FILE: &'static str = "LOCK";
-
-	fn acquire_lock_file<P: AsRef<Path>>(path: P) -> Result<File> {
+	/// Path of the files of databases created with `in_memory`.
+	const MEMORY_PATH: &'static str = ":memory:";
+	pub(crate) const KEYSPACES_DIR: &'static str = "keyspaces";
+	pub(crate) const INDEXES_DIR: &'static str = "indexes";
+	/// Prefix of the names of the keyspaces storing secondary indexes.
+	const INDEX_KEYSPACE_PREFIX: &'static str = "index/";
+	/// Stores the sequence number of the first era whose entries are applied to a built index.
+	const INDEX_FIRST_ERA_FILE: &'static str = "FIRST_ERA";
+
+	fn acquire_lock_file<P: AsRef<Path>>(path: P, listener: &EventListener) -> Result<File> {
 		let lock_file_path = path.as_ref().join(Self::LOCK_FILE);
 		let lock_file = fs::OpenOptions::new()
 			.write(true)
 			.create(true)
 			.open(&lock_file_path)?;
-		lock_file.try_lock_exclusive().map_err(|_| ErrorKind::DatabaseLocked(lock_file_path))?;
+		if lock_file.try_lock_exclusive().is_err() {
+			listener.on_event(&Event::LockContended(LockContended { path: lock_file_path.clone() }));
+			return Err(ErrorKind::DatabaseLocked(lock_file_path).into());
+		}
 
 		Ok(lock_file)
 	}
 
+	/// Reports the journal corruption the error is caused by, if any.
+	fn report_corruption(listener: &EventListener, err: Error) -> Error {
+		let corrupted = match *err.kind() {
+			ErrorKind::CorruptedJournal(ref path, ref reason) => Some((path.clone(), reason.clone())),
+			ErrorKind::Corrupted(ref path, offset, ref msg) => Some((path.clone(), format!("{} at offset {}", msg, offset))),
+			_ => None,
+		};
+		if let Some((path, reason)) = corrupted {
+			listener.on_event(&Event::JournalEraCorrupted(JournalEraCorrupted { path, reason }));
+		}
+		err
+	}
+
 	/// Creates new database at given location.
 	pub fn create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
 		let options = InternalOptions::from_external(options)?;
@@ -110,350 +167,925 @@ impl Database {
 		fs::create_dir_all(&path)?;
 
 		// Create/Acquire Lock file.
-		let lock_file = Self::acquire_lock_file(&path)?;
+		let lock_file = Self::acquire_lock_file(&path, &*options.external.event_listener)?;
 
-		// Create DB file.
-		{
-			let db_file_path = path.as_ref().join(Self::DB_FILE);
-			let mut file = fs::OpenOptions::new()
-				.write(true)
-				.create_new(true)
-				.open(&db_file_path)?;
-			file.set_len(options.initial_db_size)?;
-			file.flush()?;
-		}
+		// The version is written first, so a database interrupted while being created
+		// is never mistaken for one written in an older format.
+		migration::write_version(&options.vfs, path.as_ref(), migration::FORMAT_VERSION, options.external.durability)?;
+		Keyspace::create(&path, &options)?;
 
-		// Create Metadata file.
-		{
-			let meta_file_path = path.as_ref().join(Self::META_FILE);
-			let mut file = fs::OpenOptions::new()
-				.write(true)
-				.create_new(true)
-				.open(&meta_file_path)?;
-			file.set_len(metadata::bytes::len(options.external.key_index_bits) as u64)?;
-			file.flush()?;
-		}
+		Self::open_internal(path, Some(lock_file), options)
+	}
+
+	/// Creates a new database whose files are stored in heap buffers instead of the disk.
+	///
+	/// The database behaves like one created with `create`, but nothing is ever synced,
+	/// so `durability` of the options is ignored, and all of the data is gone once
+	/// the database is dropped.
+	pub fn in_memory(options: Options) -> Result<Self> {
+		Self::create_in_memory(InternalOptions::from_external(options)?.with_vfs(Vfs::memory()))
+	}
+
+	/// Creates a new database in the file system in memory of the options.
+	fn create_in_memory(options: InternalOptions) -> Result<Self> {
+		let path = Path::new(Self::MEMORY_PATH);
 
-		Self::open_internal(path, lock_file, options.external)
+		options.vfs.create_dir_all(path)?;
+		migration::write_version(&options.vfs, path, migration::FORMAT_VERSION, options.external.durability)?;
+		Keyspace::create(path, &options)?;
+
+		Self::open_internal(path, None, options)
 	}
 
 	/// Opens an existing DB at given location.
 	pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
-		let lock_file = Self::acquire_lock_file(&path)?;
-		Self::open_internal(path, lock_file, options)
+		let options = InternalOptions::from_external(options)?;
+		let lock_file = Self::acquire_lock_file(&path, &*options.external.event_listener)?;
+		Self::open_internal(path, Some(lock_file), options)
 	}
 
-	fn open_internal<P: AsRef<Path>>(path: P, lock_file: File, options: Options) -> Result<Self> {
-		let options = InternalOptions::from_external(options)?;
-		let journal = Journal::open(&path)?;
+	fn open_internal<P: AsRef<Path>>(path: P, lock_file: Option<File>, options: InternalOptions) -> Result<Self> {
+		let listener = options.external.event_listener.clone();
+		migration::migrate(
+			&options.vfs,
+			path.as_ref(),
+			migration::MIGRATIONS,
+			migration::FORMAT_VERSION,
+			options.external.durability,
+			&*listener,
+		)?;
 
-		let db_file_path = path.as_ref().join(Self::DB_FILE);
-		let mut mmap = Mmap::open_path(db_file_path, Protection::ReadWrite)?;
+		let journal = Journal::open(&options.vfs, &path, options.external.durability, options.external.journal_archive.clone())
+			.map_err(|err| Self::report_corruption(&*listener, err))?;
 
-		let meta_file_path = path.as_ref().join(Self::META_FILE);
-		let mut metadata_mmap = Mmap::open_path(meta_file_path, Protection::ReadWrite)?;
+		let mut keyspaces = BTreeMap::new();
+		keyspaces.insert(DEFAULT_KEYSPACE.to_vec(), Keyspace::open(&path, options)?);
 
-		let mut metadata = metadata::bytes::read(unsafe { metadata_mmap.as_slice() }, options.external.key_index_bits);
+		let mut db = Database {
+			path: path.as_ref().to_owned(),
+			journal,
+			keyspaces,
+			index_first_eras: HashMap::new(),
+			subscribers: Mutex::new(Vec::new()),
+			lock_file,
+		};
 
-		if let Some(flush) = Flush::open(path.as_ref(), options.external.key_index_bits)? {
-			flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { metadata_mmap.as_mut_slice() }, &mut metadata);
-			mmap.flush()?;
-			metadata_mmap.flush()?;
-			flush.delete()?;
+		let mut recovery = db.journal.take_recovery();
+		recovery.extend(db.open_indexes()?);
+		for keyspace in db.keyspaces.values_mut() {
+			recovery.extend(keyspace.take_recovery());
 		}
 
-		let mut collisions = BTreeMap::new();
+		listener.on_event(&Event::DatabaseOpened(DatabaseOpened {
+			path: db.path.clone(),
+			journal_eras: db.journal.len(),
+			recovery,
+		}));
 
-		for prefix in metadata.collided_prefixes.prefixes_iter() {
-			let collision_file = Collision::open(&path, prefix)?.expect(
-				"prefix is declared as collided in metadata; \
-				 collision file should exist; qed");
+		Ok(db)
+	}
 
-			collisions.insert(prefix, collision_file);
+	/// Opens the declared secondary indexes, building the missing ones from the values
+	/// of the database. Indexes which are no longer declared are removed.
+	/// Returns the built and removed indexes.
+	fn open_indexes(&mut self) -> Result<Vec<RecoveryAction>> {
+		let indexes = self.options().external.indexes.clone();
+		let dir = self.path.join(Self::INDEXES_DIR);
+		let durability = self.options().external.durability;
+		let vfs = self.options().vfs.clone();
+		let mut recovery = Vec::new();
+
+		if vfs.is_dir(&dir) {
+			for path in vfs.read_dir(&dir)? {
+				let name = path.file_name().expect("entries of a directory have names; qed");
+				let declared = name.to_str()
+					.map_or(false, |name| indexes.iter().any(|index| index.name == name));
+				if !declared {
+					vfs.remove_dir_all(&path)?;
+					recovery.push(RecoveryAction::IndexRemoved(name.to_string_lossy().into_owned()));
+				}
+			}
 		}
 
-		Ok(Database {
-			path: path.as_ref().to_owned(),
-			options,
-			journal,
-			metadata,
-			metadata_mmap,
-			mmap,
-			collisions,
-			lock_file,
-		})
+		for index in &indexes {
+			let path = dir.join(&index.name);
+			if !vfs.is_dir(&path) {
+				// the index is built in a temporary directory, so a partially built
+				// index is never mistaken for a complete one
+				let tmp_path = dir.join(format!("{}.tmp", index.name));
+				vfs.create_dir_all(&tmp_path)?;
+				let options = self.index_options(index)?;
+				Keyspace::create(&tmp_path, &options)?;
+				self.build_index(index, Keyspace::open(&tmp_path, options)?)?;
+				// entries journaled so far, possibly by an earlier index with the same name, are not flushed
+				let mut bytes = [0; 8];
+				LittleEndian::write_u64(&mut bytes, self.journal.next_seq());
+				vfs.write(&tmp_path.join(Self::INDEX_FIRST_ERA_FILE), &bytes, true, durability)?;
+				vfs.sync_dir(&tmp_path, durability)?;
+				vfs.rename(&tmp_path, &path)?;
+				vfs.sync_dir(&dir, durability)?;
+				vfs.sync_dir(&self.path, durability)?;
+				recovery.push(RecoveryAction::IndexBuilt(index.name.clone()));
+			}
+
+			let name = Self::index_keyspace_name(index);
+			let first_era_file = path.join(Self::INDEX_FIRST_ERA_FILE);
+			if vfs.exists(&first_era_file) {
+				let bytes = vfs.read(&first_era_file)?;
+				if bytes.len() != 8 {
+					return Err(ErrorKind::Corrupted(first_era_file, 0, format!("Expected 8 bytes, got {}", bytes.len())).into());
+				}
+				self.index_first_eras.insert(name.clone(), LittleEndian::read_u64(&bytes));
+			}
+
+			let keyspace = Keyspace::open(&path, self.index_options(index)?)?;
+			self.keyspaces.insert(name, keyspace);
+		}
+
+		Ok(recovery)
+	}
+
+	/// Writes index entries of all the values of the database directly to the data file of the index.
+	fn build_index(&self, index: &Index, mut keyspace: Keyspace) -> Result<()> {
+		let mut entries = Vec::new();
+		for pair in self.iter()? {
+			let (key, value) = pair?;
+			if let Some(mut entry) = index.key(&value.to_vec())? {
+				entry.extend_from_slice(key);
+				entries.push(entry);
+			}
+		}
+
+		entries.sort();
+		let operations = entries.iter().map(|entry| Operation::Insert(entry, &[])).collect();
+		let flush = keyspace.prepare_flush(operations, &mut BTreeSet::new())?;
+		keyspace.apply_flush(flush)
+	}
+
+	/// Returns options of the keyspace storing the index. Keys of the keyspace are index keys
+	/// followed by keys of the database, values are empty.
+	fn index_options(&self, index: &Index) -> Result<InternalOptions> {
+		let options = &self.options().external;
+		InternalOptions::from_external(Options {
+			extend_threshold_percent: options.extend_threshold_percent,
+			key_index_bits: index.key_index_bits,
+			key_len: index.key_len + options.key_len,
+			value_len: ValuesLen::Constant(0),
+			max_prefix_collisions: options.max_prefix_collisions,
+			compaction: options.compaction,
+			compaction_budget: options.compaction_budget,
+			durability: options.durability,
+			storage: options.storage,
+			metrics: options.metrics.clone(),
+			event_listener: options.event_listener.clone(),
+			..Default::default()
+		}).map(|index_options| index_options.with_vfs(self.options().vfs.clone()))
+	}
+
+	fn index_keyspace_name(index: &Index) -> Vec<u8> {
+		format!("{}{}", Self::INDEX_KEYSPACE_PREFIX, index.name).into_bytes()
+	}
+
+	fn is_index_keyspace(name: &[u8]) -> bool {
+		name.starts_with(Self::INDEX_KEYSPACE_PREFIX.as_bytes())
+	}
+
+	/// Creates a named keyspace with its own data, metadata and collision files.
+	///
+	/// Keyspaces share the journal of the database, so a single transaction
+	/// can modify several of them atomically. `journal_eras` of the keyspace
+	/// options is ignored.
+	pub fn create_keyspace(&mut self, name: &str, options: Options) -> Result<()> {
+		let options = self.keyspace_options(options)?;
+		let path = self.keyspace_path(name)?;
+		if self.keyspaces.contains_key(name.as_bytes()) {
+			return Err(ErrorKind::KeyspaceAlreadyOpen(name.to_owned()).into());
+		}
+
+		options.vfs.create_dir_all(&path)?;
+		Keyspace::create(&path, &options)?;
+		// make the keyspace directory visible after a power failure
+		let durability = self.options().external.durability;
+		options.vfs.sync_dir(&self.path.join(Self::KEYSPACES_DIR), durability)?;
+		options.vfs.sync_dir(&self.path, durability)?;
+
+		self.insert_keyspace(name, path, options)
+	}
+
+	/// Opens a named keyspace created with `create_keyspace`.
+	///
+	/// Keyspaces with journaled operations have to be opened before the journal is flushed.
+	pub fn open_keyspace(&mut self, name: &str, options: Options) -> Result<()> {
+		let options = self.keyspace_options(options)?;
+		let path = self.keyspace_path(name)?;
+		if self.keyspaces.contains_key(name.as_bytes()) {
+			return Err(ErrorKind::KeyspaceAlreadyOpen(name.to_owned()).into());
+		}
+
+		if !options.vfs.is_dir(&path) {
+			return Err(ErrorKind::KeyspaceNotFound(name.to_owned()).into());
+		}
+
+		self.insert_keyspace(name, path, options)
+	}
+
+	fn insert_keyspace(&mut self, name: &str, path: PathBuf, options: InternalOptions) -> Result<()> {
+		let mut keyspace = Keyspace::open(path, options)?;
+		// recovery of keyspaces opened with the database is reported by `DatabaseOpened`
+		for action in keyspace.take_recovery() {
+			if let RecoveryAction::FlushReplayed(path) = action {
+				let listener = &keyspace.options.external.event_listener;
+				listener.on_event(&Event::FlushReplayed(FlushReplayed { path }));
+			}
+		}
+		self.keyspaces.insert(name.as_bytes().to_vec(), keyspace);
+		Ok(())
+	}
+
+	/// Returns the options of a named keyspace, whose files are stored with the files of the database.
+	fn keyspace_options(&self, options: Options) -> Result<InternalOptions> {
+		if !options.indexes.is_empty() {
+			bail!(ErrorKind::InvalidOptions(
+				"indexes",
+				"secondary indexes are only supported by the database keyspace".into()
+			));
+		}
+
+		InternalOptions::from_external(options).map(|options| options.with_vfs(self.options().vfs.clone()))
+	}
+
+	/// Returns the names of the open keyspaces.
+	pub fn keyspace_names(&self) -> Vec<&str> {
+		self.keyspaces.keys()
+			.filter(|name| name.as_slice() != DEFAULT_KEYSPACE && !Self::is_index_keyspace(name))
+			.map(|name| str::from_utf8(name).expect("keyspace names are validated when the keyspace is opened; qed"))
+			.collect()
+	}
+
+	/// Returns the directory of the named keyspace. Fails if the name is not valid.
+	fn keyspace_path(&self, name: &str) -> Result<PathBuf> {
+		if !options::is_valid_name(name) {
+			return Err(ErrorKind::InvalidKeyspaceName(name.to_owned()).into());
+		}
+
+		Ok(self.path.join(Self::KEYSPACES_DIR).join(name))
+	}
+
+	fn keyspace(&self, name: &[u8]) -> Result<&Keyspace> {
+		match self.keyspaces.get(name) {
+			Some(keyspace) => Ok(keyspace),
+			None => Err(ErrorKind::KeyspaceNotFound(String::from_utf8_lossy(name).into_owned()).into()),
+		}
+	}
+
+	fn keyspace_mut(&mut self, name: &[u8]) -> Result<&mut Keyspace> {
+		match self.keyspaces.get_mut(name) {
+			Some(keyspace) => Ok(keyspace),
+			None => Err(ErrorKind::KeyspaceNotFound(String::from_utf8_lossy(name).into_owned()).into()),
+		}
+	}
+
+	/// Returns the options of the database itself.
+	fn options(&self) -> &InternalOptions {
+		&self.keyspaces[DEFAULT_KEYSPACE].options
 	}
 
 	/// Create a new transaction.
 	pub fn create_transaction(&self) -> Transaction {
-		Transaction::new(self.options.external.key_len)
+		let options = self.options();
+		let mut tx = Transaction::with_value_len(options.external.key_len, options.value_len_limit());
+		if options.external.ttl {
+			tx.enable_ttl();
+		}
+		for name in self.keyspace_names() {
+			let options = &self.keyspaces[name.as_bytes()].options;
+			tx.add_keyspace(name, options.external.key_len, options.value_len_limit(), options.external.ttl);
+		}
+
+		tx
 	}
 
-	/// Commits changes in the transaction.
-	pub fn commit(&mut self, tx: &Transaction) -> Result<()> {
-		self.journal.push(tx)?;
-		Ok(())
+	/// Commits changes in the transaction. Returns the sequence number of the journal era
+	/// the transaction was written to.
+	///
+	/// Fails without writing anything if a precondition of any conditional operation fails.
+	/// Entries of secondary indexes are written to the same era as the values.
+	pub fn commit(&mut self, tx: &Transaction) -> Result<u64> {
+		let started = Instant::now();
+		let (indexed_tx, changes) = {
+			let mut pending = HashMap::new();
+			self.check_preconditions(tx.operations().with_keyspaces(), &mut pending)?;
+			(self.with_index_entries(tx.raw(), &pending)?, self.watched_changes(&pending)?)
+		};
+
+		let seq = match indexed_tx {
+			Some(indexed_tx) => self.journal.push(&indexed_tx)?,
+			None => self.journal.push(tx)?,
+		};
+
+		self.publish(changes, seq);
+		self.record_commit(started);
+		Ok(seq)
 	}
 
-	/// Flushes up to `max` excessive journal eras to the disk.
-	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
-		let len = self.journal.len();
-		let max = max.into().unwrap_or(len);
+	/// Writes the transaction to a file without modifying the database.
+	///
+	/// Requires only read access, so the transaction can be prepared on any thread.
+	/// It becomes visible once it is passed to `apply`.
+	pub fn prepare(&self, tx: &Transaction) -> Result<PreparedTransaction> {
+		self.journal.prepare(tx)
+	}
 
-		if len < self.options.external.journal_eras {
-			return Ok(())
+	/// Commits a prepared transaction. Returns the sequence number of the journal era
+	/// the transaction was moved to.
+	///
+	/// Fails without writing anything if a precondition of any conditional operation fails.
+	pub fn apply(&mut self, prepared: PreparedTransaction) -> Result<u64> {
+		let started = Instant::now();
+		let (indexed_tx, changes) = {
+			let mut pending = HashMap::new();
+			self.check_preconditions(prepared.operations().with_keyspaces(), &mut pending)?;
+			(self.with_index_entries(prepared.raw(), &pending)?, self.watched_changes(&pending)?)
+		};
+
+		// the prepared file can't be moved into the journal if index entries have to be added to it
+		let seq = match indexed_tx {
+			Some(indexed_tx) => self.journal.push(&indexed_tx)?,
+			None => self.journal.apply(prepared)?,
+		};
+
+		self.publish(changes, seq);
+		self.record_commit(started);
+		Ok(seq)
+	}
+
+	/// Reports the latency of the commit which started at `started` and the size of its era.
+	fn record_commit(&self, started: Instant) {
+		let metrics = &self.options().external.metrics;
+		metrics.observe(names::COMMIT_LATENCY, metrics::micros(started.elapsed()));
+		if let Some(era) = self.journal.back() {
+			metrics.observe(names::ERA_SIZE, era.size());
+		}
+		metrics.gauge(names::JOURNAL_ERAS, self.journal.len() as u64);
+		metrics.gauge(names::JOURNAL_SIZE, self.journal.size());
+	}
+
+	/// Subscribes to the changes of the database keys matching the filter. Changes of each
+	/// committed transaction are sent once it is written to the journal, ordered by key.
+	///
+	/// Up to `Options::subscription_buffer` changes are buffered. Later changes are dropped
+	/// and counted by the subscription until it receives the buffered ones.
+	pub fn subscribe(&self, filter: KeyFilter) -> Subscription {
+		let (subscriber, subscription) = Subscriber::new(filter, self.options().external.subscription_buffer);
+		self.subscribers.lock().push(subscriber);
+		subscription
+	}
+
+	/// Returns the database keys written by the `pending` operations which are watched by any
+	/// subscriber, together with their current and new values.
+	fn watched_changes<'a>(&'a self, pending: &HashMap<(&'a [u8], &'a [u8]), PendingValue<'a>>) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>)>> {
+		let subscribers = self.subscribers.lock();
+		if subscribers.is_empty() {
+			return Ok(Vec::new());
+		}
+
+		Self::pending_keys(pending).into_iter()
+			.filter(|key| subscribers.iter().any(|subscriber| subscriber.matches(key)))
+			.map(|key| {
+				let old_value = self.get_from(DEFAULT_KEYSPACE, key)?.map(|value| value.to_vec());
+				let new_value = self.get_pending(DEFAULT_KEYSPACE, key, pending)?.map(|value| value.to_vec());
+				Ok((key.to_vec(), old_value, new_value))
+			})
+			.collect()
+	}
+
+	/// Sends the changes committed in the era `seq` to their subscribers.
+	/// Subscribers whose subscriptions were dropped are removed.
+	fn publish(&self, changes: Vec<(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>)>, seq: u64) {
+		if changes.is_empty() {
+			return;
 		}
 
-		let to_flush = cmp::min(len - self.options.external.journal_eras, max);
+		self.subscribers.lock().retain(|subscriber| {
+			changes.iter()
+				.filter(|&&(ref key, _, _)| subscriber.matches(key))
+				.all(|&(ref key, ref old_value, ref new_value)| subscriber.send(Change {
+					key: key.clone(),
+					old_value: old_value.clone(),
+					new_value: new_value.clone(),
+					seq,
+				}))
+		});
+	}
 
-		let prefix_bits = self.options.external.key_index_bits;
-		let collisions = &mut self.collisions;
+	/// Returns the sorted keys of the database written by the `pending` operations.
+	fn pending_keys<'a>(pending: &HashMap<(&'a [u8], &'a [u8]), PendingValue<'a>>) -> Vec<&'a [u8]> {
+		let mut keys: Vec<_> = pending.keys()
+			.filter(|&&(keyspace, _)| keyspace == DEFAULT_KEYSPACE)
+			.map(|&(_, key)| key)
+			.collect();
+		keys.sort();
+		keys
+	}
 
-		for era in self.journal.drain_front(to_flush) {
-			let flush = {
-				let collided_prefixes = &self.metadata.collided_prefixes;
+	/// Returns a transaction with serialized operations `raw` followed by the updates
+	/// of secondary indexes caused by the `pending` values of the database keys.
+	/// Returns `None` if no index is updated.
+	fn with_index_entries<'a>(&'a self, raw: &[u8], pending: &HashMap<(&'a [u8], &'a [u8]), PendingValue<'a>>) -> Result<Option<Transaction>> {
+		let indexes = &self.options().external.indexes;
+		if indexes.is_empty() {
+			return Ok(None);
+		}
 
-				// partition operations by whether they affect collided prefixes
-				let (collided_operations, operations): (Vec<_>, Vec<_>) =
-					era.iter().partition(|op| {
-						let key = Key::new(op.key(), prefix_bits);
-						collided_prefixes.has(key.prefix).unwrap_or(false)
-					});
+		let keys = Self::pending_keys(pending);
+		let mut tx = self.create_transaction();
+		tx.extend_raw(raw);
+		let mut updated = false;
+		let mut entry = Vec::new();
+
+		for key in keys {
+			let old_value = self.get_from(DEFAULT_KEYSPACE, key)?.map(|value| value.to_vec());
+			let new_value = self.get_pending(DEFAULT_KEYSPACE, key, pending)?.map(|value| value.to_vec());
+
+			for index in indexes {
+				let old_key = match old_value {
+					Some(ref value) => index.key(value)?,
+					None => None,
+				};
+				let new_key = match new_value {
+					Some(ref value) => index.key(value)?,
+					None => None,
+				};
+
+				if old_key == new_key {
+					continue;
+				}
 
-				// flush operations for collided prefixes to their own collision file
-				for op in collided_operations {
-					let key = Key::new(op.key(), prefix_bits);
-					let collision = collisions.get_mut(&key.prefix).expect(
-						"prefix is declared as collided; \
-						 collision file should exist in collisions index; qed");
+				let name = Self::index_keyspace_name(index);
+				if let Some(old_key) = old_key {
+					entry.clear();
+					entry.extend_from_slice(&old_key);
+					entry.extend_from_slice(key);
+					tx.push_in(&name, Operation::Delete(&entry));
+				}
 
-					collision.apply(op)?;
+				if let Some(new_key) = new_key {
+					entry.clear();
+					entry.extend_from_slice(&new_key);
+					entry.extend_from_slice(key);
+					tx.push_in(&name, Operation::Insert(&entry, &[]));
 				}
 
-				// create flush to data file for everything else
-				Flush::new(
-					&self.path,
-					&self.options,
-					unsafe { self.mmap.as_slice() },
-					&self.metadata,
-					operations.into_iter(),
-				)?
+				updated = true;
+			}
+		}
+
+		Ok(if updated { Some(tx) } else { None })
+	}
+
+	/// Checks preconditions of conditional operations against the journal and the data.
+	/// Fails if the transaction contains merge operands, but there is no merge operator.
+	///
+	/// Each operation sees the effects of the operations preceding it. Effects of the checked
+	/// operations are added to `pending`, which may also contain effects of other uncommitted transactions.
+	/// `pending` is indexed by keyspace name and key.
+	pub(crate) fn check_preconditions<'a, I>(&self, operations: I, pending: &mut HashMap<(&'a [u8], &'a [u8]), PendingValue<'a>>) -> Result<()>
+		where I: IntoIterator<Item = (&'a [u8], Operation<'a>)>
+	{
+		for (keyspace, operation) in operations {
+			let key = operation.key();
+			let failure = match operation {
+				Operation::Insert(..) | Operation::Delete(_) | Operation::Merge(..) => None,
+				Operation::InsertIfAbsent(..) => match self.get_pending(keyspace, key, pending)? {
+					Some(_) => Some("key is already present"),
+					None => None,
+				},
+				Operation::UpdateIfEquals(_, expected, _) => match self.get_pending(keyspace, key, pending)? {
+					Some(ref value) if *value == expected => None,
+					Some(_) => Some("current value is different"),
+					None => Some("key is not present"),
+				},
+				Operation::DeleteIfPresent(_) => match self.get_pending(keyspace, key, pending)? {
+					Some(_) => None,
+					None => Some("key is not present"),
+				},
 			};
 
-			era.delete()?;
+			if let Some(reason) = failure {
+				return Err(ErrorKind::PreconditionFailed(key.to_vec(), reason).into());
+			}
 
-			// TODO: metadata should be a single structure
-			// updating self.metadata should happen after all calls
-			// which may fail ("?")
-			flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
-			self.mmap.flush()?;
-			self.metadata_mmap.flush()?;
-			flush.delete()?;
+			self.apply_pending(keyspace, operation, pending)?;
 		}
 
 		Ok(())
 	}
 
-	/// Lookup a value associated with given `key`.
-	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
-		let key = key.as_ref();
-		if key.len() != self.options.external.key_len {
-			return Err(ErrorKind::InvalidKeyLen(self.options.external.key_len, key.len()).into());
+	/// Adds the effect of the operation to the values written by uncommitted operations.
+	pub(crate) fn apply_pending<'a>(&self, keyspace_name: &'a [u8], operation: Operation<'a>, pending: &mut HashMap<(&'a [u8], &'a [u8]), PendingValue<'a>>) -> Result<()> {
+		let key = operation.key();
+		let value = match operation.effect() {
+			Operation::Insert(_, value) => match self.keyspace(keyspace_name)?.unexpired(Value::Raw(value))? {
+				Some(Value::Raw(value)) => Some(Cow::Borrowed(value)),
+				Some(_) => unreachable!("expiry is split off raw values as raw values; qed"),
+				None => None,
+			},
+			Operation::Delete(_) => None,
+			Operation::Merge(_, operand) => {
+				let keyspace = self.keyspace(keyspace_name)?;
+				// operands are merged at once, so malformed operands never get to the journal
+				let merged = match pending.remove(&(keyspace_name, key)) {
+					Some(value) => keyspace.merge_operands(key, value.map(|value| Value::Merged(value.into_owned())), &[operand])?,
+					None => keyspace.merge_operands(key, self.get_from(keyspace_name, key)?, &[operand])?,
+				};
+				Some(Cow::Owned(merged))
+			},
+			_ => unreachable!("effect is either an insert, a delete or a merge; qed"),
+		};
+
+		pending.insert((keyspace_name, key), value);
+		Ok(())
+	}
+
+	/// Lookup a value of the key written by uncommitted operations or stored in the database.
+	pub(crate) fn get_pending<'a>(&'a self, keyspace: &[u8], key: &[u8], pending: &HashMap<(&'a [u8], &'a [u8]), PendingValue<'a>>) -> Result<Option<Value<'a>>> {
+		match pending.get(&(keyspace, key)) {
+			Some(&None) => Ok(None),
+			Some(&Some(Cow::Borrowed(value))) => Ok(Some(Value::Raw(value))),
+			Some(&Some(Cow::Owned(ref value))) => Ok(Some(Value::Merged(value.clone()))),
+			None => self.get_from(keyspace, key),
 		}
+	}
 
-		// check if the key-value pair is currently journaled
-		if let Some(res) = self.journal.get(key) {
-			return Ok(Some(Value::Raw(res)));
+	/// Returns the length of the database keys.
+	pub fn key_len(&self) -> usize {
+		self.options().external.key_len
+	}
+
+	/// Returns the number of eras in the journal.
+	pub fn journal_len(&self) -> usize {
+		self.journal.len()
+	}
+
+	/// Returns the total size of the journal era files in bytes.
+	pub fn journal_size(&self) -> u64 {
+		self.journal.size()
+	}
+
+	/// Returns the number of eras kept in the journal when it is flushed.
+	pub fn journal_eras(&self) -> usize {
+		self.options().external.journal_eras
+	}
+
+	/// Flushes up to `max` excessive journal eras to the disk.
+	///
+	/// Keyspaces are compacted afterwards if their configured compaction policies say so.
+	///
+	/// Fails without flushing the oldest era, and the eras after it, if its contents can't be
+	/// written to the data files, e.g. when a merge operand can't be merged. See `quarantine_era`.
+	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
+		let len = self.journal.len();
+		let max = max.into().unwrap_or(len);
+		let journal_eras = self.journal_eras();
+
+		if len < journal_eras {
+			return Ok(())
 		}
 
-		let field_body_size = self.options.field_body_size;
-		let value_size = self.options.value_size;
+		let to_flush = cmp::min(len - journal_eras, max);
+
+		let durability = self.options().external.durability;
+		let mut flushed_prefixes = HashMap::new();
+
+		for _ in 0..to_flush {
+			let started = Instant::now();
+			let seq = self.journal.first_seq();
+			// merge operands are resolved and era contents are validated
+			// before the era is removed from the journal and anything is written,
+			// so an era which can't be flushed stays in the journal
+			let merged = self.check_era(self.journal.front().expect("to_flush <= journal.len(); qed"), seq)?;
+			let era = self.journal.pop_front().expect("to_flush <= journal.len(); qed");
+
+			// operations are ordered by keyspace, so each keyspace gets a single flush file
+			let mut flushes = Vec::new();
+			for (name, operations) in &era.iter().group_by(|&(name, _)| name) {
+				if self.is_outdated_index(name, seq) {
+					continue;
+				}
+
+				let operations = {
+					let keyspace = self.keyspace(name)?;
+					operations.map(|(_, op)| Ok(match op {
+						Operation::Merge(key, _) => Operation::Insert(key, &merged[name][key]),
+						// expired values are not written to the data file
+						Operation::Insert(key, value) => match keyspace.unexpired(Value::Raw(value))? {
+							Some(_) => Operation::Insert(key, value),
+							None => Operation::Delete(key),
+						},
+						op => op,
+					})).collect::<Result<_>>()?
+				};
+
+				let keyspace = self.keyspaces.get_mut(name).expect("keyspaces of the era were checked; qed");
+				let prefixes = flushed_prefixes.entry(name.to_vec()).or_insert_with(BTreeSet::new);
+				flushes.push((name.to_vec(), keyspace.prepare_flush(operations, prefixes)?));
+			}
+
+			// the flush files are already synced, if we crash after this the flushes are applied on
+			// restart and the era is not replayed
+			self.journal.remove(era)?;
+			self.options().vfs.sync_dir(&self.path, durability)?;
 
-		let key = Key::new(key, self.options.external.key_index_bits);
+			for (name, flush) in flushes {
+				self.keyspace_mut(&name)?.apply_flush(flush)?;
+			}
 
-		// fetch from the collision file if this is a collided prefix
-		if self.metadata.collided_prefixes.has(key.prefix).unwrap_or(false) {
-			let collision = self.collisions.get(&key.prefix).expect(
-				"prefix is declared as collided; \
-				 collision file should exist in collisions index; qed");
+			let metrics = &self.options().external.metrics;
+			metrics.observe(names::FLUSH_DURATION, metrics::micros(started.elapsed()));
+			metrics.increment(names::FLUSHED_ERAS, 1);
+		}
 
-			return Ok(collision.get(key.key)?.map(Value::Raw))
+		if to_flush > 0 {
+			let metrics = &self.options().external.metrics;
+			metrics.gauge(names::JOURNAL_ERAS, self.journal.len() as u64);
+			metrics.gauge(names::JOURNAL_SIZE, self.journal.size());
 		}
 
-		// check if there's any data stored on the data file for the given prefix
-		if !self.metadata.prefixes.has(key.prefix).unwrap_or(false) {
-			return Ok(None);
+		let empty = BTreeSet::new();
+		for (name, keyspace) in self.keyspaces.iter_mut() {
+			keyspace.compact_after_flush(to_flush, flushed_prefixes.get(name).unwrap_or(&empty))?;
 		}
 
-		let offset = key.prefix as usize * self.options.record_offset;
-		let data = unsafe { &self.mmap.as_slice()[offset..] };
+		Ok(())
+	}
 
-		match find::find_record(data, field_body_size, value_size, key.key)? {
-			find::RecordResult::Found(record) => Ok(Some(Value::from(record))),
-			find::RecordResult::NotFound => Ok(None),
-			find::RecordResult::OutOfRange => unimplemented!(),
+	/// Moves the oldest journal era `seq` aside to a file with `.corrupted` extension, which is
+	/// not opened as a part of the journal, so the eras after it can be flushed. Returns the path
+	/// of the file.
+	///
+	/// The transactions of the era are lost, even though they were committed, so this is only
+	/// meant to be called by operators once `flush_journal` keeps failing on the era.
+	pub fn quarantine_era(&mut self, seq: u64) -> Result<PathBuf> {
+		let first_seq = self.journal.first_seq();
+		if seq < first_seq || seq >= self.journal.next_seq() {
+			bail!(ErrorKind::JournalEraMissing(seq));
 		}
+		if seq != first_seq {
+			bail!(ErrorKind::JournalEraNotOldest(seq, first_seq));
+		}
+
+		let era = self.journal.pop_front().expect("seq is in the journal; qed");
+		let path = self.journal.quarantine(era)?;
+
+		let metrics = &self.options().external.metrics;
+		metrics.gauge(names::JOURNAL_ERAS, self.journal.len() as u64);
+		metrics.gauge(names::JOURNAL_SIZE, self.journal.size());
+		Ok(path)
 	}
 
-	/// Returns an iterator over all the database key-value pairs ordered by key.
-	pub fn iter(&self) -> Result<DatabaseIterator> {
-		let record_collisions_iter = self.record_collisions_iter()?;
-		let journal_iter = self.journal.iter();
-		let pending = IteratorValue::None;
+	/// Returns the operations on the database keys committed since the era `seq`, including it.
+	///
+	/// Eras are read from the journal and from `Options::journal_archive`, so a consumer can resume
+	/// from the sequence number of the last era it has seen. Fails if any of the eras was deleted.
+	pub fn changes_since(&self, seq: u64) -> Result<Changes> {
+		self.changes_since_from(DEFAULT_KEYSPACE, seq)
+	}
 
-		Ok(DatabaseIterator { record_collisions_iter, journal_iter, pending })
+	/// Returns the operations on the keys of the named keyspace committed since the era `seq`.
+	pub fn changes_since_in(&self, keyspace: &str, seq: u64) -> Result<Changes> {
+		self.changes_since_from(keyspace.as_bytes(), seq)
 	}
 
-	/// Returns an iterator over only the database key-value pairs stored in the data file ordered
-	/// by key (i.e. it doesn't include data from the journal or collision files).
-	fn record_iter(&self) -> Result<RecordIterator> {
-		let data = unsafe { &self.mmap.as_slice() };
-		let occupied_prefixes_iter = self.metadata.prefixes.prefixes_iter();
-		let field_body_size = self.options.field_body_size;
-		let key_size = self.options.external.key_len;
-		let value_size = self.options.value_size;
-
-		let record_iter = find::iter(
-			data,
-			occupied_prefixes_iter,
-			field_body_size,
-			key_size,
-			value_size,
-		)?;
+	fn changes_since_from(&self, name: &[u8], seq: u64) -> Result<Changes> {
+		let ttl = self.keyspace(name)?.options.external.ttl;
+		let eras = self.journal.changes_since(seq)
+			.map_err(|err| Self::report_corruption(&*self.options().external.event_listener, err))?;
+		Ok(Changes::new(eras, name, ttl))
+	}
 
-		Ok(record_iter)
+	/// Lookup a value associated with given `key`.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
+		self.get_counted(DEFAULT_KEYSPACE, key.as_ref())
 	}
 
-	// TODO: refactor to avoid boxed iterator
-	/// Returns an iterator over the database key-value pairs stored in the data file and collision
-	/// files.
-	fn record_collisions_iter<'a>(&'a self) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
-		let collided_records = self.collisions.values()
-			.flat_map(|it| it.iter().ok()) // FIXME: swallowing errors here
-			.flat_map(|it| it);
+	/// Lookup a value associated with given `key` in the named keyspace.
+	pub fn get_in<K: AsRef<[u8]>>(&self, keyspace: &str, key: K) -> Result<Option<Value>> {
+		self.get_counted(keyspace.as_bytes(), key.as_ref())
+	}
 
-		let records = self.record_iter()?;
+	/// Lookup a value of the key counted by the `get.*` metrics, which count only the reads
+	/// of the database users.
+	fn get_counted(&self, name: &[u8], key: &[u8]) -> Result<Option<Value>> {
+		let (value, counter) = self.lookup(name, key)?;
+		self.keyspace(name)?.options.external.metrics.increment(counter, 1);
+		Ok(value)
+	}
 
-		Ok(Box::new(records.merge_join_by(collided_records, |r, c| {
-			match (r, c) {
-				(&Err(_), _) => Ordering::Less,
-				(_, &Err(_)) => Ordering::Greater,
-				(&Ok(ref r), &Ok(ref c)) => r.key().cmp(&c.0),
-			}
-		}).map(|either| {
-			match either {
-				EitherOrBoth::Left(Err(err)) => Err(err.into()),
-				EitherOrBoth::Right(Err(err)) => Err(err),
-				EitherOrBoth::Left(Ok(r)) => Ok((r.key(), Value::Record(r))),
-				EitherOrBoth::Right(Ok(c)) => Ok((c.0, Value::Raw(c.1))),
-				EitherOrBoth::Both(_, _) =>
-					unreachable!("value exists in collision file; \
-								  so cannot exist in data file; qed"),
-			}
-		})))
+	fn get_from(&self, name: &[u8], key: &[u8]) -> Result<Option<Value>> {
+		self.lookup(name, key).map(|(value, _)| value)
 	}
 
-	fn collisions(&self) -> Result<BTreeMap<u32, Vec<&[u8]>>> {
-		let mut collisions: BTreeMap<u32, Vec<&[u8]>> = BTreeMap::new();
+	/// Lookup a value of the key. Returns the value with the name of the `get.*` counter
+	/// of the place which answered the lookup.
+	fn lookup(&self, name: &[u8], key: &[u8]) -> Result<(Option<Value>, &'static str)> {
+		let keyspace = self.keyspace(name)?;
+		let key_len = keyspace.options.external.key_len;
+		if key.len() != key_len {
+			return Err(ErrorKind::InvalidKeyLen(key_len, key.len()).into());
+		}
 
-		for record in self.record_iter()? {
-			let key = record?.key();
+		// check if the key-value pair is currently journaled
+		match self.journal.get(name, key) {
+			Some(JournalOperation::Insert(value)) => Ok((keyspace.unexpired(Value::Raw(value))?, names::GET_JOURNAL)),
+			Some(JournalOperation::Delete) => Ok((None, names::GET_JOURNAL)),
+			Some(JournalOperation::Merge(base, operands)) => {
+				let base = match base {
+					MergeBase::Previous => keyspace.get(key)?,
+					MergeBase::Inserted(value) => Some(Value::Raw(value)),
+					MergeBase::Deleted => None,
+				};
+				let value = keyspace.merge_operands(key, base, &operands)?;
+				Ok((Some(Value::Merged(value)), names::GET_JOURNAL))
+			},
+			None => match keyspace.lookup(key)? {
+				(Some(value), counter) => Ok((keyspace.unexpired(value)?, counter)),
+				(None, counter) => Ok((None, counter)),
+			},
+		}
+	}
 
-			let prefix = Key::new(key, self.options.external.key_index_bits).prefix;
-			match collisions.entry(prefix) {
-				Entry::Vacant(entry) => {
-					entry.insert(vec![key]);
-				},
-				Entry::Occupied(mut entry) => {
-					entry.get_mut().push(key);
-				},
+	/// Resolves merge operands of the era with values flushed by the preceding eras.
+	/// Returns the merged values indexed by keyspace name and key.
+	fn resolve_merges(&self, era: &JournalEra) -> Result<HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>> {
+		let mut resolved = HashMap::new();
+		for (name, key, operation) in era.merges() {
+			if let JournalOperation::Merge(base, operands) = operation {
+				let keyspace = self.keyspace(name)?;
+				let base = match base {
+					MergeBase::Previous => keyspace.get(key)?,
+					MergeBase::Inserted(value) => Some(Value::Raw(value)),
+					MergeBase::Deleted => None,
+				};
+				let value = keyspace.merge_operands(key, base, &operands)?;
+				resolved.entry(name.to_vec()).or_insert_with(HashMap::new).insert(key.to_vec(), value);
 			}
 		}
 
-		// drop prefixes that have a number of collisions lower than the allowed threshold
-		let collisions: BTreeMap<u32, Vec<&[u8]>> =
-			collisions.into_iter().filter(|p| {
-				p.1.len() >= self.options.external.max_prefix_collisions
-			}).collect();
+		Ok(resolved)
+	}
+
+	/// Resolves merge operands of the era `seq` and checks that keys and values of the era can be written
+	/// to the data files of their keyspaces. Returns the merged values indexed by keyspace name and key.
+	fn check_era(&self, era: &JournalEra, seq: u64) -> Result<HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>> {
+		let merged = self.resolve_merges(era)?;
+		for (name, operation) in era.iter().filter(|&(name, _)| !self.is_outdated_index(name, seq)) {
+			let merged = merged.get(name)
+				.and_then(|merged| merged.get(operation.key()))
+				.map(|value| value.as_slice());
+			self.keyspace(name)?.check(&operation, merged)?;
+		}
 
-		Ok(collisions)
+		Ok(merged)
 	}
 
-	/// Finds prefixes that have a number of collisions higher than the configured threshold and
-	/// moves all their data to a separate file (one file for each collided prefix). Returns a
-	/// vector of collided prefixes (empty if no collisions have been found).
-	pub fn compact(&mut self) -> Result<Vec<u32>> {
-		let mut collision_files = Vec::new();
-		let mut collided_prefixes = Vec::new();
+	/// Returns true if the keyspace stored an index which is no longer declared, or if the index
+	/// was built after the era `seq`. Journaled entries of such eras are not flushed.
+	fn is_outdated_index(&self, name: &[u8], seq: u64) -> bool {
+		if !Self::is_index_keyspace(name) {
+			return false;
+		}
 
-		let (metadata, flush) = {
-			let collisions = self.collisions()?;
+		match self.index_first_eras.get(name) {
+			Some(&first_seq) => seq < first_seq,
+			None => !self.keyspaces.contains_key(name),
+		}
+	}
 
-			// create collision files and insert data from collided prefixes
-			for (prefix, keys) in collisions.iter() {
-				let mut collision_file = Collision::create(&self.path, *prefix)?;
+	/// Returns the keys and values of the database whose index key is `key`, ordered by key.
+	pub fn get_by_index<K: AsRef<[u8]>>(&self, index: &str, key: K) -> Result<Vec<(&[u8], Value)>> {
+		let key = key.as_ref();
+		self.iter_index(index, Bound::Included(key), Bound::Included(key))?
+			.map(|entry| entry.map(|(_, key, value)| (key, value)))
+			.collect()
+	}
 
-				for key in keys {
-					// FIXME: store a reference to the value in the return Map from collisions
-					let value = self.get(&key)?.expect("The key has been returned by the iterator; qed");
-					collision_file.insert(&key, value.as_slice().unwrap_or(&value.to_vec()))?;
-				}
+	/// Returns an iterator over the index keys within the given bounds together with
+	/// the keys and values of the database, ordered by index key and key.
+	pub fn iter_index(&self, index: &str, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<IndexIterator> {
+		let index = match self.options().external.indexes.iter().find(|i| i.name == index) {
+			Some(index) => index,
+			None => return Err(ErrorKind::IndexNotFound(index.to_owned()).into()),
+		};
 
-				collision_files.push(collision_file);
-				collided_prefixes.push(*prefix);
+		for bound in &[start, end] {
+			match *bound {
+				Bound::Included(key) | Bound::Excluded(key) if key.len() != index.key_len =>
+					return Err(ErrorKind::InvalidKeyLen(index.key_len, key.len()).into()),
+				_ => {},
 			}
+		}
 
-			// clone metadata and update it with collided prefixes but don't persist it
-			let mut metadata = self.metadata.clone();
-			for prefix in collided_prefixes.iter() {
-				metadata.add_prefix_collision(*prefix);
-			}
+		// index entries start with their index keys
+		let seek = match start {
+			Bound::Included(key) | Bound::Excluded(key) => key,
+			Bound::Unbounded => &[],
+		};
+		let iter = self.iter_keyspace(&Self::index_keyspace_name(index), seek)?;
+		Ok(IndexIterator::new(self, index, iter, start, end))
+	}
+
+	/// Returns an iterator over all the database key-value pairs ordered by key.
+	pub fn iter(&self) -> Result<DatabaseIterator> {
+		self.iter_keyspace(DEFAULT_KEYSPACE, &[])
+	}
 
-			// prepare flush to delete colliding keys but don't apply it
-			let deletions = collisions.values().flat_map(|ks| ks).map(|k| Operation::Delete(k));
+	/// Returns an iterator over the database key-value pairs whose keys are greater or equal
+	/// to `start`, ordered by key. `start` can be shorter than the keys.
+	pub fn iter_from<K: AsRef<[u8]>>(&self, start: K) -> Result<DatabaseIterator> {
+		self.iter_keyspace(DEFAULT_KEYSPACE, start.as_ref())
+	}
 
-			let flush = Flush::new(
-				&self.path,
-				&self.options,
-				unsafe { self.mmap.as_slice() },
-				&metadata,
-				deletions)?;
+	/// Returns an iterator over all the key-value pairs of the named keyspace ordered by key.
+	pub fn iter_in(&self, keyspace: &str) -> Result<DatabaseIterator> {
+		self.iter_keyspace(keyspace.as_bytes(), &[])
+	}
 
-			(metadata, flush)
+	fn iter_keyspace(&self, name: &[u8], start: &[u8]) -> Result<DatabaseIterator> {
+		let keyspace = self.keyspace(name)?;
+		let record_collisions_iter: Box<Iterator<Item=_>> = if keyspace.options.external.ttl {
+			Box::new(keyspace.iter_from(start)?.filter_map(move |record| match record {
+				Ok((key, value)) => match keyspace.unexpired(value) {
+					Ok(Some(value)) => Some(Ok((key, value))),
+					Ok(None) => None,
+					Err(err) => Some(Err(err)),
+				},
+				Err(err) => Some(Err(err)),
+			}))
+		} else {
+			keyspace.iter_from(start)?
 		};
+		let first_seq = self.index_first_eras.get(name).cloned().unwrap_or(0);
+		let journal_iter = self.journal.iter(name, first_seq)
+			.filter(|operation| operation.key() >= start)
+			.map(|operation| match operation {
+				// expired inserts hide the values of the data file like deletes
+				Operation::Insert(key, value) => keyspace.unexpired(Value::Raw(value)).map(|value| (key, value)),
+				Operation::Delete(key) => Ok((key, None)),
+				// merge operands are resolved with the value from the data file
+				Operation::Merge(key, _) => self.get_from(name, key).map(|value| (key, value)),
+				_ => unreachable!("journal returns only inserts, deletes and merges; qed"),
+			})
+			.collect::<Result<Vec<_>>>()?
+			.into_iter();
+		let pending = IteratorValue::None;
 
-		// persist metadata updated with collided prefixes
-		// if we crash after this the flush will be applied on restart and the metadata will
-		// already be properly updated
-		metadata.as_bytes().copy_to_slice(unsafe { self.metadata_mmap.as_mut_slice() });
-		self.metadata_mmap.flush()?;
+		Ok(DatabaseIterator { record_collisions_iter, journal_iter, pending })
+	}
 
-		// perform the flush and update metadata
-		flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
-		self.mmap.flush()?;
-		self.metadata_mmap.flush()?;
-		flush.delete()?;
+	/// Returns an iterator over all the database key-value pairs ordered by key, with
+	/// values of the `overlay` replacing the values of the database.
+	///
+	/// `overlay` has to be ordered by key. Keys with `None` values are skipped.
+	pub(crate) fn iter_with_overlay<'a>(&'a self, overlay: Vec<(&'a [u8], Option<Value<'a>>)>) -> Result<DatabaseIterator<'a>> {
+		let record_collisions_iter = Box::new(self.iter()?);
+		let journal_iter = overlay.into_iter();
+		let pending = IteratorValue::None;
 
-		// update collisions index
-		for collision_file in collision_files {
-			let prev = self.collisions.insert(collision_file.prefix(), collision_file);
-			assert!(prev.is_none());
-		}
+		Ok(DatabaseIterator { record_collisions_iter, journal_iter, pending })
+	}
+
+	/// Finds prefixes that have a number of collisions higher than the configured threshold and
+	/// moves all their data to a separate file (one file for each collided prefix). Returns a
+	/// vector of collided prefixes (empty if no collisions have been found).
+	///
+	/// Collided prefixes whose number of records dropped to half of the threshold or lower are
+	/// moved back to the data file and their collision files are deleted.
+	pub fn compact(&mut self) -> Result<Vec<u32>> {
+		self.compact_keyspace(DEFAULT_KEYSPACE)
+	}
 
-		Ok(collided_prefixes)
+	/// Compacts the named keyspace like `compact`.
+	pub fn compact_in(&mut self, keyspace: &str) -> Result<Vec<u32>> {
+		self.compact_keyspace(keyspace.as_bytes())
+	}
+
+	fn compact_keyspace(&mut self, name: &[u8]) -> Result<Vec<u32>> {
+		self.keyspace_mut(name)?.compact_within(CompactionBudget::Unlimited)
 	}
 }
 
 impl Drop for Database {
 	fn drop(&mut self) {
-		let _ = self.lock_file.unlock();
+		if let Some(ref lock_file) = self.lock_file {
+			let _ = lock_file.unlock();
+		}
 	}
 }
 
 #[derive(Debug)]
 enum IteratorValue<'a> {
 	None,
-	Journal(Operation<'a>),
+	Journal((&'a [u8], Option<Value<'a>>)),
 	DB((&'a [u8], Value<'a>)),
 }
 
@@ -464,7 +1096,7 @@ impl<'a> IteratorValue<'a> {
 }
 
 pub struct DatabaseIterator<'a> {
-	journal_iter: btree_set::IntoIter<Operation<'a>>,
+	journal_iter: vec::IntoIter<(&'a [u8], Option<Value<'a>>)>,
 	record_collisions_iter: Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>,
 	pending: IteratorValue<'a>,
 }
@@ -508,16 +1140,10 @@ impl<'a> Iterator for DatabaseIterator<'a> {
 			};
 
 			#[inline]
-			// returns `None` if the operation is a `Delete` and we should skip to the next value
-			fn handle_journal_operation<'a>(o: Operation<'a>) -> Option<Result<(&'a [u8], Value<'a>)>> {
-				match o {
-					Operation::Delete(_) => {
-						None
-					},
-					Operation::Insert(key, value) => {
-						Some(Ok((key, Value::Raw(value))))
-					},
-				}
+			// returns `None` if the key was deleted and we should skip to the next value
+			fn handle_journal_operation<'a>(o: (&'a [u8], Option<Value<'a>>)) -> Option<Result<(&'a [u8], Value<'a>)>> {
+				let (key, value) = o;
+				value.map(|value| Ok((key, value)))
 			}
 
 			match (operation, record) {
@@ -531,7 +1157,7 @@ impl<'a> Iterator for DatabaseIterator<'a> {
 					return Some(Ok(r))
 				},
 				(IteratorValue::Journal(o), IteratorValue::DB(r)) => {
-					let ord = (*r.0).partial_cmp(o.key()).expect(
+					let ord = (*r.0).partial_cmp(o.0).expect(
 						"only returns None when compared keys don't have the same size; \
 						 all keys should have the same size; qed");
 
@@ -567,10 +1193,29 @@ impl<'a> Iterator for DatabaseIterator<'a> {
 mod tests {
 	extern crate tempdir;
 
+	use std::{fs, mem};
+	use std::collections::BTreeSet;
+	use std::io::Write;
+	use std::ops::Bound;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use byteorder::{ByteOrder, LittleEndian};
 	use super::{Database, Options};
-	use options::ValuesLen;
-	use error::ErrorKind;
+	use collision::Collision;
+	use durability::Durability;
+	use event::{DatabaseOpened, Event, EventListener, FlushReplayed, LockContended, PrefixMigrated, RecoveryAction};
+	use expiry::Clock;
+	use index::{Index, ValueBytes};
+	use options::{CompactionBudget, CompactionPolicy, InternalOptions, ValuesLen};
+	use error::{ErrorKind, Result};
+	use merge::U64Add;
+	use metrics::{names, InMemoryMetrics};
+	use migration;
 	use quickcheck::TestResult;
+	use storage::Storage;
+	use subscription::{Change, KeyFilter};
+	use transaction::{Operation, Transaction, DEFAULT_KEYSPACE};
+	use vfs::{Fault, MemoryFs, Vfs};
 
 	#[test]
 	fn create_insert_and_query() {
@@ -643,6 +1288,7 @@ mod tests {
 		let mut db = Database::create(temp.path(), Options {
 			journal_eras: 0,
 			key_len: 3,
+			value_len: ValuesLen::Constant(3),
 			..Default::default()
 		}).unwrap();
 
@@ -746,6 +1392,1802 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_conditional_operations() {
+		let temp = tempdir::TempDir::new("test_conditional_operations").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert_if_absent("abc", "001").unwrap();
+		tx.update_if_equals("abc", "001", "002").unwrap();
+		tx.insert("cde", "001").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"002");
+
+		// preconditions are checked against the data file as well
+		db.flush_journal(None).unwrap();
+
+		let failures = vec![
+			(b"abc", "key is already present"),
+			(b"abc", "current value is different"),
+			(b"xyz", "key is not present"),
+		];
+
+		for (i, (key, reason)) in failures.into_iter().enumerate() {
+			let mut tx = db.create_transaction();
+			tx.insert("cde", "002").unwrap();
+			match i {
+				0 => tx.insert_if_absent(key, "003").unwrap(),
+				1 => tx.update_if_equals(key, "001", "003").unwrap(),
+				_ => tx.delete_if_present(key).unwrap(),
+			}
+
+			assert_eq!(db.commit(&tx).unwrap_err().kind(), &ErrorKind::PreconditionFailed(key.to_vec(), reason));
+			assert_eq!(db.journal_len(), 0);
+			assert_eq!(db.get("cde").unwrap().unwrap(), b"001");
+		}
+
+		let mut tx = db.create_transaction();
+		tx.update_if_equals("abc", "002", "003").unwrap();
+		tx.delete_if_present("cde").unwrap();
+		tx.insert_if_absent("cde", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"003");
+		assert_eq!(db.get("cde").unwrap().unwrap(), b"003");
+	}
+
+	fn u64_bytes(value: u64) -> [u8; 8] {
+		let mut bytes = [0; 8];
+		LittleEndian::write_u64(&mut bytes, value);
+		bytes
+	}
+
+	#[test]
+	fn test_merge_operands() {
+		let temp = tempdir::TempDir::new("test_merge_operands").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(8),
+			merge_operator: Some(Arc::new(U64Add)),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", u64_bytes(10)).unwrap();
+		tx.insert("cde", u64_bytes(10)).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", u64_bytes(5)).unwrap();
+		tx.merge("xyz", u64_bytes(1)).unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", u64_bytes(5)).unwrap();
+		tx.delete("cde").unwrap();
+		tx.merge("cde", u64_bytes(3)).unwrap();
+		db.commit(&tx).unwrap();
+
+		// operands are resolved on reads
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(20));
+		assert_eq!(db.get("cde").unwrap().unwrap(), u64_bytes(3));
+		assert_eq!(db.get("xyz").unwrap().unwrap(), u64_bytes(1));
+		assert_eq!(
+			db.iter().unwrap().map(|item| item.map(|(key, value)| (key.to_vec(), value.to_vec()))).collect::<Result<Vec<_>>>().unwrap(),
+			vec![
+				(b"abc".to_vec(), u64_bytes(20).to_vec()),
+				(b"cde".to_vec(), u64_bytes(3).to_vec()),
+				(b"xyz".to_vec(), u64_bytes(1).to_vec()),
+			]
+		);
+
+		// and folded into inserts when the journal is flushed
+		db.flush_journal(1).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(20));
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(20));
+		assert_eq!(db.get("cde").unwrap().unwrap(), u64_bytes(3));
+		assert_eq!(db.get("xyz").unwrap().unwrap(), u64_bytes(1));
+	}
+
+	#[test]
+	fn test_merge_operands_with_preconditions() {
+		let temp = tempdir::TempDir::new("test_merge_operands_with_preconditions").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(8),
+			merge_operator: Some(Arc::new(U64Add)),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", u64_bytes(10)).unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", u64_bytes(5)).unwrap();
+		tx.update_if_equals("abc", u64_bytes(15), u64_bytes(1)).unwrap();
+		tx.merge("abc", u64_bytes(1)).unwrap();
+		tx.update_if_equals("abc", u64_bytes(2), u64_bytes(3)).unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(3));
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", u64_bytes(5)).unwrap();
+		tx.update_if_equals("abc", u64_bytes(3), u64_bytes(1)).unwrap();
+		assert_eq!(
+			db.commit(&tx).unwrap_err().kind(),
+			&ErrorKind::PreconditionFailed(b"abc".to_vec(), "current value is different")
+		);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", u64_bytes(1)).unwrap();
+		tx.merge("abc", b"1").unwrap();
+		assert_eq!(
+			db.commit(&tx).unwrap_err().kind(),
+			&ErrorKind::MergeFailed(b"abc".to_vec(), "operand is 1 bytes long, expected 8".into())
+		);
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(3));
+	}
+
+	#[test]
+	fn should_reject_malformed_merge_operands_on_commit() {
+		let temp = tempdir::TempDir::new("should_reject_malformed_merge_operands_on_commit").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(8),
+			merge_operator: Some(Arc::new(U64Add)),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", u64_bytes(10)).unwrap();
+		db.commit(&tx).unwrap();
+
+		// lone operands are merged into the stored value, or into none
+		for key in &["abc", "cde"] {
+			let mut tx = db.create_transaction();
+			tx.merge(key, b"123").unwrap();
+			assert_eq!(
+				db.commit(&tx).unwrap_err().kind(),
+				&ErrorKind::MergeFailed(key.as_bytes().to_vec(), "operand is 3 bytes long, expected 8".into())
+			);
+		}
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(10));
+		assert_eq!(db.get("cde").unwrap(), None);
+	}
+
+	#[test]
+	fn should_keep_eras_with_malformed_merge_operands_until_quarantined() {
+		let temp = tempdir::TempDir::new("should_keep_eras_with_malformed_merge_operands_until_quarantined").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(8),
+			merge_operator: Some(Arc::new(U64Add)),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = Transaction::new(3);
+		tx.merge("abc", b"123").unwrap();
+		db.journal.push(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", u64_bytes(1)).unwrap();
+		tx.merge("abc", u64_bytes(2)).unwrap();
+		db.journal.push(&tx).unwrap();
+
+		// committed transactions are never dropped by flushes
+		assert!(matches!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::MergeFailed(..)));
+		assert!(matches!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::MergeFailed(..)));
+		assert_eq!(db.journal_len(), 2);
+		assert!(temp.path().join("0.era").exists());
+
+		assert_eq!(*db.quarantine_era(1).unwrap_err().kind(), ErrorKind::JournalEraNotOldest(1, 0));
+		assert_eq!(*db.quarantine_era(2).unwrap_err().kind(), ErrorKind::JournalEraMissing(2));
+		assert_eq!(db.quarantine_era(0).unwrap(), temp.path().join("0.era.corrupted"));
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(3));
+		assert!(temp.path().join("0.era.corrupted").exists());
+	}
+
+	#[test]
+	fn should_require_merge_operator() {
+		let temp = tempdir::TempDir::new("should_require_merge_operator").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(8),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.merge("abc", u64_bytes(5)).unwrap();
+		assert_eq!(db.commit(&tx).unwrap_err().kind(), &ErrorKind::MergeOperatorMissing);
+		assert_eq!(db.journal_len(), 0);
+	}
+
+	fn positions_options() -> Options {
+		Options {
+			key_len: 2,
+			key_index_bits: 4,
+			value_len: ValuesLen::Constant(8),
+			merge_operator: Some(Arc::new(U64Add)),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_keyspaces() {
+		let temp = tempdir::TempDir::new("test_keyspaces").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		db.create_keyspace("positions", positions_options()).unwrap();
+		assert_eq!(db.keyspace_names(), vec!["positions"]);
+
+		// a single transaction modifies both keyspaces
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert_in("positions", "ab", u64_bytes(5)).unwrap();
+		tx.merge_in("positions", "ab", u64_bytes(2)).unwrap();
+		tx.merge_in("positions", "cd", u64_bytes(3)).unwrap();
+		assert_eq!(tx.get_in(&db, "positions", "ab").unwrap().unwrap(), u64_bytes(7));
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.get_in("positions", "ab").unwrap().unwrap(), u64_bytes(7));
+		assert_eq!(*db.get_in("positions", "abc").unwrap_err().kind(), ErrorKind::InvalidKeyLen(2, 3));
+
+		db.flush_journal(None).unwrap();
+		assert!(temp.path().join("keyspaces/positions/data.db").exists());
+
+		let mut tx = db.create_transaction();
+		tx.delete_in("positions", "cd").unwrap();
+		tx.insert_in("positions", "ef", u64_bytes(1)).unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(
+			db.iter_in("positions").unwrap().map(|r| r.unwrap().0.to_vec()).collect::<Vec<_>>(),
+			vec![b"ab".to_vec(), b"ef".to_vec()]
+		);
+		assert_eq!(db.iter().unwrap().count(), 1);
+
+		drop(db);
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		db.open_keyspace("positions", positions_options()).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("cde").unwrap(), None);
+		assert_eq!(db.get_in("positions", "ab").unwrap().unwrap(), u64_bytes(7));
+		assert_eq!(db.get_in("positions", "cd").unwrap(), None);
+		assert_eq!(db.get_in("positions", "ef").unwrap().unwrap(), u64_bytes(1));
+	}
+
+	#[test]
+	fn test_keyspace_errors() {
+		let temp = tempdir::TempDir::new("test_keyspace_errors").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		assert_eq!(
+			*db.create_keyspace("../positions", positions_options()).unwrap_err().kind(),
+			ErrorKind::InvalidKeyspaceName("../positions".into())
+		);
+		assert_eq!(
+			*db.create_keyspace("", positions_options()).unwrap_err().kind(),
+			ErrorKind::InvalidKeyspaceName("".into())
+		);
+		assert_eq!(
+			*db.open_keyspace("positions", positions_options()).unwrap_err().kind(),
+			ErrorKind::KeyspaceNotFound("positions".into())
+		);
+		assert_eq!(*db.get_in("positions", "ab").unwrap_err().kind(), ErrorKind::KeyspaceNotFound("positions".into()));
+
+		db.create_keyspace("positions", positions_options()).unwrap();
+		assert_eq!(
+			*db.open_keyspace("positions", positions_options()).unwrap_err().kind(),
+			ErrorKind::KeyspaceAlreadyOpen("positions".into())
+		);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert_in("positions", "ab", u64_bytes(1)).unwrap();
+		db.commit(&tx).unwrap();
+
+		// the journal is not flushed until all keyspaces with journaled operations are open
+		drop(db);
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::KeyspaceNotFound("positions".into()));
+		assert_eq!(db.journal_len(), 1);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+
+		db.open_keyspace("positions", positions_options()).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get_in("positions", "ab").unwrap().unwrap(), u64_bytes(1));
+	}
+
+	fn owners_options(journal_eras: usize) -> Options {
+		// values are owner (2 bytes) followed by amount (2 bytes)
+		Options {
+			journal_eras,
+			key_len: 3,
+			value_len: ValuesLen::Constant(4),
+			indexes: vec![Index::new("by_owner", 2, Arc::new(ValueBytes { offset: 0, len: 2 }))],
+			..Default::default()
+		}
+	}
+
+	fn keys_by_owner(db: &Database, owner: &str) -> Vec<Vec<u8>> {
+		db.get_by_index("by_owner", owner).unwrap().into_iter().map(|(key, _)| key.to_vec()).collect()
+	}
+
+	#[test]
+	fn test_secondary_index() {
+		let temp = tempdir::TempDir::new("test_secondary_index").unwrap();
+		let mut db = Database::create(temp.path(), owners_options(1)).unwrap();
+		assert!(db.keyspace_names().is_empty());
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "aa01").unwrap();
+		tx.insert("bcd", "bb02").unwrap();
+		tx.insert("cde", "aa03").unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(keys_by_owner(&db, "aa"), vec![b"abc".to_vec(), b"cde".to_vec()]);
+		assert_eq!(db.get_by_index("by_owner", "bb").unwrap()[0].1, b"bb02");
+		assert!(keys_by_owner(&db, "cc").is_empty());
+
+		// the entries are updated in the same era as the values
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "cc01").unwrap();
+		tx.delete("bcd").unwrap();
+		tx.insert("cde", "aa04").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.journal_len(), 2);
+
+		assert_eq!(keys_by_owner(&db, "aa"), vec![b"cde".to_vec()]);
+		assert!(keys_by_owner(&db, "bb").is_empty());
+		assert_eq!(keys_by_owner(&db, "cc"), vec![b"abc".to_vec()]);
+
+		db.flush_journal(None).unwrap();
+		assert!(temp.path().join("indexes/by_owner/data.db").exists());
+
+		let mut tx = db.create_transaction();
+		tx.insert("def", "bb05").unwrap();
+		db.commit(&tx).unwrap();
+
+		let range = db.iter_index("by_owner", Bound::Excluded(b"aa"), Bound::Included(b"cc")).unwrap()
+			.map(|entry| {
+				let (index_key, key, value) = entry.unwrap();
+				(index_key.to_vec(), key.to_vec(), value.to_vec())
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(range, vec![
+			(b"bb".to_vec(), b"def".to_vec(), b"bb05".to_vec()),
+			(b"cc".to_vec(), b"abc".to_vec(), b"cc01".to_vec()),
+		]);
+
+		drop(db);
+		let mut db = Database::open(temp.path(), owners_options(0)).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(keys_by_owner(&db, "aa"), vec![b"cde".to_vec()]);
+		assert_eq!(keys_by_owner(&db, "bb"), vec![b"def".to_vec()]);
+		assert_eq!(db.iter_index("by_owner", Bound::Unbounded, Bound::Unbounded).unwrap().count(), 3);
+	}
+
+	#[test]
+	fn should_build_index_declared_for_existing_database() {
+		let temp = tempdir::TempDir::new("should_build_index_declared_for_existing_database").unwrap();
+		let options = || Options { indexes: Vec::new(), ..owners_options(1) };
+		let mut db = Database::create(temp.path(), options()).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "aa01").unwrap();
+		tx.insert("bcd", "bb02").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("cde", "aa03").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 1);
+
+		// flushed and journaled values are indexed
+		drop(db);
+		let mut db = Database::open(temp.path(), owners_options(0)).unwrap();
+		assert_eq!(keys_by_owner(&db, "aa"), vec![b"abc".to_vec(), b"cde".to_vec()]);
+
+		let mut tx = db.create_transaction();
+		tx.insert("cde", "bb03").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(keys_by_owner(&db, "aa"), vec![b"abc".to_vec()]);
+		assert_eq!(keys_by_owner(&db, "bb"), vec![b"bcd".to_vec(), b"cde".to_vec()]);
+
+		// an index which is no longer declared is removed with its journaled entries
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "bb01").unwrap();
+		db.commit(&tx).unwrap();
+		drop(db);
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert!(!temp.path().join("indexes/by_owner").exists());
+		db.flush_journal(None).unwrap();
+		assert_eq!(*db.get_by_index("by_owner", "bb").unwrap_err().kind(), ErrorKind::IndexNotFound("by_owner".into()));
+	}
+
+	#[test]
+	fn should_not_flush_entries_journaled_before_index_was_rebuilt() {
+		let temp = tempdir::TempDir::new("should_not_flush_entries_journaled_before_index_was_rebuilt").unwrap();
+		let mut db = Database::create(temp.path(), owners_options(2)).unwrap();
+		commit_records(&mut db, &[("abc", "aa01")], &[]).unwrap();
+
+		// the index is removed, then declared again with another extractor
+		drop(db);
+		let db = Database::open(temp.path(), Options { indexes: Vec::new(), ..owners_options(2) }).unwrap();
+		drop(db);
+		let by_amount = |journal_eras| {
+			let mut options = owners_options(journal_eras);
+			options.indexes[0].extractor = Arc::new(ValueBytes { offset: 2, len: 2 });
+			options
+		};
+		let mut db = Database::open(temp.path(), by_amount(2)).unwrap();
+		commit_records(&mut db, &[("bcd", "bb02")], &[]).unwrap();
+
+		let entries = |db: &Database| db.iter_index("by_owner", Bound::Unbounded, Bound::Unbounded).unwrap()
+			.map(|entry| {
+				let (index_key, key, _) = entry.unwrap();
+				(index_key.to_vec(), key.to_vec())
+			})
+			.collect::<Vec<_>>();
+		let expected = vec![(b"01".to_vec(), b"abc".to_vec()), (b"02".to_vec(), b"bcd".to_vec())];
+		assert_eq!(entries(&db), expected);
+		assert!(keys_by_owner(&db, "aa").is_empty());
+
+		drop(db);
+		let mut db = Database::open(temp.path(), by_amount(0)).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(entries(&db), expected);
+		assert_eq!(
+			db.iter_index("by_owner", Bound::Excluded(b"01"), Bound::Unbounded).unwrap().count(),
+			1
+		);
+	}
+
+	#[test]
+	fn should_reject_invalid_index_keys() {
+		let temp = tempdir::TempDir::new("should_reject_invalid_index_keys").unwrap();
+		let mut options = owners_options(0);
+		options.value_len = ValuesLen::Variable { expected: 4 };
+		options.indexes[0].extractor = Arc::new(ValueBytes { offset: 0, len: 3 });
+		let mut db = Database::create(temp.path(), options).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "aa01").unwrap();
+		assert_eq!(
+			*db.commit(&tx).unwrap_err().kind(),
+			ErrorKind::IndexFailed("by_owner".into(), "index key is 3 bytes long, expected 2".into())
+		);
+		assert_eq!(db.journal_len(), 0);
+
+		// values too short for the extractor are not indexed
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "a").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.iter_index("by_owner", Bound::Unbounded, Bound::Unbounded).unwrap().count(), 0);
+
+		assert_eq!(*db.get_by_index("by_owner", "aaa").unwrap_err().kind(), ErrorKind::InvalidKeyLen(2, 3));
+		assert_eq!(*db.get_by_index("by_amount", "01").unwrap_err().kind(), ErrorKind::IndexNotFound("by_amount".into()));
+		assert_eq!(
+			*db.create_keyspace("owners", owners_options(0)).unwrap_err().kind(),
+			ErrorKind::InvalidOptions("indexes", "secondary indexes are only supported by the database keyspace".into())
+		);
+	}
+
+	#[derive(Debug, Default)]
+	struct TestClock(AtomicUsize);
+
+	impl TestClock {
+		fn set(&self, now: usize) {
+			self.0.store(now, Ordering::SeqCst);
+		}
+	}
+
+	impl Clock for TestClock {
+		fn now(&self) -> u64 {
+			self.0.load(Ordering::SeqCst) as u64
+		}
+	}
+
+	fn pairs(db: &Database) -> Vec<(Vec<u8>, Vec<u8>)> {
+		db.iter().unwrap().map(|pair| {
+			let (key, value) = pair.unwrap();
+			(key.to_vec(), value.to_vec())
+		}).collect()
+	}
+
+	#[test]
+	fn test_ttl() {
+		let temp = tempdir::TempDir::new("test_ttl").unwrap();
+		let clock = Arc::new(TestClock::default());
+		clock.set(100);
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 5 },
+			ttl: true,
+			clock: clock.clone(),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "1").unwrap();
+		tx.insert_with_ttl("bcd", "2", 110).unwrap();
+		tx.insert_with_ttl("cde", "3", 200).unwrap();
+		tx.insert_with_ttl("def", "4", 110).unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("bcd").unwrap().unwrap(), b"2");
+		assert_eq!(pairs(&db).len(), 4);
+
+		// expired values are hidden from reads and preconditions
+		clock.set(110);
+		assert!(db.get("bcd").unwrap().is_none());
+		assert!(db.get("def").unwrap().is_none());
+		assert_eq!(pairs(&db), vec![
+			(b"abc".to_vec(), b"1".to_vec()),
+			(b"cde".to_vec(), b"3".to_vec()),
+		]);
+
+		let mut tx = db.create_transaction();
+		tx.insert_if_absent("bcd", "5").unwrap();
+		assert_eq!(tx.get(&db, "bcd").unwrap().unwrap(), b"5");
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("bcd").unwrap().unwrap(), b"5");
+
+		// expired values are not flushed, so they don't come back with a clock going back
+		db.flush_journal(None).unwrap();
+		clock.set(100);
+		assert!(db.get("def").unwrap().is_none());
+		assert_eq!(db.get("cde").unwrap().unwrap(), b"3");
+
+		// values expired in the data file are hidden and removed by compaction
+		clock.set(200);
+		assert!(db.get("cde").unwrap().is_none());
+		db.compact().unwrap();
+		clock.set(100);
+		assert_eq!(pairs(&db), vec![
+			(b"abc".to_vec(), b"1".to_vec()),
+			(b"bcd".to_vec(), b"5".to_vec()),
+		]);
+	}
+
+	#[test]
+	fn should_require_ttl_option() {
+		let temp = tempdir::TempDir::new("should_require_ttl_option").unwrap();
+
+		let db = Database::create(temp.path(), Options {
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 5 },
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		assert_eq!(*tx.insert_with_ttl("abc", "1", 100).unwrap_err().kind(), ErrorKind::TtlDisabled);
+	}
+
+	#[test]
+	fn should_not_open_with_another_ttl_option() {
+		let temp = tempdir::TempDir::new("should_not_open_with_another_ttl_option").unwrap();
+		let options = |ttl| Options {
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 5 },
+			ttl,
+			..Default::default()
+		};
+
+		let without_ttl = temp.path().join("without_ttl");
+		Database::create(&without_ttl, options(false)).unwrap();
+		let err = Database::open(&without_ttl, options(true)).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("ttl", "true does not match the keyspace created with ttl false".into()));
+		Database::open(&without_ttl, options(false)).unwrap();
+
+		let with_ttl = temp.path().join("with_ttl");
+		Database::create(&with_ttl, options(true)).unwrap();
+		let err = Database::open(&with_ttl, options(false)).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("ttl", "false does not match the keyspace created with ttl true".into()));
+		Database::open(&with_ttl, options(true)).unwrap();
+	}
+
+	#[test]
+	fn should_report_values_shorter_than_expiry_as_corrupted() {
+		let temp = tempdir::TempDir::new("should_report_values_shorter_than_expiry_as_corrupted").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 5 },
+			ttl: true,
+			..Default::default()
+		}).unwrap();
+
+		// a transaction without `ttl` stores the value without its expiry time
+		let mut tx = Transaction::new(3);
+		tx.insert("abc", "1").unwrap();
+		db.journal.push(&tx).unwrap();
+
+		match *db.get("abc").unwrap_err().kind() {
+			ErrorKind::Corrupted(..) => {},
+			ref kind => panic!("Expected corruption, got {:?}", kind),
+		}
+		let corrupted = match db.iter() {
+			Err(ref err) => match *err.kind() {
+				ErrorKind::Corrupted(..) => true,
+				_ => false,
+			},
+			Ok(_) => false,
+		};
+		assert!(corrupted);
+	}
+
+	#[test]
+	fn should_report_changes_with_values_shorter_than_expiry_as_corrupted() {
+		let temp = tempdir::TempDir::new("should_report_changes_with_values_shorter_than_expiry_as_corrupted").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 5 },
+			ttl: true,
+			journal_archive: Some(temp.path().join("archive")),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = Transaction::new(3);
+		tx.insert("abc", "1").unwrap();
+		db.journal.push(&tx).unwrap();
+
+		let corruption = |path| ErrorKind::Corrupted(path, 0, "Value is shorter than its expiry time of 8 bytes".into());
+		let changes = db.changes_since(0).unwrap();
+		let mut eras = changes.iter();
+		assert_eq!(*eras.next().unwrap().unwrap_err().kind(), corruption(temp.path().join("0.era")));
+		assert!(eras.next().is_none());
+		drop(changes);
+
+		// archived eras are read the same way
+		let era = db.journal.pop_front().unwrap();
+		db.journal.remove(era).unwrap();
+		let changes = db.changes_since(0).unwrap();
+		let mut eras = changes.iter();
+		assert_eq!(*eras.next().unwrap().unwrap_err().kind(), corruption(temp.path().join("archive").join("0.era")));
+	}
+
+	#[test]
+	fn test_subscribe() {
+		let temp = tempdir::TempDir::new("test_subscribe").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			subscription_buffer: 2,
+			..Default::default()
+		}).unwrap();
+
+		let key = db.subscribe(KeyFilter::Key(b"abc".to_vec()));
+		let prefix = db.subscribe(KeyFilter::Prefix(b"b".to_vec()));
+		let all = db.subscribe(KeyFilter::Range(Bound::Unbounded, Bound::Unbounded));
+		let dropped = db.subscribe(KeyFilter::Prefix(Vec::new()));
+		drop(dropped);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("bcd", "001").unwrap();
+		tx.insert("bce", "001").unwrap();
+		assert_eq!(db.commit(&tx).unwrap(), 0);
+		assert_eq!(db.subscribers.lock().len(), 3);
+
+		let change = |key: &[u8], old_value: Option<&[u8]>, new_value: Option<&[u8]>, seq| Change {
+			key: key.to_vec(),
+			old_value: old_value.map(|value| value.to_vec()),
+			new_value: new_value.map(|value| value.to_vec()),
+			seq,
+		};
+
+		assert_eq!(key.try_iter().collect::<Vec<_>>(), vec![change(b"abc", None, Some(b"001"), 0)]);
+		assert_eq!(prefix.try_iter().collect::<Vec<_>>(), vec![
+			change(b"bcd", None, Some(b"001"), 0),
+			change(b"bce", None, Some(b"001"), 0),
+		]);
+		assert_eq!(prefix.take_dropped(), 0);
+		assert_eq!(all.try_iter().count(), 2);
+		assert_eq!(all.take_dropped(), 1);
+
+		// old values are read from the data file, failed transactions are not published
+		db.flush_journal(None).unwrap();
+		let mut tx = db.create_transaction();
+		tx.delete("abc").unwrap();
+		tx.update_if_equals("bcd", "001", "002").unwrap();
+		let prepared = db.prepare(&tx).unwrap();
+		assert_eq!(db.apply(prepared).unwrap(), 1);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "003").unwrap();
+		tx.delete_if_present("cde").unwrap();
+		assert!(db.commit(&tx).is_err());
+
+		assert_eq!(key.try_iter().collect::<Vec<_>>(), vec![change(b"abc", Some(b"001"), None, 1)]);
+		assert_eq!(prefix.try_iter().collect::<Vec<_>>(), vec![change(b"bcd", Some(b"001"), Some(b"002"), 1)]);
+	}
+
+	#[test]
+	fn test_metrics() {
+		let temp = tempdir::TempDir::new("test_metrics").unwrap();
+		let metrics = Arc::new(InMemoryMetrics::default());
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 1,
+			metrics: metrics.clone(),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("abd", "002").unwrap();
+		tx.insert("bcd", "003").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.counter(names::GET_JOURNAL), 1);
+		assert_eq!(snapshot.histogram(names::COMMIT_LATENCY).unwrap().count, 1);
+		assert_eq!(snapshot.histogram(names::ERA_SIZE).unwrap().sum, db.journal.size());
+		assert_eq!(snapshot.gauges[names::JOURNAL_ERAS], 1);
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("abd").unwrap().unwrap(), b"002");
+		assert_eq!(db.get("xyz").unwrap(), None);
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.counter(names::FLUSHED_ERAS), 1);
+		assert_eq!(snapshot.gauges[names::JOURNAL_ERAS], 0);
+		assert_eq!(snapshot.histogram(names::FLUSH_DURATION).unwrap().count, 1);
+		assert_eq!(snapshot.histogram(names::FLUSH_WRITTEN_BYTES).unwrap().count, 1);
+		assert_eq!(snapshot.histogram(names::FLUSH_SHIFT).unwrap().count, 3);
+		assert_eq!(snapshot.counter(names::GET_DATA_FILE), 1);
+		assert_eq!(snapshot.counter(names::GET_ABSENT), 1);
+		assert_eq!(snapshot.histogram(names::FIND_PROBE_LENGTH).unwrap().max, 2);
+
+		assert_eq!(db.compact().unwrap(), vec![0x61, 0x62]);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.counter(names::COMPACTION_MOVED_PREFIXES), 2);
+		assert_eq!(snapshot.counter(names::COMPACTION_RESTORED_PREFIXES), 0);
+		assert!(snapshot.counter(names::COMPACTION_WRITTEN_BYTES) > 0);
+		assert_eq!(snapshot.histogram(names::COMPACTION_DURATION).unwrap().count, 1);
+		assert_eq!(snapshot.counter(names::GET_COLLISION), 1);
+	}
+
+	#[test]
+	fn should_count_only_reads_of_users() {
+		let temp = tempdir::TempDir::new("should_count_only_reads_of_users").unwrap();
+		let metrics = Arc::new(InMemoryMetrics::default());
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(8),
+			merge_operator: Some(Arc::new(U64Add)),
+			metrics: metrics.clone(),
+			..Default::default()
+		}).unwrap();
+		let _subscription = db.subscribe(KeyFilter::Prefix(Vec::new()));
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", u64_bytes(1)).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		// merge operands and notifications of subscribers read the previous values
+		let mut tx = db.create_transaction();
+		tx.merge("abc", u64_bytes(2)).unwrap();
+		tx.merge("bcd", u64_bytes(3)).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.counter(names::GET_JOURNAL), 0);
+		assert_eq!(snapshot.counter(names::GET_DATA_FILE), 0);
+		assert_eq!(snapshot.counter(names::GET_ABSENT), 0);
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), u64_bytes(3));
+		assert_eq!(db.get("xyz").unwrap(), None);
+		assert_eq!(metrics.snapshot().counter(names::GET_DATA_FILE), 1);
+		assert_eq!(metrics.snapshot().counter(names::GET_ABSENT), 1);
+	}
+
+	#[test]
+	fn test_changes_since() {
+		let temp = tempdir::TempDir::new("test_changes_since").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			journal_archive: Some(temp.path().join("archive")),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		assert_eq!(db.commit(&tx).unwrap(), 0);
+
+		let mut tx = db.create_transaction();
+		tx.delete("abc").unwrap();
+		tx.insert_if_absent("bcd", "002").unwrap();
+		assert_eq!(db.commit(&tx).unwrap(), 1);
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+
+		let mut tx = db.create_transaction();
+		tx.insert("cde", "003").unwrap();
+		assert_eq!(db.commit(&tx).unwrap(), 2);
+
+		{
+			let changes = db.changes_since(1).unwrap();
+			assert_eq!(changes.len(), 2);
+			assert_eq!(changes.iter().collect::<Result<Vec<_>>>().unwrap(), vec![
+				(1, vec![Operation::Delete(b"abc"), Operation::InsertIfAbsent(b"bcd", b"002")]),
+				(2, vec![Operation::Insert(b"cde", b"003")]),
+			]);
+		}
+
+		// consumers resume from their cursor after the database is reopened
+		db.flush_journal(None).unwrap();
+		drop(db);
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.commit(&db.create_transaction()).unwrap(), 3);
+		assert_eq!(db.changes_since(0).unwrap().iter().map(|era| era.unwrap().0).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+		assert!(db.changes_since(4).unwrap().is_empty());
+
+		fs::remove_file(temp.path().join("archive").join("0.era")).unwrap();
+		assert_eq!(*db.changes_since(0).unwrap_err().kind(), ErrorKind::ChangesPruned(0, 1));
+	}
+
+	#[test]
+	fn should_validate_value_len() {
+		let temp = tempdir::TempDir::new("should_validate_value_len").unwrap();
+		let listener = Arc::new(RecordingListener::default());
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			event_listener: listener.clone(),
+			..Default::default()
+		}).unwrap();
+		listener.take();
+
+		let mut tx = db.create_transaction();
+		assert_eq!(*tx.insert("abc", "0001").unwrap_err().kind(), ErrorKind::InvalidValueLen("3".into(), 4));
+
+		// eras which bypassed the validation are not flushed, until they are moved aside
+		let mut tx = Transaction::new(3);
+		tx.insert("abc", "001").unwrap();
+		tx.insert("cde", "0001").unwrap();
+		db.journal.push(&tx).unwrap();
+		commit_records(&mut db, &[("fgh", "002")], &[]).unwrap();
+
+		assert_eq!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::InvalidValueLen("3".into(), 4));
+		assert_eq!(db.journal_len(), 2);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(listener.take(), vec![]);
+
+		db.quarantine_era(0).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get("abc").unwrap(), None);
+		assert_eq!(db.get("fgh").unwrap().unwrap(), b"002");
+		assert!(!temp.path().join("0.era").exists());
+		assert!(temp.path().join("0.era.corrupted").exists());
+		drop(db);
+
+		let db = Database::open(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get("fgh").unwrap().unwrap(), b"002");
+		drop(db);
+
+		let temp = tempdir::TempDir::new("should_validate_value_len").unwrap();
+
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 3 },
+			max_value_len: 5,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "").unwrap();
+		tx.insert("abc", "00001").unwrap();
+		assert_eq!(*tx.insert("abc", "000001").unwrap_err().kind(), ErrorKind::InvalidValueLen("at most 5".into(), 6));
+	}
+
+	#[test]
+	fn test_apply_checks_preconditions() {
+		let temp = tempdir::TempDir::new("test_apply_checks_preconditions").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert_if_absent("abc", "001").unwrap();
+		let prepared1 = db.prepare(&tx).unwrap();
+		let prepared2 = db.prepare(&tx).unwrap();
+
+		assert_eq!(db.apply(prepared1).unwrap(), 0);
+		assert_eq!(
+			db.apply(prepared2).unwrap_err().kind(),
+			&ErrorKind::PreconditionFailed(b"abc".to_vec(), "key is already present")
+		);
+		assert_eq!(db.journal_len(), 1);
+	}
+
+	#[test]
+	fn test_compact_restores_shrunk_collisions() {
+		let temp = tempdir::TempDir::new("test_compact_restores_shrunk_collisions").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 4,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aac", "003").unwrap();
+		tx.insert("aad", "004").unwrap();
+		tx.insert("zzz", "005").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(1).unwrap();
+
+		assert_eq!(db.compact().unwrap(), vec![97]);
+		assert!(temp.path().join("collision-97.log").exists());
+
+		let mut tx = db.create_transaction();
+		tx.delete("aab").unwrap();
+		tx.delete("aac").unwrap();
+		tx.delete("aad").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(1).unwrap();
+
+		assert_eq!(db.compact().unwrap(), Vec::<u32>::new());
+		assert!(!temp.path().join("collision-97.log").exists());
+		assert!(!db.keyspaces[DEFAULT_KEYSPACE].metadata.collided_prefixes.has(97).unwrap());
+		assert!(db.keyspaces[DEFAULT_KEYSPACE].collisions.is_empty());
+
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("aab").unwrap(), None);
+		assert_eq!(db.get("zzz").unwrap().unwrap(), b"005");
+
+		drop(db);
+		let db = Database::open(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 4,
+			..Default::default()
+		}).unwrap();
+
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("zzz").unwrap().unwrap(), b"005");
+	}
+
+	fn insert_collided_prefixes(db: &mut Database) {
+		let mut tx = db.create_transaction();
+		for key in &["aaa", "aab", "aac", "jaa", "jab", "jac", "zzz"] {
+			tx.insert(key, "001").unwrap();
+		}
+		db.commit(&tx).unwrap();
+	}
+
+	#[test]
+	fn test_iter_from() {
+		let temp = tempdir::TempDir::new("test_iter_from").unwrap();
+
+		let mut db = Database::create(temp.path(), collided_options()).unwrap();
+		insert_collided_prefixes(&mut db);
+		commit_records(&mut db, &[("bcd", "002"), ("kaa", "003")], &[]).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![0x61, 0x6a]);
+		commit_records(&mut db, &[("abc", "004"), ("jad", "005")], &["kaa"]).unwrap();
+
+		let keys_from = |start: &str| db.iter_from(start).unwrap()
+			.map(|item| String::from_utf8(item.unwrap().0.to_vec()).unwrap())
+			.collect::<Vec<_>>();
+		let keys = keys_from("");
+		assert_eq!(keys, vec!["aaa", "aab", "aac", "abc", "bcd", "jaa", "jab", "jac", "jad", "zzz"]);
+
+		for start in &["a", "aab", "aaz", "b", "jab", "k", "zzz"] {
+			let expected: Vec<_> = keys.iter().filter(|key| key.as_str() >= *start).cloned().collect();
+			assert_eq!(keys_from(start), expected, "iterating from {}", start);
+		}
+
+		assert_eq!(db.iter_from("aaaa").err().unwrap().kind(), &ErrorKind::InvalidKeyLen(3, 4));
+	}
+
+	#[test]
+	fn test_compaction_every_flushes() {
+		let temp = tempdir::TempDir::new("test_compaction_every_flushes").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 3,
+			compaction: CompactionPolicy::EveryFlushes(2),
+			..Default::default()
+		}).unwrap();
+
+		insert_collided_prefixes(&mut db);
+		db.flush_journal(None).unwrap();
+		assert!(db.keyspaces[DEFAULT_KEYSPACE].collisions.is_empty());
+
+		db.commit(&db.create_transaction()).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![97, 106]);
+		assert_eq!(db.get("jab").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("zzz").unwrap().unwrap(), b"001");
+	}
+
+	#[test]
+	fn test_compaction_probe_length() {
+		let temp = tempdir::TempDir::new("test_compaction_probe_length").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 3,
+			compaction: CompactionPolicy::ProbeLength(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aac", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].probe_length(97).unwrap(), 3);
+		assert!(db.keyspaces[DEFAULT_KEYSPACE].collisions.is_empty());
+
+		let mut tx = db.create_transaction();
+		tx.insert("aad", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![97]);
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].probe_length(97).unwrap(), 0);
+		assert_eq!(db.get("aad").unwrap().unwrap(), b"004");
+	}
+
+	#[test]
+	fn test_compaction_budget_moves_prefixes_incrementally() {
+		let temp = tempdir::TempDir::new("test_compaction_budget").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 3,
+			compaction: CompactionPolicy::EveryFlushes(1),
+			compaction_budget: CompactionBudget::Bytes(1),
+			..Default::default()
+		}).unwrap();
+
+		insert_collided_prefixes(&mut db);
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![97]);
+
+		db.commit(&db.create_transaction()).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![97, 106]);
+
+		for key in &["aaa", "aab", "aac", "jaa", "jab", "jac", "zzz"] {
+			assert_eq!(db.get(key).unwrap().unwrap(), b"001");
+		}
+	}
+
+	#[test]
+	fn test_compaction_probe_length_moves_long_probe_prefixes() {
+		let temp = tempdir::TempDir::new("test_compaction_probe_length_moves_long_probe_prefixes").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 100,
+			compaction: CompactionPolicy::ProbeLength(2),
+			..Default::default()
+		}).unwrap();
+
+		// the prefix has fewer records than `max_prefix_collisions`, but its probe chain is too long
+		insert_collided_prefixes(&mut db);
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![97, 106]);
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].probe_length(97).unwrap(), 0);
+		assert_eq!(db.get("aab").unwrap().unwrap(), b"001");
+	}
+
+	#[test]
+	fn test_compaction_budget_restores_prefixes_incrementally() {
+		let temp = tempdir::TempDir::new("test_compaction_budget_restores").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 3,
+			compaction: CompactionPolicy::EveryFlushes(1),
+			compaction_budget: CompactionBudget::Bytes(1),
+			..Default::default()
+		}).unwrap();
+
+		insert_collided_prefixes(&mut db);
+		db.flush_journal(None).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![97, 106]);
+
+		let mut tx = db.create_transaction();
+		for key in &["aab", "aac", "jab", "jac"] {
+			tx.delete(key).unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].collisions.keys().cloned().collect::<Vec<_>>(), vec![106]);
+
+		db.commit(&db.create_transaction()).unwrap();
+		db.flush_journal(None).unwrap();
+		assert!(db.keyspaces[DEFAULT_KEYSPACE].collisions.is_empty());
+
+		for key in &["aaa", "jaa", "zzz"] {
+			assert_eq!(db.get(key).unwrap().unwrap(), b"001");
+		}
+		assert_eq!(db.get("jab").unwrap(), None);
+	}
+
+	#[test]
+	fn should_recover_after_crash_at_every_flush_step() {
+		check_crash_points(
+			|| Options {
+				journal_eras: 0,
+				key_len: 3,
+				value_len: ValuesLen::Constant(3),
+				..Default::default()
+			},
+			|db| {
+				commit_records(db, &[("abc", "001"), ("cde", "002")], &[]).unwrap();
+				db.flush_journal(None).unwrap();
+				commit_records(db, &[("abc", "003"), ("fgh", "004")], &["cde"]).unwrap();
+			},
+			|db| db.flush_journal(None),
+		);
+	}
+
+	#[test]
+	fn should_remove_orphaned_collision_files_on_open() {
+		let temp = tempdir::TempDir::new("orphaned_collision_files").unwrap();
+
+		{
+			let _db = Database::create(temp.path(), Default::default()).unwrap();
+		}
+
+		{
+			let mut collision = Collision::create(&Vfs::Disk, temp.path(), 5, Durability::Full).unwrap();
+			collision.insert(&[5; 32], &[0; 64]).unwrap();
+		}
+
+		let _db = Database::open(temp.path(), Default::default()).unwrap();
+		assert!(Collision::open(&Vfs::Disk, temp.path(), 5, Durability::Full).unwrap().is_none());
+	}
+
+	#[derive(Debug, Default)]
+	struct RecordingListener(::parking_lot::Mutex<Vec<Event>>);
+
+	impl EventListener for RecordingListener {
+		fn on_event(&self, event: &Event) {
+			self.0.lock().push(event.clone());
+		}
+	}
+
+	impl RecordingListener {
+		fn take(&self) -> Vec<Event> {
+			mem::replace(&mut *self.0.lock(), Vec::new())
+		}
+	}
+
+	#[test]
+	fn test_event_listener() {
+		let temp = tempdir::TempDir::new("test_event_listener").unwrap();
+		let listener = Arc::new(RecordingListener::default());
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 1,
+			event_listener: listener.clone(),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		match listener.take()[..] {
+			[Event::DatabaseOpened(ref opened)] => assert_eq!((opened.journal_eras, opened.recovery.len()), (0, 0)),
+			ref events => panic!("unexpected events {:?}", events),
+		}
+
+		assert!(Database::open(temp.path(), options()).is_err());
+		assert_eq!(listener.take(), vec![Event::LockContended(LockContended { path: temp.path().join("LOCK") })]);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("abd", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![0x61]);
+		assert_eq!(listener.take(), vec![Event::PrefixMigrated(PrefixMigrated {
+			path: temp.path().to_owned(),
+			prefix: 0x61,
+			records: 2,
+		})]);
+
+		// crash with a prepared transaction and a flush file left behind
+		let mut tx = db.create_transaction();
+		tx.insert("bcd", "003").unwrap();
+		mem::forget(db.prepare(&tx).unwrap());
+		db.commit(&tx).unwrap();
+		let era = db.journal.pop_front().unwrap();
+		let operations = era.iter().map(|(_, op)| op).collect();
+		db.keyspaces.get_mut(DEFAULT_KEYSPACE).unwrap().prepare_flush(operations, &mut BTreeSet::new()).unwrap();
+		drop(db);
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(listener.take(), vec![
+			Event::DatabaseOpened(DatabaseOpened {
+				path: temp.path().to_owned(),
+				journal_eras: 1,
+				recovery: vec![
+					RecoveryAction::PreparedTransactionRemoved(temp.path().join("0.prepared")),
+					RecoveryAction::FlushReplayed(temp.path().to_owned()),
+				],
+			}),
+		]);
+		drop(db);
+
+		// corrupt the checksum of the journaled era
+		let era = temp.path().join("1.era");
+		fs::OpenOptions::new().write(true).open(&era).unwrap().write_all(&[1, 2, 3]).unwrap();
+		assert!(Database::open(temp.path(), options()).is_err());
+		match listener.take()[..] {
+			[Event::JournalEraCorrupted(ref corrupted)] => assert_eq!(corrupted.path, era),
+			ref events => panic!("unexpected events {:?}", events),
+		}
+	}
+
+	#[test]
+	fn should_report_flush_replayed_by_keyspace_opened_after_database() {
+		let temp = tempdir::TempDir::new("should_report_flush_replayed_by_keyspace_opened_after_database").unwrap();
+		let listener = Arc::new(RecordingListener::default());
+		let keyspace_options = || Options {
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			event_listener: listener.clone(),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), Options { journal_eras: 0, ..keyspace_options() }).unwrap();
+		db.create_keyspace("positions", keyspace_options()).unwrap();
+		listener.take();
+
+		// crash with a flush file of the keyspace left behind
+		let mut tx = db.create_transaction();
+		tx.insert_in("positions", "abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+		let era = db.journal.pop_front().unwrap();
+		let operations = era.iter().filter(|&(name, _)| name == b"positions").map(|(_, op)| op).collect();
+		db.keyspaces.get_mut(&b"positions"[..]).unwrap().prepare_flush(operations, &mut BTreeSet::new()).unwrap();
+		drop(db);
+
+		let mut db = Database::open(temp.path(), Options { journal_eras: 0, ..keyspace_options() }).unwrap();
+		match listener.take()[..] {
+			[Event::DatabaseOpened(ref opened)] => assert!(opened.recovery.is_empty()),
+			ref events => panic!("unexpected events {:?}", events),
+		}
+
+		db.open_keyspace("positions", keyspace_options()).unwrap();
+		assert_eq!(listener.take(), vec![
+			Event::FlushReplayed(FlushReplayed { path: temp.path().join("keyspaces").join("positions") }),
+		]);
+		assert_eq!(db.get_in("positions", "abc").unwrap().unwrap(), b"001");
+	}
+
+	#[test]
+	fn should_report_corrupted_data_file() {
+		let temp = tempdir::TempDir::new("should_report_corrupted_data_file").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("abc", "001").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+
+		// replace the header of the record with an invalid one
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		let offset = data.windows(3).position(|window| window == b"abc").unwrap() - 1;
+		assert_eq!(data[offset], 1);
+		data[offset] = 7;
+		fs::write(&path, &data).unwrap();
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		match *db.get("abc").unwrap_err().kind() {
+			ErrorKind::Corrupted(ref corrupted, at, ref msg) => {
+				assert_eq!(corrupted, &path);
+				assert_eq!(at, offset as u64);
+				assert_eq!(msg, "Invalid field header 7");
+			},
+			ref kind => panic!("unexpected error {:?}", kind),
+		}
+	}
+
+	#[test]
+	fn should_report_missing_collision_file() {
+		let temp = tempdir::TempDir::new("should_report_missing_collision_file").unwrap();
+
+		{
+			let mut db = Database::create(temp.path(), collided_options()).unwrap();
+			insert_collided_prefixes(&mut db);
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.compact().unwrap(), vec![0x61, 0x6a]);
+		}
+
+		let path = Collision::collision_file_path(temp.path(), 0x61);
+		fs::remove_file(&path).unwrap();
+
+		let err = Database::open(temp.path(), collided_options()).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::Corrupted(path, 0, "Collision file of the collided prefix 97 is missing".into()));
+	}
+
+	#[test]
+	fn should_report_garbled_collision_file() {
+		let temp = tempdir::TempDir::new("should_report_garbled_collision_file").unwrap();
+
+		{
+			let mut db = Database::create(temp.path(), collided_options()).unwrap();
+			insert_collided_prefixes(&mut db);
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.compact().unwrap(), vec![0x61, 0x6a]);
+		}
+
+		let path = Collision::collision_file_path(temp.path(), 0x61);
+		let len = fs::read(&path).unwrap().len();
+		fs::write(&path, &vec![0xff; len]).unwrap();
+
+		match *Database::open(temp.path(), collided_options()).unwrap_err().kind() {
+			ErrorKind::Corrupted(ref corrupted, _, _) =>
+				assert_eq!(corrupted, &path),
+			ref kind => panic!("unexpected error {:?}", kind),
+		}
+	}
+
+	#[test]
+	fn should_report_lookup_reaching_end_of_data_file() {
+		let temp = tempdir::TempDir::new("should_report_lookup_reaching_end_of_data_file").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let record_offset = {
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("abc", "001").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+			db.options().record_offset
+		};
+
+		// fill the rest of the data file with copies of the record, so that no field ends the lookup
+		let path = temp.path().join("data.db");
+		let mut data = fs::read(&path).unwrap();
+		let offset = data.windows(3).position(|window| window == b"abc").unwrap() - 1;
+		let record = data[offset..offset + record_offset].to_vec();
+		let end = data.len() - (data.len() - offset) % record_offset;
+		for field in data[offset..end].chunks_mut(record_offset) {
+			field.copy_from_slice(&record);
+		}
+		fs::write(&path, &data).unwrap();
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(
+			*db.get("abd").unwrap_err().kind(),
+			ErrorKind::Corrupted(path, data.len() as u64, "Lookup reached the end of the data file".into())
+		);
+	}
+
+	#[test]
+	fn should_read_probe_chains_longer_than_read_window() {
+		for storage in &[Storage::Mmap, Storage::Pread] {
+			let temp = tempdir::TempDir::new("should_read_probe_chains_longer_than_read_window").unwrap();
+			let mut db = Database::create(temp.path(), Options {
+				journal_eras: 0,
+				key_len: 3,
+				value_len: ValuesLen::Constant(3),
+				storage: *storage,
+				..Default::default()
+			}).unwrap();
+
+			// all keys share the prefix, so their records are probed one after another
+			let keys = (0..100u8).map(|i| vec![b'a', b'0' + i / 10, b'0' + i % 10]).collect::<Vec<_>>();
+			let mut tx = db.create_transaction();
+			for key in &keys {
+				tx.insert(key, key).unwrap();
+			}
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+
+			for key in &keys {
+				assert_eq!(db.get(key).unwrap().unwrap(), key);
+			}
+			assert_eq!(db.get("a:0").unwrap(), None);
+			assert_eq!(db.keyspaces[DEFAULT_KEYSPACE].probe_length(b'a' as u32).unwrap(), 100);
+		}
+	}
+
+	#[test]
+	fn should_read_records_continuing_after_read_window() {
+		for storage in &[Storage::Mmap, Storage::Pread] {
+			let temp = tempdir::TempDir::new("should_read_records_continuing_after_read_window").unwrap();
+			let mut db = Database::create(temp.path(), Options {
+				journal_eras: 0,
+				key_len: 3,
+				value_len: ValuesLen::Variable { expected: 3 },
+				storage: *storage,
+				..Default::default()
+			}).unwrap();
+
+			// records of different lengths end in the middle of the read windows
+			let keys = (0..50u8).map(|i| vec![b'a', b'0' + i / 10, b'0' + i % 10]).collect::<Vec<_>>();
+			let mut tx = db.create_transaction();
+			for (i, key) in keys.iter().enumerate() {
+				tx.insert(key, &vec![b'v'; i % 30]).unwrap();
+			}
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+
+			for (i, key) in keys.iter().enumerate() {
+				assert_eq!(db.get(key).unwrap().unwrap(), vec![b'v'; i % 30]);
+			}
+		}
+	}
+
+	#[test]
+	fn should_refuse_to_open_newer_format() {
+		let temp = tempdir::TempDir::new("should_refuse_to_open_newer_format").unwrap();
+		drop(Database::create(temp.path(), Default::default()).unwrap());
+		assert_eq!(migration::read_version(&Vfs::Disk, temp.path()).unwrap(), migration::FORMAT_VERSION);
+
+		migration::write_version(&Vfs::Disk, temp.path(), migration::FORMAT_VERSION + 1, Durability::Full).unwrap();
+		assert_eq!(
+			*Database::open(temp.path(), Default::default()).unwrap_err().kind(),
+			ErrorKind::UnsupportedFormatVersion(migration::FORMAT_VERSION + 1, migration::FORMAT_VERSION)
+		);
+
+		// databases created before the format was versioned are of the first version
+		fs::remove_file(temp.path().join("VERSION")).unwrap();
+		Database::open(temp.path(), Default::default()).unwrap();
+	}
+
+	#[test]
+	fn test_in_memory() {
+		let mut db = Database::in_memory(Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			durability: Durability::Full,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aac", "003").unwrap();
+		tx.insert("zzz", "004").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.delete("zzz").unwrap();
+		let prepared = db.prepare(&tx).unwrap();
+		assert_eq!(db.apply(prepared).unwrap(), 1);
+		assert_eq!(db.get("zzz").unwrap(), None);
+
+		// the colliding keys are moved to a collision file
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal_len(), 0);
+		assert_eq!(db.get("aab").unwrap().unwrap(), b"002");
+		assert_eq!(db.get("zzz").unwrap(), None);
+
+		let mut tx = db.create_transaction();
+		tx.insert("aad", "005").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![0x61]);
+		assert_eq!(db.get("aad").unwrap().unwrap(), b"005");
+
+		let records = db.iter().unwrap()
+			.map(|item| {
+				let (key, value) = item.unwrap();
+				(key.to_vec(), value.to_vec())
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(records, vec![
+			(b"aaa".to_vec(), b"001".to_vec()),
+			(b"aab".to_vec(), b"002".to_vec()),
+			(b"aac".to_vec(), b"003".to_vec()),
+			(b"aad".to_vec(), b"005".to_vec()),
+		]);
+
+		assert!(!::std::path::Path::new(Database::MEMORY_PATH).exists());
+	}
+
+	#[test]
+	fn test_in_memory_keyspaces_and_indexes() {
+		let mut db = Database::in_memory(owners_options(0)).unwrap();
+		db.create_keyspace("positions", Options {
+			key_len: 2,
+			value_len: ValuesLen::Constant(1),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(
+			*db.create_keyspace("positions", Default::default()).unwrap_err().kind(),
+			ErrorKind::KeyspaceAlreadyOpen("positions".into())
+		);
+		assert_eq!(
+			*db.open_keyspace("orders", Default::default()).unwrap_err().kind(),
+			ErrorKind::KeyspaceNotFound("orders".into())
+		);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "aa01").unwrap();
+		tx.insert("bcd", "bb02").unwrap();
+		tx.insert_in("positions", "ab", "1").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(keys_by_owner(&db, "aa"), vec![b"abc".to_vec()]);
+		assert_eq!(db.get_in("positions", "ab").unwrap().unwrap(), b"1");
+
+		// every database in memory has its own files
+		let other = Database::in_memory(owners_options(0)).unwrap();
+		assert_eq!(other.get("abc").unwrap(), None);
+		assert!(keys_by_owner(&other, "aa").is_empty());
+	}
+
+	/// Returns the records of the database, journaled ones included, followed by the entries
+	/// of its secondary indexes.
+	fn records_and_index_entries(db: &Database) -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut records = db.iter().unwrap()
+			.map(|item| {
+				let (key, value) = item.unwrap();
+				(key.to_vec(), value.to_vec())
+			})
+			.collect::<Vec<_>>();
+
+		for index in &db.options().external.indexes {
+			records.extend(db.iter_index(&index.name, Bound::Unbounded, Bound::Unbounded).unwrap().map(|entry| {
+				let (index_key, key, _) = entry.unwrap();
+				(index_key.to_vec(), key.to_vec())
+			}));
+		}
+
+		records
+	}
+
+	/// Runs `operation` on a database in memory prepared by `setup`, simulating a crash at each
+	/// write, sync, rename and removal of the operation in turn. The database reopened after
+	/// the crash must hold either the records it held before the operation or the records it
+	/// holds once the operation completes, and must still flush and compact.
+	fn check_crash_points<F, S, O>(options: F, setup: S, operation: O) where
+		F: Fn() -> Options,
+		S: Fn(&mut Database),
+		O: Fn(&mut Database) -> Result<()>,
+	{
+		let internal_options = |memory: &Arc<MemoryFs>| {
+			InternalOptions::from_external(options()).unwrap().with_vfs(Vfs::Memory(memory.clone()))
+		};
+		let create = || {
+			let memory = Arc::new(MemoryFs::default());
+			let mut db = Database::create_in_memory(internal_options(&memory)).unwrap();
+			setup(&mut db);
+			(memory, db)
+		};
+
+		let (memory, mut db) = create();
+		let before = records_and_index_entries(&db);
+		let start = memory.operations();
+		operation(&mut db).unwrap();
+		let operations = memory.operations() - start;
+		let after = records_and_index_entries(&db);
+		assert!(operations > 0);
+
+		for crash in 0..operations {
+			let (memory, mut db) = create();
+			memory.inject(memory.operations() + crash, Fault::Crash);
+			assert!(operation(&mut db).is_err(), "crash at operation {} of {} was not reported", crash, operations);
+			drop(db);
+			memory.restart();
+
+			let mut db = Database::open_internal(Database::MEMORY_PATH, None, internal_options(&memory))
+				.unwrap_or_else(|err| panic!("reopening after crash at operation {} of {} failed: {}", crash, operations, err));
+			let records = records_and_index_entries(&db);
+			assert!(records == before || records == after, "crash at operation {} of {} left {:?}", crash, operations, records);
+
+			db.flush_journal(None).unwrap();
+			db.compact().unwrap();
+			assert_eq!(records_and_index_entries(&db), records, "crash at operation {} of {}", crash, operations);
+		}
+	}
+
+	fn collided_options() -> Options {
+		Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 3,
+			..Default::default()
+		}
+	}
+
+	fn commit_records(db: &mut Database, records: &[(&str, &str)], deleted: &[&str]) -> Result<()> {
+		let mut tx = db.create_transaction();
+		for &(key, value) in records {
+			tx.insert(key, value)?;
+		}
+		for key in deleted {
+			tx.delete(key)?;
+		}
+		db.commit(&tx).map(|_| ())
+	}
+
+	#[test]
+	fn should_recover_from_crash_during_commit() {
+		check_crash_points(
+			collided_options,
+			|db| {
+				commit_records(db, &[("abc", "001"), ("bcd", "002")], &[]).unwrap();
+				db.flush_journal(None).unwrap();
+			},
+			|db| commit_records(db, &[("abd", "003"), ("cde", "004")], &["bcd"]),
+		);
+	}
+
+	#[test]
+	fn should_recover_from_crash_during_flush() {
+		check_crash_points(
+			|| owners_options(0),
+			|db| {
+				commit_records(db, &[("abc", "aa01"), ("bcd", "bb02")], &[]).unwrap();
+				db.flush_journal(None).unwrap();
+				commit_records(db, &[("abc", "cc01"), ("cde", "aa03")], &["bcd"]).unwrap();
+				commit_records(db, &[("def", "bb04")], &[]).unwrap();
+			},
+			|db| db.flush_journal(None),
+		);
+	}
+
+	#[test]
+	fn should_recover_from_crash_during_flush_to_collision_file() {
+		check_crash_points(
+			collided_options,
+			|db| {
+				commit_records(db, &[("aaa", "001"), ("aab", "002"), ("aac", "003"), ("bcd", "004")], &[]).unwrap();
+				db.flush_journal(None).unwrap();
+				assert_eq!(db.compact().unwrap(), vec![0x61]);
+				commit_records(db, &[("aad", "005"), ("bce", "006")], &["aaa"]).unwrap();
+			},
+			|db| db.flush_journal(None),
+		);
+	}
+
+	#[test]
+	fn should_recover_from_crash_during_compaction() {
+		check_crash_points(
+			collided_options,
+			|db| {
+				commit_records(db, &[("aaa", "001"), ("aab", "002"), ("aac", "003"), ("bcd", "004")], &[]).unwrap();
+				db.flush_journal(None).unwrap();
+			},
+			|db| db.compact().map(|_| ()),
+		);
+
+		// prefixes which shrank are moved back to the data file
+		check_crash_points(
+			collided_options,
+			|db| {
+				commit_records(db, &[("aaa", "001"), ("aab", "002"), ("aac", "003"), ("bcd", "004")], &[]).unwrap();
+				db.flush_journal(None).unwrap();
+				db.compact().unwrap();
+				commit_records(db, &[], &["aaa", "aab"]).unwrap();
+				db.flush_journal(None).unwrap();
+			},
+			|db| db.compact().map(|_| ()),
+		);
+	}
+
 	#[test]
 	fn should_validate_exclusive_access() {
 		let temp = tempdir::TempDir::new("exclusive_access").unwrap();
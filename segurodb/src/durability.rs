@@ -0,0 +1,58 @@
+//! Syncing database files to the disk.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use memmap::Mmap;
+
+/// Decides which writes are synced to the disk before an operation returns.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Durability {
+	/// Nothing is synced explicitly, the OS decides when the data reaches the disk.
+	/// Committed transactions may be lost on power failure.
+	None,
+	/// Contents of era, flush, data and metadata files are synced.
+	/// Creation and removal of files may still be lost on power failure.
+	Data,
+	/// File contents and directory entries of created and removed files are synced.
+	Full,
+}
+
+impl Durability {
+	/// Syncs the contents of the file.
+	pub(crate) fn sync_file(&self, file: &File) -> io::Result<()> {
+		match *self {
+			Durability::None => Ok(()),
+			Durability::Data | Durability::Full => file.sync_all(),
+		}
+	}
+
+	/// Syncs the contents of the memory mapped file.
+	pub(crate) fn sync_mmap(&self, mmap: &Mmap) -> io::Result<()> {
+		match *self {
+			Durability::None => Ok(()),
+			Durability::Data | Durability::Full => mmap.flush(),
+		}
+	}
+
+	/// Syncs the directory entries, so files created or removed in the directory
+	/// survive a power failure.
+	pub(crate) fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+		match *self {
+			Durability::None | Durability::Data => Ok(()),
+			Durability::Full => sync_dir(dir.as_ref()),
+		}
+	}
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+	File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+	// directories can't be opened as files on other platforms
+	Ok(())
+}
@@ -2,7 +2,9 @@
 #![allow(missing_docs)]
 
 use std::{io, num};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use hex_slice::AsHex;
 
 use field;
 
@@ -21,6 +23,10 @@ error_chain! {
 			description("Invalid key length")
 			display("Invalid key length. Expected: {}, got: {}", expected, got),
 		}
+		InvalidValueLen(expected: String, got: usize) {
+			description("Invalid value length")
+			display("Invalid value length. Expected: {}, got: {}", expected, got),
+		}
 		CorruptedFlush(path: PathBuf, msg: String) {
 			description("Hash of flush data is invalid"),
 			display("Database flush corruption detected in file at {}. {}", path.display(), msg),
@@ -29,6 +35,10 @@ error_chain! {
 			description("Hash of journal data is invalid"),
 			display("Database journal corruption detected in file at {}. {}", path.display(), msg),
 		}
+		Corrupted(path: PathBuf, offset: u64, msg: String) {
+			description("Malformed bytes were read from a database file"),
+			display("Database corruption detected in file at {}, offset {}. {}", path.display(), offset, msg),
+		}
 		InvalidJournalLocation(path: PathBuf) {
 			description("Path to journal is a file"),
 			display("Expected a directory at {}, got file.", path.display()),
@@ -37,16 +47,84 @@ error_chain! {
 			description("Eras are not consecutive"),
 			display("Missing era file with index {}", idx),
 		}
+		JournalEraNotOldest(idx: u64, oldest: u64) {
+			description("Era is not the oldest era of the journal"),
+			display("Era {} is not the oldest era of the journal {}", idx, oldest),
+		}
+		PreconditionFailed(key: Vec<u8>, reason: &'static str) {
+			description("Precondition of a conditional operation failed"),
+			display("Precondition failed for key {:02x}: {}", key.as_hex(), reason),
+		}
+		MergeOperatorMissing {
+			description("Merge operator is not registered"),
+			display("Transaction contains merge operands, but no merge operator is registered in options"),
+		}
+		MergeFailed(key: Vec<u8>, msg: String) {
+			description("Merge operator failed"),
+			display("Merging operand into key {:02x} failed: {}", key.as_hex(), msg),
+		}
+		ChangesPruned(seq: u64, oldest: u64) {
+			description("Requested journal eras were deleted"),
+			display("Changes since era {} were pruned, the oldest available era is {}", seq, oldest),
+		}
+		InvalidPreparedTransaction(path: PathBuf) {
+			description("Prepared transaction belongs to another database"),
+			display("Prepared transaction at {} belongs to another database", path.display()),
+		}
+		KeyspaceNotFound(name: String) {
+			description("Keyspace not found"),
+			display("Keyspace {} does not exist or is not open", name),
+		}
+		KeyspaceAlreadyOpen(name: String) {
+			description("Keyspace is already open"),
+			display("Keyspace {} is already open", name),
+		}
+		InvalidKeyspaceName(name: String) {
+			description("Invalid keyspace name"),
+			display("Invalid keyspace name: {:?}. Only ASCII letters, digits, '-' and '_' are allowed", name),
+		}
+		IndexNotFound(name: String) {
+			description("Index not found"),
+			display("Index {} is not declared in options", name),
+		}
+		IndexFailed(name: String, msg: String) {
+			description("Index key could not be extracted"),
+			display("Updating index {} failed: {}", name, msg),
+		}
+		TtlDisabled {
+			description("Values are stored without expiry time"),
+			display("Transaction contains values with expiry, but `ttl` is not enabled in options"),
+		}
+		InvalidEncoding(msg: String) {
+			description("Invalid encoding of a typed key or value"),
+			display("Invalid encoding: {}", msg),
+		}
 		InvalidOptions(field: &'static str, error: String) {
 			description("Invalid options were provided"),
 			display("Invalid value of `{}`: {}", field, error),
 		}
+		GroupCommitFailed(error: String) {
+			description("Group commit failed"),
+			display("Transaction was committed in a group which failed: {}", error),
+		}
+		FlushFailed(error: String) {
+			description("Background flush failed"),
+			display("Background flush of the journal failed: {}", error),
+		}
 		DatabaseLocked(path: PathBuf) {
 			description("Database file lock is currently acquired"),
 			display("Could not acquire database file lock: {}. \
 					 If you're sure that no other process is using \
 					 the database you can delete this file.", path.display()),
 		}
+		UnsupportedFormatVersion(version: u16, supported: u16) {
+			description("Database was written in a newer format"),
+			display("Database format version {} is newer than the supported version {}", version, supported),
+		}
+		MigrationMissing(version: u16) {
+			description("No migration upgrades the database format"),
+			display("No migration upgrades the database format from version {}", version),
+		}
 	}
 }
 
@@ -57,15 +135,77 @@ impl PartialEq for ErrorKind {
 		match (self, other) {
 			(&InvalidKeyLen(expected, got), &InvalidKeyLen(expected2, got2))
 				if expected == expected2 && got == got2 => true,
+			(&InvalidValueLen(ref expected, got), &InvalidValueLen(ref expected2, got2))
+				if expected == expected2 && got == got2 => true,
 			(&CorruptedJournal(ref path, ref msg), &CorruptedJournal(ref path2, ref msg2))
 				if path == path2 && msg == msg2 => true,
+			(&Corrupted(ref path, offset, ref msg), &Corrupted(ref path2, offset2, ref msg2))
+				if path == path2 && offset == offset2 && msg == msg2 => true,
 			(&InvalidJournalLocation(ref path), &InvalidJournalLocation(ref path2))
 				if path == path2 => true,
 			(&JournalEraMissing(idx), &JournalEraMissing(idx2))
 				if idx == idx2 => true,
+			(&JournalEraNotOldest(idx, oldest), &JournalEraNotOldest(idx2, oldest2))
+				if idx == idx2 && oldest == oldest2 => true,
+			(&PreconditionFailed(ref key, reason), &PreconditionFailed(ref key2, reason2))
+				if key == key2 && reason == reason2 => true,
+			(&MergeOperatorMissing, &MergeOperatorMissing) => true,
+			(&MergeFailed(ref key, ref msg), &MergeFailed(ref key2, ref msg2))
+				if key == key2 && msg == msg2 => true,
+			(&ChangesPruned(seq, oldest), &ChangesPruned(seq2, oldest2))
+				if seq == seq2 && oldest == oldest2 => true,
+			(&InvalidPreparedTransaction(ref path), &InvalidPreparedTransaction(ref path2))
+				if path == path2 => true,
+			(&KeyspaceNotFound(ref name), &KeyspaceNotFound(ref name2)) if name == name2 => true,
+			(&KeyspaceAlreadyOpen(ref name), &KeyspaceAlreadyOpen(ref name2)) if name == name2 => true,
+			(&InvalidKeyspaceName(ref name), &InvalidKeyspaceName(ref name2)) if name == name2 => true,
+			(&IndexNotFound(ref name), &IndexNotFound(ref name2)) if name == name2 => true,
+			(&IndexFailed(ref name, ref msg), &IndexFailed(ref name2, ref msg2))
+				if name == name2 && msg == msg2 => true,
+			(&TtlDisabled, &TtlDisabled) => true,
+			(&InvalidEncoding(ref msg), &InvalidEncoding(ref msg2)) if msg == msg2 => true,
 			(&InvalidOptions(field, ref error), &InvalidOptions(field2, ref error2))
 				if field == field2 && error == error2 => true,
+			(&GroupCommitFailed(ref error), &GroupCommitFailed(ref error2))
+				if error == error2 => true,
+			(&FlushFailed(ref error), &FlushFailed(ref error2))
+				if error == error2 => true,
+			(&UnsupportedFormatVersion(version, supported), &UnsupportedFormatVersion(version2, supported2))
+				if version == version2 && supported == supported2 => true,
+			(&MigrationMissing(version), &MigrationMissing(version2)) if version == version2 => true,
 			_ => false,
 		}
 	}
 }
+
+/// Malformed bytes found by a parser which doesn't know the file the bytes were read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+	/// Offset of the malformed bytes from the beginning of the parsed slice.
+	pub offset: usize,
+	/// Description of the corruption.
+	pub msg: String,
+}
+
+impl Corruption {
+	pub fn new<S: Into<String>>(offset: usize, msg: S) -> Self {
+		Corruption {
+			offset,
+			msg: msg.into(),
+		}
+	}
+
+	/// Returns the corruption moved by `offset` bytes, for slices which don't start at the beginning
+	/// of their file.
+	pub fn shifted(self, offset: usize) -> Self {
+		Corruption {
+			offset: self.offset + offset,
+			msg: self.msg,
+		}
+	}
+
+	/// Returns the error of the corruption found in the file.
+	pub fn in_file<P: AsRef<Path>>(self, path: P) -> Error {
+		ErrorKind::Corrupted(path.as_ref().to_path_buf(), self.offset as u64, self.msg).into()
+	}
+}
@@ -0,0 +1,151 @@
+//! Events describing what the storage engine did and why.
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// Receiver of the events of the database.
+///
+/// Listeners are called synchronously by the thread causing the event, so they should not block
+/// and must not call back into the database.
+pub trait EventListener: fmt::Debug + Send + Sync {
+	/// Called once the event happened.
+	fn on_event(&self, event: &Event);
+}
+
+/// Listener ignoring all events.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopListener;
+
+impl EventListener for NoopListener {
+	fn on_event(&self, _event: &Event) {}
+}
+
+/// Event of the database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+	/// The database was opened.
+	DatabaseOpened(DatabaseOpened),
+	/// A flush interrupted by a crash was applied while opening a keyspace of an open database.
+	/// Flushes applied while opening the database are reported by `DatabaseOpened::recovery`.
+	FlushReplayed(FlushReplayed),
+	/// A prefix with too many records was moved from the data file to a collision file.
+	PrefixMigrated(PrefixMigrated),
+	/// A prefix was moved back from its collision file to the data file.
+	PrefixRestored(PrefixRestored),
+	/// The database could not be opened, because it is used by another process.
+	LockContended(LockContended),
+	/// A journal era failed checksum verification.
+	JournalEraCorrupted(JournalEraCorrupted),
+	/// A flush file failed checksum verification.
+	FlushCorrupted(FlushCorrupted),
+	/// A step upgrading the on-disk format reported its progress.
+	MigrationProgress(MigrationProgress),
+	/// A step upgrading the on-disk format completed.
+	FormatMigrated(FormatMigrated),
+}
+
+/// The database was opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseOpened {
+	/// Directory of the database.
+	pub path: PathBuf,
+	/// Number of eras in the journal, which are not flushed yet.
+	pub journal_eras: usize,
+	/// Actions taken to restore a consistent state after the database was last closed.
+	pub recovery: Vec<RecoveryAction>,
+}
+
+/// Action taken while opening the database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecoveryAction {
+	/// A flush interrupted by a crash was applied to the data file in the directory.
+	FlushReplayed(PathBuf),
+	/// A collision file left by an interrupted compaction was removed.
+	CollisionFileRemoved(PathBuf),
+	/// A transaction which was prepared, but never applied, was removed.
+	PreparedTransactionRemoved(PathBuf),
+	/// A newly declared secondary index was built.
+	IndexBuilt(String),
+	/// A secondary index which is no longer declared was removed.
+	IndexRemoved(String),
+}
+
+/// A flush interrupted by a crash was applied while opening a keyspace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushReplayed {
+	/// Directory of the keyspace.
+	pub path: PathBuf,
+}
+
+/// A prefix with too many records was moved from the data file to a collision file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixMigrated {
+	/// Directory of the keyspace.
+	pub path: PathBuf,
+	/// Moved prefix.
+	pub prefix: u32,
+	/// Number of records of the prefix.
+	pub records: usize,
+}
+
+/// A prefix was moved back from its collision file to the data file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixRestored {
+	/// Directory of the keyspace.
+	pub path: PathBuf,
+	/// Restored prefix.
+	pub prefix: u32,
+	/// Number of records of the prefix.
+	pub records: usize,
+}
+
+/// The database could not be opened, because it is used by another process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockContended {
+	/// Path of the lock file.
+	pub path: PathBuf,
+}
+
+/// A journal era failed checksum verification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEraCorrupted {
+	/// Path of the era file.
+	pub path: PathBuf,
+	/// Description of the corruption.
+	pub reason: String,
+}
+
+/// A flush file failed checksum verification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushCorrupted {
+	/// Path of the flush file.
+	pub path: PathBuf,
+	/// Description of the corruption.
+	pub reason: String,
+}
+
+/// A step upgrading the on-disk format reported its progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationProgress {
+	/// Directory of the database.
+	pub path: PathBuf,
+	/// Name of the step.
+	pub name: &'static str,
+	/// Units of work done by the step so far.
+	pub done: u64,
+	/// Units of work of the whole step.
+	pub total: u64,
+}
+
+/// A step upgrading the on-disk format completed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatMigrated {
+	/// Directory of the database.
+	pub path: PathBuf,
+	/// Name of the step.
+	pub name: &'static str,
+	/// Format version before the step.
+	pub from: u16,
+	/// Format version after the step.
+	pub to: u16,
+}
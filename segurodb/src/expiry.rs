@@ -0,0 +1,75 @@
+//! Expiry of values inserted with `Transaction::insert_with_ttl`.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Size of the expiry time stored before the values of keyspaces with `Options::ttl`.
+pub(crate) const EXPIRY_SIZE: usize = 8;
+
+/// Expiry time of values which never expire.
+pub(crate) const NEVER: u64 = 0;
+
+/// Source of the current time deciding which values are expired.
+pub trait Clock: fmt::Debug + Send + Sync {
+	/// Returns the number of seconds since the Unix epoch.
+	fn now(&self) -> u64;
+}
+
+/// Clock reading the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH)
+			.map(|duration| duration.as_secs())
+			.unwrap_or(0)
+	}
+}
+
+/// Returns the value stored with its expiry time.
+///
+/// ```text
+///  8 bytes
+///   /
+/// | expires_at | value |
+/// ```
+pub(crate) fn encode(value: &[u8], expires_at: u64) -> Vec<u8> {
+	let mut stored = vec![0; EXPIRY_SIZE + value.len()];
+	LittleEndian::write_u64(&mut stored[..EXPIRY_SIZE], expires_at);
+	stored[EXPIRY_SIZE..].copy_from_slice(value);
+	stored
+}
+
+/// Reads the expiry time written by `encode`.
+pub(crate) fn read(expiry: &[u8]) -> u64 {
+	LittleEndian::read_u64(expiry)
+}
+
+/// Returns true if the value expiring at `expires_at` is expired at `now`.
+pub(crate) fn is_expired(expires_at: u64, now: u64) -> bool {
+	expires_at != NEVER && expires_at <= now
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{encode, is_expired, read, EXPIRY_SIZE, NEVER};
+
+	#[test]
+	fn test_encode() {
+		let stored = encode(b"value", 0x0102);
+		assert_eq!(stored.len(), EXPIRY_SIZE + 5);
+		assert_eq!(read(&stored[..EXPIRY_SIZE]), 0x0102);
+		assert_eq!(&stored[EXPIRY_SIZE..], b"value");
+	}
+
+	#[test]
+	fn test_is_expired() {
+		assert!(!is_expired(NEVER, u64::max_value()));
+		assert!(!is_expired(10, 9));
+		assert!(is_expired(10, 10));
+		assert!(is_expired(10, 11));
+	}
+}
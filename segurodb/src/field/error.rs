@@ -7,9 +7,13 @@ error_chain! {
 	}
 
 	errors {
-		InvalidHeader {
+		InvalidHeader(offset: usize, byte: u8) {
 			description("invalid header"),
-			display("invalid header"),
+			display("invalid header {} of the field at offset {}", byte, offset),
+		}
+		RecordOutOfBounds(offset: usize) {
+			description("record out of bounds"),
+			display("record at offset {} does not fit in the data", offset),
 		}
 		InvalidLength {
 			description("invalid length"),
@@ -1,5 +1,3 @@
-use field::error::{Result, ErrorKind};
-
 pub const HEADER_SIZE: usize = 1;
 
 /// `Header` is a first byte of database field.
@@ -19,13 +17,13 @@ impl Header {
 	const INSERTED: u8 = 1;
 	const CONTINUED: u8 = 2;
 
-	/// Converts `u8` into Header.
-	pub fn from_u8(byte: u8) -> Result<Header> {
+	/// Converts `u8` into Header. Returns `None` if the byte is not a valid header.
+	pub fn from_u8(byte: u8) -> Option<Header> {
 		match byte {
-			Self::UNINITIALIZED => Ok(Header::Uninitialized),
-			Self::INSERTED => Ok(Header::Inserted),
-			Self::CONTINUED => Ok(Header::Continued),
-			_ => Err(ErrorKind::InvalidHeader.into()),
+			Self::UNINITIALIZED => Some(Header::Uninitialized),
+			Self::INSERTED => Some(Header::Inserted),
+			Self::CONTINUED => Some(Header::Continued),
+			_ => None,
 		}
 	}
 }
@@ -39,6 +37,6 @@ mod test {
 		assert_eq!(Header::Uninitialized, Header::from_u8(Header::Uninitialized as u8).unwrap());
 		assert_eq!(Header::Inserted, Header::from_u8(Header::Inserted as u8).unwrap());
 		assert_eq!(Header::Continued, Header::from_u8(Header::Continued as u8).unwrap());
-		assert!(Header::from_u8(100u8).is_err());
+		assert!(Header::from_u8(100u8).is_none());
 	}
 }
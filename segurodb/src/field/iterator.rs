@@ -6,6 +6,8 @@ use field::header::Header;
 pub struct FieldHeaderIterator<'a> {
 	data: &'a [u8],
 	field_size: usize,
+	/// Offset of the next field from the beginning of the iterated data.
+	offset: usize,
 }
 
 impl<'a> FieldHeaderIterator<'a> {
@@ -18,6 +20,7 @@ impl<'a> FieldHeaderIterator<'a> {
 		Ok(FieldHeaderIterator {
 			data,
 			field_size,
+			offset: 0,
 		})
 	}
 }
@@ -31,7 +34,9 @@ impl<'a> Iterator for FieldHeaderIterator<'a> {
 		}
 
 		let (next_field, new_data) = self.data.split_at(self.field_size);
+		let offset = self.offset;
 		self.data = new_data;
-		Some(Header::from_u8(next_field[0]))
+		self.offset += self.field_size;
+		Some(Header::from_u8(next_field[0]).ok_or_else(|| ErrorKind::InvalidHeader(offset, next_field[0]).into()))
 	}
 }
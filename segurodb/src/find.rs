@@ -1,9 +1,12 @@
 use std::cmp;
 
+use error;
 use field::iterator::FieldHeaderIterator;
-use field::{Error, Header, field_size};
+use field::{Error, ErrorKind, Header, field_size};
+use key::Key;
 use prefix_tree::OccupiedPrefixesIterator;
 use record::{ValueSize, Record};
+use storage::{ReadAt, StorageFile, Windows};
 
 /// Record location.
 #[derive(Debug)]
@@ -16,51 +19,88 @@ pub enum RecordResult<'a> {
 	OutOfRange,
 }
 
+/// Looks up the record of the key. Returns its location and the number of probed fields.
 pub fn find_record<'a>(
 	data: &'a [u8],
 	field_body_size: usize,
 	value_size: ValueSize,
 	key: &[u8],
-) -> Result<RecordResult<'a>, Error> {
+) -> Result<(RecordResult<'a>, usize), Error> {
 	let iter = FieldHeaderIterator::new(data, field_body_size)?;
 
 	let field_size = field_size(field_body_size);
 	let mut offset = 0;
+	let mut probed = 0;
 	for header in iter {
 		let header = header?;
+		probed += 1;
 		match header {
-			Header::Uninitialized => return Ok(RecordResult::NotFound),
+			Header::Uninitialized => return Ok((RecordResult::NotFound, probed)),
 			Header::Inserted => {
 				let slice = &data[offset..];
 				match Record::extract_key(slice, field_body_size, key.len()).partial_cmp(&key).unwrap() {
 					cmp::Ordering::Less => {},
 					cmp::Ordering::Equal => {
-						let record = Record::new(slice, field_body_size, value_size, key.len());
-						return Ok(RecordResult::Found(record));
+						let record = Record::new(slice, field_body_size, value_size, key.len())
+							.ok_or(ErrorKind::RecordOutOfBounds(offset))?;
+						return Ok((RecordResult::Found(record), probed));
 					},
-					cmp::Ordering::Greater => return Ok(RecordResult::NotFound),
+					cmp::Ordering::Greater => return Ok((RecordResult::NotFound, probed)),
 				}
 			},
 			Header::Continued => {},
 		}
 		offset += field_size;
 	}
-	Ok(RecordResult::OutOfRange)
+	Ok((RecordResult::OutOfRange, probed))
 }
 
-pub fn iter<'a>(
-	data: &'a [u8],
-	occupied_prefixes_iter: OccupiedPrefixesIterator<'a>,
+/// Returns the number of fields which have to be probed to reach the end of records with
+/// the given `prefix`. `data` should start at the offset of the prefix.
+pub fn probe_length(
+	data: &[u8],
+	field_body_size: usize,
+	key_size: usize,
+	prefix: u32,
+	prefix_bits: u8,
+) -> Result<usize, Error> {
+	let iter = FieldHeaderIterator::new(data, field_body_size)?;
+
+	let field_size = field_size(field_body_size);
+	let mut offset = 0;
+	for header in iter {
+		match header? {
+			Header::Uninitialized => break,
+			Header::Inserted => {
+				let key = Record::extract_key(&data[offset..], field_body_size, key_size);
+				let key = key.raw_slice().expect("keys are always stored in a single field; qed");
+				if Key::new(key, prefix_bits).prefix > prefix {
+					break;
+				}
+			},
+			Header::Continued => {},
+		}
+		offset += field_size;
+	}
+	Ok(offset / field_size)
+}
+
+/// Returns an iterator over the records of the occupied prefixes, starting at the field `start`.
+/// Records of the prefixes lower than `start` are skipped, unless they are shifted past it.
+pub fn iter<'a, T: Iterator<Item=u32>, D: ReadAt + ?Sized>(
+	data: &'a D,
+	occupied_prefixes_iter: T,
+	start: u32,
 	field_body_size: usize,
 	key_size: usize,
 	value_size: ValueSize
-) -> Result<RecordIterator<'a>, Error> {
-	let offset = 0;
+) -> Result<RecordIterator<'a, T, D>, Error> {
+	let offset = start;
 	let peek_offset = None;
 	let field_size = field_size(field_body_size);
 
 	Ok(RecordIterator {
-		data,
+		data: Windows::new(data),
 		occupied_prefixes_iter,
 		offset,
 		peek_offset,
@@ -71,8 +111,8 @@ pub fn iter<'a>(
 	})
 }
 
-pub struct RecordIterator<'a, T = OccupiedPrefixesIterator<'a>> {
-	data: &'a [u8],
+pub struct RecordIterator<'a, T = OccupiedPrefixesIterator<'a>, D: ?Sized + 'a = StorageFile> {
+	data: Windows<'a, D>,
 	occupied_prefixes_iter: T,
 	offset: u32,
 	peek_offset: Option<u32>,
@@ -82,8 +122,26 @@ pub struct RecordIterator<'a, T = OccupiedPrefixesIterator<'a>> {
 	value_size: ValueSize
 }
 
-impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
-	type Item = Result<Record<'a>, Error>;
+impl<'a, T: Iterator<Item=u32>, D: ReadAt + ?Sized> RecordIterator<'a, T, D> {
+	/// Reads the record starting at `offset`, reading larger windows until the record fits in one.
+	fn read_record(&mut self, offset: usize) -> error::Result<Record<'a>> {
+		let mut len = cmp::min(self.field_size, self.data.len() - offset);
+		loop {
+			let slice = self.data.read_from(offset, len)?;
+			if let Some(record) = Record::new(slice, self.field_body_size, self.value_size, self.key_size) {
+				return Ok(record);
+			}
+
+			if offset + slice.len() == self.data.len() {
+				return Err(error::ErrorKind::Field(ErrorKind::RecordOutOfBounds(offset)).into());
+			}
+			len = cmp::min(slice.len() * 2, self.data.len() - offset);
+		}
+	}
+}
+
+impl<'a, T: Iterator<Item=u32>, D: ReadAt + ?Sized> Iterator for RecordIterator<'a, T, D> {
+	type Item = error::Result<Record<'a>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		loop {
@@ -102,18 +160,22 @@ impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
 
 			match self.peek_offset {
 				Some(offset) => {
+					let field_offset = offset as usize * self.field_size;
 					// reached eof
-					if offset as usize * self.field_size >= self.data.len() { return None }
+					if field_offset >= self.data.len() { return None }
 
 					self.offset += 1;
 
-					let slice = &self.data[offset as usize * self.field_size..];
-
-					let header = match Header::from_u8(slice[0]) {
-						Ok(header) => header,
+					let byte = match self.data.read_from(field_offset, 1) {
+						Ok(slice) => slice[0],
 						Err(err) => return Some(Err(err)),
 					};
 
+					let header = match Header::from_u8(byte) {
+						Some(header) => header,
+						None => return Some(Err(error::ErrorKind::Field(ErrorKind::InvalidHeader(field_offset, byte)).into())),
+					};
+
 					match header {
 						Header::Uninitialized => {
 							self.peek_offset = None;
@@ -123,8 +185,7 @@ impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
 						},
 						Header::Inserted => {
 							self.peek_offset = Some(offset + 1);
-							let record = Record::new(slice, self.field_body_size, self.value_size, self.key_size);
-							return Some(Ok(record))
+							return Some(self.read_record(field_offset))
 						}
 					}
 				},
@@ -136,7 +197,7 @@ impl<'a, T: Iterator<Item=u32>> Iterator for RecordIterator<'a, T> {
 
 #[cfg(test)]
 mod tests {
-	use super::{find_record, RecordIterator, RecordResult};
+	use super::{find_record, iter, probe_length, RecordResult};
 	use record;
 
 	fn expect_record(a: RecordResult, key: &[u8], value: &[u8]) {
@@ -172,8 +233,8 @@ mod tests {
 		let key = [1, 2, 3];
 		let key2 = [4, 5, 6];
 
-		expect_record(find_record(&data, body_size, value_size, &key).unwrap(), &[1, 2, 3], &[]);
-		expect_record(find_record(&data, body_size, value_size, &key2).unwrap(), &[4, 5, 6], &[]);
+		expect_record(find_record(&data, body_size, value_size, &key).unwrap().0, &[1, 2, 3], &[]);
+		expect_record(find_record(&data, body_size, value_size, &key2).unwrap().0, &[4, 5, 6], &[]);
 	}
 
 	#[test]
@@ -184,7 +245,7 @@ mod tests {
 		let key = [1, 4, 5];
 		let location = RecordResult::NotFound;
 
-		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap());
+		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap().0);
 	}
 
 	#[test]
@@ -195,7 +256,7 @@ mod tests {
 		let key = [4, 5, 7];
 		let location = RecordResult::OutOfRange;
 
-		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap());
+		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap().0);
 	}
 
 	#[test]
@@ -208,8 +269,29 @@ mod tests {
 		let location = RecordResult::NotFound;
 		let location2 = RecordResult::NotFound;
 
-		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap());
-		assert_eq(location2, find_record(&data, body_size, value_size, &key2).unwrap());
+		assert_eq(location, find_record(&data, body_size, value_size, &key).unwrap().0);
+		assert_eq(location2, find_record(&data, body_size, value_size, &key2).unwrap().0);
+	}
+
+	#[test]
+	fn test_probe_length() {
+		let body_size = 2;
+		let key_size = 2;
+		let prefix_bits = 8;
+		let data = [
+			1, 1, 1,
+			1, 1, 2,
+			1, 2, 1,
+			2, 0, 0,
+			1, 4, 4,
+			0, 0, 0,
+		];
+
+		assert_eq!(probe_length(&data, body_size, key_size, 1, prefix_bits).unwrap(), 2);
+		assert_eq!(probe_length(&data[3..], body_size, key_size, 1, prefix_bits).unwrap(), 1);
+		assert_eq!(probe_length(&data[6..], body_size, key_size, 2, prefix_bits).unwrap(), 2);
+		assert_eq!(probe_length(&data, body_size, key_size, 4, prefix_bits).unwrap(), 5);
+		assert_eq!(probe_length(&data[15..], body_size, key_size, 5, prefix_bits).unwrap(), 0);
 	}
 
 	#[test]
@@ -218,22 +300,11 @@ mod tests {
 		let occupied_prefixes_iter = vec![0u32, 2u32, 3u32, 6u32].into_iter();
 
 		let offset = 0;
-		let peek_offset = None;
 		let field_body_size = 2;
-		let field_size = 3;
 		let key_size = 2;
 		let value_size = record::ValueSize::Constant(0);
 
-		let records = RecordIterator {
-			data,
-			occupied_prefixes_iter,
-			offset,
-			peek_offset,
-			field_body_size,
-			field_size,
-			key_size,
-			value_size,
-		};
+		let records = iter(&data[..], occupied_prefixes_iter, offset, field_body_size, key_size, value_size).unwrap();
 
 		let keys: Vec<_> = records.map(|record| {
 			let record = record.unwrap();
@@ -251,4 +322,15 @@ mod tests {
 			]
 		);
 	}
+
+	#[test]
+	fn test_iter_from_prefix() {
+		let data = &[1, 1, 1, 0, 0, 0, 1, 2, 2, 1, 3, 3, 0, 0, 0, 0, 0, 0, 1, 4, 4, 1, 5, 5];
+		let occupied_prefixes_iter = vec![0u32, 2u32, 3u32, 6u32].into_iter();
+
+		let records = iter(&data[..], occupied_prefixes_iter, 3, 2, 2, record::ValueSize::Constant(0)).unwrap();
+		let keys: Vec<_> = records.map(|record| record.unwrap().key().to_vec()).collect();
+
+		assert_eq!(keys, vec![vec![3, 3], vec![4, 4], vec![5, 5]]);
+	}
 }
@@ -57,8 +57,11 @@ pub enum Decision<'o, 'db> {
 	ShiftOccupiedSpace {
 		data: &'db [u8],
 	},
-	/// Returned when backwards shift should end.
-	FinishBackwardShift
+	/// Returned when backwards shift should end. `len` bytes of the shifted space are left empty,
+	/// so no record is moved before its min offset.
+	FinishBackwardShift {
+		len: usize,
+	},
 }
 
 /// Compares occupied space data and operation key.
@@ -99,6 +102,15 @@ pub fn min_offset_for_key(key: &[u8], prefix_bits: u8, field_body_size: usize) -
 	min_offset
 }
 
+/// Ends the backward shift at `min_offset`, or at the `offset` of the space if it is lower.
+#[inline]
+fn finish_backward_shift<'o, 'db>(offset: usize, shift: isize, min_offset: usize) -> Decision<'o, 'db> {
+	let end = cmp::min(min_offset, offset);
+	Decision::FinishBackwardShift {
+		len: end - (offset - (-shift) as usize),
+	}
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum Shift {
 	None,
@@ -118,7 +130,7 @@ impl From<isize> for Shift {
 
 pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isize, field_body_size: usize, prefix_bits: u8) -> Decision<'o, 'db> {
 	let tip = shift.into();
-	match (operation, space, tip) {
+	match (operation.effect(), space, tip) {
 		(Operation::Insert(key, value), Space::Empty(space), Shift::None) => Decision::InsertOperationIntoEmptySpace {
 			key,
 			value,
@@ -133,7 +145,7 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 				space_len: space.len,
 			}
 		} else {
-			Decision::FinishBackwardShift
+			finish_backward_shift(space.offset, shift, min_offset_for_key(key, prefix_bits, field_body_size))
 		},
 		(Operation::Insert(_, _), Space::Empty(space), Shift::Forward) => Decision::ConsumeEmptySpace {
 			len: space.len,
@@ -146,11 +158,21 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 						data: space.data,
 					}
 				} else {
-					Decision::FinishBackwardShift
+					finish_backward_shift(space.offset, shift, min_offset_for_space(space.data, prefix_bits, field_body_size))
 				},
 				(cmp::Ordering::Less, Shift::Forward) => Decision::ShiftOccupiedSpace {
 					data: space.data,
 				},
+				(cmp::Ordering::Equal, Shift::Backward) => if is_min_offset_for_key(space.offset, shift, key, prefix_bits, field_body_size) {
+					Decision::OverwriteOperation {
+						key,
+						value,
+						offset: space.offset,
+						old_len: space.data.len()
+					}
+				} else {
+					finish_backward_shift(space.offset, shift, min_offset_for_key(key, prefix_bits, field_body_size))
+				},
 				(cmp::Ordering::Equal, _) => Decision::OverwriteOperation {
 					key,
 					value,
@@ -164,7 +186,7 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 						offset: space.offset,
 					}
 				} else {
-					Decision::FinishBackwardShift
+					finish_backward_shift(space.offset, shift, min_offset_for_key(key, prefix_bits, field_body_size))
 				},
 				(cmp::Ordering::Greater, Shift::None) | (cmp::Ordering::Greater , Shift::Forward) => Decision::InsertOperationBeforeOccupiedSpace {
 					key,
@@ -179,11 +201,11 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 			len: space.len,
 		},
 		(Operation::Delete(key), Space::Empty(space), Shift::Backward) => if is_min_offset_for_key(space.offset, shift, key, prefix_bits, field_body_size) {
-			Decision::ConsumeEmptySpace {
-				len: space.len,
-			}
-		} else {
+			// records are never stored after an empty space following their min offset
 			Decision::IgnoreOperation
+		} else {
+			// the record may be stored after its min offset
+			finish_backward_shift(space.offset, shift, min_offset_for_key(key, prefix_bits, field_body_size))
 		},
 		(Operation::Delete(key), Space::Occupied(space), _) => {
 			match (compare_space_and_operation(space.data, key, field_body_size), tip) {
@@ -193,7 +215,7 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 						data: space.data,
 					}
 				} else {
-					Decision::FinishBackwardShift
+					finish_backward_shift(space.offset, shift, min_offset_for_space(space.data, prefix_bits, field_body_size))
 				},
 				(cmp::Ordering::Less, Shift::Forward) => Decision::ShiftOccupiedSpace {
 					data: space.data,
@@ -206,5 +228,6 @@ pub fn decision<'o, 'db>(operation: Operation<'o>, space: Space<'db>, shift: isi
 				(cmp::Ordering::Greater, _) => Decision::IgnoreOperation,
 			}
 		},
+		_ => unreachable!("conditional operations and merge operands are resolved before they are flushed; qed"),
 	}
 }
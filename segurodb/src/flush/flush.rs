@@ -1,40 +1,45 @@
-use std::io::{self, Write};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::{mem, fs};
+use std::mem;
 
 use hex_slice::AsHex;
-use memmap::{Mmap, Protection};
-use tiny_keccak::{sha3_256, Keccak};
+use tiny_keccak::sha3_256;
 
-use error::{ErrorKind, Result};
+use durability::Durability;
+use error::{Corruption, ErrorKind, Result};
 use flush::iterator::IdempotentOperationIterator;
 use flush::writer::OperationWriter;
 use metadata::{self, Metadata};
+use metrics::names;
 use options::InternalOptions;
+use storage::{ReadAt, StorageFile};
+use vfs::{Mapping, Vfs};
 use transaction::Operation;
 
 /// Stores transaction operations as a set of idempotent operations.
 #[derive(Debug)]
 pub struct Flush {
 	path: PathBuf,
-	mmap: Mmap,
+	mmap: Mapping,
 	prefix_bits: u8,
 	metadata: Metadata,
+	vfs: Vfs,
+	durability: Durability,
 }
 
 impl Flush {
-	const FILE_NAME: &'static str = "db.flush";
+	pub(crate) const FILE_NAME: &'static str = "db.flush";
 	const CHECKSUM_SIZE: usize = 32;
 
 	/// Creates memmap which is a set of only idempotent operations.
-	pub fn new<'a, I, P>(
+	pub fn new<'a, I, P, D>(
 		dir: P,
 		options: &InternalOptions,
-		db: &[u8],
+		db: &D,
 		metadata: &Metadata,
 		operations: I,
 	) -> Result<Flush>
-		where I: IntoIterator<Item = Operation<'a>>, P: AsRef<Path> {
+		where I: IntoIterator<Item = Operation<'a>>, P: AsRef<Path>, D: ReadAt + ?Sized {
 
 		let mut metadata = metadata.clone();
 
@@ -45,43 +50,49 @@ impl Flush {
 			options.field_body_size,
 			options.external.key_index_bits,
 			options.external.value_len.is_const(),
+			&*options.external.metrics,
 		).run()?;
+		options.external.metrics.observe(names::FLUSH_WRITTEN_BYTES, flush_data.len() as u64);
 
 		let path = dir.as_ref().join(Flush::FILE_NAME);
+		let durability = options.external.durability;
 
-		let mut file = fs::OpenOptions::new()
-			.write(true)
-			.read(true)
-			.create_new(true)
-			.open(&path)?;
-		file.set_len(flush_data.len() as u64 + Self::CHECKSUM_SIZE as u64)?;
-		file.flush()?;
-
-		let mut mmap = Mmap::open(&file, Protection::ReadWrite)?;
-		Keccak::sha3_256(&flush_data, unsafe { &mut mmap.as_mut_slice()[..Self::CHECKSUM_SIZE] });
-		unsafe { &mut mmap.as_mut_slice()[Self::CHECKSUM_SIZE..] }.write_all(&flush_data)?;
-		mmap.flush()?;
+		let mut file_data = Vec::with_capacity(Self::CHECKSUM_SIZE + flush_data.len());
+		file_data.extend_from_slice(&sha3_256(&flush_data));
+		file_data.extend_from_slice(&flush_data);
+		options.vfs.write(&path, &file_data, true, durability)?;
+		// the flush file must survive a power failure before anything relies on it
+		options.vfs.sync_dir(dir.as_ref(), durability)?;
+		let mmap = options.vfs.map(&path)?;
 
 		Ok(Flush {
 			path,
 			mmap,
 			metadata,
 			prefix_bits: options.external.key_index_bits,
+			vfs: options.vfs.clone(),
+			durability,
 		})
 	}
 
-	/// Open flush file if it exists. It it does not, returns None.
-	pub fn open<P: AsRef<Path>>(dir: P, prefix_bits: u8) -> Result<Option<Flush>> {
+	/// Open flush file of a data file with `db_len` bytes if it exists. It it does not, returns None.
+	pub fn open<P: AsRef<Path>>(dir: P, options: &InternalOptions, db_len: usize) -> Result<Option<Flush>> {
+		let prefix_bits = options.external.key_index_bits;
 		let path = dir.as_ref().join(Self::FILE_NAME);
-		let mmap = match Mmap::open_path(&path, Protection::Read) {
+		let mmap = match options.vfs.map(&path) {
 			Ok(mmap) => mmap,
 			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
 			Err(err) => return Err(err.into()),
 		};
 
+		let meta_len = metadata::bytes::len(prefix_bits);
+		if mmap.len() < Self::CHECKSUM_SIZE + meta_len {
+			return Err(Corruption::new(0, format!("Flush file of {} bytes is truncated", mmap.len())).in_file(path));
+		}
+
 		{
-			let checksum = unsafe { &mmap.as_slice()[..Self::CHECKSUM_SIZE] };
-			let data = unsafe { &mmap.as_slice()[Self::CHECKSUM_SIZE..] };
+			let checksum = &mmap.as_slice()[..Self::CHECKSUM_SIZE];
+			let data = &mmap.as_slice()[Self::CHECKSUM_SIZE..];
 			let hash = sha3_256(data);
 			if hash != checksum {
 				return Err(ErrorKind::CorruptedFlush(
@@ -95,34 +106,49 @@ impl Flush {
 			}
 		}
 
-		let meta_offset = mmap.len() - metadata::bytes::len(prefix_bits);
-		let metadata = metadata::bytes::read(unsafe { &mmap.as_slice()[meta_offset..] }, prefix_bits);
+		let meta_offset = mmap.len() - meta_len;
+		let operations = &mmap.as_slice()[Self::CHECKSUM_SIZE..meta_offset];
+		if let Err(corruption) = IdempotentOperationIterator::validate(operations, db_len) {
+			return Err(corruption.shifted(Self::CHECKSUM_SIZE).in_file(path));
+		}
+
+		let metadata = match metadata::bytes::read(&mmap.as_slice()[meta_offset..], prefix_bits) {
+			Ok(metadata) => metadata,
+			Err(corruption) => return Err(corruption.shifted(meta_offset).in_file(path)),
+		};
+
 		Ok(Some(Flush {
 			path,
 			mmap,
 			prefix_bits,
 			metadata,
+			vfs: options.vfs.clone(),
+			durability: options.external.durability,
 		}))
 	}
 
 	/// Flushes idempotent operations to the database.
-	pub fn flush(&self, db: &mut [u8], raw_metadata: &mut [u8], metadata: &mut Metadata) {
+	pub fn flush(&self, db: &mut StorageFile, raw_metadata: &mut StorageFile, metadata: &mut Metadata) -> Result<()> {
 		let meta_offset = self.mmap.len() - metadata::bytes::len(self.prefix_bits);
-		let operations = unsafe { &self.mmap.as_slice()[Self::CHECKSUM_SIZE..meta_offset] };
+		let operations = &self.mmap.as_slice()[Self::CHECKSUM_SIZE..meta_offset];
 		let operations = IdempotentOperationIterator::new(operations);
 
 		for o in operations {
-			db[o.offset..o.offset + o.data.len()].copy_from_slice(o.data);
+			db.write(o.offset, o.data)?;
 		}
 
-		let meta = unsafe { &self.mmap.as_slice()[meta_offset..] };
-		raw_metadata.copy_from_slice(meta);
+		let meta = &self.mmap.as_slice()[meta_offset..];
+		raw_metadata.write(0, meta)?;
 		mem::swap(&mut self.metadata.clone(), metadata);
+		Ok(())
 	}
 
 	/// Delete flush file. Should be called only after database has been successfully flushed.
 	pub fn delete(self) -> Result<()> {
-		fs::remove_file(self.path)?;
+		self.vfs.remove_file(&self.path)?;
+		if let Some(dir) = self.path.parent() {
+			self.vfs.sync_dir(dir, self.durability)?;
+		}
 		Ok(())
 	}
 }
@@ -2,6 +2,11 @@
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use error::Corruption;
+
+/// Size of the offset and the data length preceding the data of each operation.
+const HEADER_SIZE: usize = 12;
+
 /// Idempotent operation can be applied multiple times without changing
 /// the result beyond initial application.
 #[derive(Debug, PartialEq)]
@@ -17,11 +22,50 @@ pub struct IdempotentOperationIterator<'a> {
 }
 
 impl<'a> IdempotentOperationIterator<'a> {
+	/// Creates an iterator over operations which were written by `OperationWriter`
+	/// or checked with `validate`.
 	pub fn new(data: &'a [u8]) -> Self {
 		IdempotentOperationIterator {
 			data,
 		}
 	}
+
+	/// Checks that the operations can be read and fit in a data file of `db_len` bytes.
+	pub fn validate(mut data: &[u8], db_len: usize) -> Result<(), Corruption> {
+		let mut position = 0;
+		while !data.is_empty() {
+			let (operation, end) = read(data).map_err(|corruption| corruption.shifted(position))?;
+			if operation.offset.checked_add(operation.data.len()).map_or(true, |end| end > db_len) {
+				return Err(Corruption::new(position, format!("Operation at offset {} exceeds the data file", operation.offset)));
+			}
+
+			data = &data[end..];
+			position += end;
+		}
+
+		Ok(())
+	}
+}
+
+/// Reads the operation at the beginning of the data. Returns the operation and its length.
+fn read(data: &[u8]) -> Result<(IdempotentOperation, usize), Corruption> {
+	if data.len() < HEADER_SIZE {
+		return Err(Corruption::new(0, "Truncated operation header"));
+	}
+
+	let offset = LittleEndian::read_u64(&data[0..8]) as usize;
+	let data_len = LittleEndian::read_u32(&data[8..12]) as usize;
+	let end = HEADER_SIZE + data_len;
+	if end > data.len() {
+		return Err(Corruption::new(0, format!("Operation data of {} bytes is truncated", data_len)));
+	}
+
+	let operation = IdempotentOperation {
+		offset,
+		data: &data[HEADER_SIZE..end],
+	};
+
+	Ok((operation, end))
 }
 
 impl<'a> Iterator for IdempotentOperationIterator<'a> {
@@ -32,14 +76,7 @@ impl<'a> Iterator for IdempotentOperationIterator<'a> {
 			return None;
 		}
 
-		let offset = LittleEndian::read_u64(&self.data[0..8]) as usize;
-		let data_len = LittleEndian::read_u32(&self.data[8..12]) as usize;
-		let end = 12 + data_len;
-		let result = IdempotentOperation {
-			offset,
-			data: &self.data[12..end],
-		};
-
+		let (result, end) = read(self.data).expect("operations are validated before they are iterated; qed");
 		self.data = &self.data[end..];
 		Some(result)
 	}
@@ -47,6 +84,7 @@ impl<'a> Iterator for IdempotentOperationIterator<'a> {
 
 #[cfg(test)]
 mod tests {
+	use error::Corruption;
 	use super::{IdempotentOperation, IdempotentOperationIterator};
 
 	#[test]
@@ -93,4 +131,37 @@ mod tests {
 		assert_eq!(expected2, iterator.next().unwrap());
 		assert!(iterator.next().is_none());
 	}
+
+	#[test]
+	fn should_reject_truncated_operations() {
+		let data = &[
+			5, 0, 0, 0, 0, 0, 0, 0,
+			6, 0, 0, 0,
+			1, 2, 3, 4, 5, 6,
+			20, 0, 0, 0, 0, 0, 0, 0,
+			2, 0, 0, 0,
+			1, 2,
+		];
+
+		assert_eq!(IdempotentOperationIterator::validate(data, 22), Ok(()));
+		for len in 1..data.len() {
+			if len == 18 { continue; }
+			let offset = if len < 18 { 0 } else { 18 };
+			assert_eq!(IdempotentOperationIterator::validate(&data[..len], 22).unwrap_err().offset, offset);
+		}
+
+		assert_eq!(
+			IdempotentOperationIterator::validate(data, 21),
+			Err(Corruption::new(18, "Operation at offset 20 exceeds the data file"))
+		);
+	}
+
+	quickcheck! {
+		fn quickcheck_validating_garbled_operations_never_panics(data: Vec<u8>, db_len: usize) -> bool {
+			if IdempotentOperationIterator::validate(&data, db_len).is_ok() {
+				assert!(IdempotentOperationIterator::new(&data).all(|o| o.offset + o.data.len() <= db_len));
+			}
+			true
+		}
+	}
 }
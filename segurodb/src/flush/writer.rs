@@ -8,8 +8,10 @@ use error::Result;
 use flush::decision::{decision, Decision, is_min_offset_for_space, min_offset_for_space};
 use key::Key;
 use metadata::Metadata;
+use metrics::{names, Metrics};
 use record::{append_record};
 use space::{SpaceIterator, Space};
+use storage::ReadAt;
 use transaction::Operation;
 
 #[inline]
@@ -62,29 +64,31 @@ enum OperationWriterStep {
 }
 
 /// Writes transactions as a set of idempotent operations
-pub struct OperationWriter<'db, I: Iterator> {
+pub struct OperationWriter<'db, I: Iterator, D: ?Sized + 'db> {
 	operations: Peekable<I>,
-	spaces: SpaceIterator<'db>,
+	spaces: SpaceIterator<'db, D>,
 	metadata: &'db mut Metadata,
 	buffer: OperationBuffer,
 	field_body_size: usize,
 	prefix_bits: u8,
 	const_value: bool,
+	metrics: &'db Metrics,
 	/// shift is always increased or decreased by a len of inserted/deleted
 	/// record or an empty field. inserted and deleted records are always
 	/// aligned by function append_record from src/record/append.rs.
 	shift: isize,
 }
 
-impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
+impl<'op, 'db, I: Iterator<Item = Operation<'op>>, D: ReadAt + ?Sized> OperationWriter<'db, I, D> {
 	/// Creates new operations writer. All operations needs to be ordered by key.
 	pub fn new(
 		operations: I,
-		database: &'db [u8],
+		database: &'db D,
 		metadata: &'db mut Metadata,
 		field_body_size: usize,
 		prefix_bits: u8,
 		const_value: bool,
+		metrics: &'db Metrics,
 	) -> Self {
 		OperationWriter {
 			operations: operations.peekable(),
@@ -94,6 +98,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 			field_body_size,
 			prefix_bits,
 			const_value,
+			metrics,
 			shift: 0,
 		}
 	}
@@ -105,6 +110,10 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 				Space::Empty(space) => {
 					if self.shift > 0 {
 						self.shift -= space.len as isize;
+					} else {
+						// records after an empty space are never shifted backward
+						write_empty_bytes(self.buffer.as_raw_mut(), (-self.shift) as usize);
+						self.shift = 0;
 					}
 				},
 				Space::Occupied(space) => {
@@ -169,6 +178,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 				self.buffer.denote_operation_start(offset as u64);
 				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.const_value);
 				self.shift += written as isize - space_len as isize;
+				self.observe_shift();
 				// insert metadata
 				self.metadata.insert_record(prefixed_key.prefix, written);
 			},
@@ -180,6 +190,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 				self.buffer.denote_operation_start(offset as u64);
 				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.const_value);
 				self.shift += written as isize;
+				self.observe_shift();
 				// insert metadata
 				self.metadata.insert_record(prefixed_key.prefix, written);
 			},
@@ -192,6 +203,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 				self.buffer.denote_operation_start(offset as u64);
 				let written = write_insert_operation(self.buffer.as_raw_mut(), key, value, self.field_body_size, self.const_value);
 				self.shift += written as isize - old_len as isize;
+				self.observe_shift();
 				// update metadata
 				self.metadata.update_record_len(old_len, written);
 			},
@@ -213,12 +225,13 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 				// rewrite the space to a buffer
 				self.buffer.as_raw_mut().extend_from_slice(data);
 			},
-			Decision::FinishBackwardShift => {
+			Decision::FinishBackwardShift { len } => {
 				// do not advance iterator
-				// finish shift backwards
+				// finish shift backwards, the shift remains negative if the space
+				// before the next record can still be used
 				assert!(self.shift < 0, "we are in delete mode");
-				write_empty_bytes(self.buffer.as_raw_mut(), (-self.shift) as usize);
-				self.shift = 0;
+				write_empty_bytes(self.buffer.as_raw_mut(), len);
+				self.shift += len as isize;
 			},
 			Decision::DeleteOperation { offset, len } => {
 				// advance operations
@@ -228,6 +241,7 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 				// denote operation start
 				self.buffer.denote_operation_start(offset as u64);
 				self.shift -= len as isize;
+				self.observe_shift();
 				// update metadata
 				self.metadata.remove_record(len);
 			},
@@ -236,6 +250,11 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 		Ok(OperationWriterStep::Stepped)
 	}
 
+	/// Reports the distance the records following the written operation are shifted by.
+	fn observe_shift(&self) {
+		self.metrics.observe(names::FLUSH_SHIFT, self.shift.abs() as u64);
+	}
+
 	#[inline]
 	pub fn run(mut self) -> Result<Vec<u8>> {
 		while let OperationWriterStep::Stepped = self.step()? {}
@@ -247,3 +266,79 @@ impl<'op, 'db, I: Iterator<Item = Operation<'op>>> OperationWriter<'db, I> {
 		Ok(result)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use field::field_size;
+	use find::{find_record, RecordResult};
+	use flush::iterator::IdempotentOperationIterator;
+	use metadata::{self, Metadata};
+	use metrics::NoopMetrics;
+	use prefix_tree::PrefixTree;
+	use record::ValueSize;
+	use transaction::Operation;
+	use super::OperationWriter;
+
+	const PREFIX_BITS: u8 = 8;
+	const FIELD_BODY_SIZE: usize = 6;
+	const VALUE_SIZE: ValueSize = ValueSize::Constant(3);
+
+	fn new_metadata() -> Metadata {
+		Metadata {
+			db_version: Metadata::DB_VERSION,
+			occupied_bytes: 0,
+			prefix_bits: PREFIX_BITS,
+			prefixes: PrefixTree::new(PREFIX_BITS),
+			collided_prefixes: PrefixTree::new(PREFIX_BITS),
+		}
+	}
+
+	/// Writes the operations and applies the written idempotent operations to the data.
+	fn flush(data: &mut Vec<u8>, metadata: &mut Metadata, operations: Vec<Operation>) {
+		let written = OperationWriter::new(
+			operations.into_iter(),
+			&data[..],
+			metadata,
+			FIELD_BODY_SIZE,
+			PREFIX_BITS,
+			true,
+			&NoopMetrics,
+		).run().unwrap();
+
+		let operations_len = written.len() - metadata::bytes::len(PREFIX_BITS);
+		for operation in IdempotentOperationIterator::new(&written[..operations_len]) {
+			data[operation.offset..operation.offset + operation.data.len()].copy_from_slice(operation.data);
+		}
+	}
+
+	fn get<'a>(data: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+		let offset = key[0] as usize * field_size(FIELD_BODY_SIZE);
+		match find_record(&data[offset..], FIELD_BODY_SIZE, VALUE_SIZE, key).unwrap().0 {
+			RecordResult::Found(record) => record.value_raw_slice(),
+			RecordResult::NotFound => None,
+			RecordResult::OutOfRange => panic!("the data file ends after the record"),
+		}
+	}
+
+	#[test]
+	fn test_backward_shift_finishes_at_min_offset() {
+		let mut data = vec![0u8; 1024 * field_size(FIELD_BODY_SIZE)];
+		let mut metadata = new_metadata();
+
+		// "aab" is stored in the field of prefix "b"
+		flush(&mut data, &mut metadata, vec![Operation::Insert(b"aaa", b"001"), Operation::Insert(b"aab", b"002")]);
+		assert_eq!(get(&data, b"aab"), Some(b"002" as &[u8]));
+
+		// deletes shift the following records backward, but not before the field of their prefix
+		flush(&mut data, &mut metadata, vec![
+			Operation::Delete(b"aaa"),
+			Operation::Delete(b"aab"),
+			Operation::Insert(b"bbb", b"003"),
+			Operation::Insert(b"bbc", b"004"),
+		]);
+		assert_eq!(get(&data, b"aaa"), None);
+		assert_eq!(get(&data, b"aab"), None);
+		assert_eq!(get(&data, b"bbb"), Some(b"003" as &[u8]));
+		assert_eq!(get(&data, b"bbc"), Some(b"004" as &[u8]));
+	}
+}
@@ -0,0 +1,345 @@
+//! Entry points of the fuzz targets in `fuzz/fuzz_targets`.
+//!
+//! Each function parses arbitrary bytes the way the database parses one of its files read
+//! from the disk. Malformed files have to be reported as errors, so any panic is a bug.
+//! The functions return whether the input was a valid file.
+//!
+//! Targets which depend on the options of a keyspace read them from the first bytes of the input,
+//! see `options`. The seed corpora in `fuzz/seeds` are written by `generate_seed_corpora`.
+//!
+//! The module is only a part of the crate built with the `fuzzing` feature, which is enabled
+//! by `fuzz/Cargo.toml`, and of its tests.
+
+use std::path::Path;
+
+use tiny_keccak::sha3_256;
+
+use collision::Collision;
+use durability::Durability;
+use find;
+use flush::Flush;
+use journal::{self, Journal};
+use metadata;
+use options::{InternalOptions, Options, ValuesLen};
+use space::SpaceIterator;
+use storage::Storage;
+use vfs::Vfs;
+
+/// Number of bytes of the input read by `options`.
+const OPTIONS_LEN: usize = 3;
+
+/// Returns the options of a keyspace encoded in the first bytes of `data`, and the rest of `data`.
+///
+/// The bytes are `key_index_bits - 1`, `key_len - 1` and the value length, which has the highest
+/// bit set for variable values. Returns `None` if there are not enough bytes or they are invalid options.
+fn options(data: &[u8]) -> Option<(InternalOptions, &[u8])> {
+	if data.len() < OPTIONS_LEN {
+		return None;
+	}
+
+	let value_len = (data[2] & 0x7f) as usize;
+	let options = Options {
+		journal_eras: 0,
+		key_index_bits: data[0] % 12 + 1,
+		key_len: data[1] as usize % 32 + 1,
+		value_len: match data[2] & 0x80 {
+			0 => ValuesLen::Constant(value_len),
+			_ => ValuesLen::Variable { expected: value_len },
+		},
+		durability: Durability::None,
+		..Default::default()
+	};
+
+	match InternalOptions::from_external(options) {
+		Ok(options) => Some((options.with_vfs(Vfs::memory()), &data[OPTIONS_LEN..])),
+		Err(_) => None,
+	}
+}
+
+/// Writes `data` prefixed with its checksum, so that the contents are parsed rather than rejected.
+fn write_checksummed(vfs: &Vfs, path: &Path, data: &[u8]) {
+	let mut file = sha3_256(data).to_vec();
+	file.extend_from_slice(data);
+	vfs.write(path, &file, true, Durability::None).expect("writing to memory never fails; qed");
+}
+
+/// Opens a journal with the operations of an era file in `data` and reads all of them.
+pub fn journal_era(data: &[u8]) -> bool {
+	let vfs = Vfs::memory();
+	let dir = Path::new("journal");
+	vfs.create_dir_all(dir).expect("writing to memory never fails; qed");
+	write_checksummed(&vfs, &journal::dir::next_era_filename(dir, 0), data);
+
+	let journal = match Journal::open(&vfs, dir, Durability::None, None) {
+		Ok(journal) => journal,
+		Err(_) => return false,
+	};
+
+	let era = journal.front().expect("the era file was valid; qed");
+	era.merges();
+	for (keyspace, operation) in era.iter() {
+		journal.get(keyspace, operation.key());
+	}
+	true
+}
+
+/// Opens a flush file with the idempotent operations and metadata in `data` and applies it
+/// to a data file of the options the input starts with.
+pub fn flush(data: &[u8]) -> bool {
+	let (options, data) = match options(data) {
+		Some(options) => options,
+		None => return false,
+	};
+
+	let dir = Path::new("keyspace");
+	let vfs = &options.vfs;
+	let prefix_bits = options.external.key_index_bits;
+	vfs.create_dir_all(dir).expect("writing to memory never fails; qed");
+	write_checksummed(vfs, &dir.join(Flush::FILE_NAME), data);
+
+	let flush = match Flush::open(dir, &options, options.initial_db_size as usize) {
+		Ok(Some(flush)) => flush,
+		Ok(None) => unreachable!("the flush file was written; qed"),
+		Err(_) => return false,
+	};
+
+	let (db_path, meta_path) = (dir.join("data.db"), dir.join("meta.db"));
+	let meta_len = metadata::bytes::len(prefix_bits);
+	vfs.create_sized(&db_path, options.initial_db_size, Durability::None).expect("writing to memory never fails; qed");
+	vfs.create_sized(&meta_path, meta_len as u64, Durability::None).expect("writing to memory never fails; qed");
+	let mut db = vfs.open_storage(&db_path, Storage::Mmap, 0).expect("the file was created; qed");
+	let mut meta = vfs.open_storage(&meta_path, Storage::Mmap, 0).expect("the file was created; qed");
+	let mut metadata = metadata::bytes::read(&vec![0; meta_len], prefix_bits).expect("empty metadata is valid; qed");
+
+	flush.flush(&mut *db, &mut *meta, &mut metadata).expect("writing to memory never fails; qed");
+	true
+}
+
+/// Opens a collision file with the log in `data` and reads all of its entries.
+pub fn collision_log(data: &[u8]) -> bool {
+	let vfs = Vfs::memory();
+	let dir = Path::new("keyspace");
+	vfs.create_dir_all(dir).expect("writing to memory never fails; qed");
+	vfs.write(&Collision::collision_file_path(dir, 0), data, true, Durability::None).expect("writing to memory never fails; qed");
+
+	let collision = match Collision::open(&vfs, dir, 0, Durability::None) {
+		Ok(collision) => collision.expect("the collision file was written; qed"),
+		Err(_) => return false,
+	};
+
+	let entries = match collision.iter() {
+		Ok(entries) => entries,
+		Err(_) => return false,
+	};
+
+	for entry in entries {
+		match entry {
+			Ok((key, value)) => assert_eq!(collision.get(key).ok(), Some(Some(value))),
+			Err(_) => return false,
+		}
+	}
+	true
+}
+
+/// Reads the metadata in `data` for the `key_index_bits` the input starts with.
+pub fn metadata(data: &[u8]) -> bool {
+	let (prefix_bits, data) = match data.split_first() {
+		Some((byte, data)) => (byte % 12 + 1, data),
+		None => return false,
+	};
+
+	let metadata = match metadata::bytes::read(data, prefix_bits) {
+		Ok(metadata) => metadata,
+		Err(_) => return false,
+	};
+
+	for prefix in metadata.prefixes.prefixes_iter().chain(metadata.collided_prefixes.prefixes_iter()) {
+		assert!(prefix < 1 << prefix_bits);
+	}
+
+	// inner nodes of small prefix trees share bytes with the leaves, but are rebuilt from them
+	let mut serialized = vec![0; metadata::bytes::len(prefix_bits)];
+	metadata.as_bytes().copy_to_slice(&mut serialized);
+	let reread = metadata::bytes::read(&serialized, prefix_bits).expect("serialized metadata is valid; qed");
+	let mut reserialized = vec![0; serialized.len()];
+	reread.as_bytes().copy_to_slice(&mut reserialized);
+	assert_eq!(serialized, reserialized);
+	true
+}
+
+/// Iterates the spaces and records of a data file in `data` of the options the input starts with.
+pub fn data_file(data: &[u8]) -> bool {
+	let (options, data) = match options(data) {
+		Some(options) => options,
+		None => return false,
+	};
+
+	// errors are returned again by the next call, so iterations stop at the first one
+	for space in SpaceIterator::new(data, options.field_body_size, 0) {
+		if space.is_err() {
+			return false;
+		}
+	}
+
+	let prefixes = data.len() / options.record_offset;
+	let records = find::iter(
+		data,
+		0..prefixes as u32,
+		0,
+		options.field_body_size,
+		options.external.key_len,
+		options.value_size,
+	).expect("creating the iterator never fails; qed");
+
+	for record in records {
+		match record {
+			Ok(record) => {
+				assert_eq!(record.key().len(), options.external.key_len);
+				let mut value = vec![0; record.value_len()];
+				record.read_value(&mut value);
+			},
+			Err(_) => return false,
+		}
+	}
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+	use std::path::{Path, PathBuf};
+
+	use collision::Collision;
+	use database::Database;
+	use flush::Flush;
+	use metadata;
+	use options::{InternalOptions, Options, ValuesLen};
+	use transaction::Operation;
+	use super::OPTIONS_LEN;
+
+	/// Returns the bytes `options` decodes into the options of the database.
+	fn encoded_options(options: &Options) -> [u8; OPTIONS_LEN] {
+		let value_len = match options.value_len {
+			ValuesLen::Constant(len) => len as u8,
+			ValuesLen::Variable { expected } => expected as u8 | 0x80,
+		};
+		[options.key_index_bits - 1, options.key_len as u8 - 1, value_len]
+	}
+
+	fn write_seed(target: &str, name: &str, parts: &[&[u8]]) {
+		let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/seeds").join(target);
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join(name), parts.concat()).unwrap();
+	}
+
+	const SEED_DATABASES: &'static [&'static str] = &["constant", "variable", "small_prefixes"];
+
+	fn seed_options(name: &str) -> Options {
+		let (key_index_bits, value_len) = match name {
+			"constant" => (8, ValuesLen::Constant(3)),
+			"variable" => (8, ValuesLen::Variable { expected: 3 }),
+			"small_prefixes" => (2, ValuesLen::Variable { expected: 3 }),
+			_ => unreachable!(),
+		};
+
+		Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits,
+			value_len,
+			max_prefix_collisions: 2,
+			..Default::default()
+		}
+	}
+
+	/// Writes the seed corpora of the fuzz targets from the files of the `SEED_DATABASES`.
+	/// Run with `cargo test generate_seed_corpora -- --ignored` after changing the file formats.
+	#[test]
+	#[ignore]
+	fn generate_seed_corpora() {
+		for &name in SEED_DATABASES {
+			let temp = tempdir::TempDir::new("generate_seed_corpora").unwrap();
+			let options = seed_options(name);
+			let encoded = encoded_options(&options);
+			let key_index_bits = options.key_index_bits;
+			// values of constant length keep the last bytes of the variable ones
+			let value_len = match options.value_len {
+				ValuesLen::Constant(len) => Some(len),
+				ValuesLen::Variable { .. } => None,
+			};
+			let value = |value: &'static str| value_len.map_or(value, |len| &value[value.len() - len..]);
+			let internal_options = InternalOptions::from_external(seed_options(name)).unwrap();
+			let mut db = Database::create(temp.path(), options).unwrap();
+
+			let mut tx = db.create_transaction();
+			tx.insert("abc", value("001")).unwrap();
+			tx.insert("abd", value("0002")).unwrap();
+			tx.insert("bcd", value("003")).unwrap();
+			db.commit(&tx).unwrap();
+			let mut tx = db.create_transaction();
+			tx.delete("abd").unwrap();
+			tx.insert("abe", value("000004")).unwrap();
+			tx.insert("cde", value("005")).unwrap();
+			db.commit(&tx).unwrap();
+
+			// the eras are removed when they are flushed
+			for (i, era) in journal_era_files(temp.path()).iter().enumerate() {
+				let era = fs::read(era).unwrap();
+				write_seed("journal_era", &format!("{}_{}", name, i), &[&era[32..]]);
+			}
+
+			db.flush_journal(None).unwrap();
+			// the default keyspace is stored in the directory of the database
+			let keyspace = temp.path();
+			let data = fs::read(keyspace.join("data.db")).unwrap();
+			let meta = fs::read(keyspace.join("meta.db")).unwrap();
+			write_seed("data_file", name, &[&encoded, &data]);
+			write_seed("metadata", name, &[&encoded[..1], &meta]);
+
+			// flushes are removed once they are applied, so one is prepared from the flushed files
+			let flushed = metadata::bytes::read(&meta, key_index_bits).unwrap();
+			let operations = vec![Operation::Delete(b"abc"), Operation::Insert(b"abf", value("0006").as_bytes())];
+			let flush_dir = temp.path().join("flush");
+			fs::create_dir(&flush_dir).unwrap();
+			Flush::new(&flush_dir, &internal_options, &data[..], &flushed, operations).unwrap();
+			let flush = fs::read(flush_dir.join(Flush::FILE_NAME)).unwrap();
+			write_seed("flush", name, &[&encoded, &flush[32..]]);
+
+			db.compact().unwrap();
+			for prefix in Collision::file_prefixes(&internal_options.vfs, keyspace).unwrap() {
+				let log = fs::read(Collision::collision_file_path(keyspace, prefix)).unwrap();
+				write_seed("collision_log", &format!("{}_{}", name, prefix), &[&log]);
+			}
+		}
+	}
+
+	fn journal_era_files(dir: &Path) -> Vec<PathBuf> {
+		let mut eras = fs::read_dir(dir).unwrap()
+			.map(|entry| entry.unwrap().path())
+			.filter(|path| path.extension().map_or(false, |extension| extension == "era"))
+			.collect::<Vec<_>>();
+		eras.sort();
+		eras
+	}
+
+	#[test]
+	fn seeds_are_parsed() {
+		let seeds = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/seeds");
+		let targets: &[(&str, fn(&[u8]) -> bool)] = &[
+			("journal_era", super::journal_era),
+			("flush", super::flush),
+			("collision_log", super::collision_log),
+			("metadata", super::metadata),
+			("data_file", super::data_file),
+		];
+
+		for &(target, fuzz) in targets {
+			for seed in fs::read_dir(seeds.join(target)).unwrap() {
+				let seed = seed.unwrap().path();
+				assert!(fuzz(&fs::read(&seed).unwrap()), "{} is not a valid input", seed.display());
+			}
+		}
+	}
+}
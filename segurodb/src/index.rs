@@ -0,0 +1,194 @@
+//! Secondary indexes maintained by the database.
+
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use database::{Database, DatabaseIterator, Value};
+use error::{ErrorKind, Result};
+
+/// Extracts the index key from a value of the database.
+///
+/// Index entries are written with the transaction which writes the value,
+/// so the same extractor has to be registered every time the database is opened.
+pub trait IndexExtractor: fmt::Debug + Send + Sync {
+	/// Returns the index key of the `value`, or `None` if the value is not indexed.
+	fn extract(&self, value: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Indexes values by `len` bytes starting at `offset`.
+///
+/// Values shorter than `offset + len` are not indexed.
+#[derive(Debug, Clone, Copy)]
+pub struct ValueBytes {
+	/// Offset of the index key in the value.
+	pub offset: usize,
+	/// Length of the index key.
+	pub len: usize,
+}
+
+impl IndexExtractor for ValueBytes {
+	fn extract(&self, value: &[u8]) -> Option<Vec<u8>> {
+		value.get(self.offset..self.offset + self.len).map(|key| key.to_vec())
+	}
+}
+
+/// Declaration of a secondary index.
+///
+/// Entries of the index are stored in an internal keyspace, which is updated
+/// in the same journal era as the values. An index declared for an existing
+/// database is built from its values when the database is opened.
+#[derive(Debug, Clone)]
+pub struct Index {
+	/// Name of the index. Only ASCII letters, digits, '-' and '_' are allowed.
+	pub name: String,
+	/// Length of the index keys in bytes.
+	pub key_len: usize,
+	/// Number of bits from the index key used to create search index.
+	pub key_index_bits: u8,
+	/// Extracts index keys from the values.
+	pub extractor: Arc<IndexExtractor>,
+}
+
+/// Indexes are equal only if they share the extractor.
+impl PartialEq for Index {
+	fn eq(&self, other: &Self) -> bool {
+		self.name == other.name &&
+			self.key_len == other.key_len &&
+			self.key_index_bits == other.key_index_bits &&
+			Arc::ptr_eq(&self.extractor, &other.extractor)
+	}
+}
+
+impl Index {
+	/// Declares an index using up to 8 bits of its keys for the search index.
+	pub fn new<S: Into<String>>(name: S, key_len: usize, extractor: Arc<IndexExtractor>) -> Self {
+		Index {
+			name: name.into(),
+			key_len,
+			key_index_bits: (key_len * 8).min(8) as u8,
+			extractor,
+		}
+	}
+
+	/// Returns the index key of the value. Fails if the key has invalid length.
+	pub(crate) fn key(&self, value: &[u8]) -> Result<Option<Vec<u8>>> {
+		match self.extractor.extract(value) {
+			Some(ref key) if key.len() != self.key_len => Err(ErrorKind::IndexFailed(
+				self.name.clone(),
+				format!("index key is {} bytes long, expected {}", key.len(), self.key_len)
+			).into()),
+			key => Ok(key),
+		}
+	}
+}
+
+/// An iterator over the entries of a secondary index ordered by the index key
+/// and then by the key of the database.
+pub struct IndexIterator<'a> {
+	db: &'a Database,
+	index: &'a Index,
+	iter: DatabaseIterator<'a>,
+	start: Bound<Vec<u8>>,
+	end: Bound<Vec<u8>>,
+}
+
+impl<'a> IndexIterator<'a> {
+	pub(crate) fn new(db: &'a Database, index: &'a Index, iter: DatabaseIterator<'a>, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self {
+		IndexIterator {
+			db,
+			index,
+			iter,
+			start: to_owned_bound(start),
+			end: to_owned_bound(end),
+		}
+	}
+}
+
+impl<'a> Iterator for IndexIterator<'a> {
+	/// Index key, key of the database and its value.
+	type Item = Result<(&'a [u8], &'a [u8], Value<'a>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let entry = match self.iter.next()? {
+				Ok((entry, _)) => entry,
+				Err(err) => return Some(Err(err)),
+			};
+
+			let (index_key, key) = entry.split_at(self.index.key_len);
+
+			let after_start = match self.start {
+				Bound::Included(ref start) => index_key >= &start[..],
+				Bound::Excluded(ref start) => index_key > &start[..],
+				Bound::Unbounded => true,
+			};
+
+			if !after_start {
+				continue;
+			}
+
+			let before_end = match self.end {
+				Bound::Included(ref end) => index_key <= &end[..],
+				Bound::Excluded(ref end) => index_key < &end[..],
+				Bound::Unbounded => true,
+			};
+
+			if !before_end {
+				return None;
+			}
+
+			return Some(match self.db.get(key) {
+				Ok(Some(value)) => Ok((index_key, key, value)),
+				Ok(None) => Err(ErrorKind::IndexFailed(
+					self.index.name.clone(),
+					"index entry points to a missing key".into()
+				).into()),
+				Err(err) => Err(err),
+			});
+		}
+	}
+}
+
+fn to_owned_bound(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+	match bound {
+		Bound::Included(key) => Bound::Included(key.to_vec()),
+		Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+		Bound::Unbounded => Bound::Unbounded,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use error::ErrorKind;
+	use super::{Index, IndexExtractor, ValueBytes};
+
+	#[test]
+	fn test_value_bytes() {
+		let extractor = ValueBytes { offset: 2, len: 3 };
+		assert_eq!(extractor.extract(b"abcdef"), Some(b"cde".to_vec()));
+		assert_eq!(extractor.extract(b"abcde"), Some(b"cde".to_vec()));
+		assert_eq!(extractor.extract(b"abcd"), None);
+	}
+
+	#[derive(Debug)]
+	struct WholeValue;
+
+	impl IndexExtractor for WholeValue {
+		fn extract(&self, value: &[u8]) -> Option<Vec<u8>> {
+			Some(value.to_vec())
+		}
+	}
+
+	#[test]
+	fn should_reject_index_keys_of_invalid_length() {
+		let index = Index::new("by_value", 2, Arc::new(WholeValue));
+		assert_eq!(index.key_index_bits, 8);
+		assert_eq!(index.key(b"ab").unwrap(), Some(b"ab".to_vec()));
+		assert_eq!(
+			*index.key(b"abc").unwrap_err().kind(),
+			ErrorKind::IndexFailed("by_value".into(), "index key is 3 bytes long, expected 2".into())
+		);
+	}
+}
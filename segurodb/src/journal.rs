@@ -1,24 +1,72 @@
-use std::collections::vec_deque::Drain;
+use std::cmp;
 use std::collections::{BTreeSet, HashMap, VecDeque, btree_set};
-use std::fs;
 use std::hash::{Hash, Hasher};
-use std::io::Write;
 use std::path::{PathBuf, Path};
-use std::slice;
+use std::{mem, slice};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use byteorder::{ByteOrder, LittleEndian};
 use hex_slice::AsHex;
-use memmap::{Mmap, Protection};
 use tiny_keccak::sha3_256;
 
-use error::{ErrorKind, Result};
+use durability::Durability;
+use error::{Corruption, ErrorKind, Result};
+use event::RecoveryAction;
 use transaction::{Operation, OperationsIterator, Transaction};
+use vfs::{Mapping, Vfs};
 
 const CHECKSUM_SIZE: usize = 32;
 
 #[derive(Debug, PartialEq)]
-enum JournalOperation<T> {
+pub enum JournalOperation<T> {
 	Insert(T),
 	Delete,
+	/// Operands, oldest first, merged into the base value.
+	Merge(MergeBase<T>, Vec<T>),
+}
+
+/// Value which merge operands are applied to.
+#[derive(Debug, PartialEq)]
+pub enum MergeBase<T> {
+	/// Value of the key before the first operand was journaled.
+	Previous,
+	/// Value inserted right before the first operand.
+	Inserted(T),
+	/// Key deleted right before the first operand.
+	Deleted,
+}
+
+impl<T> JournalOperation<T> {
+	fn map<U, F: Fn(&T) -> U>(&self, f: F) -> JournalOperation<U> {
+		match *self {
+			JournalOperation::Insert(ref value) => JournalOperation::Insert(f(value)),
+			JournalOperation::Delete => JournalOperation::Delete,
+			JournalOperation::Merge(ref base, ref operands) => {
+				let base = match *base {
+					MergeBase::Previous => MergeBase::Previous,
+					MergeBase::Inserted(ref value) => MergeBase::Inserted(f(value)),
+					MergeBase::Deleted => MergeBase::Deleted,
+				};
+				JournalOperation::Merge(base, operands.iter().map(f).collect())
+			},
+		}
+	}
+
+	/// Applies `operands` following the operation.
+	fn merge(self, mut operands: Vec<T>) -> JournalOperation<T> {
+		if operands.is_empty() {
+			return self;
+		}
+
+		match self {
+			JournalOperation::Insert(value) => JournalOperation::Merge(MergeBase::Inserted(value), operands),
+			JournalOperation::Delete => JournalOperation::Merge(MergeBase::Deleted, operands),
+			JournalOperation::Merge(base, mut previous) => {
+				previous.append(&mut operands);
+				JournalOperation::Merge(base, previous)
+			},
+		}
+	}
 }
 
 /// Unsafe view onto memmap file memory which backs journal.
@@ -59,49 +107,129 @@ impl PartialEq for JournalSlice {
 
 impl Eq for JournalSlice {}
 
-unsafe fn cache_memory(memory: &[u8]) -> HashMap<JournalSlice, JournalOperation<JournalSlice>> {
-	let iterator = OperationsIterator::new(memory);
-	iterator.map(|o| match o {
-		Operation::Insert(key, value) => (JournalSlice::new(key), JournalOperation::Insert(JournalSlice::new(value))),
-		Operation::Delete(key) => (JournalSlice::new(key), JournalOperation::Delete)
-	}).collect()
+// `JournalSlice` only points to the memory of the memmap owned by the same `JournalEra`.
+unsafe impl Send for JournalSlice {}
+unsafe impl Sync for JournalSlice {}
+
+/// Keyspace name and key of a journaled operation.
+type JournalKey = (JournalSlice, JournalSlice);
+
+unsafe fn cache_memory(memory: &[u8]) -> HashMap<JournalKey, JournalOperation<JournalSlice>> {
+	let mut cache = HashMap::new();
+	// preconditions were checked when the transaction was committed
+	for (keyspace, o) in OperationsIterator::new(memory).with_keyspaces() {
+		match o.effect() {
+			Operation::Insert(key, value) => {
+				cache.insert((JournalSlice::new(keyspace), JournalSlice::new(key)), JournalOperation::Insert(JournalSlice::new(value)));
+			},
+			Operation::Delete(key) => {
+				cache.insert((JournalSlice::new(keyspace), JournalSlice::new(key)), JournalOperation::Delete);
+			},
+			Operation::Merge(key, operand) => {
+				let key = (JournalSlice::new(keyspace), JournalSlice::new(key));
+				let operation = match cache.remove(&key) {
+					Some(operation) => operation,
+					None => JournalOperation::Merge(MergeBase::Previous, Vec::new()),
+				};
+				cache.insert(key, operation.merge(vec![JournalSlice::new(operand)]));
+			},
+			_ => unreachable!("effect is either an insert, a delete or a merge; qed"),
+		}
+	}
+	cache
 }
 
 #[derive(Debug)]
 pub struct JournalEra {
 	file: PathBuf,
-	mmap: Mmap,
-	cache: HashMap<JournalSlice, JournalOperation<JournalSlice>>,
+	mmap: Mapping,
+	vfs: Vfs,
+	cache: HashMap<JournalKey, JournalOperation<JournalSlice>>,
+}
+
+/// Transaction written to an era file, which is not part of the journal yet.
+///
+/// Created with `Database::prepare` and moved into the journal with `Database::apply`.
+/// The file is removed if the transaction is dropped without being applied.
+#[derive(Debug)]
+pub struct PreparedTransaction {
+	dir: PathBuf,
+	era: Option<JournalEra>,
+}
+
+impl PreparedTransaction {
+	fn era(&self) -> &JournalEra {
+		self.era.as_ref().expect("era is only taken when the transaction is applied; qed")
+	}
+
+	fn file(&self) -> &Path {
+		&self.era().file
+	}
+
+	/// Returns an iterator over operations in the order they were added to the transaction.
+	pub(crate) fn operations<'a>(&'a self) -> OperationsIterator<'a> {
+		unsafe { OperationsIterator::new(self.raw()) }
+	}
+
+	/// Returns the serialized operations of the transaction.
+	pub(crate) fn raw(&self) -> &[u8] {
+		self.era().raw()
+	}
+}
+
+impl Drop for PreparedTransaction {
+	fn drop(&mut self) {
+		if let Some(ref era) = self.era {
+			// leftovers are also removed when the database is opened
+			let _ = era.vfs.remove_file(&era.file);
+		}
+	}
 }
 
 impl JournalEra {
-	// TODO [ToDr] Data should be written to a file earlier (for instance when transaction is created).
-	// Consider an API like this:
-	// ```
-	// let mut transaction = Transaction::new();
-	// ...
-	// let prepared = db.prepare(transaction); // writes to a file (doesn't require write access to DB)
-	// db.apply(prepared); // actually insert to db (requires write access)
-	// ```
-	fn create<P: AsRef<Path>>(file_path: P, transaction: &Transaction) -> Result<JournalEra> {
-		let hash = sha3_256(transaction.raw());
-		let mut file = fs::OpenOptions::new()
-			.write(true)
-			.create_new(true)
-			.open(&file_path)?;
-
-		file.write_all(&hash)?;
-		file.write_all(transaction.raw())?;
-		file.flush()?;
-
-		Self::open(file_path)
-	}
-
-	fn open<P: AsRef<Path>>(file: P) -> Result<JournalEra> {
-		let mmap = Mmap::open_path(&file, Protection::Read)?;
-		let cache = {
-			let checksum = unsafe { &mmap.as_slice()[..CHECKSUM_SIZE] };
-			let data = unsafe { &mmap.as_slice()[CHECKSUM_SIZE..] };
+	fn create<P: AsRef<Path>>(vfs: &Vfs, file_path: P, transaction: &Transaction, durability: Durability) -> Result<JournalEra> {
+		Self::write(vfs, &file_path, transaction, durability)?;
+		Self::open(vfs, file_path)
+	}
+
+	/// Writes checksummed transaction to a new file.
+	fn write<P: AsRef<Path>>(vfs: &Vfs, file_path: P, transaction: &Transaction, durability: Durability) -> Result<()> {
+		let mut data = Vec::with_capacity(CHECKSUM_SIZE + transaction.raw().len());
+		data.extend_from_slice(&sha3_256(transaction.raw()));
+		data.extend_from_slice(transaction.raw());
+		vfs.write(file_path.as_ref(), &data, true, durability)?;
+		Ok(())
+	}
+
+	fn open<P: AsRef<Path>>(vfs: &Vfs, file: P) -> Result<JournalEra> {
+		Self::load(vfs, file, true)
+	}
+
+	fn load<P: AsRef<Path>>(vfs: &Vfs, file: P, verify: bool) -> Result<JournalEra> {
+		let mmap = Self::map(vfs, &file, verify)?;
+		// the mapping is moved into the era, which keeps the cached slices valid
+		let cache = unsafe { cache_memory(&mmap.as_slice()[CHECKSUM_SIZE..]) };
+
+		let era = JournalEra {
+			file: file.as_ref().to_path_buf(),
+			mmap,
+			vfs: vfs.clone(),
+			cache,
+		};
+
+		Ok(era)
+	}
+
+	/// Maps the era file to the memory, verifying its checksum if `verify` is set.
+	/// Fails if the era contains malformed operations.
+	fn map<P: AsRef<Path>>(vfs: &Vfs, file: P, verify: bool) -> Result<Mapping> {
+		let mmap = vfs.map(file.as_ref())?;
+		if mmap.len() < CHECKSUM_SIZE {
+			return Err(Corruption::new(0, format!("Era file of {} bytes is truncated", mmap.len())).in_file(file));
+		}
+		if verify {
+			let checksum = &mmap.as_slice()[..CHECKSUM_SIZE];
+			let data = &mmap.as_slice()[CHECKSUM_SIZE..];
 			let hash = sha3_256(data);
 			if hash != checksum {
 				return Err(ErrorKind::CorruptedJournal(
@@ -113,76 +241,85 @@ impl JournalEra {
 					)
 				).into());
 			}
+		}
 
-			unsafe { cache_memory(data) }
-		};
+		if let Err(corruption) = OperationsIterator::validate(&mmap.as_slice()[CHECKSUM_SIZE..]) {
+			return Err(corruption.shifted(CHECKSUM_SIZE).in_file(file));
+		}
 
-		let era = JournalEra {
-			file: file.as_ref().to_path_buf(),
-			mmap,
-			cache,
-		};
+		Ok(mmap)
+	}
 
-		Ok(era)
+	/// Returns the serialized operations of the era.
+	fn raw(&self) -> &[u8] {
+		&self.mmap.as_slice()[CHECKSUM_SIZE..]
 	}
 
-	fn get<'a>(&'a self, key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
-		let key = JournalSlice::new(key);
+	fn get<'a>(&'a self, keyspace: &[u8], key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
+		let key = (JournalSlice::new(keyspace), JournalSlice::new(key));
 
-		match self.cache.get(&key) {
-			None => None,
-			Some(&JournalOperation::Insert(ref value)) => Some(JournalOperation::Insert(unsafe { value.as_slice() })),
-			Some(&JournalOperation::Delete) => Some(JournalOperation::Delete),
-		}
+		self.cache.get(&key).map(|operation| operation.map(|value| unsafe { value.as_slice() }))
 	}
 
-	fn operations(&self) -> BTreeSet<Operation> {
+	/// Returns merge operands of all keys merged in the era with the names of their keyspaces.
+	pub fn merges(&self) -> Vec<(&[u8], &[u8], JournalOperation<&[u8]>)> {
+		self.cache.iter()
+			.filter(|&(_, operation)| match *operation {
+				JournalOperation::Merge(..) => true,
+				_ => false,
+			})
+			.map(|(&(ref keyspace, ref key), operation)| unsafe {
+				(keyspace.as_slice(), key.as_slice(), operation.map(|value| value.as_slice()))
+			})
+			.collect()
+	}
+
+	fn operations(&self) -> BTreeSet<(&[u8], Operation)> {
 		let mut ops = BTreeSet::new();
 
-		for o in unsafe { OperationsIterator::new(&self.mmap.as_slice()[CHECKSUM_SIZE..]) } {
-			ops.replace(o);
+		for (keyspace, o) in unsafe { OperationsIterator::new(self.raw()).with_keyspaces() } {
+			ops.replace((keyspace, o.effect()));
 		}
 
 		ops
 	}
 
-	/// Returns an iterator over era entries
-	pub fn iter(&self) -> btree_set::IntoIter<Operation> {
+	/// Returns the size of the era file in bytes.
+	pub fn size(&self) -> u64 {
+		self.mmap.len() as u64
+	}
+
+	/// Returns an iterator over era entries with the names of their keyspaces,
+	/// ordered by keyspace and key.
+	pub fn iter(&self) -> btree_set::IntoIter<(&[u8], Operation)> {
 		self.operations().into_iter()
 	}
 
 	/// Deletes underlying file
 	pub fn delete(self) -> Result<()> {
-		fs::remove_file(self.file)?;
+		self.vfs.remove_file(&self.file)?;
 		Ok(())
 	}
 }
 
-mod dir {
-	use std::fs::read_dir;
+pub(crate) mod dir {
 	use std::path::{Path, PathBuf};
 	use error::{ErrorKind, Result};
+	use vfs::Vfs;
 
 	const ERA_EXTENSION: &str = ".era";
+	const PREPARED_EXTENSION: &str = ".prepared";
 
-	pub fn era_files<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
-		if !dir.as_ref().is_dir() {
+	pub fn era_files<P: AsRef<Path>>(vfs: &Vfs, dir: P) -> Result<Vec<PathBuf>> {
+		if !vfs.is_dir(dir.as_ref()) {
 			return Err(ErrorKind::InvalidJournalLocation(dir.as_ref().into()).into());
 		}
 
-		let mut era_files: Vec<_> = read_dir(dir)?
-			.collect::<::std::result::Result<Vec<_>, _>>()?
-			.into_iter()
-			.filter(|entry| entry.file_name().to_string_lossy().ends_with(ERA_EXTENSION))
-			.map(|entry| entry.path())
-			.collect();
-
-		era_files.sort();
-
+		let era_files = numbered_era_files(vfs, dir)?;
 		let mut last = None;
 
-		for era in &era_files {
-			let idx = era_index(era)?;
+		for &(seq, _) in &era_files {
+			let idx = seq + 1;
 			match last.take() {
 				Some(era) if idx == era + 1 => {},
 				None => {},
@@ -193,6 +330,20 @@ mod dir {
 			last = Some(idx);
 		}
 
+		Ok(era_files.into_iter().map(|(_, path)| path).collect())
+	}
+
+	/// Returns the era files in the directory with their sequence numbers, ordered by
+	/// the sequence number. The eras don't have to be consecutive.
+	pub fn numbered_era_files<P: AsRef<Path>>(vfs: &Vfs, dir: P) -> Result<Vec<(u64, PathBuf)>> {
+		let mut era_files = vfs.read_dir(dir.as_ref())?
+			.into_iter()
+			.filter(|path| has_extension(path, ERA_EXTENSION))
+			.map(|path| era_index(&path).map(|idx| (idx - 1, path)))
+			.collect::<Result<Vec<_>>>()?;
+
+		// file names are not ordered like the numbers, e.g. 10.era < 9.era
+		era_files.sort();
 		Ok(era_files)
 	}
 
@@ -219,9 +370,41 @@ mod dir {
 		dir
 	}
 
+	pub fn prepared_files<P: AsRef<Path>>(vfs: &Vfs, dir: P) -> Result<Vec<PathBuf>> {
+		let prepared_files = vfs.read_dir(dir.as_ref())?
+			.into_iter()
+			.filter(|path| has_extension(path, PREPARED_EXTENSION))
+			.collect();
+
+		Ok(prepared_files)
+	}
+
+	fn has_extension(path: &Path, extension: &str) -> bool {
+		path.file_name().map_or(false, |name| name.to_string_lossy().ends_with(extension))
+	}
+
+	/// Returns the index following the highest index of the prepared files.
+	pub fn next_prepared_index<P: AsRef<Path>>(files: &[P]) -> usize {
+		files.iter()
+			.filter_map(|path| path.as_ref().file_stem()?.to_string_lossy().parse::<usize>().ok())
+			.max()
+			.map_or(0, |index| index + 1)
+	}
+
+	pub fn prepared_filename<P: AsRef<Path>>(dir: P, index: usize) -> PathBuf {
+		let mut dir = dir.as_ref().to_path_buf();
+		dir.push(format!("{}{}", index, PREPARED_EXTENSION));
+		dir
+	}
+
 	#[cfg(test)]
 	mod tests {
-		use super::era_index;
+		extern crate tempdir;
+
+		use self::tempdir::TempDir;
+		use std::fs::File;
+		use super::{era_files, era_index};
+		use vfs::Vfs;
 
 		#[test]
 		fn test_era_index() {
@@ -230,71 +413,390 @@ mod dir {
 			assert!(era_index("a.era").is_err());
 			assert!(era_index("/path/..").is_err());
 		}
+
+		#[test]
+		fn should_order_era_files_by_number() {
+			let temp = TempDir::new("should_order_era_files_by_number").unwrap();
+			for idx in 8..12 {
+				File::create(temp.path().join(format!("{}.era", idx))).unwrap();
+			}
+
+			let era_files = era_files(&Vfs::Disk, temp.path()).unwrap();
+			let names: Vec<_> = era_files.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+			assert_eq!(names, vec!["8.era", "9.era", "10.era", "11.era"]);
+		}
+	}
+}
+
+/// Serialized operations of an era read by `Journal::changes_since`.
+#[derive(Debug)]
+pub enum EraOperations<'a> {
+	/// Operations of an era in the journal.
+	Journaled(&'a JournalEra),
+	/// Era moved to the archive directory.
+	Archived(PathBuf, Mapping),
+}
+
+impl<'a> EraOperations<'a> {
+	fn raw(&self) -> &[u8] {
+		match *self {
+			EraOperations::Journaled(era) => era.raw(),
+			EraOperations::Archived(_, ref mmap) => &mmap.as_slice()[CHECKSUM_SIZE..],
+		}
+	}
+
+	fn path(&self) -> &Path {
+		match *self {
+			EraOperations::Journaled(era) => &era.file,
+			EraOperations::Archived(ref path, _) => path,
+		}
+	}
+}
+
+/// Operations of the eras committed since the sequence number passed to `Database::changes_since`.
+#[derive(Debug)]
+pub struct Changes<'a> {
+	eras: Vec<(u64, EraOperations<'a>)>,
+	keyspace: Vec<u8>,
+	ttl: bool,
+}
+
+impl<'a> Changes<'a> {
+	pub(crate) fn new(eras: Vec<(u64, EraOperations<'a>)>, keyspace: &[u8], ttl: bool) -> Self {
+		Changes {
+			eras,
+			keyspace: keyspace.to_vec(),
+			ttl,
+		}
+	}
+
+	/// Returns the number of eras.
+	pub fn len(&self) -> usize {
+		self.eras.len()
+	}
+
+	/// Returns true if no era was committed since the sequence number.
+	pub fn is_empty(&self) -> bool {
+		self.eras.is_empty()
+	}
+
+	/// Returns an iterator over the sequence numbers of the eras and their operations
+	/// in the order they were committed, oldest era first.
+	pub fn iter(&self) -> ChangesIterator {
+		ChangesIterator {
+			changes: self,
+			next: 0,
+		}
+	}
+}
+
+/// Iterator over the eras of `Changes`.
+#[derive(Debug)]
+pub struct ChangesIterator<'a> {
+	changes: &'a Changes<'a>,
+	next: usize,
+}
+
+impl<'a> Iterator for ChangesIterator<'a> {
+	type Item = Result<(u64, Vec<Operation<'a>>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let changes = self.changes;
+		let &(seq, ref era) = changes.eras.get(self.next)?;
+		self.next += 1;
+
+		// era files were verified when they were loaded
+		let operations = unsafe { OperationsIterator::new(era.raw()) }.with_keyspaces()
+			.filter(|&(keyspace, _)| keyspace == &changes.keyspace[..])
+			.map(|(_, operation)| if changes.ttl {
+				// values are returned without their expiry time
+				operation.without_expiry().map_err(|corruption| corruption.in_file(era.path()))
+			} else {
+				Ok(operation)
+			})
+			.collect::<Result<_>>();
+
+		Some(operations.map(|operations| (seq, operations)))
 	}
 }
 
 #[derive(Debug)]
 pub struct Journal {
 	dir: PathBuf,
+	vfs: Vfs,
 	eras: VecDeque<JournalEra>,
 	next_era_index: u64,
+	next_prepared_index: AtomicUsize,
+	durability: Durability,
+	/// Directory where flushed eras are moved instead of being deleted.
+	archive: Option<PathBuf>,
+	/// Actions taken while opening the journal, which were not returned yet.
+	recovery: Vec<RecoveryAction>,
 }
 
 impl Journal {
-	pub fn open<P: AsRef<Path>>(jdir: P) -> Result<Self> {
-		let era_files = dir::era_files(&jdir)?;
-		let next_era_index = dir::next_era_index(&era_files)?;
+	/// Stores the sequence number of the next era once all eras were flushed.
+	const NEXT_ERA_FILE: &'static str = "NEXT_ERA";
+	/// Appended to the names of era files whose contents can't be flushed.
+	const CORRUPTED_EXTENSION: &'static str = ".corrupted";
+
+	/// Opens the journal in the directory. Flushed eras are moved to the `archive` directory if there is any.
+	pub fn open<P: AsRef<Path>>(vfs: &Vfs, jdir: P, durability: Durability, archive: Option<PathBuf>) -> Result<Self> {
+		let era_files = dir::era_files(vfs, &jdir)?;
+		let mut next_era_index = dir::next_era_index(&era_files)?;
+
+		// sequence numbers are not reused after all eras were flushed
+		let next_era_file = jdir.as_ref().join(Self::NEXT_ERA_FILE);
+		if vfs.exists(&next_era_file) {
+			let bytes = vfs.read(&next_era_file)?;
+			if bytes.len() != 8 {
+				let msg = format!("Expected 8 bytes of the next era index, got {}", bytes.len());
+				return Err(Corruption::new(0, msg).in_file(&next_era_file));
+			}
+			next_era_index = cmp::max(next_era_index, LittleEndian::read_u64(&bytes));
+		}
+
+		if let Some(ref archive) = archive {
+			vfs.create_dir_all(archive)?;
+			if let Some(&(seq, _)) = dir::numbered_era_files(vfs, archive)?.last() {
+				next_era_index = cmp::max(next_era_index, seq + 1);
+			}
+		}
+
+		// transactions prepared, but never applied before the database was closed;
+		// their files are not reused in case the transactions are still around
+		let prepared_files = dir::prepared_files(vfs, &jdir)?;
+		let next_prepared_index = dir::next_prepared_index(&prepared_files);
+		let mut recovery = Vec::new();
+		for file in prepared_files {
+			vfs.remove_file(&file)?;
+			recovery.push(RecoveryAction::PreparedTransactionRemoved(file));
+		}
 
 		let eras = era_files.into_iter()
-			.map(JournalEra::open)
+			.map(|file| JournalEra::open(vfs, file))
 			.collect::<Result<VecDeque<_>>>()?;
 
 		let journal = Journal {
 			dir: jdir.as_ref().to_path_buf(),
+			vfs: vfs.clone(),
 			eras,
 			next_era_index,
+			next_prepared_index: AtomicUsize::new(next_prepared_index),
+			durability,
+			archive,
+			recovery,
 		};
 
 		Ok(journal)
 	}
 
-	pub fn push(&mut self, transaction: &Transaction) -> Result<()> {
-		let new_path = dir::next_era_filename(&self.dir, self.next_era_index);
+	/// Returns the actions taken while opening the journal, which were not returned yet.
+	pub fn take_recovery(&mut self) -> Vec<RecoveryAction> {
+		mem::replace(&mut self.recovery, Vec::new())
+	}
+
+	/// Writes the transaction to a file which can be later applied to the journal.
+	pub fn prepare(&self, transaction: &Transaction) -> Result<PreparedTransaction> {
+		let index = self.next_prepared_index.fetch_add(1, Ordering::Relaxed);
+		let file = dir::prepared_filename(&self.dir, index);
+		JournalEra::write(&self.vfs, &file, transaction, self.durability)?;
+		// checksum was computed from the same transaction
+		let era = JournalEra::load(&self.vfs, file, false)?;
+
+		Ok(PreparedTransaction {
+			dir: self.dir.clone(),
+			era: Some(era),
+		})
+	}
+
+	/// Moves the prepared transaction into a new era file. Returns the sequence number of the era.
+	pub fn apply(&mut self, mut prepared: PreparedTransaction) -> Result<u64> {
+		if prepared.dir != self.dir {
+			return Err(ErrorKind::InvalidPreparedTransaction(prepared.file().into()).into());
+		}
+
+		let era_index = self.next_era_index;
+		let new_path = dir::next_era_filename(&self.dir, era_index);
+		self.vfs.rename(prepared.file(), &new_path)?;
+		let mut new_era = prepared.era.take().expect("era is only taken when the transaction is applied; qed");
+		new_era.file = new_path;
+		self.next_era_index += 1;
+
+		// make the renamed era file visible after a power failure
+		self.vfs.sync_dir(&self.dir, self.durability)?;
+		self.eras.push_back(new_era);
+
+		Ok(era_index)
+	}
+
+	/// Writes the transaction to a new era file. Returns the sequence number of the era.
+	pub fn push(&mut self, transaction: &Transaction) -> Result<u64> {
+		let era_index = self.next_era_index;
+		let new_path = dir::next_era_filename(&self.dir, era_index);
 		self.next_era_index += 1;
 
-		let new_era = JournalEra::create(new_path, &transaction)?;
+		let new_era = JournalEra::create(&self.vfs, new_path, &transaction, self.durability)?;
+		// make the new era file visible after a power failure
+		self.vfs.sync_dir(&self.dir, self.durability)?;
 		self.eras.push_back(new_era);
 
+		Ok(era_index)
+	}
+
+	pub fn front(&self) -> Option<&JournalEra> {
+		self.eras.front()
+	}
+
+	pub fn back(&self) -> Option<&JournalEra> {
+		self.eras.back()
+	}
+
+	pub fn pop_front(&mut self) -> Option<JournalEra> {
+		self.eras.pop_front()
+	}
+
+	/// Removes the era taken with `pop_front` from the disk, or moves it to the archive directory.
+	pub fn remove(&self, era: JournalEra) -> Result<()> {
+		self.save_next_era_index()?;
+
+		match self.archive {
+			Some(ref archive) => {
+				let name = era.file.file_name().expect("era files are named after their sequence numbers; qed");
+				self.vfs.rename(&era.file, &archive.join(name))?;
+				self.vfs.sync_dir(archive, self.durability)?;
+				Ok(())
+			},
+			None => era.delete(),
+		}
+	}
+
+	/// Moves the era taken with `pop_front` aside to a file with `.corrupted` extension,
+	/// which is not opened as a part of the journal. Returns the path of the file.
+	pub fn quarantine(&self, era: JournalEra) -> Result<PathBuf> {
+		self.save_next_era_index()?;
+
+		let mut path = era.file.clone().into_os_string();
+		path.push(Self::CORRUPTED_EXTENSION);
+		let path = PathBuf::from(path);
+		self.vfs.rename(&era.file, &path)?;
+		self.vfs.sync_dir(&self.dir, self.durability)?;
+		Ok(path)
+	}
+
+	fn save_next_era_index(&self) -> Result<()> {
+		if self.eras.is_empty() {
+			// the sequence number of the next era can't be read from era files after restart;
+			// the file is replaced atomically, so a crash never leaves it torn
+			let path = self.dir.join(Self::NEXT_ERA_FILE);
+			let tmp_path = self.dir.join(format!("{}.tmp", Self::NEXT_ERA_FILE));
+			let mut bytes = [0; 8];
+			LittleEndian::write_u64(&mut bytes, self.next_era_index);
+			self.vfs.write(&tmp_path, &bytes, false, self.durability)?;
+			self.vfs.rename(&tmp_path, &path)?;
+			self.vfs.sync_dir(&self.dir, self.durability)?;
+		}
+
 		Ok(())
 	}
 
-	pub fn drain_front(&mut self, elems: usize) -> Drain<JournalEra> {
-		self.eras.drain(..elems)
+	/// Returns the serialized operations of the eras committed since the era `seq`, oldest first.
+	/// Eras which are no longer in the journal are read from the archive directory.
+	///
+	/// Fails if any of the eras was already deleted.
+	pub fn changes_since(&self, seq: u64) -> Result<Vec<(u64, EraOperations)>> {
+		let first_journaled = self.first_seq();
+		let mut changes = Vec::new();
+
+		if seq < first_journaled {
+			let archived = match self.archive {
+				Some(ref archive) => dir::numbered_era_files(&self.vfs, archive)?,
+				None => Vec::new(),
+			};
+
+			// archived eras have to be consecutive up to the first journaled era
+			let mut expected = first_journaled;
+			for &(archived_seq, ref file) in archived.iter().rev() {
+				if archived_seq >= expected {
+					continue;
+				}
+				if archived_seq + 1 != expected || archived_seq < seq {
+					break;
+				}
+
+				changes.push((archived_seq, EraOperations::Archived(file.clone(), JournalEra::map(&self.vfs, file, true)?)));
+				expected = archived_seq;
+			}
+
+			if expected > seq {
+				return Err(ErrorKind::ChangesPruned(seq, expected).into());
+			}
+
+			changes.reverse();
+		}
+
+		for (journaled_seq, era) in (first_journaled..).zip(self.eras.iter()) {
+			if journaled_seq >= seq {
+				changes.push((journaled_seq, EraOperations::Journaled(era)));
+			}
+		}
+
+		Ok(changes)
 	}
 
 	pub fn len(&self) -> usize {
 		self.eras.len()
 	}
 
-	pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
+	/// Returns the sequence number of the oldest era in the journal,
+	/// or of the next era if the journal is empty.
+	pub fn first_seq(&self) -> u64 {
+		self.next_era_index - self.eras.len() as u64
+	}
+
+	/// Returns the sequence number of the next era.
+	pub fn next_seq(&self) -> u64 {
+		self.next_era_index
+	}
+
+	/// Returns the total size of all era files in bytes.
+	pub fn size(&self) -> u64 {
+		self.eras.iter().map(JournalEra::size).sum()
+	}
+
+	/// Returns the latest journaled operation on the key, with merge operands collected
+	/// from all eras since the key was last inserted or deleted.
+	pub fn get<'a>(&'a self, keyspace: &[u8], key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
+		let mut operands = Vec::new();
+
 		for era in self.eras.iter().rev() {
-			if let Some(operation) = era.get(&key) {
-				return match operation {
-					JournalOperation::Insert(insert) => Some(insert),
-					JournalOperation::Delete => None,
-				}
+			match era.get(keyspace, key) {
+				None => continue,
+				Some(JournalOperation::Merge(MergeBase::Previous, mut era_operands)) => {
+					// operands of newer eras are applied last
+					era_operands.append(&mut operands);
+					operands = era_operands;
+				},
+				Some(operation) => return Some(operation.merge(operands)),
 			}
 		}
 
-		None
+		if operands.is_empty() {
+			None
+		} else {
+			Some(JournalOperation::Merge(MergeBase::Previous, operands))
+		}
 	}
 
-	/// Returns an iterator over the journal entries across all eras
-	pub fn iter(&self) -> btree_set::IntoIter<Operation> {
+	/// Returns an iterator over the journal entries of the keyspace across the eras since the era `seq`
+	pub fn iter(&self, keyspace: &[u8], seq: u64) -> btree_set::IntoIter<Operation> {
 		let mut ops = BTreeSet::new();
-		for era in self.eras.iter() {
-			// append should take the value from `era` for keys that are equal
-			ops.append(&mut era.operations())
+		let skipped = seq.saturating_sub(self.first_seq()) as usize;
+		for era in self.eras.iter().skip(skipped) {
+			// operations of newer eras replace the older ones
+			for (_, operation) in era.operations().into_iter().filter(|&(name, _)| name == keyspace) {
+				ops.replace(operation);
+			}
 		}
 
 		ops.into_iter()
@@ -306,11 +808,76 @@ mod tests {
 	extern crate tempdir;
 
 	use self::tempdir::TempDir;
-	use std::fs;
+	use std::{fs, mem};
 	use std::io::Write;
+	use durability::Durability;
 	use error::ErrorKind;
-	use transaction::{Operation, Transaction};
-	use super::{Journal, JournalEra, JournalOperation};
+	use options::ValueLenLimit;
+	use transaction::{Operation, Transaction, DEFAULT_KEYSPACE};
+	use super::{Changes, Journal, JournalEra, JournalOperation, MergeBase};
+	use vfs::Vfs;
+
+	#[test]
+	fn test_journal_merge() {
+		let temp = TempDir::new("test_journal_merge").unwrap();
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+
+		let mut tx1 = Transaction::new(4);
+		tx1.merge(b"key1", b"a").unwrap();
+		tx1.delete(b"key3").unwrap();
+
+		let mut tx2 = Transaction::new(4);
+		tx2.insert(b"key2", b"value").unwrap();
+		tx2.merge(b"key2", b"b").unwrap();
+		tx2.merge(b"key3", b"c").unwrap();
+
+		let mut tx3 = Transaction::new(4);
+		tx3.merge(b"key1", b"d").unwrap();
+		tx3.merge(b"key2", b"e").unwrap();
+		tx3.merge(b"key1", b"f").unwrap();
+
+		journal.push(&tx1).unwrap();
+		journal.push(&tx2).unwrap();
+		journal.push(&tx3).unwrap();
+
+		assert_eq!(
+			journal.get(DEFAULT_KEYSPACE, b"key1"),
+			Some(JournalOperation::Merge(MergeBase::Previous, vec![b"a" as &[u8], b"d", b"f"]))
+		);
+		assert_eq!(
+			journal.get(DEFAULT_KEYSPACE, b"key2"),
+			Some(JournalOperation::Merge(MergeBase::Inserted(b"value" as &[u8]), vec![b"b" as &[u8], b"e"]))
+		);
+		assert_eq!(
+			journal.get(DEFAULT_KEYSPACE, b"key3"),
+			Some(JournalOperation::Merge(MergeBase::Deleted, vec![b"c" as &[u8]]))
+		);
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key4"), None);
+	}
+
+	#[test]
+	fn test_journal_keyspaces() {
+		let temp = TempDir::new("test_journal_keyspaces").unwrap();
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+
+		let mut tx = Transaction::new(4);
+		tx.add_keyspace("positions", 4, ValueLenLimit::Max(10), false);
+		tx.insert(b"key1", b"value1").unwrap();
+		tx.insert_in("positions", b"key1", b"value2").unwrap();
+		tx.insert_in("positions", b"key2", b"value3").unwrap();
+		journal.push(&tx).unwrap();
+
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key1"), Some(JournalOperation::Insert(b"value1" as &[u8])));
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key2"), None);
+		assert_eq!(journal.get(b"positions", b"key1"), Some(JournalOperation::Insert(b"value2" as &[u8])));
+		assert_eq!(journal.iter(b"positions", 0).count(), 2);
+		assert_eq!(
+			journal.front().unwrap().iter().map(|(keyspace, _)| keyspace).collect::<Vec<_>>(),
+			vec![b"" as &[u8], b"positions", b"positions"]
+		);
+	}
 
 	#[test]
 	fn test_era_create() {
@@ -325,33 +892,198 @@ mod tests {
 		tx.insert(b"key2", b"value2").unwrap();
 		tx.delete(b"key3").unwrap();
 
-		let era = JournalEra::create(path, &tx).unwrap();
-		assert_eq!(JournalOperation::Insert(b"value" as &[u8]), era.get(b"key1").unwrap());
-		assert_eq!(JournalOperation::Insert(b"value2" as &[u8]), era.get(b"key2").unwrap());
-		assert_eq!(JournalOperation::Delete, era.get(b"key3").unwrap());
-		assert_eq!(None, era.get(b"key4"));
+		let era = JournalEra::create(&Vfs::Disk, path, &tx, Durability::Full).unwrap();
+		assert_eq!(JournalOperation::Insert(b"value" as &[u8]), era.get(DEFAULT_KEYSPACE, b"key1").unwrap());
+		assert_eq!(JournalOperation::Insert(b"value2" as &[u8]), era.get(DEFAULT_KEYSPACE, b"key2").unwrap());
+		assert_eq!(JournalOperation::Delete, era.get(DEFAULT_KEYSPACE, b"key3").unwrap());
+		assert_eq!(None, era.get(DEFAULT_KEYSPACE, b"key4"));
 	}
 
 	#[test]
 	fn test_journal_new() {
 		let temp = TempDir::new("test_journal_new").unwrap();
 
-		let mut journal = Journal::open(temp.path()).unwrap();
-		journal.push(&Transaction::new(1)).unwrap();
-		journal.push(&Transaction::new(1)).unwrap();
-		journal.push(&Transaction::new(1)).unwrap();
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		assert_eq!(journal.push(&Transaction::new(1)).unwrap(), 0);
+		assert_eq!(journal.push(&Transaction::new(1)).unwrap(), 1);
+		assert_eq!(journal.push(&Transaction::new(1)).unwrap(), 2);
 		assert_eq!(journal.len(), 3);
 
-		journal.drain_front(2);
+		assert_eq!(journal.size(), 3 * 32);
+
+		journal.pop_front().unwrap();
+		journal.pop_front().unwrap();
 
 		assert_eq!(journal.len(), 1);
+		assert_eq!(journal.size(), 32);
+	}
+
+	#[test]
+	fn should_not_reuse_sequence_numbers_of_removed_eras() {
+		let temp = TempDir::new("should_not_reuse_sequence_numbers_of_removed_eras").unwrap();
+		let next_era_file = temp.path().join("NEXT_ERA");
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		assert_eq!(journal.push(&Transaction::new(1)).unwrap(), 0);
+		assert_eq!(journal.push(&Transaction::new(1)).unwrap(), 1);
+		let era = journal.pop_front().unwrap();
+		journal.remove(era).unwrap();
+		assert!(!next_era_file.exists());
+		let era = journal.pop_front().unwrap();
+		journal.remove(era).unwrap();
+		assert_eq!(fs::read(&next_era_file).unwrap(), vec![2, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(!temp.path().join("NEXT_ERA.tmp").exists());
+		drop(journal);
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		assert_eq!(journal.push(&Transaction::new(1)).unwrap(), 2);
+		drop(journal);
+
+		// a torn file can't tell which sequence numbers were used
+		fs::write(&next_era_file, &[2, 0, 0]).unwrap();
+		assert_eq!(
+			*Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap_err().kind(),
+			ErrorKind::Corrupted(next_era_file, 0, "Expected 8 bytes of the next era index, got 3".into())
+		);
+	}
+
+	fn changed_keys(journal: &Journal, seq: u64) -> Vec<(u64, Vec<Vec<u8>>)> {
+		let changes = Changes::new(journal.changes_since(seq).unwrap(), DEFAULT_KEYSPACE, false);
+		changes.iter()
+			.map(|era| era.unwrap())
+			.map(|(seq, operations)| (seq, operations.into_iter().map(|o| o.key().to_vec()).collect()))
+			.collect()
+	}
+
+	#[test]
+	fn test_journal_changes_since() {
+		let temp = TempDir::new("test_journal_changes_since").unwrap();
+		let archive = temp.path().join("archive");
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, Some(archive.clone())).unwrap();
+		for key in &[b"key0", b"key1", b"key2"] {
+			let mut tx = Transaction::new(4);
+			tx.insert(key, b"value").unwrap();
+			journal.push(&tx).unwrap();
+		}
+
+		let era = journal.pop_front().unwrap();
+		journal.remove(era).unwrap();
+		assert!(archive.join("0.era").exists());
+		assert_eq!(changed_keys(&journal, 0), vec![
+			(0, vec![b"key0".to_vec()]),
+			(1, vec![b"key1".to_vec()]),
+			(2, vec![b"key2".to_vec()]),
+		]);
+		assert_eq!(changed_keys(&journal, 2), vec![(2, vec![b"key2".to_vec()])]);
+		assert_eq!(changed_keys(&journal, 3), vec![]);
+
+		// sequence numbers continue after all eras were flushed
+		while let Some(era) = journal.pop_front() {
+			journal.remove(era).unwrap();
+		}
+		fs::remove_dir_all(&archive).unwrap();
+		drop(journal);
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		assert_eq!(journal.push(&Transaction::new(4)).unwrap(), 3);
+		assert_eq!(changed_keys(&journal, 3), vec![(3, vec![])]);
+		assert_eq!(*journal.changes_since(2).unwrap_err().kind(), ErrorKind::ChangesPruned(2, 3));
+	}
+
+	#[test]
+	fn test_journal_prepare_apply() {
+		let temp = TempDir::new("test_journal_prepare_apply").unwrap();
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+
+		let mut tx1 = Transaction::new(4);
+		tx1.insert(b"key1", b"value1").unwrap();
+		let mut tx2 = Transaction::new(4);
+		tx2.insert(b"key1", b"value2").unwrap();
+
+		let prepared1 = journal.prepare(&tx1).unwrap();
+		let prepared2 = journal.prepare(&tx2).unwrap();
+		assert_eq!(journal.len(), 0);
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key1"), None);
+
+		// eras are ordered by the time they are applied
+		assert_eq!(journal.apply(prepared2).unwrap(), 0);
+		assert_eq!(journal.apply(prepared1).unwrap(), 1);
+		assert_eq!(journal.len(), 2);
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key1"), Some(JournalOperation::Insert(b"value1" as &[u8])));
+		assert_eq!(journal.push(&Transaction::new(4)).unwrap(), 2);
+
+		drop(journal);
+		let journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		assert_eq!(journal.len(), 3);
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key1"), Some(JournalOperation::Insert(b"value1" as &[u8])));
+	}
+
+	#[test]
+	fn should_remove_abandoned_prepared_transactions() {
+		let temp = TempDir::new("should_remove_abandoned_prepared_transactions").unwrap();
+		let count_files = || fs::read_dir(temp.path()).unwrap().count();
+
+		let journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		let prepared = journal.prepare(&Transaction::new(4)).unwrap();
+		assert_eq!(count_files(), 1);
+		drop(prepared);
+		assert_eq!(count_files(), 0);
+
+		// simulate a crash before the transaction is applied
+		mem::forget(journal.prepare(&Transaction::new(4)).unwrap());
+		assert_eq!(count_files(), 1);
+		drop(journal);
+
+		let journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		assert_eq!(count_files(), 0);
+		assert_eq!(journal.len(), 0);
+	}
+
+	#[test]
+	fn should_not_reuse_files_of_outstanding_prepared_transactions() {
+		let temp = TempDir::new("should_not_reuse_files_of_outstanding_prepared_transactions").unwrap();
+
+		let journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		let outstanding = journal.prepare(&Transaction::new(4)).unwrap();
+		drop(journal);
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		let mut tx = Transaction::new(4);
+		tx.insert("key1", "value1").unwrap();
+		let prepared = journal.prepare(&tx).unwrap();
+		assert_ne!(prepared.file(), outstanding.file());
+
+		// the outstanding transaction removes only its own file
+		drop(outstanding);
+		assert!(prepared.file().exists());
+		assert_eq!(journal.apply(prepared).unwrap(), 0);
+		assert_eq!(journal.get(DEFAULT_KEYSPACE, b"key1"), Some(JournalOperation::Insert(b"value1" as &[u8])));
+	}
+
+	#[test]
+	fn should_reject_prepared_transaction_of_another_journal() {
+		let temp = TempDir::new("should_reject_prepared_transaction_of_another_journal").unwrap();
+		let other = TempDir::new("should_reject_prepared_transaction_of_another_journal").unwrap();
+
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
+		let other_journal = Journal::open(&Vfs::Disk, other.path(), Durability::Full, None).unwrap();
+
+		let prepared = other_journal.prepare(&Transaction::new(4)).unwrap();
+		let path = prepared.file().to_path_buf();
+		assert_eq!(
+			journal.apply(prepared).unwrap_err().kind(),
+			&ErrorKind::InvalidPreparedTransaction(path)
+		);
+		assert_eq!(journal.len(), 0);
 	}
 
 	#[test]
 	fn test_journal_iter() {
 		let temp = TempDir::new("test_journal_iter").unwrap();
 
-		let mut journal = Journal::open(temp.path()).unwrap();
+		let mut journal = Journal::open(&Vfs::Disk, temp.path(), Durability::Full, None).unwrap();
 
 		let mut tx1 = Transaction::new(4);
 		tx1.insert(b"key1", b"value").unwrap();
@@ -369,7 +1101,7 @@ mod tests {
 		assert_eq!(journal.len(), 2);
 
 		assert_eq!(
-			journal.iter().collect::<Vec<_>>(),
+			journal.iter(DEFAULT_KEYSPACE, 0).collect::<Vec<_>>(),
 			vec![
 				Operation::Insert(b"key1" as &[u8], b"value" as &[u8]),
 				Operation::Insert(b"key2" as &[u8], b"value2" as &[u8]),
@@ -391,7 +1123,7 @@ mod tests {
 		tx.insert(b"key3", b"value").unwrap();
 		tx.insert(b"key2", b"value2").unwrap();
 		tx.delete(b"key3").unwrap();
-		let _ = JournalEra::create(&path, &tx).unwrap();
+		let _ = JournalEra::create(&Vfs::Disk, &path, &tx, Durability::Full).unwrap();
 
 		// alter hash
 		let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
@@ -399,7 +1131,7 @@ mod tests {
 		file.flush().unwrap();
 
 		// Try to open era
-		assert_eq!(JournalEra::open(&path).unwrap_err().kind(), &ErrorKind::CorruptedJournal(
+		assert_eq!(JournalEra::open(&Vfs::Disk, &path).unwrap_err().kind(), &ErrorKind::CorruptedJournal(
 			path,
 			"Expected: [69 53 c1 6d b6 8a 85 9a b9 d8 b3 da 13 1d ba 6b 2a 17 d9 84 8d bf 6e d4 c0 d6 64 5d b3 98 5d 0c], Got: [01 02 03 6d b6 8a 85 9a b9 d8 b3 da 13 1d ba 6b 2a 17 d9 84 8d bf 6e d4 c0 d6 64 5d b3 98 5d 0c]".into()
 		));
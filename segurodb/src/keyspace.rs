@@ -0,0 +1,811 @@
+//! Data, metadata and collision files of a keyspace.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{PathBuf, Path};
+use std::{cmp, iter, mem};
+use std::time::Instant;
+
+use itertools::Itertools;
+use itertools::EitherOrBoth;
+
+use collision::Collision;
+use database::Value;
+use error::{Corruption, Error, ErrorKind, Result};
+use event::{Event, FlushCorrupted, PrefixMigrated, PrefixRestored, RecoveryAction};
+use expiry::{self, EXPIRY_SIZE};
+use field;
+use find;
+use find::RecordIterator;
+use flush::Flush;
+use merge::MergeOperator;
+use metrics::{self, names};
+use key::Key;
+use metadata::{self, Metadata};
+use options::{CompactionBudget, CompactionPolicy, InternalOptions};
+use record::Record;
+use storage::StorageFile;
+use transaction::Operation;
+
+/// Storage of the keys of `Database` or one of its named keyspaces.
+///
+/// Keyspaces share the journal of the database, which is flushed to each of them separately.
+#[derive(Debug)]
+pub struct Keyspace {
+	pub path: PathBuf,
+	pub options: InternalOptions,
+	pub metadata: Metadata,
+	pub metadata_file: Box<StorageFile>,
+	pub collisions: BTreeMap<u32, Collision>,
+	pub data: Box<StorageFile>,
+	/// Number of eras flushed since the last automatic compaction.
+	flushed_eras: usize,
+	/// Actions taken while opening the keyspace, which were not reported yet.
+	recovery: Vec<RecoveryAction>,
+}
+
+impl Keyspace {
+	const DB_FILE: &'static str = "data.db";
+	const META_FILE: &'static str = "meta.db";
+	/// Size of the flags stored in the metadata file after the metadata. Metadata files written
+	/// before the flags were introduced end with the metadata and have no flags set.
+	const FLAGS_SIZE: usize = 1;
+	/// Flag of keyspaces whose values are stored with their expiry time.
+	const TTL_FLAG: u8 = 1;
+	/// Number of fields read at once by lookups, doubled until the lookup is done.
+	const READ_WINDOW_FIELDS: usize = 16;
+
+	/// Creates the data and metadata files of a new keyspace in the directory.
+	pub fn create<P: AsRef<Path>>(path: P, options: &InternalOptions) -> Result<()> {
+		let durability = options.external.durability;
+
+		// Create DB file.
+		options.vfs.create_sized(&path.as_ref().join(Self::DB_FILE), options.initial_db_size, durability)?;
+
+		// Create Metadata file.
+		let meta_file_path = path.as_ref().join(Self::META_FILE);
+		let meta_len = metadata::bytes::len(options.external.key_index_bits);
+		let mut meta = vec![0; meta_len + Self::FLAGS_SIZE];
+		if options.external.ttl {
+			// the expiry time can't be told apart from the values, so the keyspace
+			// is never opened with another `ttl`
+			meta[meta_len] = Self::TTL_FLAG;
+		}
+		options.vfs.write(&meta_file_path, &meta, true, durability)?;
+
+		// Make created files visible after a power failure.
+		options.vfs.sync_dir(path.as_ref(), options.external.durability)?;
+
+		Ok(())
+	}
+
+	/// Opens the keyspace in the directory and applies an interrupted flush.
+	pub fn open<P: AsRef<Path>>(path: P, options: InternalOptions) -> Result<Self> {
+		let durability = options.external.durability;
+
+		let storage = options.external.storage;
+
+		let db_file_path = path.as_ref().join(Self::DB_FILE);
+		let mut data = options.vfs.open_storage(&db_file_path, storage, options.external.page_cache_size)?;
+
+		let meta_file_path = path.as_ref().join(Self::META_FILE);
+		let mut metadata_file = options.vfs.open_storage(&meta_file_path, storage, options.external.page_cache_size)?;
+
+		let (mut metadata, flags) = {
+			let raw = metadata_file.read_all()?;
+			let meta_len = metadata::bytes::len(options.external.key_index_bits);
+			let (raw_metadata, flags) = if raw.len() > meta_len {
+				raw.split_at(meta_len)
+			} else {
+				(raw, &[][..])
+			};
+
+			if flags.len() > Self::FLAGS_SIZE {
+				let msg = format!("Expected {} bytes of metadata, got {}", meta_len + Self::FLAGS_SIZE, raw.len());
+				return Err(Corruption::new(0, msg).in_file(&meta_file_path));
+			}
+
+			let metadata = metadata::bytes::read(raw_metadata, options.external.key_index_bits)
+				.map_err(|corruption| corruption.in_file(&meta_file_path))?;
+			(metadata, flags.first().cloned().unwrap_or(0))
+		};
+
+		let ttl = flags & Self::TTL_FLAG != 0;
+		if ttl != options.external.ttl {
+			bail!(ErrorKind::InvalidOptions(
+				"ttl",
+				format!("{} does not match the keyspace created with ttl {}", options.external.ttl, ttl)
+			));
+		}
+
+		let listener = options.external.event_listener.clone();
+		let mut recovery = Vec::new();
+
+		let flush = Flush::open(path.as_ref(), &options, data.len()).map_err(|err| {
+			let corrupted = match *err.kind() {
+				ErrorKind::CorruptedFlush(ref path, ref reason) => Some((path.clone(), reason.clone())),
+				ErrorKind::Corrupted(ref path, offset, ref msg) => Some((path.clone(), format!("{} at offset {}", msg, offset))),
+				_ => None,
+			};
+			if let Some((path, reason)) = corrupted {
+				listener.on_event(&Event::FlushCorrupted(FlushCorrupted { path, reason }));
+			}
+			err
+		})?;
+
+		if let Some(flush) = flush {
+			flush.flush(&mut *data, &mut *metadata_file, &mut metadata)?;
+			data.sync(durability)?;
+			metadata_file.sync(durability)?;
+			flush.delete()?;
+
+			recovery.push(RecoveryAction::FlushReplayed(path.as_ref().to_owned()));
+		}
+
+		let mut collisions = BTreeMap::new();
+
+		for prefix in metadata.collided_prefixes.prefixes_iter() {
+			let collision_file = match Collision::open(&options.vfs, &path, prefix, durability)? {
+				Some(collision_file) => collision_file,
+				None => {
+					let msg = format!("Collision file of the collided prefix {} is missing", prefix);
+					return Err(Corruption::new(0, msg).in_file(Collision::collision_file_path(&path, prefix)));
+				},
+			};
+
+			collisions.insert(prefix, collision_file);
+		}
+
+		// collision files of prefixes which are not declared as collided in metadata are
+		// leftovers of an interrupted compaction; their data lives in the data file
+		for prefix in Collision::file_prefixes(&options.vfs, &path)? {
+			if !metadata.collided_prefixes.has(prefix).unwrap_or(false) {
+				Collision::remove(&options.vfs, &path, prefix)?;
+				recovery.push(RecoveryAction::CollisionFileRemoved(Collision::collision_file_path(&path, prefix)));
+			}
+		}
+		options.vfs.sync_dir(path.as_ref(), durability)?;
+
+		Ok(Keyspace {
+			path: path.as_ref().to_owned(),
+			options,
+			metadata,
+			metadata_file,
+			data,
+			collisions,
+			flushed_eras: 0,
+			recovery,
+		})
+	}
+
+	/// Returns the actions taken while opening the keyspace, which were not returned yet.
+	pub fn take_recovery(&mut self) -> Vec<RecoveryAction> {
+		mem::replace(&mut self.recovery, Vec::new())
+	}
+
+	/// Checks that the key and the value of the operation can be written to the data file.
+	/// `merged` is the value resolved from merge operands of the key.
+	pub fn check(&self, operation: &Operation, merged: Option<&[u8]>) -> Result<()> {
+		let key_len = self.options.external.key_len;
+		let value_len = self.options.value_len_limit();
+
+		let key = operation.key();
+		if key.len() != key_len {
+			return Err(ErrorKind::InvalidKeyLen(key_len, key.len()).into());
+		}
+
+		match *operation {
+			// values with expiry are longer than the values accepted by transactions
+			Operation::Insert(_, value) if self.options.external.ttl => match value.len().checked_sub(EXPIRY_SIZE) {
+				Some(len) => value_len.check(len),
+				None => Err(self.expiry_corruption()),
+			},
+			Operation::Insert(_, value) => value_len.check(value.len()),
+			Operation::Merge(..) => value_len.check(merged.expect("merge operands are resolved; qed").len()),
+			_ => Ok(()),
+		}
+	}
+
+	/// Moves journaled operations of collided prefixes to their collision files and writes
+	/// the rest to a flush file, which is applied with `apply_flush`.
+	///
+	/// Prefixes of the flushed operations are added to `flushed_prefixes`.
+	pub fn prepare_flush<'a>(&mut self, operations: Vec<Operation<'a>>, flushed_prefixes: &mut BTreeSet<u32>) -> Result<Flush> {
+		let prefix_bits = self.options.external.key_index_bits;
+
+		// partition operations by whether they affect collided prefixes
+		let (collided_operations, operations): (Vec<_>, Vec<_>) = {
+			let collided_prefixes = &self.metadata.collided_prefixes;
+			operations.into_iter().partition(|op| {
+				let key = Key::new(op.key(), prefix_bits);
+				collided_prefixes.has(key.prefix).unwrap_or(false)
+			})
+		};
+
+		// flush operations for collided prefixes to their own collision file
+		for op in collided_operations {
+			let key = Key::new(op.key(), prefix_bits);
+			let collision = self.collisions.get_mut(&key.prefix).expect(
+				"prefix is declared as collided; \
+				 collision file should exist in collisions index; qed");
+
+			collision.apply(op)?;
+		}
+
+		flushed_prefixes.extend(operations.iter().map(|op| Key::new(op.key(), prefix_bits).prefix));
+
+		// create flush to data file for everything else
+		self.new_flush(&self.metadata, operations)
+	}
+
+	/// Writes the flush to the data file and deletes the flush file.
+	pub fn apply_flush(&mut self, flush: Flush) -> Result<()> {
+		// TODO: metadata should be a single structure
+		// updating self.metadata should happen after all calls
+		// which may fail ("?")
+		flush.flush(&mut *self.data, &mut *self.metadata_file, &mut self.metadata)?;
+		self.sync_data()?;
+		flush.delete()?;
+		Ok(())
+	}
+
+	/// Compacts the keyspace after `eras` were flushed if the configured compaction policy says so.
+	pub fn compact_after_flush(&mut self, eras: usize, flushed_prefixes: &BTreeSet<u32>) -> Result<()> {
+		self.flushed_eras += eras;
+
+		// the prefixes are moved by the criterion of the policy which triggered the compaction
+		let candidates = match self.options.external.compaction {
+			CompactionPolicy::Manual => return Ok(()),
+			CompactionPolicy::EveryFlushes(flushes) => {
+				if self.flushed_eras < flushes {
+					return Ok(());
+				}
+				None
+			},
+			CompactionPolicy::ProbeLength(max) => {
+				let prefixes = self.long_probe_prefixes(flushed_prefixes, max)?;
+				if prefixes.is_empty() {
+					return Ok(());
+				}
+				Some(prefixes)
+			},
+		};
+
+		self.flushed_eras = 0;
+		let budget = self.options.external.compaction_budget;
+		self.compact_prefixes(candidates, budget)?;
+		Ok(())
+	}
+
+	/// Lookup a value of the key in the data file or its collision file.
+	pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+		self.lookup(key).map(|(value, _)| value)
+	}
+
+	/// Lookup a value of the key in the data file or its collision file. Returns the value
+	/// with the name of the `get.*` counter of the file which answered the lookup.
+	pub fn lookup(&self, key: &[u8]) -> Result<(Option<Value>, &'static str)> {
+		let field_body_size = self.options.field_body_size;
+		let value_size = self.options.value_size;
+
+		let key = Key::new(key, self.options.external.key_index_bits);
+
+		// fetch from the collision file if this is a collided prefix
+		if self.metadata.collided_prefixes.has(key.prefix).unwrap_or(false) {
+			let collision = self.collisions.get(&key.prefix).expect(
+				"prefix is declared as collided; \
+				 collision file should exist in collisions index; qed");
+
+			let value = collision.get(key.key)?;
+			let counter = if value.is_some() { names::GET_COLLISION } else { names::GET_ABSENT };
+			return Ok((value.map(Value::Raw), counter))
+		}
+
+		// check if there's any data stored on the data file for the given prefix
+		if !self.metadata.prefixes.has(key.prefix).unwrap_or(false) {
+			return Ok((None, names::GET_ABSENT));
+		}
+
+		let offset = key.prefix as usize * self.options.record_offset;
+		let (result, probed) = self.read_window(offset, |data, at_end| {
+			let (result, probed) = find::find_record(data, field_body_size, value_size, key.key)?;
+			match result {
+				find::RecordResult::OutOfRange if !at_end => Ok(None),
+				result => Ok(Some((result, probed))),
+			}
+		})?;
+
+		self.options.external.metrics.observe(names::FIND_PROBE_LENGTH, probed as u64);
+		match result {
+			find::RecordResult::Found(record) => Ok((Some(Value::from(record)), names::GET_DATA_FILE)),
+			find::RecordResult::NotFound => Ok((None, names::GET_ABSENT)),
+			// records of a prefix are followed by an empty field or a greater key before the end of the file
+			find::RecordResult::OutOfRange => {
+				let msg = "Lookup reached the end of the data file";
+				Err(Corruption::new(self.data.len(), msg).in_file(self.path.join(Self::DB_FILE)))
+			},
+		}
+	}
+
+	/// Reads growing windows of the data file starting at `offset` until `read` returns a result.
+	/// `read` gets the window and whether it reaches the end of the file, and returns `None`
+	/// if the window ended before it was done.
+	fn read_window<'a, T, F>(&'a self, offset: usize, mut read: F) -> Result<T>
+		where F: FnMut(&'a [u8], bool) -> ::std::result::Result<Option<T>, field::Error> {
+
+		let field_size = self.options.record_offset;
+		let available = self.data.len() - offset;
+		let mut len = Self::READ_WINDOW_FIELDS * field_size;
+		loop {
+			len = cmp::min(len, available);
+			let at_end = len == available;
+			let window = self.data.read(offset, len)?;
+			match read(window, at_end) {
+				Ok(Some(result)) => return Ok(result),
+				Ok(None) => len *= 2,
+				// a record continuing after the window is only corrupted at the end of the file
+				Err(field::Error(field::ErrorKind::RecordOutOfBounds(_), _)) if !at_end => len *= 2,
+				Err(err) => return Err(self.data_corruption(err.into(), offset)),
+			}
+		}
+	}
+
+	/// Splits the expiry time off a value stored with `ttl`. Returns `None` if the value expired.
+	/// Values of keyspaces without `ttl` are returned unchanged.
+	pub fn unexpired<'a>(&self, value: Value<'a>) -> Result<Option<Value<'a>>> {
+		if !self.options.external.ttl {
+			return Ok(Some(value));
+		}
+
+		let (expires_at, value) = match value.split_expiry() {
+			Some(split) => split,
+			None => return Err(self.expiry_corruption()),
+		};
+
+		if expiry::is_expired(expires_at, self.options.external.clock.now()) {
+			Ok(None)
+		} else {
+			Ok(Some(value))
+		}
+	}
+
+	/// Returns the error of a value of the keyspace, which is too short to be stored with its expiry time.
+	fn expiry_corruption(&self) -> Error {
+		Corruption::new(0, format!("Value is shorter than its expiry time of {} bytes", EXPIRY_SIZE)).in_file(&self.path)
+	}
+
+	/// Folds merge operands, oldest first, into the `base` value.
+	pub fn merge_operands(&self, key: &[u8], base: Option<Value>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+		let merge_operator = self.merge_operator()?;
+		let mut value = base.map(|value| value.to_vec());
+		for operand in operands {
+			value = Some(merge_operator.merge(key, value.as_ref().map(|value| value.as_slice()), operand)?);
+		}
+
+		let value = value.expect("merge has at least one operand; qed");
+		self.options.value_len_limit().check(value.len())?;
+		Ok(value)
+	}
+
+	pub fn merge_operator(&self) -> Result<&MergeOperator> {
+		match self.options.external.merge_operator {
+			Some(ref merge_operator) => Ok(&**merge_operator),
+			None => Err(ErrorKind::MergeOperatorMissing.into()),
+		}
+	}
+
+	/// Syncs the data and metadata files to the disk.
+	pub fn sync_data(&self) -> Result<()> {
+		let durability = self.options.external.durability;
+		self.data.sync(durability)?;
+		self.metadata_file.sync(durability)?;
+		Ok(())
+	}
+
+	/// Returns the prefixes stored in the data file whose probe chains are longer than `max` fields.
+	fn long_probe_prefixes(&self, prefixes: &BTreeSet<u32>, max: usize) -> Result<Vec<u32>> {
+		let mut long_prefixes = Vec::new();
+		for prefix in prefixes {
+			let stored = self.metadata.prefixes.has(*prefix).unwrap_or(false) &&
+				!self.metadata.collided_prefixes.has(*prefix).unwrap_or(false);
+			if stored && self.probe_length(*prefix)? > max {
+				long_prefixes.push(*prefix);
+			}
+		}
+
+		Ok(long_prefixes)
+	}
+
+	/// Returns an iterator over only the database key-value pairs stored in the data file ordered
+	/// by key (i.e. it doesn't include data from the journal or collision files).
+	fn record_iter(&self) -> Result<RecordIterator> {
+		self.record_iter_from(0)
+	}
+
+	/// Same as `record_iter`, but starts at the records of the `prefix`.
+	fn record_iter_from(&self, prefix: u32) -> Result<RecordIterator> {
+		let occupied_prefixes_iter = self.metadata.prefixes.prefixes_iter();
+		let field_body_size = self.options.field_body_size;
+		let key_size = self.options.external.key_len;
+		let value_size = self.options.value_size;
+
+		let record_iter = find::iter(
+			&*self.data,
+			occupied_prefixes_iter,
+			prefix,
+			field_body_size,
+			key_size,
+			value_size,
+		)?;
+
+		Ok(record_iter)
+	}
+
+	// TODO: refactor to avoid boxed iterator
+	/// Returns an iterator over the key-value pairs stored in the data file and collision
+	/// files ordered by key.
+	pub fn iter<'a>(&'a self) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		self.iter_from(&[])
+	}
+
+	/// Same as `iter`, but starts at the first key greater or equal to `start`.
+	/// `start` can be shorter than the keys.
+	pub fn iter_from<'a>(&'a self, start: &[u8]) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		let key_len = self.options.external.key_len;
+		if start.len() > key_len {
+			return Err(ErrorKind::InvalidKeyLen(key_len, start.len()).into());
+		}
+
+		// keys starting with `start` are not lower than `start` padded with zeros
+		let mut start = start.to_vec();
+		let prefix = match start.len() {
+			0 => 0,
+			_ => {
+				start.resize(key_len, 0);
+				Key::new(&start, self.options.external.key_index_bits).prefix
+			},
+		};
+
+		let collided_records = self.collisions.range(prefix..)
+			.map(|(_, collision)| collision.iter())
+			.collect::<Result<Vec<_>>>()?
+			.into_iter()
+			.flat_map(|it| it);
+
+		let records = self.record_iter_from(prefix)?;
+
+		Ok(Box::new(records.merge_join_by(collided_records, |r, c| {
+			match (r, c) {
+				(&Err(_), _) => Ordering::Less,
+				(_, &Err(_)) => Ordering::Greater,
+				(&Ok(ref r), &Ok(ref c)) => r.key().cmp(&c.0),
+			}
+		}).map(move |either| {
+			match either {
+				EitherOrBoth::Left(Err(err)) => Err(self.data_corruption(err, 0)),
+				EitherOrBoth::Right(Err(err)) => Err(err),
+				EitherOrBoth::Left(Ok(r)) => Ok((r.key(), Value::Record(r))),
+				EitherOrBoth::Right(Ok(c)) => Ok((c.0, Value::Raw(c.1))),
+				EitherOrBoth::Both(_, _) =>
+					unreachable!("value exists in collision file; \
+								  so cannot exist in data file; qed"),
+			}
+		}).skip_while(move |item| match *item {
+			Ok((key, _)) => key < &start[..],
+			Err(_) => false,
+		})))
+	}
+
+	/// Returns the prefixes stored in the data file which have a number of collisions higher
+	/// than the configured threshold.
+	fn find_collided_prefixes(&self) -> Result<Vec<u32>> {
+		let mut collisions: BTreeMap<u32, usize> = BTreeMap::new();
+
+		for record in self.record_iter()? {
+			let key = record.map_err(|err| self.data_corruption(err, 0))?.key();
+
+			let prefix = Key::new(key, self.options.external.key_index_bits).prefix;
+			*collisions.entry(prefix).or_insert(0) += 1;
+		}
+
+		// drop prefixes that have a number of collisions lower than the allowed threshold
+		let collided_prefixes = collisions.into_iter()
+			.filter(|p| p.1 >= self.options.external.max_prefix_collisions)
+			.map(|p| p.0)
+			.collect();
+
+		Ok(collided_prefixes)
+	}
+
+	/// Returns the records stored in the data file for the given prefix ordered by key.
+	fn prefix_records(&self, prefix: u32) -> Result<Vec<Record>> {
+		let prefix_bits = self.options.external.key_index_bits;
+
+		let records = find::iter(
+			&*self.data,
+			iter::once(prefix),
+			0,
+			self.options.field_body_size,
+			self.options.external.key_len,
+			self.options.value_size,
+		)?;
+
+		let mut prefix_records = Vec::new();
+		for record in records {
+			let record = record.map_err(|err| self.data_corruption(err.into(), 0))?;
+			match Key::new(record.key(), prefix_bits).prefix.cmp(&prefix) {
+				Ordering::Less => {},
+				Ordering::Equal => prefix_records.push(record),
+				Ordering::Greater => break,
+			}
+		}
+
+		Ok(prefix_records)
+	}
+
+	/// Converts malformed fields found in the data file into a corruption of the file.
+	/// `offset` is the offset of the parsed slice from the beginning of the data file.
+	fn data_corruption(&self, err: Error, offset: usize) -> Error {
+		let corruption = match *err.kind() {
+			ErrorKind::Field(field::ErrorKind::InvalidHeader(at, byte)) =>
+				Corruption::new(at, format!("Invalid field header {}", byte)),
+			ErrorKind::Field(field::ErrorKind::RecordOutOfBounds(at)) =>
+				Corruption::new(at, "Record does not fit in the data file"),
+			_ => return err,
+		};
+
+		corruption.shifted(offset).in_file(self.path.join(Self::DB_FILE))
+	}
+
+	/// Prepares a flush of the operations ordered by key, which updates the metadata to `metadata`.
+	fn new_flush<'a, I: IntoIterator<Item = Operation<'a>>>(&self, metadata: &Metadata, operations: I) -> Result<Flush> {
+		Flush::new(
+			&self.path,
+			&self.options,
+			&*self.data,
+			metadata,
+			operations,
+		).map_err(|err| self.data_corruption(err, 0))
+	}
+
+	/// Returns the number of fields probed to reach the end of records with the given prefix.
+	pub fn probe_length(&self, prefix: u32) -> Result<usize> {
+		let offset = prefix as usize * self.options.record_offset;
+		let field_size = self.options.record_offset;
+
+		self.read_window(offset, |data, at_end| {
+			let probed = find::probe_length(
+				data,
+				self.options.field_body_size,
+				self.options.external.key_len,
+				prefix,
+				self.options.external.key_index_bits,
+			)?;
+
+			// the records of the prefix may continue after a window which was probed completely
+			if probed == data.len() / field_size && !at_end {
+				Ok(None)
+			} else {
+				Ok(Some(probed))
+			}
+		})
+	}
+
+	/// Compacts the database moving collided prefixes one by one until the `budget` is exhausted.
+	/// At least one prefix is moved if there are any collisions.
+	pub fn compact_within(&mut self, budget: CompactionBudget) -> Result<Vec<u32>> {
+		self.compact_prefixes(None, budget)
+	}
+
+	/// Compacts the database moving `candidates` to collision files, or the prefixes with
+	/// `max_prefix_collisions` records if there are none, and restoring shrunk collided prefixes.
+	fn compact_prefixes(&mut self, candidates: Option<Vec<u32>>, budget: CompactionBudget) -> Result<Vec<u32>> {
+		let started = Instant::now();
+		let expired = self.remove_expired()?;
+		let candidates = match candidates {
+			Some(candidates) => candidates,
+			None => self.find_collided_prefixes()?,
+		};
+		let mut collided_prefixes = Vec::new();
+		let mut written_bytes = 0;
+
+		match budget {
+			CompactionBudget::Unlimited => {
+				if !candidates.is_empty() {
+					written_bytes = self.migrate_prefixes(&candidates)?;
+				}
+				collided_prefixes = candidates;
+			},
+			CompactionBudget::Time(_) | CompactionBudget::Bytes(_) => {
+				for prefix in candidates {
+					written_bytes += self.migrate_prefixes(&[prefix])?;
+					collided_prefixes.push(prefix);
+
+					if budget.is_exhausted(started.elapsed(), written_bytes) {
+						break;
+					}
+				}
+			},
+		}
+
+		// prefixes moved by this compaction are not restored even if they are small
+		let restorable = self.restorable_prefixes(&collided_prefixes);
+		let mut restored_prefixes = Vec::new();
+		match budget {
+			CompactionBudget::Unlimited => {
+				if !restorable.is_empty() {
+					written_bytes += self.restore_prefixes(&restorable)?;
+				}
+				restored_prefixes = restorable;
+			},
+			CompactionBudget::Time(_) | CompactionBudget::Bytes(_) => {
+				for prefix in restorable {
+					if budget.is_exhausted(started.elapsed(), written_bytes) {
+						break;
+					}
+
+					written_bytes += self.restore_prefixes(&[prefix])?;
+					restored_prefixes.push(prefix);
+				}
+			},
+		}
+
+		let metrics = &self.options.external.metrics;
+		metrics.observe(names::COMPACTION_DURATION, metrics::micros(started.elapsed()));
+		metrics.increment(names::COMPACTION_MOVED_PREFIXES, collided_prefixes.len() as u64);
+		metrics.increment(names::COMPACTION_RESTORED_PREFIXES, restored_prefixes.len() as u64);
+		metrics.increment(names::COMPACTION_WRITTEN_BYTES, written_bytes);
+		metrics.increment(names::COMPACTION_EXPIRED, expired as u64);
+
+		Ok(collided_prefixes)
+	}
+
+	/// Deletes expired values from the data file and collision files.
+	/// Returns the number of deleted values.
+	fn remove_expired(&mut self) -> Result<usize> {
+		if !self.options.external.ttl {
+			return Ok(0);
+		}
+
+		let mut expired = Vec::new();
+		for record in self.iter()? {
+			let (key, value) = record?;
+			if self.unexpired(value)?.is_none() {
+				expired.push(key.to_vec());
+			}
+		}
+
+		if expired.is_empty() {
+			return Ok(0);
+		}
+
+		let deletions = expired.iter().map(|key| Operation::Delete(key)).collect();
+		let flush = self.prepare_flush(deletions, &mut BTreeSet::new())?;
+		self.apply_flush(flush)?;
+
+		Ok(expired.len())
+	}
+
+	/// Moves all the data of given prefixes from the data file to their own collision files.
+	/// Returns the number of bytes written to the collision files.
+	fn migrate_prefixes(&mut self, prefixes: &[u32]) -> Result<u64> {
+		let mut collision_files = Vec::new();
+		let mut written_bytes = 0;
+
+		let (metadata, flush) = {
+			let mut deletions = Vec::new();
+
+			// create collision files and insert data from collided prefixes
+			for prefix in prefixes {
+				let mut collision_file = Collision::create(&self.options.vfs, &self.path, *prefix, self.options.external.durability)?;
+
+				for record in self.prefix_records(*prefix)? {
+					let key = record.key();
+					written_bytes += (key.len() + record.value_len()) as u64;
+
+					let value = Value::from(record);
+					collision_file.insert(key, value.as_slice().unwrap_or(&value.to_vec()))?;
+
+					deletions.push(Operation::Delete(key));
+				}
+
+				collision_files.push(collision_file);
+			}
+
+			// clone metadata and update it with collided prefixes but don't persist it
+			let mut metadata = self.metadata.clone();
+			for prefix in prefixes {
+				metadata.add_prefix_collision(*prefix);
+			}
+
+			// prepare flush to delete colliding keys but don't apply it
+			let flush = self.new_flush(&metadata, deletions)?;
+
+			(metadata, flush)
+		};
+
+		// persist metadata updated with collided prefixes
+		// if we crash after this the flush will be applied on restart and the metadata will
+		// already be properly updated
+		let mut raw_metadata = vec![0; metadata.as_bytes().len()];
+		metadata.as_bytes().copy_to_slice(&mut raw_metadata);
+		self.metadata_file.write(0, &raw_metadata)?;
+		self.metadata_file.sync(self.options.external.durability)?;
+
+		// perform the flush and update metadata
+		flush.flush(&mut *self.data, &mut *self.metadata_file, &mut self.metadata)?;
+		self.sync_data()?;
+		flush.delete()?;
+
+		// update collisions index
+		for collision_file in collision_files {
+			self.options.external.event_listener.on_event(&Event::PrefixMigrated(PrefixMigrated {
+				path: self.path.clone(),
+				prefix: collision_file.prefix(),
+				records: collision_file.len(),
+			}));
+
+			let prev = self.collisions.insert(collision_file.prefix(), collision_file);
+			assert!(prev.is_none());
+		}
+
+		Ok(written_bytes)
+	}
+
+	/// Returns the collided prefixes that shrank to half of `max_prefix_collisions` or lower,
+	/// except for the `migrated` ones.
+	fn restorable_prefixes(&self, migrated: &[u32]) -> Vec<u32> {
+		let threshold = self.options.external.max_prefix_collisions / 2;
+		self.collisions.iter()
+			.filter(|&(prefix, collision)| collision.len() <= threshold && !migrated.contains(prefix))
+			.map(|(prefix, _)| *prefix)
+			.collect()
+	}
+
+	/// Moves the data of given collided prefixes back to the data file.
+	/// Returns the number of bytes written to the data file.
+	fn restore_prefixes(&mut self, restored_prefixes: &[u32]) -> Result<u64> {
+		let mut written_bytes = 0;
+		let flush = {
+			// metadata is only updated by the flush, if we crash before the flush is applied
+			// the prefixes are still declared as collided and the collision files are intact
+			let mut metadata = self.metadata.clone();
+			for prefix in restored_prefixes.iter() {
+				metadata.remove_prefix_collision(*prefix);
+			}
+
+			// collisions are ordered by prefix and collision records are ordered by key,
+			// so the insertions are ordered by key
+			let mut insertions = Vec::new();
+			for prefix in restored_prefixes.iter() {
+				for record in self.collisions[prefix].iter()? {
+					let (key, value) = record?;
+					written_bytes += (key.len() + value.len()) as u64;
+					insertions.push(Operation::Insert(key, value));
+				}
+			}
+
+			self.new_flush(&metadata, insertions)?
+		};
+
+		flush.flush(&mut *self.data, &mut *self.metadata_file, &mut self.metadata)?;
+		self.sync_data()?;
+		flush.delete()?;
+
+		// if we crash before deleting them, orphaned collision files are removed on open
+		for prefix in restored_prefixes.iter() {
+			let collision = self.collisions.remove(prefix).expect(
+				"restored prefixes are taken from collisions index; qed");
+			let records = collision.len();
+			collision.delete_file()?;
+
+			self.options.external.event_listener.on_event(&Event::PrefixRestored(PrefixRestored {
+				path: self.path.clone(),
+				prefix: *prefix,
+				records,
+			}));
+		}
+
+		Ok(written_bytes)
+	}
+}
@@ -51,6 +51,8 @@
 //! The index of the field for a record is determined using the first X bytes of the key.
 
 #![warn(missing_docs)]
+// error_chain! expands recursively once per error kind
+#![recursion_limit = "256"]
 
 extern crate bit_vec;
 extern crate byteorder;
@@ -69,25 +71,55 @@ extern crate matches;
 #[macro_use]
 extern crate quickcheck;
 
+mod codec;
 mod collision;
 mod database;
+mod durability;
 mod error;
+mod event;
+mod expiry;
 mod field;
 mod find;
 mod flush;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzz;
+mod index;
 mod journal;
 mod key;
+mod keyspace;
+mod merge;
 mod metadata;
+mod metrics;
+mod migration;
 mod options;
 mod prefix_tree;
 mod record;
+mod shared;
 mod space;
+mod storage;
+mod subscription;
+mod table;
 mod transaction;
+mod vfs;
 
+pub use codec::{KeyCodec, ValueCodec};
 pub use database::{Database, Value};
+pub use durability::Durability;
 pub use error::{Error, Result, ErrorKind};
-pub use options::{Options, ValuesLen};
+pub use event::{DatabaseOpened, Event, EventListener, FlushCorrupted, FlushReplayed, FormatMigrated, JournalEraCorrupted, LockContended, MigrationProgress, NoopListener, PrefixMigrated, PrefixRestored, RecoveryAction};
+pub use expiry::{Clock, SystemClock};
+pub use index::{Index, IndexExtractor, IndexIterator, ValueBytes};
+pub use journal::{Changes, ChangesIterator, PreparedTransaction};
+pub use merge::{MergeOperator, I64Add, I64SaturatingSub, U64Add, U64SaturatingSub};
+pub use metrics::{names as metric_names, Histogram, InMemoryMetrics, Metrics, MetricsSnapshot, NoopMetrics};
+pub use migration::FORMAT_VERSION;
+pub use options::{CompactionBudget, CompactionPolicy, Options, ValuesLen};
 pub use record::Record;
-pub use transaction::Transaction;
+pub use shared::{FlushPolicy, GroupCommit, SharedDatabase};
+pub use storage::Storage;
+pub use subscription::{Change, KeyFilter, Subscription};
+pub use table::{Table, TableIterator, TableTransaction};
+pub use transaction::{Operation, Transaction};
 #[doc(hidden)]
 pub use prefix_tree::PrefixTree;
@@ -0,0 +1,155 @@
+//! Merge operators resolving operands added with `Transaction::merge`.
+
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::{ErrorKind, Result};
+
+/// Combines a merge operand with the current value of a key.
+///
+/// Operands are kept in the journal and resolved when the key is read or flushed,
+/// so the same operator has to be registered every time the database is opened.
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+	/// Returns the value of `key` after applying `operand` to the `existing` value.
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Adds little-endian `i64` operands to the value. Saturates on overflow.
+///
+/// Missing values are treated as zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct I64Add;
+
+/// Subtracts little-endian `i64` operands from the value. Saturates on overflow.
+///
+/// Missing values are treated as zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct I64SaturatingSub;
+
+/// Adds little-endian `u64` operands to the value. Saturates at `u64::max_value()`.
+///
+/// Missing values are treated as zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct U64Add;
+
+/// Subtracts little-endian `u64` operands from the value. Saturates at zero.
+///
+/// Missing values are treated as zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct U64SaturatingSub;
+
+impl MergeOperator for I64Add {
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+		merge_i64(key, existing, operand, i64::saturating_add)
+	}
+}
+
+impl MergeOperator for I64SaturatingSub {
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+		merge_i64(key, existing, operand, i64::saturating_sub)
+	}
+}
+
+impl MergeOperator for U64Add {
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+		merge_u64(key, existing, operand, u64::saturating_add)
+	}
+}
+
+impl MergeOperator for U64SaturatingSub {
+	fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+		merge_u64(key, existing, operand, u64::saturating_sub)
+	}
+}
+
+fn check_len(key: &[u8], name: &str, value: &[u8]) -> Result<()> {
+	if value.len() != 8 {
+		return Err(ErrorKind::MergeFailed(
+			key.to_vec(),
+			format!("{} is {} bytes long, expected 8", name, value.len())
+		).into());
+	}
+
+	Ok(())
+}
+
+fn merge_u64<F>(key: &[u8], existing: Option<&[u8]>, operand: &[u8], f: F) -> Result<Vec<u8>> where F: Fn(u64, u64) -> u64 {
+	check_len(key, "operand", operand)?;
+	let existing = match existing {
+		Some(existing) => {
+			check_len(key, "existing value", existing)?;
+			LittleEndian::read_u64(existing)
+		},
+		None => 0,
+	};
+
+	let mut result = vec![0; 8];
+	LittleEndian::write_u64(&mut result, f(existing, LittleEndian::read_u64(operand)));
+	Ok(result)
+}
+
+fn merge_i64<F>(key: &[u8], existing: Option<&[u8]>, operand: &[u8], f: F) -> Result<Vec<u8>> where F: Fn(i64, i64) -> i64 {
+	check_len(key, "operand", operand)?;
+	let existing = match existing {
+		Some(existing) => {
+			check_len(key, "existing value", existing)?;
+			LittleEndian::read_i64(existing)
+		},
+		None => 0,
+	};
+
+	let mut result = vec![0; 8];
+	LittleEndian::write_i64(&mut result, f(existing, LittleEndian::read_i64(operand)));
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use byteorder::{ByteOrder, LittleEndian};
+	use error::ErrorKind;
+	use super::{MergeOperator, I64Add, I64SaturatingSub, U64Add, U64SaturatingSub};
+
+	fn u64_bytes(value: u64) -> Vec<u8> {
+		let mut bytes = vec![0; 8];
+		LittleEndian::write_u64(&mut bytes, value);
+		bytes
+	}
+
+	fn i64_bytes(value: i64) -> Vec<u8> {
+		let mut bytes = vec![0; 8];
+		LittleEndian::write_i64(&mut bytes, value);
+		bytes
+	}
+
+	#[test]
+	fn test_u64_operators() {
+		assert_eq!(U64Add.merge(b"key", None, &u64_bytes(5)).unwrap(), u64_bytes(5));
+		assert_eq!(U64Add.merge(b"key", Some(&u64_bytes(5)), &u64_bytes(7)).unwrap(), u64_bytes(12));
+		assert_eq!(U64Add.merge(b"key", Some(&u64_bytes(u64::max_value())), &u64_bytes(1)).unwrap(), u64_bytes(u64::max_value()));
+		assert_eq!(U64SaturatingSub.merge(b"key", Some(&u64_bytes(7)), &u64_bytes(5)).unwrap(), u64_bytes(2));
+		assert_eq!(U64SaturatingSub.merge(b"key", Some(&u64_bytes(5)), &u64_bytes(7)).unwrap(), u64_bytes(0));
+		assert_eq!(U64SaturatingSub.merge(b"key", None, &u64_bytes(7)).unwrap(), u64_bytes(0));
+	}
+
+	#[test]
+	fn test_i64_operators() {
+		assert_eq!(I64Add.merge(b"key", None, &i64_bytes(-5)).unwrap(), i64_bytes(-5));
+		assert_eq!(I64Add.merge(b"key", Some(&i64_bytes(5)), &i64_bytes(-7)).unwrap(), i64_bytes(-2));
+		assert_eq!(I64Add.merge(b"key", Some(&i64_bytes(i64::max_value())), &i64_bytes(1)).unwrap(), i64_bytes(i64::max_value()));
+		assert_eq!(I64SaturatingSub.merge(b"key", Some(&i64_bytes(5)), &i64_bytes(7)).unwrap(), i64_bytes(-2));
+		assert_eq!(I64SaturatingSub.merge(b"key", Some(&i64_bytes(i64::min_value())), &i64_bytes(1)).unwrap(), i64_bytes(i64::min_value()));
+	}
+
+	#[test]
+	fn should_reject_values_of_invalid_length() {
+		assert_eq!(
+			*U64Add.merge(b"key", None, b"1234").unwrap_err().kind(),
+			ErrorKind::MergeFailed(b"key".to_vec(), "operand is 4 bytes long, expected 8".into())
+		);
+		assert_eq!(
+			*I64Add.merge(b"key", Some(b"1234"), &i64_bytes(1)).unwrap_err().kind(),
+			ErrorKind::MergeFailed(b"key".to_vec(), "existing value is 4 bytes long, expected 8".into())
+		);
+	}
+}
@@ -21,6 +21,7 @@ pub struct Metadata {
 }
 
 impl Metadata {
+	/// Version of the metadata layout, rewritten by the migrations which change it.
 	pub const DB_VERSION: u16 = 0;
 
 	/// Notify that record was inserted.
@@ -51,6 +52,14 @@ impl Metadata {
 		self.prefixes.remove(prefix);
 	}
 
+	/// Notify that a given prefix is no longer collided.
+	///
+	/// The prefix is removed from `collided_prefixes`. It is marked in `prefixes` again
+	/// once its records are written back to the data file.
+	pub fn remove_prefix_collision(&mut self, prefix: u32) {
+		self.collided_prefixes.remove(prefix);
+	}
+
 	/// Returns bytes representation of `Metadata`.
 	pub fn as_bytes(&self) -> bytes::Metadata {
 		bytes::Metadata::new(self)
@@ -61,6 +70,7 @@ impl Metadata {
 pub mod bytes {
 	use byteorder::{LittleEndian, ByteOrder};
 
+	use error::Corruption;
 	use prefix_tree::PrefixTree;
 
 	/// Bytes representation of `Metadata`.
@@ -115,25 +125,49 @@ pub mod bytes {
 	}
 
 	/// Read `Metadata` from given slice.
-	pub fn read(data: &[u8], prefix_bits: u8) -> super::Metadata {
+	pub fn read(data: &[u8], prefix_bits: u8) -> Result<super::Metadata, Corruption> {
+		if data.len() != len(prefix_bits) {
+			return Err(Corruption::new(0, format!("Expected {} bytes of metadata, got {}", len(prefix_bits), data.len())));
+		}
+
 		let db_version = LittleEndian::read_u16(&data[..Metadata::VERSION_SIZE]);
+		if db_version != super::Metadata::DB_VERSION {
+			return Err(Corruption::new(0, format!("Unsupported database version {}", db_version)));
+		}
+
 		let occupied_bytes = LittleEndian::read_u64(&data[Metadata::VERSION_SIZE..]);
 
 		let prefix_leaves_offset = prefix_leaves_offset();
 		let collided_prefix_leaves_offset = collided_prefix_leaves_offset(prefix_bits);
 
-		let prefixes = PrefixTree::from_leaves(&data[prefix_leaves_offset..collided_prefix_leaves_offset], prefix_bits);
-		let collided_prefixes = PrefixTree::from_leaves(&data[collided_prefix_leaves_offset..], prefix_bits);
-
-		assert_eq!(db_version, super::Metadata::DB_VERSION);
+		let prefix_leaves = &data[prefix_leaves_offset..collided_prefix_leaves_offset];
+		let collided_prefix_leaves = &data[collided_prefix_leaves_offset..];
+		check_leaves(prefix_leaves, prefix_bits).map_err(|c| c.shifted(prefix_leaves_offset))?;
+		check_leaves(collided_prefix_leaves, prefix_bits).map_err(|c| c.shifted(collided_prefix_leaves_offset))?;
 
-		super::Metadata {
+		Ok(super::Metadata {
 			db_version,
 			occupied_bytes,
 			prefix_bits,
-			prefixes,
-			collided_prefixes,
+			prefixes: PrefixTree::from_leaves(prefix_leaves, prefix_bits),
+			collided_prefixes: PrefixTree::from_leaves(collided_prefix_leaves, prefix_bits),
+		})
+	}
+
+	/// Checks that the leaves don't mark prefixes which are out of range.
+	fn check_leaves(leaves: &[u8], prefix_bits: u8) -> Result<(), Corruption> {
+		let end = PrefixTree::leaves_offset(prefix_bits) + (1usize << prefix_bits);
+		if end % 8 == 0 {
+			return Ok(());
+		}
+
+		// only the last byte has bits for prefixes out of range
+		let last = leaves.len() - 1;
+		if leaves[last] >> (end % 8) != 0 {
+			return Err(Corruption::new(last, "Prefix out of range is marked as occupied"));
 		}
+
+		Ok(())
 	}
 }
 
@@ -156,7 +190,7 @@ mod tests {
 			}
 
 			let initial_zeroed_buf: Vec<u8> = vec![0; bytes::len(key_index_bits)];
-			let metadata = bytes::read(&initial_zeroed_buf[..], key_index_bits);
+			let metadata = bytes::read(&initial_zeroed_buf[..], key_index_bits).unwrap();
 			assert_eq!(metadata.db_version, 0);
 			assert_eq!(metadata.occupied_bytes, 0);
 
@@ -171,4 +205,38 @@ mod tests {
 			TestResult::passed()
 		}
 	}
+
+	#[test]
+	fn should_reject_corrupted_metadata() {
+		let key_index_bits = 1;
+		let data = vec![0; bytes::len(key_index_bits)];
+		assert!(bytes::read(&data, key_index_bits).is_ok());
+
+		for len in 0..data.len() {
+			assert_eq!(bytes::read(&data[..len], key_index_bits).unwrap_err().offset, 0);
+		}
+
+		let mut version = data.clone();
+		version[0] = 1;
+		assert_eq!(bytes::read(&version, key_index_bits).unwrap_err().offset, 0);
+
+		// the 2 prefixes follow the inner nodes of the tree in the leaves byte
+		let mut leaves = data.clone();
+		leaves[bytes::prefix_leaves_offset()] = 0b1_0000;
+		assert_eq!(bytes::read(&leaves, key_index_bits).unwrap_err().offset, bytes::prefix_leaves_offset());
+	}
+
+	quickcheck! {
+		fn quickcheck_reading_garbled_metadata_never_panics(data: Vec<u8>, key_index_bits: u8) -> bool {
+			let key_index_bits = key_index_bits % 12 + 1;
+			let _ = bytes::read(&data, key_index_bits);
+			let mut padded = data.clone();
+			padded.resize(bytes::len(key_index_bits), 0);
+			// a supported version, so the prefix leaves are parsed
+			padded[0] = 0;
+			padded[1] = 0;
+			let _ = bytes::read(&padded, key_index_bits);
+			true
+		}
+	}
 }
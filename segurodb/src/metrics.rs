@@ -0,0 +1,189 @@
+//! Metrics of reads, commits, flushes and compactions.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+/// Names of the metrics reported by the database.
+pub mod names {
+	/// Counter of reads answered by the journal.
+	pub const GET_JOURNAL: &str = "get.journal";
+	/// Counter of reads answered by a collision file.
+	pub const GET_COLLISION: &str = "get.collision";
+	/// Counter of reads answered by the data file.
+	pub const GET_DATA_FILE: &str = "get.data_file";
+	/// Counter of reads of absent keys.
+	pub const GET_ABSENT: &str = "get.absent";
+	/// Histogram of the number of fields probed to find a record in the data file.
+	pub const FIND_PROBE_LENGTH: &str = "find.probe_length";
+	/// Histogram of the commit latency in microseconds.
+	pub const COMMIT_LATENCY: &str = "commit.latency_us";
+	/// Histogram of the sizes of committed era files in bytes.
+	pub const ERA_SIZE: &str = "journal.era_size";
+	/// Gauge of the number of eras in the journal.
+	pub const JOURNAL_ERAS: &str = "journal.eras";
+	/// Gauge of the total size of the era files in bytes.
+	pub const JOURNAL_SIZE: &str = "journal.size";
+	/// Histogram of the sizes of flush files written for the data files in bytes.
+	pub const FLUSH_WRITTEN_BYTES: &str = "flush.written_bytes";
+	/// Histogram of the distances in bytes the records following written operations are shifted by.
+	pub const FLUSH_SHIFT: &str = "flush.shift";
+	/// Histogram of the time of flushing a single era in microseconds.
+	pub const FLUSH_DURATION: &str = "flush.duration_us";
+	/// Counter of flushed eras.
+	pub const FLUSHED_ERAS: &str = "flush.eras";
+	/// Histogram of the compaction time in microseconds.
+	pub const COMPACTION_DURATION: &str = "compaction.duration_us";
+	/// Counter of prefixes moved to collision files.
+	pub const COMPACTION_MOVED_PREFIXES: &str = "compaction.moved_prefixes";
+	/// Counter of prefixes moved back from collision files to the data file.
+	pub const COMPACTION_RESTORED_PREFIXES: &str = "compaction.restored_prefixes";
+	/// Counter of bytes written to collision files.
+	pub const COMPACTION_WRITTEN_BYTES: &str = "compaction.written_bytes";
+	/// Counter of expired values removed by compactions.
+	pub const COMPACTION_EXPIRED: &str = "compaction.expired";
+}
+
+/// Receiver of the metrics reported by the database.
+///
+/// Implementations should be cheap, metrics are reported on every read and commit.
+pub trait Metrics: fmt::Debug + Send + Sync {
+	/// Adds `value` to the counter.
+	fn increment(&self, name: &'static str, value: u64);
+	/// Sets the gauge to `value`.
+	fn gauge(&self, name: &'static str, value: u64);
+	/// Records `value` in the histogram.
+	fn observe(&self, name: &'static str, value: u64);
+}
+
+/// Returns the duration in microseconds.
+pub(crate) fn micros(duration: Duration) -> u64 {
+	duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1_000
+}
+
+/// Metrics which are not recorded anywhere.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+	fn increment(&self, _name: &'static str, _value: u64) {}
+
+	fn gauge(&self, _name: &'static str, _value: u64) {}
+
+	fn observe(&self, _name: &'static str, _value: u64) {}
+}
+
+/// Summary of the values recorded in a histogram.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Histogram {
+	/// Number of recorded values.
+	pub count: u64,
+	/// Sum of recorded values.
+	pub sum: u64,
+	/// Smallest recorded value.
+	pub min: u64,
+	/// Largest recorded value.
+	pub max: u64,
+}
+
+impl Histogram {
+	fn record(&mut self, value: u64) {
+		if self.count == 0 || value < self.min {
+			self.min = value;
+		}
+		if value > self.max {
+			self.max = value;
+		}
+		self.count += 1;
+		self.sum = self.sum.saturating_add(value);
+	}
+}
+
+/// Metrics recorded by `InMemoryMetrics`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+	/// Counters by name.
+	pub counters: BTreeMap<&'static str, u64>,
+	/// Gauges by name.
+	pub gauges: BTreeMap<&'static str, u64>,
+	/// Histograms by name.
+	pub histograms: BTreeMap<&'static str, Histogram>,
+}
+
+impl MetricsSnapshot {
+	/// Returns the value of the counter, 0 if it was never incremented.
+	pub fn counter(&self, name: &str) -> u64 {
+		self.counters.get(name).cloned().unwrap_or(0)
+	}
+
+	/// Returns the histogram, `None` if no value was recorded in it.
+	pub fn histogram(&self, name: &str) -> Option<&Histogram> {
+		self.histograms.get(name)
+	}
+}
+
+/// Metrics kept in memory, which can be read with `snapshot`.
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+	snapshot: Mutex<MetricsSnapshot>,
+}
+
+impl InMemoryMetrics {
+	/// Returns a copy of the metrics recorded so far.
+	pub fn snapshot(&self) -> MetricsSnapshot {
+		self.snapshot.lock().clone()
+	}
+
+	/// Clears the recorded metrics.
+	pub fn reset(&self) {
+		*self.snapshot.lock() = MetricsSnapshot::default();
+	}
+}
+
+impl Metrics for InMemoryMetrics {
+	fn increment(&self, name: &'static str, value: u64) {
+		*self.snapshot.lock().counters.entry(name).or_insert(0) += value;
+	}
+
+	fn gauge(&self, name: &'static str, value: u64) {
+		self.snapshot.lock().gauges.insert(name, value);
+	}
+
+	fn observe(&self, name: &'static str, value: u64) {
+		self.snapshot.lock().histograms.entry(name).or_insert_with(Histogram::default).record(value);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	use super::{micros, Histogram, InMemoryMetrics, Metrics};
+
+	#[test]
+	fn test_in_memory_metrics() {
+		let metrics = InMemoryMetrics::default();
+		metrics.increment("counter", 2);
+		metrics.increment("counter", 3);
+		metrics.gauge("gauge", 7);
+		metrics.gauge("gauge", 5);
+		metrics.observe("histogram", 4);
+		metrics.observe("histogram", 1);
+		metrics.observe("histogram", 9);
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.counter("counter"), 5);
+		assert_eq!(snapshot.counter("missing"), 0);
+		assert_eq!(snapshot.gauges["gauge"], 5);
+		assert_eq!(snapshot.histogram("histogram"), Some(&Histogram { count: 3, sum: 14, min: 1, max: 9 }));
+
+		metrics.reset();
+		assert!(metrics.snapshot().counters.is_empty());
+	}
+
+	#[test]
+	fn test_micros() {
+		assert_eq!(micros(Duration::new(2, 3_999)), 2_000_003);
+	}
+}
@@ -0,0 +1,321 @@
+//! Upgrades of the on-disk format of databases written by older versions.
+//!
+//! The format version of a database is stored in its `VERSION` file. Databases created
+//! before the file was introduced have no such file and are of version 0.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use database::Database;
+use durability::Durability;
+use error::{Corruption, ErrorKind, Result};
+use event::{Event, EventListener, FormatMigrated, MigrationProgress};
+use vfs::Vfs;
+
+/// Version of the on-disk format written by this version of the database.
+pub const FORMAT_VERSION: u16 = 0;
+
+const VERSION_FILE: &'static str = "VERSION";
+const VERSION_SIZE: usize = 2;
+
+/// Step upgrading the format of a database from version `from` to `from + 1`.
+///
+/// The version is bumped only after the step completes, so a step interrupted by a crash
+/// is run again on the next open. Steps must therefore be idempotent, e.g. by writing
+/// new files next to the old ones and replacing them with a rename.
+pub(crate) struct Migration {
+	/// Version of the format the step upgrades.
+	pub from: u16,
+	/// Name of the step reported in the events.
+	pub name: &'static str,
+	/// Upgrades the files of the database.
+	pub run: fn(&MigrationContext) -> Result<()>,
+}
+
+/// Steps upgrading databases to `FORMAT_VERSION`, ordered by the version they upgrade.
+pub(crate) const MIGRATIONS: &'static [Migration] = &[];
+
+/// Database being upgraded by a migration step.
+// no steps are registered while the format is at its first version
+#[allow(dead_code)]
+pub(crate) struct MigrationContext<'a> {
+	path: &'a Path,
+	vfs: &'a Vfs,
+	durability: Durability,
+	listener: &'a EventListener,
+	name: &'static str,
+}
+
+#[allow(dead_code)]
+impl<'a> MigrationContext<'a> {
+	/// Returns the directory of the database.
+	pub fn path(&self) -> &Path {
+		self.path
+	}
+
+	/// Returns the durability the upgraded files should be written with.
+	pub fn durability(&self) -> Durability {
+		self.durability
+	}
+
+	/// Returns the directories of the keyspaces of the database, including the keyspace of the
+	/// database itself and the keyspaces of secondary indexes.
+	pub fn keyspace_dirs(&self) -> Result<Vec<PathBuf>> {
+		let mut dirs = vec![self.path.to_owned()];
+		for subdir in &[Database::KEYSPACES_DIR, Database::INDEXES_DIR] {
+			let subdir = self.path.join(subdir);
+			if !self.vfs.is_dir(&subdir) {
+				continue;
+			}
+
+			for path in self.vfs.read_dir(&subdir)? {
+				// partially built indexes are rebuilt from scratch
+				if self.vfs.is_dir(&path) && path.extension().map_or(true, |extension| extension != "tmp") {
+					dirs.push(path);
+				}
+			}
+		}
+
+		dirs.sort();
+		Ok(dirs)
+	}
+
+	/// Reports that `done` out of `total` units of work of the step are done.
+	pub fn progress(&self, done: u64, total: u64) {
+		self.listener.on_event(&Event::MigrationProgress(MigrationProgress {
+			path: self.path.to_owned(),
+			name: self.name,
+			done,
+			total,
+		}));
+	}
+}
+
+/// Reads the format version of the database in the directory.
+pub(crate) fn read_version(vfs: &Vfs, dir: &Path) -> Result<u16> {
+	let path = dir.join(VERSION_FILE);
+	let data = match vfs.read(&path) {
+		Ok(data) => data,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+		Err(err) => return Err(err.into()),
+	};
+
+	if data.len() != VERSION_SIZE {
+		return Err(Corruption::new(0, format!("Expected {} bytes of version, got {}", VERSION_SIZE, data.len())).in_file(path));
+	}
+
+	Ok(LittleEndian::read_u16(&data))
+}
+
+/// Atomically replaces the format version of the database in the directory.
+pub(crate) fn write_version(vfs: &Vfs, dir: &Path, version: u16, durability: Durability) -> Result<()> {
+	let path = dir.join(VERSION_FILE);
+	let tmp_path = dir.join(format!("{}.tmp", VERSION_FILE));
+
+	let mut data = [0; VERSION_SIZE];
+	LittleEndian::write_u16(&mut data, version);
+	vfs.write(&tmp_path, &data, false, durability)?;
+	vfs.rename(&tmp_path, &path)?;
+	vfs.sync_dir(dir, durability)?;
+	Ok(())
+}
+
+/// Upgrades the database in the directory to the `target` version by running the `migrations`
+/// in order. Fails if the database was written in a format newer than `target`.
+pub(crate) fn migrate(
+	vfs: &Vfs,
+	dir: &Path,
+	migrations: &[Migration],
+	target: u16,
+	durability: Durability,
+	listener: &EventListener,
+) -> Result<()> {
+	let mut version = read_version(vfs, dir)?;
+	if version > target {
+		bail!(ErrorKind::UnsupportedFormatVersion(version, target));
+	}
+
+	while version < target {
+		let migration = match migrations.iter().find(|migration| migration.from == version) {
+			Some(migration) => migration,
+			None => bail!(ErrorKind::MigrationMissing(version)),
+		};
+
+		let context = MigrationContext {
+			path: dir,
+			vfs,
+			durability,
+			listener,
+			name: migration.name,
+		};
+		(migration.run)(&context)?;
+
+		write_version(vfs, dir, version + 1, durability)?;
+		listener.on_event(&Event::FormatMigrated(FormatMigrated {
+			path: dir.to_owned(),
+			name: migration.name,
+			from: version,
+			to: version + 1,
+		}));
+		version += 1;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::{fs, io, mem};
+	use std::io::Write;
+	use std::path::Path;
+
+	use parking_lot::Mutex;
+
+	use super::{migrate, read_version, write_version, Migration, MigrationContext, FORMAT_VERSION, MIGRATIONS};
+	use durability::Durability;
+	use error::{ErrorKind, Result};
+	use event::{Event, EventListener, FormatMigrated, MigrationProgress, NoopListener};
+	use vfs::Vfs;
+
+	#[derive(Debug, Default)]
+	struct RecordingListener(Mutex<Vec<Event>>);
+
+	impl EventListener for RecordingListener {
+		fn on_event(&self, event: &Event) {
+			self.0.lock().push(event.clone());
+		}
+	}
+
+	/// Appends the name of the step to the `steps` file of the database.
+	fn record_step(context: &MigrationContext, name: &str) -> Result<()> {
+		let mut file = fs::OpenOptions::new().append(true).create(true).open(context.path().join("steps"))?;
+		file.write_all(name.as_bytes())?;
+		Ok(())
+	}
+
+	fn first(context: &MigrationContext) -> Result<()> {
+		context.progress(1, 2);
+		record_step(context, "first;")?;
+		context.progress(2, 2);
+		Ok(())
+	}
+
+	fn second(context: &MigrationContext) -> Result<()> {
+		record_step(context, "second;")
+	}
+
+	fn crash(context: &MigrationContext) -> Result<()> {
+		record_step(context, "crash;")?;
+		Err(io::Error::new(io::ErrorKind::Other, "crash").into())
+	}
+
+	fn steps(dir: &Path) -> String {
+		fs::read_to_string(dir.join("steps")).unwrap_or_default()
+	}
+
+	const TEST_MIGRATIONS: &'static [Migration] = &[
+		Migration { from: 0, name: "first", run: first },
+		Migration { from: 1, name: "second", run: second },
+	];
+
+	#[test]
+	fn test_registry_upgrades_to_format_version() {
+		for (version, migration) in MIGRATIONS.iter().enumerate() {
+			assert_eq!(migration.from as usize, version);
+		}
+		assert_eq!(MIGRATIONS.len(), FORMAT_VERSION as usize);
+	}
+
+	#[test]
+	fn test_version_roundtrip() {
+		let temp = tempdir::TempDir::new("test_version_roundtrip").unwrap();
+		assert_eq!(read_version(&Vfs::Disk, temp.path()).unwrap(), 0);
+
+		write_version(&Vfs::Disk, temp.path(), 3, Durability::Full).unwrap();
+		assert_eq!(read_version(&Vfs::Disk, temp.path()).unwrap(), 3);
+		assert!(!temp.path().join("VERSION.tmp").exists());
+
+		fs::write(temp.path().join("VERSION"), [1, 2, 3]).unwrap();
+		assert!(matches!(*read_version(&Vfs::Disk, temp.path()).unwrap_err().kind(), ErrorKind::Corrupted(_, 0, _)));
+	}
+
+	#[test]
+	fn should_run_migrations_in_order() {
+		let temp = tempdir::TempDir::new("should_run_migrations_in_order").unwrap();
+		let listener = RecordingListener::default();
+
+		migrate(&Vfs::Disk, temp.path(), TEST_MIGRATIONS, 2, Durability::Full, &listener).unwrap();
+		assert_eq!(steps(temp.path()), "first;second;");
+		assert_eq!(read_version(&Vfs::Disk, temp.path()).unwrap(), 2);
+
+		let path = temp.path().to_owned();
+		assert_eq!(mem::replace(&mut *listener.0.lock(), Vec::new()), vec![
+			Event::MigrationProgress(MigrationProgress { path: path.clone(), name: "first", done: 1, total: 2 }),
+			Event::MigrationProgress(MigrationProgress { path: path.clone(), name: "first", done: 2, total: 2 }),
+			Event::FormatMigrated(FormatMigrated { path: path.clone(), name: "first", from: 0, to: 1 }),
+			Event::FormatMigrated(FormatMigrated { path: path.clone(), name: "second", from: 1, to: 2 }),
+		]);
+
+		// upgraded databases are not migrated again
+		migrate(&Vfs::Disk, temp.path(), TEST_MIGRATIONS, 2, Durability::Full, &listener).unwrap();
+		assert_eq!(steps(temp.path()), "first;second;");
+		assert!(listener.0.lock().is_empty());
+	}
+
+	#[test]
+	fn should_run_interrupted_migration_again() {
+		let temp = tempdir::TempDir::new("should_run_interrupted_migration_again").unwrap();
+		let crashing = [
+			Migration { from: 0, name: "first", run: first },
+			Migration { from: 1, name: "second", run: crash },
+		];
+
+		assert!(migrate(&Vfs::Disk, temp.path(), &crashing, 2, Durability::Full, &NoopListener).is_err());
+		assert_eq!(read_version(&Vfs::Disk, temp.path()).unwrap(), 1);
+
+		migrate(&Vfs::Disk, temp.path(), TEST_MIGRATIONS, 2, Durability::Full, &NoopListener).unwrap();
+		assert_eq!(steps(temp.path()), "first;crash;second;");
+		assert_eq!(read_version(&Vfs::Disk, temp.path()).unwrap(), 2);
+	}
+
+	#[test]
+	fn should_refuse_newer_format() {
+		let temp = tempdir::TempDir::new("should_refuse_newer_format").unwrap();
+		write_version(&Vfs::Disk, temp.path(), 3, Durability::Full).unwrap();
+
+		assert_eq!(
+			*migrate(&Vfs::Disk, temp.path(), TEST_MIGRATIONS, 2, Durability::Full, &NoopListener).unwrap_err().kind(),
+			ErrorKind::UnsupportedFormatVersion(3, 2)
+		);
+		assert_eq!(
+			*migrate(&Vfs::Disk, temp.path(), TEST_MIGRATIONS, 4, Durability::Full, &NoopListener).unwrap_err().kind(),
+			ErrorKind::MigrationMissing(3)
+		);
+		assert_eq!(steps(temp.path()), "");
+	}
+
+	#[test]
+	fn test_keyspace_dirs() {
+		let temp = tempdir::TempDir::new("test_keyspace_dirs").unwrap();
+		for dir in &["keyspaces/a", "indexes/b", "indexes/c.tmp"] {
+			fs::create_dir_all(temp.path().join(dir)).unwrap();
+		}
+
+		let context = MigrationContext {
+			path: temp.path(),
+			vfs: &Vfs::Disk,
+			durability: Durability::Full,
+			listener: &NoopListener,
+			name: "test",
+		};
+		assert_eq!(context.keyspace_dirs().unwrap(), vec![
+			temp.path().to_owned(),
+			temp.path().join("indexes/b"),
+			temp.path().join("keyspaces/a"),
+		]);
+	}
+}
@@ -1,6 +1,19 @@
+use std::cmp;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use durability::Durability;
 use error::{ErrorKind, Result};
+use event::{EventListener, NoopListener};
+use expiry::{Clock, SystemClock, EXPIRY_SIZE};
 use field;
+use index::Index;
+use merge::MergeOperator;
+use metrics::{Metrics, NoopMetrics};
 use record;
+use storage::Storage;
+use vfs::Vfs;
 
 /// A length of values stored in the DB.
 #[derive(Debug, PartialEq)]
@@ -40,8 +53,68 @@ impl ValuesLen {
 	}
 }
 
+/// Largest value length which can be stored in the journal and the data file.
+pub(crate) const MAX_VALUE_LEN: usize = u32::max_value() as usize;
+
+/// Lengths of values accepted by the database.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum ValueLenLimit {
+	/// Values must have exactly this length.
+	Exact(usize),
+	/// Values must not be longer than this.
+	Max(usize),
+}
+
+impl ValueLenLimit {
+	pub(crate) fn check(&self, len: usize) -> Result<()> {
+		match *self {
+			ValueLenLimit::Exact(expected) if len != expected =>
+				Err(ErrorKind::InvalidValueLen(expected.to_string(), len).into()),
+			ValueLenLimit::Max(max) if len > max =>
+				Err(ErrorKind::InvalidValueLen(format!("at most {}", max), len).into()),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Policy deciding when `Database::flush_journal` compacts the database.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompactionPolicy {
+	/// The database is compacted only when `Database::compact` is called.
+	Manual,
+	/// The database is compacted after every `n` flushed journal eras.
+	EveryFlushes(usize),
+	/// The database is compacted when the probe chain of a prefix touched by the flush
+	/// exceeds given number of fields.
+	ProbeLength(usize),
+}
+
+/// Limits the amount of work done by a single automatic compaction.
+///
+/// Prefixes are moved to collision files one by one until the budget is exhausted.
+/// The remaining prefixes are moved by subsequent compactions.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompactionBudget {
+	/// All collided prefixes are moved at once.
+	Unlimited,
+	/// No more prefixes are moved once the compaction takes longer than given duration.
+	Time(Duration),
+	/// No more prefixes are moved once given number of bytes was written to collision files.
+	Bytes(u64),
+}
+
+impl CompactionBudget {
+	pub(crate) fn is_exhausted(&self, elapsed: Duration, written_bytes: u64) -> bool {
+		match *self {
+			CompactionBudget::Unlimited => false,
+			CompactionBudget::Time(max) => elapsed >= max,
+			CompactionBudget::Bytes(max) => written_bytes >= max,
+		}
+	}
+}
+
 /// Database options.
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct Options {
 	/// Number of eras to keep in the journal.
 	pub journal_eras: usize,
@@ -54,8 +127,70 @@ pub struct Options {
 	pub key_len: usize,
 	/// Value length in bytes.
 	pub value_len: ValuesLen,
+	/// Maximum length of values in bytes when `value_len` is `ValuesLen::Variable`.
+	pub max_value_len: usize,
 	/// Maximum number of collisions per prefix before moving data to its own file.
 	pub max_prefix_collisions: usize,
+	/// When the database should be compacted while flushing the journal.
+	pub compaction: CompactionPolicy,
+	/// Limit of work done by compactions triggered by `compaction` policy.
+	pub compaction_budget: CompactionBudget,
+	/// Which writes are synced to the disk.
+	pub durability: Durability,
+	/// How the data and metadata files are read and written.
+	pub storage: Storage,
+	/// Maximum size in bytes of the pages cached for each data and metadata file read with
+	/// `Storage::Pread`. Pages read since the last write to a file are cached regardless,
+	/// because the values read from the file borrow them.
+	pub page_cache_size: usize,
+	/// Operator resolving operands added with `Transaction::merge`.
+	pub merge_operator: Option<Arc<MergeOperator>>,
+	/// Secondary indexes of the values. Only supported by the database itself, not by named keyspaces.
+	pub indexes: Vec<Index>,
+	/// Stores an expiry time with each value, so values can be inserted with `Transaction::insert_with_ttl`.
+	/// Only supported with `ValuesLen::Variable`, and neither with merge operators nor secondary indexes.
+	pub ttl: bool,
+	/// Clock deciding which values are expired.
+	pub clock: Arc<Clock>,
+	/// Number of changes buffered for each subscription created with `Database::subscribe`.
+	pub subscription_buffer: usize,
+	/// Directory where flushed journal eras are moved instead of being deleted, so they can be read
+	/// with `Database::changes_since`. Has to be on the same filesystem as the database.
+	pub journal_archive: Option<PathBuf>,
+	/// Receiver of the metrics of reads, commits, flushes and compactions.
+	pub metrics: Arc<Metrics>,
+	/// Receiver of the events of opening, recovering and compacting the database.
+	pub event_listener: Arc<EventListener>,
+}
+
+/// Merge operators are equal only if they are the same object.
+/// The clock, metrics and event listener are not compared.
+impl PartialEq for Options {
+	fn eq(&self, other: &Self) -> bool {
+		let merge_operators_eq = match (&self.merge_operator, &other.merge_operator) {
+			(&Some(ref merge_operator), &Some(ref other)) => Arc::ptr_eq(merge_operator, other),
+			(&None, &None) => true,
+			_ => false,
+		};
+
+		self.journal_eras == other.journal_eras &&
+			self.extend_threshold_percent == other.extend_threshold_percent &&
+			self.key_index_bits == other.key_index_bits &&
+			self.key_len == other.key_len &&
+			self.value_len == other.value_len &&
+			self.max_value_len == other.max_value_len &&
+			self.max_prefix_collisions == other.max_prefix_collisions &&
+			self.compaction == other.compaction &&
+			self.compaction_budget == other.compaction_budget &&
+			self.durability == other.durability &&
+			self.storage == other.storage &&
+			self.page_cache_size == other.page_cache_size &&
+			merge_operators_eq &&
+			self.indexes == other.indexes &&
+			self.ttl == other.ttl &&
+			self.subscription_buffer == other.subscription_buffer &&
+			self.journal_archive == other.journal_archive
+	}
 }
 
 impl Default for Options {
@@ -66,18 +201,34 @@ impl Default for Options {
 			key_index_bits: 8,
 			key_len: 32,
 			value_len: ValuesLen::Constant(64),
+			max_value_len: MAX_VALUE_LEN,
 			max_prefix_collisions: 6,
+			compaction: CompactionPolicy::Manual,
+			compaction_budget: CompactionBudget::Unlimited,
+			durability: Durability::Full,
+			storage: Storage::Mmap,
+			page_cache_size: 64 * 1024 * 1024,
+			merge_operator: None,
+			indexes: Vec::new(),
+			ttl: false,
+			clock: Arc::new(SystemClock),
+			subscription_buffer: 1024,
+			journal_archive: None,
+			metrics: Arc::new(NoopMetrics),
+			event_listener: Arc::new(NoopListener),
 		}
 	}
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct InternalOptions {
 	pub external: Options,
 	pub value_size: record::ValueSize,
 	pub field_body_size: usize,
 	pub initial_db_size: u64,
 	pub record_offset: usize,
+	/// File system the files are stored in.
+	pub vfs: Vfs,
 }
 
 impl InternalOptions {
@@ -116,6 +267,94 @@ impl InternalOptions {
 			));
 		}
 
+		if external.max_value_len > MAX_VALUE_LEN {
+			bail!(ErrorKind::InvalidOptions(
+				"max_value_len",
+				format!("{} is too large. Only values up to {} bytes are supported.", external.max_value_len, MAX_VALUE_LEN)
+			));
+		}
+
+		if let ValuesLen::Constant(len) = external.value_len {
+			if len > MAX_VALUE_LEN {
+				bail!(ErrorKind::InvalidOptions(
+					"value_len",
+					format!("{} is too large. Only values up to {} bytes are supported.", len, MAX_VALUE_LEN)
+				));
+			}
+		}
+
+		match external.compaction {
+			CompactionPolicy::EveryFlushes(0) => bail!(ErrorKind::InvalidOptions(
+				"compaction",
+				"number of flushes must be greater than 0.".into()
+			)),
+			CompactionPolicy::ProbeLength(0) => bail!(ErrorKind::InvalidOptions(
+				"compaction",
+				"probe length must be greater than 0.".into()
+			)),
+			_ => {},
+		}
+
+		if external.subscription_buffer == 0 {
+			bail!(ErrorKind::InvalidOptions(
+				"subscription_buffer",
+				"must be greater than 0.".into()
+			));
+		}
+
+		if external.ttl {
+			if external.value_len.is_const() {
+				bail!(ErrorKind::InvalidOptions(
+					"ttl",
+					"values with expiry are only supported with ValuesLen::Variable.".into()
+				));
+			}
+
+			if external.merge_operator.is_some() {
+				bail!(ErrorKind::InvalidOptions(
+					"ttl",
+					"merge operators are not supported for values with expiry.".into()
+				));
+			}
+
+			if !external.indexes.is_empty() {
+				bail!(ErrorKind::InvalidOptions(
+					"ttl",
+					"secondary indexes are not supported for values with expiry.".into()
+				));
+			}
+		}
+
+		for (i, index) in external.indexes.iter().enumerate() {
+			if !is_valid_name(&index.name) {
+				bail!(ErrorKind::InvalidOptions(
+					"indexes",
+					format!("invalid index name: {:?}", index.name)
+				));
+			}
+
+			if external.indexes[..i].iter().any(|other| other.name == index.name) {
+				bail!(ErrorKind::InvalidOptions(
+					"indexes",
+					format!("index {} is declared more than once", index.name)
+				));
+			}
+
+			if index.key_len == 0 {
+				bail!(ErrorKind::InvalidOptions(
+					"indexes",
+					format!("key length of index {} must be greater than 0.", index.name)
+				));
+			}
+
+			if index.key_index_bits == 0 || index.key_index_bits as usize > index.key_len * 8 {
+				bail!(ErrorKind::InvalidOptions(
+					"indexes",
+					format!("key_index_bits of index {} must be between 1 and {}", index.name, index.key_len * 8)
+				));
+			}
+		}
+
 		let value_size = external.value_len.to_value_size();
 		let field_body_size = external.key_len + external.value_len.size();
 		let record_offset = field::field_size(field_body_size as usize);
@@ -128,17 +367,160 @@ impl InternalOptions {
 			field_body_size,
 			initial_db_size,
 			record_offset,
+			vfs: Vfs::Disk,
 		})
 	}
+
+	/// Returns the options with the files stored in `vfs`.
+	pub fn with_vfs(mut self, vfs: Vfs) -> Self {
+		self.vfs = vfs;
+		self
+	}
+
+	/// Returns lengths of values accepted by the database.
+	pub fn value_len_limit(&self) -> ValueLenLimit {
+		match self.external.value_len {
+			ValuesLen::Constant(len) => ValueLenLimit::Exact(len),
+			// the expiry time is stored before the value
+			ValuesLen::Variable { .. } if self.external.ttl =>
+				ValueLenLimit::Max(cmp::min(self.external.max_value_len, MAX_VALUE_LEN - EXPIRY_SIZE)),
+			ValuesLen::Variable { .. } => ValueLenLimit::Max(self.external.max_value_len),
+		}
+	}
+}
+
+/// Returns true if the name of a keyspace or an index contains only ASCII letters, digits, '-' and '_'.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+	!name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
 }
 
 #[cfg(test)]
 mod tests {
-	use super::ValuesLen;
+	use std::sync::Arc;
+	use std::time::Duration;
+	use error::ErrorKind;
+	use index::{Index, ValueBytes};
+	use merge::{MergeOperator, U64Add};
+	use super::{CompactionBudget, CompactionPolicy, InternalOptions, Options, ValueLenLimit, ValuesLen, MAX_VALUE_LEN};
 
 	#[test]
 	fn test_values_len_const() {
 		assert_eq!(true, ValuesLen::Constant(1).is_const());
 		assert_eq!(false, ValuesLen::Variable { expected: 5 }.is_const());
 	}
+
+	#[test]
+	fn test_options_eq() {
+		assert_eq!(Options::default(), Options::default());
+		assert!(Options { key_len: 3, ..Default::default() } != Options::default());
+
+		let merge_operator: Arc<MergeOperator> = Arc::new(U64Add);
+		let with_merge_operator = || Options { merge_operator: Some(merge_operator.clone()), ..Default::default() };
+		assert_eq!(with_merge_operator(), with_merge_operator());
+		assert!(with_merge_operator() != Options { merge_operator: Some(Arc::new(U64Add)), ..Default::default() });
+	}
+
+	#[test]
+	fn test_compaction_budget_is_exhausted() {
+		assert!(!CompactionBudget::Unlimited.is_exhausted(Duration::from_secs(100), 100));
+		assert!(!CompactionBudget::Time(Duration::from_secs(1)).is_exhausted(Duration::from_millis(999), 100));
+		assert!(CompactionBudget::Time(Duration::from_secs(1)).is_exhausted(Duration::from_secs(1), 0));
+		assert!(!CompactionBudget::Bytes(10).is_exhausted(Duration::from_secs(100), 9));
+		assert!(CompactionBudget::Bytes(10).is_exhausted(Duration::from_secs(0), 10));
+	}
+
+	#[test]
+	fn should_validate_compaction_policy() {
+		let err = InternalOptions::from_external(Options {
+			compaction: CompactionPolicy::EveryFlushes(0),
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("compaction", "number of flushes must be greater than 0.".into()));
+
+		let err = InternalOptions::from_external(Options {
+			compaction: CompactionPolicy::ProbeLength(0),
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("compaction", "probe length must be greater than 0.".into()));
+	}
+
+	#[test]
+	fn should_validate_max_value_len() {
+		let err = InternalOptions::from_external(Options {
+			value_len: ValuesLen::Variable { expected: 5 },
+			max_value_len: MAX_VALUE_LEN + 1,
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions(
+			"max_value_len",
+			format!("{} is too large. Only values up to {} bytes are supported.", MAX_VALUE_LEN + 1, MAX_VALUE_LEN)
+		));
+	}
+
+	#[test]
+	fn test_value_len_limit() {
+		let options = InternalOptions::from_external(Options {
+			value_len: ValuesLen::Constant(5),
+			max_value_len: 3,
+			..Default::default()
+		}).unwrap();
+		assert_eq!(options.value_len_limit(), ValueLenLimit::Exact(5));
+
+		let options = InternalOptions::from_external(Options {
+			value_len: ValuesLen::Variable { expected: 5 },
+			max_value_len: 3,
+			..Default::default()
+		}).unwrap();
+		assert_eq!(options.value_len_limit(), ValueLenLimit::Max(3));
+		assert!(options.value_len_limit().check(3).is_ok());
+		assert_eq!(*options.value_len_limit().check(4).unwrap_err().kind(), ErrorKind::InvalidValueLen("at most 3".into(), 4));
+	}
+
+	#[test]
+	fn should_validate_ttl() {
+		let err = InternalOptions::from_external(Options {
+			ttl: true,
+			value_len: ValuesLen::Constant(5),
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("ttl", "values with expiry are only supported with ValuesLen::Variable.".into()));
+
+		let err = InternalOptions::from_external(Options {
+			ttl: true,
+			value_len: ValuesLen::Variable { expected: 5 },
+			merge_operator: Some(Arc::new(U64Add)),
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("ttl", "merge operators are not supported for values with expiry.".into()));
+
+		let options = InternalOptions::from_external(Options {
+			ttl: true,
+			value_len: ValuesLen::Variable { expected: 5 },
+			..Default::default()
+		}).unwrap();
+		assert_eq!(options.value_len_limit(), ValueLenLimit::Max(MAX_VALUE_LEN - 8));
+	}
+
+	#[test]
+	fn should_validate_indexes() {
+		let index = Index::new("by_owner", 2, Arc::new(ValueBytes { offset: 0, len: 2 }));
+
+		let err = InternalOptions::from_external(Options {
+			indexes: vec![index.clone(), index.clone()],
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("indexes", "index by_owner is declared more than once".into()));
+
+		let err = InternalOptions::from_external(Options {
+			indexes: vec![Index { name: "by/owner".into(), ..index.clone() }],
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("indexes", "invalid index name: \"by/owner\"".into()));
+
+		let err = InternalOptions::from_external(Options {
+			indexes: vec![Index { key_index_bits: 17, ..index }],
+			..Default::default()
+		}).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidOptions("indexes", "key_index_bits of index by_owner must be between 1 and 16".into()));
+	}
 }
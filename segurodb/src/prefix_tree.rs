@@ -24,6 +24,13 @@ impl PrefixTree {
 		((1 << prefix_bits) + 7) >> 3
 	}
 
+	#[inline]
+	/// position of the first leaf in the slice returned by `leaves`,
+	/// non-zero when the leaves share their byte with inner nodes
+	pub fn leaves_offset(prefix_bits: u8) -> usize {
+		Self::leaf_index(0, prefix_bits) % 8
+	}
+
 	/// Creates empty `PrefixTree` for given `prefix_bits`.
 	pub fn new(prefix_bits: u8) -> Self {
 		let size = 2 << prefix_bits;
@@ -40,11 +47,13 @@ impl PrefixTree {
 	pub fn from_leaves(data: &[u8], prefix_bits: u8) -> Self {
 		assert_eq!(data.len(), Self::leaf_data_len(prefix_bits));
 		let mut tree = Self::new(prefix_bits);
+		let offset = Self::leaves_offset(prefix_bits);
 		for (idx, byte) in data.iter().enumerate() {
 			let mut current = 1;
 			for i in 0..8 {
-				if byte & current == current {
-					tree.insert((idx * 8 + i) as u32);
+				// bits before the offset are inner nodes, which are rebuilt by `insert`
+				if byte & current == current && idx * 8 + i >= offset {
+					tree.insert((idx * 8 + i - offset) as u32);
 				}
 				current <<= 1;
 			}
@@ -241,6 +250,20 @@ mod tests {
 		assert_eq!(tree.bytes(), bytes);
 	}
 
+	#[test]
+	fn test_writing_and_reading_small_prefix_trees() {
+		for prefix_bits in 0..3 {
+			let prefixes = 1 << prefix_bits;
+			let mut tree = PrefixTree::new(prefix_bits);
+			tree.insert(prefixes - 1);
+
+			let tree = PrefixTree::from_leaves(tree.leaves(), prefix_bits);
+			for i in 0..prefixes {
+				assert_eq!(tree.has(i), Some(i == prefixes - 1));
+			}
+		}
+	}
+
 	#[test]
 	fn test_prefixes_iterator() {
 		let prefix_bits = 4;
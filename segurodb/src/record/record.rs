@@ -1,5 +1,6 @@
 use byteorder::{LittleEndian, ByteOrder};
 
+use field::field_size;
 use field::view::FieldsView;
 
 /// Optional size of header for variable-len records.
@@ -24,9 +25,16 @@ pub struct Record<'a> {
 
 impl<'a> Record<'a> {
 	/// Creates new record given the data slice, field body and value and key size.
-	pub fn new(data: &'a [u8], field_body_size: usize, value_size: ValueSize, key_size: usize) -> Self {
+	/// Returns `None` if the record doesn't fit in the data.
+	pub fn new(data: &'a [u8], field_body_size: usize, value_size: ValueSize, key_size: usize) -> Option<Self> {
 		assert!(key_size <= field_body_size);
 
+		// bytes of all the field bodies in the data
+		let body_len = data.len() / field_size(field_body_size) * field_body_size;
+		if key_size > body_len {
+			return None;
+		}
+
 		let view = FieldsView::new(data, field_body_size);
 		let (key, rest) = view.split_at(key_size);
 		let key = key.raw_slice().expect("only returns None when addressed value isn't stored in a single field; \
@@ -34,16 +42,28 @@ impl<'a> Record<'a> {
 
 		match value_size {
 			ValueSize::Constant(value_size) => {
+				if key_size + value_size > body_len {
+					return None;
+				}
+
 				let (value, _) = rest.split_at(value_size);
 
-				Record { key, value, len: value_size }
+				Some(Record { key, value, len: value_size })
 			},
 			ValueSize::Variable => {
+				if key_size + HEADER_SIZE > body_len {
+					return None;
+				}
+
 				let (header, rest) = rest.split_at(HEADER_SIZE);
 				let value_len = Self::read_value_len(header) as usize;
+				if key_size + HEADER_SIZE + value_len > body_len {
+					return None;
+				}
+
 				let (value, _) = rest.split_at(value_len);
 
-				Record { key, value, len: value_len }
+				Some(Record { key, value, len: value_len })
 			}
 		}
 	}
@@ -85,6 +105,17 @@ impl<'a> Record<'a> {
 	pub fn value_len(&self) -> usize {
 		self.len
 	}
+
+	/// Splits the first `len` bytes off the value.
+	/// Returns `None` if the value is shorter.
+	pub(crate) fn split_value(self, len: usize) -> Option<(FieldsView<'a>, Record<'a>)> {
+		if self.len < len {
+			return None;
+		}
+
+		let (head, value) = self.value.split_at(len);
+		Some((head, Record { key: self.key, value, len: self.len - len }))
+	}
 }
 
 #[cfg(test)]
@@ -115,7 +146,7 @@ mod tests {
 			1, 0xfd, 0xfe, 0xff, 6, 7, 8, 9, 10,
 		];
 
-		let record = Record::new(&data, body_size, value_size, key_size);
+		let record = Record::new(&data, body_size, value_size, key_size).unwrap();
 		let key = record.key();
 		assert_eq!(key, [0xfa, 0xfb, 0xfc]);
 
@@ -124,7 +155,7 @@ mod tests {
 		record.read_value(&mut value);
 		assert_eq!(value, [1, 2, 3, 4, 5]);
 
-		let record = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size);
+		let record = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size).unwrap();
 		let key = record.key();
 		assert_eq!(key, [0xfd, 0xfe, 0xff]);
 
@@ -145,7 +176,7 @@ mod tests {
 		let mut value1 = [0; 3];
 		let mut value2 = [0; 1];
 
-		let record1 = Record::new(&data, body_size, value_size, key_size);
+		let record1 = Record::new(&data, body_size, value_size, key_size).unwrap();
 		let key1 = record1.key();
 		assert_eq!(key1, [0xfa, 0xfb]);
 
@@ -153,7 +184,7 @@ mod tests {
 		record1.read_value(&mut value1);
 		assert_eq!(value1, [1, 2, 3]);
 
-		let record2 = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size);
+		let record2 = Record::new(&data[body_size + field::HEADER_SIZE..], body_size, value_size, key_size).unwrap();
 		let key2 = record2.key();
 		assert_eq!(key2, [0xfc, 0xfd]);
 
@@ -161,4 +192,42 @@ mod tests {
 		record2.read_value(&mut value2);
 		assert_eq!(value2, [4]);
 	}
+
+	#[test]
+	fn test_split_value() {
+		let body_size = 4;
+		let value_size = ValueSize::Constant(5);
+		let key_size = 2;
+		let data = [
+			1, 0xfa, 0xfb, 1, 2,
+			2, 3, 4, 5, 0,
+		];
+
+		let record = Record::new(&data, body_size, value_size, key_size).unwrap();
+		assert!(record.split_value(6).is_none());
+
+		let record = Record::new(&data, body_size, value_size, key_size).unwrap();
+		let (head, record) = record.split_value(3).unwrap();
+		assert_eq!(head, &[1, 2, 3]);
+		assert_eq!(record.key(), [0xfa, 0xfb]);
+		assert_eq!(record.value_len(), 2);
+		assert!(record.value_is_equal(&[4, 5]));
+	}
+
+	#[test]
+	fn should_not_read_record_out_of_bounds() {
+		let body_size = 4;
+		let key_size = 2;
+		// the value length in the header exceeds the data
+		let data = [
+			1, 0xfa, 0xfb, 9, 0,
+			2, 0, 0, 1, 2,
+		];
+
+		assert!(Record::new(&data, body_size, ValueSize::Variable, key_size).is_none());
+		assert!(Record::new(&data[..5], body_size, ValueSize::Variable, key_size).is_none());
+		assert!(Record::new(&data, body_size, ValueSize::Constant(7), key_size).is_none());
+		assert!(Record::new(&data, body_size, ValueSize::Constant(6), key_size).is_some());
+		assert!(Record::new(&data[..3], body_size, ValueSize::Constant(0), key_size).is_none());
+	}
 }
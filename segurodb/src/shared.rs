@@ -0,0 +1,731 @@
+//! Database shared between threads and flushed in the background.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard};
+
+use database::Database;
+use error::{Error, ErrorKind, Result};
+use journal::PreparedTransaction;
+use subscription::{KeyFilter, Subscription};
+use transaction::Transaction;
+
+/// Decides when the background worker flushes the journal.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FlushPolicy {
+	/// Flush when there are more than `n` eras above `Options::journal_eras`.
+	Eras(usize),
+	/// Flush when the journal era files take more than given number of bytes.
+	JournalBytes(u64),
+	/// Flush in given intervals.
+	Interval(Duration),
+}
+
+impl FlushPolicy {
+	fn should_flush(&self, db: &Database) -> bool {
+		match *self {
+			FlushPolicy::Eras(eras) => db.journal_len() > db.journal_eras() + eras,
+			FlushPolicy::JournalBytes(bytes) => db.journal_size() > bytes,
+			// the write lock isn't taken if there is nothing to flush
+			FlushPolicy::Interval(_) => db.journal_len() > db.journal_eras(),
+		}
+	}
+}
+
+/// Coalesces transactions committed concurrently into a single journal era.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GroupCommit {
+	/// How long the first transaction of a group waits for other transactions,
+	/// even if no other transaction is being committed yet.
+	pub window: Duration,
+	/// The group is committed immediately once it has this many transactions.
+	pub max_transactions: usize,
+}
+
+#[derive(Default)]
+struct CommitQueue {
+	/// Transactions waiting to be committed by the group leader.
+	pending: Vec<(u64, Transaction)>,
+	/// Whether some transaction is already collecting a group.
+	has_leader: bool,
+	next_ticket: u64,
+	/// Results of committed transactions which haven't been picked up yet.
+	results: HashMap<u64, Result<u64>>,
+}
+
+#[derive(Default)]
+struct CommitGroup {
+	queue: Mutex<CommitQueue>,
+	condvar: Condvar,
+}
+
+/// Publishes results of a group to the transactions waiting for them when dropped.
+/// Transactions without a result fail, so they are not left waiting if the group leader panics.
+struct GroupResults<'a> {
+	group: &'a CommitGroup,
+	tickets: Vec<u64>,
+	results: HashMap<u64, Result<u64>>,
+}
+
+impl<'a> Drop for GroupResults<'a> {
+	fn drop(&mut self) {
+		if self.tickets.is_empty() {
+			return;
+		}
+
+		let mut queue = self.group.queue.lock();
+		for ticket in self.tickets.drain(..) {
+			let result = self.results.remove(&ticket)
+				.unwrap_or_else(|| Err(ErrorKind::GroupCommitFailed("group leader panicked".into()).into()));
+			queue.results.insert(ticket, result);
+		}
+		self.group.condvar.notify_all();
+	}
+}
+
+#[derive(Debug, Default)]
+struct WorkerState {
+	committed: bool,
+	shutdown: bool,
+	/// Error of the last background flush, which wasn't returned by `commit` or `flush_journal` yet.
+	flush_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Signal {
+	state: Mutex<WorkerState>,
+	condvar: Condvar,
+}
+
+/// A database which can be shared between threads.
+///
+/// Journal eras above `Options::journal_eras` are flushed by a background thread according
+/// to the `FlushPolicy`, so `commit` never flushes the journal itself. If a background flush
+/// fails, the next `commit` or `flush_journal` call returns the error once, and the worker
+/// retries the flush.
+pub struct SharedDatabase {
+	db: Arc<RwLock<Database>>,
+	signal: Arc<Signal>,
+	policy: FlushPolicy,
+	group_commit: Option<GroupCommit>,
+	group: CommitGroup,
+	worker: Option<thread::JoinHandle<()>>,
+}
+
+impl SharedDatabase {
+	/// Moves the database to a new shared database and starts the flush worker.
+	/// Errors returned by background flushes are sent to the returned receiver.
+	pub fn new(db: Database, policy: FlushPolicy) -> Result<(Self, Receiver<Error>)> {
+		Self::new_internal(db, policy, None)
+	}
+
+	/// Same as `new`, but transactions committed concurrently are coalesced
+	/// into a single journal era.
+	pub fn with_group_commit(db: Database, policy: FlushPolicy, group_commit: GroupCommit) -> Result<(Self, Receiver<Error>)> {
+		if group_commit.max_transactions == 0 {
+			bail!(ErrorKind::InvalidOptions(
+				"max_transactions",
+				"must be greater than 0.".into()
+			));
+		}
+
+		Self::new_internal(db, policy, Some(group_commit))
+	}
+
+	fn new_internal(db: Database, policy: FlushPolicy, group_commit: Option<GroupCommit>) -> Result<(Self, Receiver<Error>)> {
+		let db = Arc::new(RwLock::new(db));
+		let signal = Arc::new(Signal::default());
+		let (errors_tx, errors_rx) = mpsc::channel();
+
+		let worker = {
+			let db = db.clone();
+			let signal = signal.clone();
+			thread::Builder::new()
+				.name("segurodb-flush".into())
+				.spawn(move || run_worker(&db, &signal, policy, &errors_tx))?
+		};
+
+		let shared = SharedDatabase {
+			db,
+			signal,
+			policy,
+			group_commit,
+			group: CommitGroup::default(),
+			worker: Some(worker),
+		};
+
+		Ok((shared, errors_rx))
+	}
+
+	/// Create a new transaction.
+	pub fn create_transaction(&self) -> Transaction {
+		self.db.read().create_transaction()
+	}
+
+	/// Subscribes to the changes of the database keys matching the filter.
+	/// See `Database::subscribe`.
+	pub fn subscribe(&self, filter: KeyFilter) -> Subscription {
+		self.db.read().subscribe(filter)
+	}
+
+	/// Commits changes in the transaction and wakes up the flush worker.
+	/// Returns the sequence number of the journal era the transaction was written to.
+	///
+	/// With group commit enabled the call blocks until the whole group is committed.
+	///
+	/// Fails without committing the transaction if the last background flush failed.
+	pub fn commit(&self, tx: &Transaction) -> Result<u64> {
+		self.take_flush_error()?;
+
+		match self.group_commit {
+			Some(group_commit) => self.commit_in_group(tx, group_commit),
+			None => self.commit_era(tx),
+		}
+	}
+
+	/// Flushes up to `max` excessive journal eras. See `Database::flush_journal`.
+	///
+	/// Fails without flushing if the last background flush failed.
+	pub fn flush_journal<T: Into<Option<usize>>>(&self, max: T) -> Result<()> {
+		self.take_flush_error()?;
+		self.db.write().flush_journal(max)
+	}
+
+	fn take_flush_error(&self) -> Result<()> {
+		match self.signal.state.lock().flush_error.take() {
+			Some(error) => Err(ErrorKind::FlushFailed(error).into()),
+			None => Ok(()),
+		}
+	}
+
+	/// Writes the transaction to a file while holding only the read lock.
+	pub fn prepare(&self, tx: &Transaction) -> Result<PreparedTransaction> {
+		self.db.read().prepare(tx)
+	}
+
+	/// Commits a prepared transaction. Returns the sequence number of the journal era.
+	pub fn apply(&self, prepared: PreparedTransaction) -> Result<u64> {
+		let era = self.db.write().apply(prepared)?;
+		self.notify_worker();
+		Ok(era)
+	}
+
+	fn commit_era(&self, tx: &Transaction) -> Result<u64> {
+		let era = self.db.write().commit(tx)?;
+		self.notify_worker();
+		Ok(era)
+	}
+
+	fn notify_worker(&self) {
+		if let FlushPolicy::Interval(_) = self.policy {
+			return;
+		}
+
+		self.signal.state.lock().committed = true;
+		self.signal.condvar.notify_one();
+	}
+
+	/// The first transaction submitted to an empty queue becomes the leader of the group.
+	/// It waits for other transactions for `GroupCommit::window`, or until the group is full,
+	/// and commits all of them in a single era.
+	/// Remaining transactions wait for the result published by the leader.
+	/// Transactions with failing preconditions are rejected without affecting the rest of the group.
+	fn commit_in_group(&self, tx: &Transaction, group_commit: GroupCommit) -> Result<u64> {
+		let mut tx_copy = self.create_transaction();
+		tx_copy.extend_raw(tx.raw());
+		let mut group_tx = self.create_transaction();
+
+		let (ticket, batch) = {
+			let mut queue = self.group.queue.lock();
+			let ticket = queue.next_ticket;
+			queue.next_ticket += 1;
+			queue.pending.push((ticket, tx_copy));
+
+			if queue.has_leader {
+				if queue.pending.len() >= group_commit.max_transactions {
+					self.group.condvar.notify_all();
+				}
+
+				loop {
+					if let Some(result) = queue.results.remove(&ticket) {
+						return result;
+					}
+					self.group.condvar.wait(&mut queue);
+				}
+			}
+
+			queue.has_leader = true;
+			let deadline = Instant::now() + group_commit.window;
+			while queue.pending.len() < group_commit.max_transactions {
+				let now = Instant::now();
+				if now >= deadline {
+					break;
+				}
+				self.group.condvar.wait_for(&mut queue, deadline - now);
+			}
+
+			// next transaction starts a new group
+			queue.has_leader = false;
+			(ticket, mem::replace(&mut queue.pending, Vec::new()))
+		};
+
+		let mut published = GroupResults {
+			group: &self.group,
+			tickets: batch.iter().map(|&(other_ticket, _)| other_ticket).filter(|&other_ticket| other_ticket != ticket).collect(),
+			results: HashMap::new(),
+		};
+
+		let mut rejected = HashMap::new();
+		let result = {
+			// preconditions are checked and the era is written under the same lock
+			let mut db = self.db.write();
+			let mut pending = HashMap::new();
+
+			// transactions are appended in submission order, so later transactions win
+			// and each of them is committed atomically as a part of the era
+			for &(other_ticket, ref other_tx) in &batch {
+				// pending values overwritten by a rejected transaction are restored
+				let overwritten: Vec<_> = other_tx.operations().with_keyspaces()
+					.map(|(keyspace, operation)| {
+						let key = (keyspace, operation.key());
+						(key, pending.get(&key).cloned())
+					})
+					.collect();
+
+				match db.check_preconditions(other_tx.operations().with_keyspaces(), &mut pending) {
+					Ok(()) => {
+						group_tx.extend_raw(other_tx.raw());
+					},
+					Err(err) => {
+						for (key, value) in overwritten.into_iter().rev() {
+							match value {
+								Some(value) => pending.insert(key, value),
+								None => pending.remove(&key),
+							};
+						}
+						rejected.insert(other_ticket, err);
+					},
+				}
+			}
+
+			if rejected.len() == batch.len() {
+				None
+			} else {
+				Some(db.commit(&group_tx))
+			}
+		};
+
+		if let Some(Ok(_)) = result {
+			self.notify_worker();
+		}
+
+		for &other_ticket in &published.tickets {
+			let other_result = match (rejected.remove(&other_ticket), &result) {
+				(Some(err), _) => Err(err),
+				(None, &Some(Ok(era))) => Ok(era),
+				(None, &Some(Err(ref err))) => Err(ErrorKind::GroupCommitFailed(err.to_string()).into()),
+				(None, &None) => unreachable!("transaction is either rejected or committed; qed"),
+			};
+			published.results.insert(other_ticket, other_result);
+		}
+
+		match (rejected.remove(&ticket), result) {
+			(Some(err), _) => Err(err),
+			(None, Some(result)) => result,
+			(None, None) => unreachable!("transaction is either rejected or committed; qed"),
+		}
+	}
+
+	/// Locks the database for reading.
+	pub fn read(&self) -> RwLockReadGuard<Database> {
+		self.db.read()
+	}
+
+	/// Stops the flush worker and returns the database.
+	pub fn close(mut self) -> Database {
+		self.stop_worker();
+
+		let db = self.db.clone();
+		drop(self);
+
+		match Arc::try_unwrap(db) {
+			Ok(db) => db.into_inner(),
+			Err(_) => unreachable!("the worker is the only other owner of the database and it has been stopped; qed"),
+		}
+	}
+
+	fn stop_worker(&mut self) {
+		if let Some(worker) = self.worker.take() {
+			self.signal.state.lock().shutdown = true;
+			self.signal.condvar.notify_one();
+			// the worker does not panic unless the database does
+			let _ = worker.join();
+		}
+	}
+}
+
+impl Drop for SharedDatabase {
+	fn drop(&mut self) {
+		self.stop_worker();
+	}
+}
+
+fn run_worker(db: &RwLock<Database>, signal: &Signal, policy: FlushPolicy, errors: &Sender<Error>) {
+	loop {
+		{
+			let mut state = signal.state.lock();
+			if !state.shutdown && !state.committed {
+				match policy {
+					FlushPolicy::Interval(interval) => {
+						signal.condvar.wait_for(&mut state, interval);
+					},
+					FlushPolicy::Eras(_) | FlushPolicy::JournalBytes(_) => {
+						signal.condvar.wait(&mut state);
+					},
+				}
+			}
+
+			if state.shutdown {
+				return;
+			}
+
+			state.committed = false;
+		}
+
+		if policy.should_flush(&db.read()) {
+			let result = db.write().flush_journal(None);
+			signal.state.lock().flush_error = result.as_ref().err().map(|err| err.to_string());
+			if let Err(err) = result {
+				// the receiver might have been dropped, the error is returned by the next commit as well
+				let _ = errors.send(err);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+	use std::ops::Bound;
+	use std::sync::{Arc, Barrier};
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use database::Database;
+	use error::{ErrorKind, Result};
+	use merge::MergeOperator;
+	use options::{Options, ValuesLen};
+	use subscription::KeyFilter;
+	use transaction::Transaction;
+	use super::{FlushPolicy, GroupCommit, SharedDatabase};
+
+	fn create_database(path: &::std::path::Path) -> Database {
+		Database::create(path, Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap()
+	}
+
+	fn wait_for<F: Fn() -> bool>(condition: F) {
+		let started = Instant::now();
+		while !condition() {
+			assert!(started.elapsed() < Duration::from_secs(10), "condition not met in time");
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn test_flush_worker_eras() {
+		let temp = tempdir::TempDir::new("test_flush_worker_eras").unwrap();
+		let (db, _errors) = SharedDatabase::new(create_database(temp.path()), FlushPolicy::Eras(1)).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		assert_eq!(db.read().journal_len(), 2);
+
+		db.commit(&db.create_transaction()).unwrap();
+		wait_for(|| db.read().journal_len() == 1);
+		assert_eq!(db.read().get("abc").unwrap().unwrap(), b"001");
+
+		let db = db.close();
+		assert_eq!(db.journal_len(), 1);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+	}
+
+	#[test]
+	fn test_flush_worker_journal_bytes() {
+		let temp = tempdir::TempDir::new("test_flush_worker_journal_bytes").unwrap();
+		let (db, _errors) = SharedDatabase::new(create_database(temp.path()), FlushPolicy::JournalBytes(64)).unwrap();
+
+		db.commit(&db.create_transaction()).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		assert_eq!(db.read().journal_len(), 2);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		db.commit(&tx).unwrap();
+		wait_for(|| db.read().journal_len() == 1);
+	}
+
+	#[test]
+	fn test_flush_worker_interval() {
+		let temp = tempdir::TempDir::new("test_flush_worker_interval").unwrap();
+		let policy = FlushPolicy::Interval(Duration::from_millis(10));
+		let (db, _errors) = SharedDatabase::new(create_database(temp.path()), policy).unwrap();
+
+		db.commit(&db.create_transaction()).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		wait_for(|| db.read().journal_len() == 1);
+	}
+
+	#[test]
+	fn test_commit_returns_era_sequence() {
+		let temp = tempdir::TempDir::new("test_commit_returns_era_sequence").unwrap();
+		let (db, _errors) = SharedDatabase::new(create_database(temp.path()), FlushPolicy::Eras(1)).unwrap();
+
+		assert_eq!(db.commit(&db.create_transaction()).unwrap(), 0);
+		assert_eq!(db.commit(&db.create_transaction()).unwrap(), 1);
+		assert_eq!(db.commit(&db.create_transaction()).unwrap(), 2);
+	}
+
+	#[test]
+	fn test_group_commit() {
+		let temp = tempdir::TempDir::new("test_group_commit").unwrap();
+		// the group is committed only once it's full
+		let group_commit = GroupCommit {
+			window: Duration::from_secs(60),
+			max_transactions: 8,
+		};
+		let (db, _errors) = SharedDatabase::with_group_commit(create_database(temp.path()), FlushPolicy::Eras(100), group_commit).unwrap();
+		let db = Arc::new(db);
+		let barrier = Arc::new(Barrier::new(8));
+
+		let handles: Vec<_> = (0..8u8).map(|i| {
+			let db = db.clone();
+			let barrier = barrier.clone();
+			thread::spawn(move || {
+				let mut tx = db.create_transaction();
+				tx.insert([b'a', b'a', b'0' + i], [b'0' + i; 3]).unwrap();
+				barrier.wait();
+				db.commit(&tx).unwrap()
+			})
+		}).collect();
+
+		let eras: Vec<u64> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+		assert_eq!(eras, vec![0; 8]);
+		assert_eq!(db.read().journal_len(), 1);
+		for i in 0..8u8 {
+			assert_eq!(db.read().get([b'a', b'a', b'0' + i]).unwrap().unwrap(), [b'0' + i; 3]);
+		}
+	}
+
+	#[test]
+	fn test_group_commit_rejects_failing_preconditions() {
+		let temp = tempdir::TempDir::new("test_group_commit_rejects_failing_preconditions").unwrap();
+		let group_commit = GroupCommit {
+			window: Duration::from_secs(60),
+			max_transactions: 4,
+		};
+		let (db, _errors) = SharedDatabase::with_group_commit(create_database(temp.path()), FlushPolicy::Eras(100), group_commit).unwrap();
+		let db = Arc::new(db);
+		let barrier = Arc::new(Barrier::new(4));
+
+		// only one of the transactions can insert the key
+		let handles: Vec<_> = (0..4u8).map(|i| {
+			let db = db.clone();
+			let barrier = barrier.clone();
+			thread::spawn(move || {
+				let mut tx = db.create_transaction();
+				tx.insert_if_absent("abc", [b'0' + i; 3]).unwrap();
+				barrier.wait();
+				db.commit(&tx)
+			})
+		}).collect();
+
+		let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+		assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+		for result in results.iter().filter(|result| result.is_err()) {
+			assert_eq!(
+				result.as_ref().unwrap_err().kind(),
+				&ErrorKind::PreconditionFailed(b"abc".to_vec(), "key is already present")
+			);
+		}
+		assert_eq!(db.read().journal_len(), 1);
+	}
+
+	#[derive(Debug)]
+	struct PanickingMerge;
+
+	impl MergeOperator for PanickingMerge {
+		fn merge(&self, _key: &[u8], _existing: Option<&[u8]>, _operand: &[u8]) -> Result<Vec<u8>> {
+			panic!("merge failed");
+		}
+	}
+
+	#[test]
+	fn test_group_commit_leader_panic_fails_group() {
+		let temp = tempdir::TempDir::new("test_group_commit_leader_panic_fails_group").unwrap();
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			merge_operator: Some(Arc::new(PanickingMerge)),
+			..Default::default()
+		}).unwrap();
+		let group_commit = GroupCommit {
+			window: Duration::from_secs(60),
+			max_transactions: 2,
+		};
+		let (db, _errors) = SharedDatabase::with_group_commit(db, FlushPolicy::Eras(100), group_commit).unwrap();
+		let db = Arc::new(db);
+		let commit = |tx: Transaction| {
+			let db = db.clone();
+			thread::spawn(move || db.commit(&tx))
+		};
+
+		let mut panicking = db.create_transaction();
+		panicking.insert("abc", "001").unwrap();
+		panicking.merge("abc", "002").unwrap();
+		let handles = vec![commit(panicking), commit(db.create_transaction())];
+		let results: Vec<_> = handles.into_iter().map(|handle| handle.join()).collect();
+
+		assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+		for result in results.into_iter().filter_map(|result| result.ok()) {
+			assert_eq!(result.unwrap_err().kind(), &ErrorKind::GroupCommitFailed("group leader panicked".into()));
+		}
+		assert_eq!(db.read().journal_len(), 0);
+	}
+
+	#[test]
+	fn should_wait_for_window_without_concurrent_transactions() {
+		let temp = tempdir::TempDir::new("should_wait_for_window_without_concurrent_transactions").unwrap();
+		let group_commit = GroupCommit {
+			window: Duration::from_secs(60),
+			max_transactions: 2,
+		};
+		let (db, _errors) = SharedDatabase::with_group_commit(create_database(temp.path()), FlushPolicy::Eras(100), group_commit).unwrap();
+		let db = Arc::new(db);
+
+		let first = {
+			let db = db.clone();
+			thread::spawn(move || db.commit(&db.create_transaction()).unwrap())
+		};
+
+		// the lone transaction waits for the next one, which arrives after it
+		wait_for(|| db.group.queue.lock().has_leader);
+		assert_eq!(db.commit(&db.create_transaction()).unwrap(), 0);
+		assert_eq!(first.join().unwrap(), 0);
+		assert_eq!(db.read().journal_len(), 1);
+	}
+
+	#[test]
+	fn test_prepare_without_write_lock() {
+		let temp = tempdir::TempDir::new("test_prepare_without_write_lock").unwrap();
+		let (db, _errors) = SharedDatabase::new(create_database(temp.path()), FlushPolicy::Eras(100)).unwrap();
+		let db = Arc::new(db);
+
+		let handles: Vec<_> = (0..4u8).map(|i| {
+			let db = db.clone();
+			thread::spawn(move || {
+				let mut tx = db.create_transaction();
+				tx.insert([b'a', b'a', b'0' + i], [b'0' + i; 3]).unwrap();
+				db.prepare(&tx).unwrap()
+			})
+		}).collect();
+
+		let prepared: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+		assert_eq!(db.read().journal_len(), 0);
+
+		for (era, prepared) in prepared.into_iter().enumerate() {
+			assert_eq!(db.apply(prepared).unwrap(), era as u64);
+		}
+
+		for i in 0..4u8 {
+			assert_eq!(db.read().get([b'a', b'a', b'0' + i]).unwrap().unwrap(), [b'0' + i; 3]);
+		}
+	}
+
+	#[test]
+	fn test_group_commit_later_transaction_wins() {
+		let temp = tempdir::TempDir::new("test_group_commit_later_transaction_wins").unwrap();
+		let group_commit = GroupCommit {
+			window: Duration::from_millis(0),
+			max_transactions: 1,
+		};
+		let (db, _errors) = SharedDatabase::with_group_commit(create_database(temp.path()), FlushPolicy::Eras(100), group_commit).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		assert_eq!(db.commit(&tx).unwrap(), 0);
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "002").unwrap();
+		assert_eq!(db.commit(&tx).unwrap(), 1);
+
+		assert_eq!(db.read().get("abc").unwrap().unwrap(), b"002");
+	}
+
+	#[test]
+	fn test_subscribe_to_group_commits() {
+		let temp = tempdir::TempDir::new("test_subscribe_to_group_commits").unwrap();
+		let group_commit = GroupCommit {
+			window: Duration::from_millis(1),
+			max_transactions: 8,
+		};
+		let (db, _errors) = SharedDatabase::with_group_commit(create_database(temp.path()), FlushPolicy::Eras(1), group_commit).unwrap();
+		let subscription = db.subscribe(KeyFilter::Range(Bound::Included(b"b".to_vec()), Bound::Unbounded));
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("bcd", "001").unwrap();
+		let seq = db.commit(&tx).unwrap();
+
+		let change = subscription.recv_timeout(Duration::from_secs(10)).unwrap();
+		assert_eq!(change.key, b"bcd");
+		assert_eq!(change.old_value, None);
+		assert_eq!(change.new_value, Some(b"001".to_vec()));
+		assert_eq!(change.seq, seq);
+
+		drop(db);
+		assert!(subscription.recv().is_err());
+	}
+
+	#[test]
+	fn should_report_flush_errors() {
+		let temp = tempdir::TempDir::new("should_report_flush_errors").unwrap();
+		let policy = FlushPolicy::Interval(Duration::from_millis(200));
+		let (db, errors) = SharedDatabase::new(create_database(temp.path()), policy).unwrap();
+
+		db.commit(&db.create_transaction()).unwrap();
+		db.commit(&db.create_transaction()).unwrap();
+		fs::remove_file(temp.path().join("0.era")).unwrap();
+
+		let err = errors.recv_timeout(Duration::from_secs(10)).unwrap();
+		let flush_failed = ErrorKind::FlushFailed(err.to_string());
+		assert_eq!(*db.commit(&db.create_transaction()).unwrap_err().kind(), flush_failed);
+	}
+
+	#[test]
+	fn should_flush_in_intervals_only_excessive_eras() {
+		let temp = tempdir::TempDir::new("should_flush_in_intervals_only_excessive_eras").unwrap();
+		let mut db = create_database(temp.path());
+		let policy = FlushPolicy::Interval(Duration::from_millis(10));
+
+		assert!(!policy.should_flush(&db));
+		db.commit(&db.create_transaction()).unwrap();
+		assert!(!policy.should_flush(&db));
+		db.commit(&db.create_transaction()).unwrap();
+		assert!(policy.should_flush(&db));
+	}
+}
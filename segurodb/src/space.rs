@@ -2,7 +2,7 @@
 
 use error::{ErrorKind, Result};
 use field::{self, field_size, Header};
-use field::iterator::FieldHeaderIterator;
+use storage::{ReadAt, Windows};
 
 macro_rules! try_next {
 	($t: expr) => {
@@ -33,16 +33,16 @@ pub enum Space<'a> {
 }
 
 #[derive(Debug)]
-pub struct SpaceIterator<'a> {
-	data: &'a [u8],
+pub struct SpaceIterator<'a, D: ?Sized + 'a> {
+	data: Windows<'a, D>,
 	field_body_size: usize,
 	offset: usize,
 }
 
-impl<'a> SpaceIterator<'a> {
-	pub fn new(data: &'a [u8], field_body_size: usize, offset: usize) -> Self {
+impl<'a, D: ReadAt + ?Sized> SpaceIterator<'a, D> {
+	pub fn new(data: &'a D, field_body_size: usize, offset: usize) -> Self {
 		SpaceIterator {
-			data,
+			data: Windows::new(data),
 			field_body_size,
 			offset,
 		}
@@ -66,89 +66,60 @@ impl<'a> SpaceIterator<'a> {
 	}
 }
 
-impl<'a> Iterator for SpaceIterator<'a> {
+impl<'a, D: ReadAt + ?Sized> Iterator for SpaceIterator<'a, D> {
 	type Item = Result<Space<'a>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		if self.data[self.offset..].is_empty() {
+		let len = self.data.len();
+		if self.offset >= len {
 			return None;
 		}
 
-		let mut first_header = None;
-		let mut start = self.offset;
 		let field_size = field_size(self.field_body_size);
-		let mut inner = try_next!(FieldHeaderIterator::new(&self.data[self.offset..], self.field_body_size));
-		while let Some(header) = inner.next() {
-			let header = try_next!(header);
+		if (len - self.offset) % field_size != 0 {
+			return Some(Err(ErrorKind::Field(field::ErrorKind::InvalidLength).into()));
+		}
+
+		let base = self.offset;
+		// set once the first field of a record is found
+		let mut occupied = false;
+		let mut start = self.offset;
+		while self.offset < len {
+			let byte = try_next!(self.data.read_from(self.offset, field_size))[0];
+			let header = try_next!(Header::from_u8(byte).ok_or(ErrorKind::Field(field::ErrorKind::InvalidHeader(self.offset, byte))));
 			match header {
-				Header::Continued => match first_header {
+				Header::Continued => {
 					// omit continued fields at the beginning
-					None => {
+					if !occupied {
 						start += field_size;
-						self.offset += field_size;
-						continue;
-					},
-					Some(Header::Inserted) => {
-						self.offset += field_size;
-					},
-					Some(Header::Continued) | Some(Header::Uninitialized) => {
-						unreachable!();
-					},
+					}
+					self.offset += field_size;
 				},
-				Header::Inserted => match first_header {
-					Some(Header::Inserted) => return Some(Ok(Space::Occupied(OccupiedSpace {
-						offset: start,
-						data: &self.data[start..self.offset],
-					}))),
-					None => {
-						self.offset += field_size;
-					},
-					Some(Header::Continued) | Some(Header::Uninitialized) => {
-						unreachable!();
-					},
+				Header::Inserted | Header::Uninitialized if occupied => break,
+				Header::Inserted => {
+					occupied = true;
+					self.offset += field_size;
 				},
-				Header::Uninitialized => match first_header {
-					// inserted is unreachable
-					Some(Header::Inserted) => return Some(Ok(Space::Occupied(OccupiedSpace {
+				Header::Uninitialized => {
+					self.offset += field_size;
+					return Some(Ok(Space::Empty(EmptySpace {
 						offset: start,
-						data: &self.data[start..self.offset],
-					}))),
-					Some(Header::Continued) | Some(Header::Uninitialized) => {
-						unreachable!();
-					},
-					None => {
-						self.offset += field_size;
-						return Some(Ok(Space::Empty(EmptySpace {
-							offset: start,
-							len: self.offset - start,
-						})))
-					},
-				}
-			}
-
-			if first_header.is_none() && header != Header::Continued {
-				first_header = Some(header);
+						len: self.offset - start,
+					})))
+				},
 			}
 		}
 
-		if first_header.is_none() {
-			// continuation was called
-			return Some(Err(ErrorKind::Field(field::ErrorKind::InvalidHeader).into()))
+		if !occupied {
+			// the data ends with fields continuing a record which doesn't exist
+			return Some(Err(ErrorKind::Field(field::ErrorKind::InvalidHeader(base, Header::Continued as u8)).into()))
 		}
 
-		first_header.map(|header| match header {
-			Header::Inserted => Ok(Space::Occupied(OccupiedSpace {
-				offset: start,
-				data: &self.data[start..self.offset],
-			})),
-			Header::Uninitialized => Ok(Space::Empty(EmptySpace {
-				offset: start,
-				len: self.offset - start,
-			})),
-			Header::Continued => {
-				unreachable!();
-			},
-		})
+		let data = try_next!(self.data.read_from(start, self.offset - start));
+		Some(Ok(Space::Occupied(OccupiedSpace {
+			offset: start,
+			data: &data[..self.offset - start],
+		})))
 	}
 }
 
@@ -158,7 +129,7 @@ mod tests {
 
 	#[test]
 	fn test_empty_space_iterator() {
-		let data = &[];
+		let data: &[u8] = &[];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -168,7 +139,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_one_uninitialized_element() {
-		let data = &[0, 1, 1, 1];
+		let data: &[u8] = &[0, 1, 1, 1];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -180,7 +151,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_one_initialized_element() {
-		let data = &[1, 1, 1, 1];
+		let data: &[u8] = &[1, 1, 1, 1];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -192,7 +163,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_two_different_spaces1() {
-		let data = &[1, 1, 1, 1, 0, 0, 0, 0];
+		let data: &[u8] = &[1, 1, 1, 1, 0, 0, 0, 0];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -206,7 +177,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_two_different_spaces2() {
-		let data = &[0, 0, 0, 0, 1, 0, 0, 0];
+		let data: &[u8] = &[0, 0, 0, 0, 1, 0, 0, 0];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -220,7 +191,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_two_inserts() {
-		let data = &[1, 0, 0, 0, 1, 2, 2, 2];
+		let data: &[u8] = &[1, 0, 0, 0, 1, 2, 2, 2];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -234,7 +205,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_one_long_space1() {
-		let data = &[1, 0, 0, 0, 2, 0, 0, 0];
+		let data: &[u8] = &[1, 0, 0, 0, 2, 0, 0, 0];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -246,7 +217,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_one_long_space2() {
-		let data = &[0, 0, 0, 0, 0, 0, 0, 0];
+		let data: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 0];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -260,7 +231,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_start_from_continued1() {
-		let data = &[2, 0, 0, 0, 0, 0, 0, 0];
+		let data: &[u8] = &[2, 0, 0, 0, 0, 0, 0, 0];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -272,7 +243,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_start_from_continued2() {
-		let data = &[
+		let data: &[u8] = &[
 			2, 0, 0, 0,
 			2, 0, 0, 0,
 			0, 0, 0, 0
@@ -288,7 +259,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_continued_error() {
-		let data = &[0, 0, 0, 0, 2, 0, 0, 0];
+		let data: &[u8] = &[0, 0, 0, 0, 2, 0, 0, 0];
 		let field_body_size = 3;
 		let offset = 0;
 
@@ -300,7 +271,7 @@ mod tests {
 
 	#[test]
 	fn test_space_iterator_short_insert_after_long_insert() {
-		let data = &[
+		let data: &[u8] = &[
 			1, 0, 0, 0,
 			2, 0, 0, 0,
 			1, 0, 0, 0
@@ -0,0 +1,479 @@
+//! Access to the data and metadata files of keyspaces.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::{cmp, fmt, io, slice, usize};
+
+use memmap::{Mmap, Protection};
+use parking_lot::Mutex;
+
+use durability::Durability;
+use error::{Corruption, Result};
+
+/// Decides how the data and metadata files of keyspaces are read and written.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Storage {
+	/// Files are memory mapped. Reads never copy data, but may stall on page faults,
+	/// and the process is killed if the file is truncated while it's mapped.
+	Mmap,
+	/// Files are read with positioned I/O into a cache of at most `Options::page_cache_size`
+	/// bytes of pages. Truncated files are reported as corrupted.
+	Pread,
+}
+
+/// Size of the pages read at once by `Storage::Pread`.
+const PAGE_SIZE: usize = 4096;
+/// Minimal size of the windows read by `Windows`.
+const WINDOW_SIZE: usize = 16 * PAGE_SIZE;
+
+/// Data which is read in ranges.
+pub(crate) trait ReadAt {
+	/// Returns the length of the data.
+	fn len(&self) -> usize;
+
+	/// Returns `len` bytes of the data at `offset`. The range must be within the data.
+	fn read(&self, offset: usize, len: usize) -> Result<&[u8]>;
+
+	/// Returns the whole data.
+	fn read_all(&self) -> Result<&[u8]> {
+		self.read(0, self.len())
+	}
+}
+
+impl ReadAt for [u8] {
+	fn len(&self) -> usize {
+		<[u8]>::len(self)
+	}
+
+	fn read(&self, offset: usize, len: usize) -> Result<&[u8]> {
+		Ok(&self[offset..offset + len])
+	}
+}
+
+/// A file of fixed length opened by one of the `Storage` implementations.
+pub(crate) trait StorageFile: ReadAt + fmt::Debug + Send + Sync {
+	/// Writes `data` to the file at `offset`. The range must be within the file.
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+
+	/// Syncs the written data to the disk.
+	fn sync(&self, durability: Durability) -> Result<()>;
+}
+
+/// File read and written with positioned I/O by `PreadFile`.
+pub(crate) trait PositionedFile: fmt::Debug + Send + Sync {
+	/// Reads exactly `buf.len()` bytes at `offset`. Fails with `io::ErrorKind::UnexpectedEof`
+	/// if the file is shorter.
+	fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+	/// Writes the whole `buf` at `offset`.
+	fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+	/// Syncs the written data to the disk.
+	fn sync(&self, durability: Durability) -> io::Result<()>;
+}
+
+impl PositionedFile for File {
+	fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+		read_exact_at(self, buf, offset)
+	}
+
+	fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+		write_all_at(self, buf, offset)
+	}
+
+	fn sync(&self, durability: Durability) -> io::Result<()> {
+		durability.sync_file(self)
+	}
+}
+
+impl Storage {
+	/// Opens an existing file for reading and writing. Files read with `Storage::Pread` cache
+	/// up to `cache_size` bytes of pages, which are not borrowed.
+	pub(crate) fn open<P: AsRef<Path>>(&self, path: P, cache_size: usize) -> Result<Box<StorageFile>> {
+		match *self {
+			Storage::Mmap => Ok(Box::new(MmapFile {
+				mmap: Mmap::open_path(path, Protection::ReadWrite)?,
+			})),
+			Storage::Pread => PreadFile::open(path.as_ref(), cache_size).map(|file| Box::new(file) as Box<StorageFile>),
+		}
+	}
+}
+
+/// Reads consecutive ranges of the data in windows of at least `WINDOW_SIZE` bytes,
+/// so that walking the fields of a file doesn't read each of them separately.
+#[derive(Debug)]
+pub(crate) struct Windows<'a, D: ?Sized + 'a> {
+	data: &'a D,
+	/// Offset of the last read window.
+	offset: usize,
+	window: &'a [u8],
+}
+
+impl<'a, D: ReadAt + ?Sized> Windows<'a, D> {
+	pub fn new(data: &'a D) -> Self {
+		Windows {
+			data,
+			offset: 0,
+			window: &[],
+		}
+	}
+
+	/// Returns the length of the data.
+	pub fn len(&self) -> usize {
+		self.data.len()
+	}
+
+	/// Returns the data from `offset` to the end of a window, which is at least `len` bytes long.
+	/// The range must be within the data.
+	pub fn read_from(&mut self, offset: usize, len: usize) -> Result<&'a [u8]> {
+		if offset < self.offset || offset + len > self.offset + self.window.len() {
+			let window_len = cmp::min(cmp::max(len, WINDOW_SIZE), self.data.len() - offset);
+			self.window = self.data.read(offset, window_len)?;
+			self.offset = offset;
+		}
+
+		Ok(&self.window[offset - self.offset..])
+	}
+}
+
+#[derive(Debug)]
+struct MmapFile {
+	mmap: Mmap,
+}
+
+impl ReadAt for MmapFile {
+	fn len(&self) -> usize {
+		self.mmap.len()
+	}
+
+	fn read(&self, offset: usize, len: usize) -> Result<&[u8]> {
+		Ok(unsafe { &self.mmap.as_slice()[offset..offset + len] })
+	}
+}
+
+impl StorageFile for MmapFile {
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+		unsafe { self.mmap.as_mut_slice()[offset..offset + data.len()].copy_from_slice(data) };
+		Ok(())
+	}
+
+	fn sync(&self, durability: Durability) -> Result<()> {
+		durability.sync_mmap(&self.mmap)?;
+		Ok(())
+	}
+}
+
+/// File read with positioned I/O into a cache of blocks of pages.
+///
+/// Reads return the data of the blocks, so the blocks read since the last write are kept
+/// until the next write, which can't happen while their data is borrowed. Other blocks
+/// are evicted, least recently read first, once the cache grows over its capacity.
+#[derive(Debug)]
+pub(crate) struct PreadFile<F = File> {
+	path: PathBuf,
+	file: F,
+	len: usize,
+	capacity: usize,
+	cache: Mutex<BlockCache>,
+}
+
+#[derive(Debug, Default)]
+struct BlockCache {
+	/// Blocks by their offset and length. Blocks may overlap.
+	blocks: BTreeMap<(usize, usize), Block>,
+	/// Offsets and lengths of the blocks by the time they were last read.
+	reads: BTreeMap<u64, (usize, usize)>,
+	/// Total length of the blocks.
+	size: usize,
+	/// No block is longer than this.
+	max_len: usize,
+	/// Time of the last read.
+	now: u64,
+	/// Time of the last write. Blocks read later may be borrowed.
+	written: u64,
+}
+
+/// Consecutive pages of the file. The data is never moved or modified while it's borrowed.
+#[derive(Debug)]
+struct Block {
+	data: Box<[u8]>,
+	read: u64,
+}
+
+impl PreadFile {
+	fn open(path: &Path, capacity: usize) -> Result<Self> {
+		let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+		let len = file.metadata()?.len() as usize;
+		Ok(PreadFile::new(path, file, len, capacity))
+	}
+}
+
+impl<F: PositionedFile> PreadFile<F> {
+	/// Returns the file of `len` bytes at `path`, caching up to `capacity` bytes of pages.
+	pub fn new(path: &Path, file: F, len: usize, capacity: usize) -> Self {
+		PreadFile {
+			path: path.to_owned(),
+			file,
+			len,
+			capacity,
+			cache: Mutex::new(BlockCache::default()),
+		}
+	}
+
+	/// Reads the pages covering `len` bytes at `offset` from the file.
+	fn load(&self, offset: usize, len: usize) -> Result<Block> {
+		let start = offset / PAGE_SIZE * PAGE_SIZE;
+		let end = cmp::min((offset + len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE, self.len);
+		let mut data = vec![0; end - start].into_boxed_slice();
+		match self.file.read_exact_at(&mut data, start as u64) {
+			Ok(()) => Ok(Block { data, read: 0 }),
+			Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof =>
+				Err(Corruption::new(start, "File was truncated while it was open").in_file(&self.path)),
+			Err(err) => Err(err.into()),
+		}
+	}
+}
+
+impl BlockCache {
+	/// Returns the offset and the length of a block containing `len` bytes at `offset`.
+	fn find(&self, offset: usize, len: usize) -> Option<(usize, usize)> {
+		let end = offset + len;
+		self.blocks.range(..(offset, usize::MAX)).rev()
+			.take_while(|&(&(start, _), _)| start + self.max_len >= end)
+			.map(|(&key, _)| key)
+			.find(|&(start, block_len)| start + block_len >= end)
+	}
+
+	/// Marks the block as read now and returns its data.
+	fn read(&mut self, key: (usize, usize)) -> &[u8] {
+		self.now += 1;
+		let block = self.blocks.get_mut(&key).expect("blocks are read after they are found; qed");
+		self.reads.remove(&block.read);
+		self.reads.insert(self.now, key);
+		block.read = self.now;
+		&block.data
+	}
+
+	fn insert(&mut self, key: (usize, usize), block: Block) {
+		self.size += key.1;
+		self.max_len = cmp::max(self.max_len, key.1);
+		self.blocks.insert(key, block);
+	}
+
+	/// Evicts the least recently read blocks, which were not read since the last write,
+	/// until the cache is not larger than `capacity`.
+	fn evict(&mut self, capacity: usize) {
+		while self.size > capacity {
+			let (read, key) = match self.reads.iter().next() {
+				Some((&read, &key)) if read <= self.written => (read, key),
+				_ => break,
+			};
+
+			self.reads.remove(&read);
+			self.blocks.remove(&key);
+			self.size -= key.1;
+		}
+	}
+}
+
+impl<F: PositionedFile> ReadAt for PreadFile<F> {
+	fn len(&self) -> usize {
+		self.len
+	}
+
+	fn read(&self, offset: usize, len: usize) -> Result<&[u8]> {
+		assert!(offset + len <= self.len, "reads are within the file; qed");
+
+		if len == 0 {
+			return Ok(&[]);
+		}
+
+		let mut cache = self.cache.lock();
+		let key = match cache.find(offset, len) {
+			Some(key) => key,
+			None => {
+				let block = self.load(offset, len)?;
+				let key = (offset / PAGE_SIZE * PAGE_SIZE, block.data.len());
+				cache.insert(key, block);
+				key
+			},
+		};
+
+		let data = cache.read(key)[offset - key.0..].as_ptr();
+		cache.evict(self.capacity);
+		// the block was read after the last write, so it's kept until the next write,
+		// which borrows the file mutably
+		Ok(unsafe { slice::from_raw_parts(data, len) })
+	}
+}
+
+impl<F: PositionedFile> StorageFile for PreadFile<F> {
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+		assert!(offset + data.len() <= self.len, "writes are within the file; qed");
+
+		self.file.write_all_at(data, offset as u64)?;
+
+		// nothing is borrowed from the cache while the file is borrowed mutably
+		let cache = self.cache.get_mut();
+		let end = offset + data.len();
+		let max_len = cache.max_len;
+		let blocks = cache.blocks.range_mut(..(end, 0)).rev()
+			.take_while(|&(&(start, _), _)| start + max_len > offset);
+		for (&(start, block_len), block) in blocks {
+			let from = cmp::max(start, offset);
+			let to = cmp::min(start + block_len, end);
+			if from < to {
+				block.data[from - start..to - start].copy_from_slice(&data[from - offset..to - offset]);
+			}
+		}
+		cache.written = cache.now;
+		cache.evict(self.capacity);
+		Ok(())
+	}
+
+	fn sync(&self, durability: Durability) -> Result<()> {
+		self.file.sync(durability)?;
+		Ok(())
+	}
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+	use std::os::unix::fs::FileExt;
+	FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+	use std::os::unix::fs::FileExt;
+	FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+	use std::os::windows::fs::FileExt;
+	while !buf.is_empty() {
+		match file.seek_read(buf, offset)? {
+			0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+			n => {
+				let rest = buf;
+				buf = &mut rest[n..];
+				offset += n as u64;
+			},
+		}
+	}
+	Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+	use std::os::windows::fs::FileExt;
+	while !buf.is_empty() {
+		match file.seek_write(buf, offset)? {
+			0 => return Err(io::ErrorKind::WriteZero.into()),
+			n => {
+				buf = &buf[n..];
+				offset += n as u64;
+			},
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+
+	use super::{PreadFile, ReadAt, Storage, StorageFile, Windows, PAGE_SIZE, WINDOW_SIZE};
+	use durability::Durability;
+	use error::ErrorKind;
+
+	fn create(dir: &tempdir::TempDir, len: usize) -> ::std::path::PathBuf {
+		let path = dir.path().join("data.db");
+		let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+		fs::write(&path, data).unwrap();
+		path
+	}
+
+	#[test]
+	fn test_read_write() {
+		for storage in &[Storage::Mmap, Storage::Pread] {
+			let temp = tempdir::TempDir::new("test_read_write").unwrap();
+			let path = create(&temp, 3 * PAGE_SIZE + 10);
+
+			let mut file = storage.open(&path, 0).unwrap();
+			assert_eq!(file.len(), 3 * PAGE_SIZE + 10);
+			assert_eq!(file.read(PAGE_SIZE - 1, 2).unwrap(), &[0xff, 0]);
+			assert_eq!(file.read(3 * PAGE_SIZE + 8, 2).unwrap(), &[8, 9]);
+			assert!(file.read(10, 0).unwrap().is_empty());
+
+			// writes are visible to the loaded and the missing pages
+			file.write(PAGE_SIZE - 1, &[1, 2]).unwrap();
+			file.write(2 * PAGE_SIZE, &[3]).unwrap();
+			file.sync(Durability::Full).unwrap();
+			assert_eq!(file.read(PAGE_SIZE - 1, 2).unwrap(), &[1, 2]);
+			assert_eq!(file.read(2 * PAGE_SIZE, 2).unwrap(), &[3, 1]);
+			drop(file);
+
+			let data = fs::read(&path).unwrap();
+			assert_eq!(&data[PAGE_SIZE - 1..PAGE_SIZE + 1], &[1, 2]);
+			assert_eq!(storage.open(&path, 0).unwrap().read_all().unwrap(), &data[..]);
+		}
+	}
+
+	#[test]
+	fn should_report_file_truncated_while_open() {
+		let temp = tempdir::TempDir::new("should_report_file_truncated_while_open").unwrap();
+		let path = create(&temp, 3 * PAGE_SIZE);
+
+		let file = Storage::Pread.open(&path, 0).unwrap();
+		assert_eq!(file.read(0, 1).unwrap(), &[0]);
+		fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(PAGE_SIZE as u64 + 1).unwrap();
+
+		// loaded pages are still readable
+		assert_eq!(file.read(0, 1).unwrap(), &[0]);
+		assert_eq!(
+			*file.read(PAGE_SIZE, PAGE_SIZE * 2).unwrap_err().kind(),
+			ErrorKind::Corrupted(path, PAGE_SIZE as u64, "File was truncated while it was open".into())
+		);
+	}
+
+	#[test]
+	fn should_evict_pages_read_before_last_write() {
+		let temp = tempdir::TempDir::new("should_evict_pages_read_before_last_write").unwrap();
+		let path = create(&temp, 4 * PAGE_SIZE);
+
+		let mut file = PreadFile::open(&path, 2 * PAGE_SIZE).unwrap();
+		for page in 0..4 {
+			assert_eq!(file.read(page * PAGE_SIZE + 1, 1).unwrap(), &[1]);
+		}
+		assert_eq!(file.read(2 * PAGE_SIZE + 2, 1).unwrap(), &[2]);
+		// pages read since the last write may be borrowed
+		assert_eq!(file.cache.lock().size, 4 * PAGE_SIZE);
+
+		// the least recently read pages are evicted
+		file.write(PAGE_SIZE, &[7]).unwrap();
+		assert_eq!(file.cache.lock().size, 2 * PAGE_SIZE);
+		assert_eq!(file.cache.lock().blocks.keys().cloned().collect::<Vec<_>>(), vec![(2 * PAGE_SIZE, PAGE_SIZE), (3 * PAGE_SIZE, PAGE_SIZE)]);
+		assert_eq!(file.read(PAGE_SIZE, 2).unwrap(), &[7, 1]);
+		assert_eq!(file.read(3 * PAGE_SIZE - 1, 2).unwrap(), &[0xff, 0]);
+		assert_eq!(file.cache.lock().blocks.keys().cloned().collect::<Vec<_>>(), vec![(PAGE_SIZE, PAGE_SIZE), (2 * PAGE_SIZE, 2 * PAGE_SIZE)]);
+
+		file.write(0, &[8]).unwrap();
+		assert_eq!(file.read_all().unwrap()[..2], [8, 1]);
+		assert_eq!(file.read(PAGE_SIZE, 1).unwrap(), &[7]);
+	}
+
+	#[test]
+	fn should_read_windows_of_the_data() {
+		let data = (0..3 * WINDOW_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+		let mut windows = Windows::new(&data[..]);
+		assert_eq!(windows.read_from(1, 1).unwrap().len(), WINDOW_SIZE);
+		assert_eq!(windows.read_from(WINDOW_SIZE, 1).unwrap(), &data[WINDOW_SIZE..WINDOW_SIZE + 1]);
+		assert_eq!(windows.read_from(WINDOW_SIZE + 1, 2 * WINDOW_SIZE - 1).unwrap(), &data[WINDOW_SIZE + 1..]);
+		assert_eq!(windows.read_from(0, 2).unwrap()[..2], [0, 1]);
+	}
+}
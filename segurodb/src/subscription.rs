@@ -0,0 +1,176 @@
+//! Subscriptions to the changes of database keys written by committed transactions.
+
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvError, RecvTimeoutError, SyncSender, TryIter, TryRecvError, TrySendError};
+use std::time::Duration;
+
+/// Keys watched by a subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyFilter {
+	/// A single key.
+	Key(Vec<u8>),
+	/// Keys starting with the bytes.
+	Prefix(Vec<u8>),
+	/// Keys within the bounds.
+	Range(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+impl KeyFilter {
+	/// Returns true if the key is watched.
+	pub fn matches(&self, key: &[u8]) -> bool {
+		match *self {
+			KeyFilter::Key(ref expected) => key == &expected[..],
+			KeyFilter::Prefix(ref prefix) => key.starts_with(prefix),
+			KeyFilter::Range(ref start, ref end) => {
+				let after_start = match *start {
+					Bound::Included(ref start) => key >= &start[..],
+					Bound::Excluded(ref start) => key > &start[..],
+					Bound::Unbounded => true,
+				};
+
+				let before_end = match *end {
+					Bound::Included(ref end) => key <= &end[..],
+					Bound::Excluded(ref end) => key < &end[..],
+					Bound::Unbounded => true,
+				};
+
+				after_start && before_end
+			},
+		}
+	}
+}
+
+/// Change of a key written by a committed transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+	/// Changed key.
+	pub key: Vec<u8>,
+	/// Value of the key before the transaction was committed.
+	pub old_value: Option<Vec<u8>>,
+	/// Value of the key written by the transaction, `None` if the key was deleted.
+	pub new_value: Option<Vec<u8>>,
+	/// Sequence number of the journal era the transaction was written to.
+	pub seq: u64,
+}
+
+/// Receiver of the changes of keys matching the filter passed to `Database::subscribe`.
+///
+/// Changes are buffered until they are received. Changes which don't fit into the full buffer
+/// are dropped and counted.
+#[derive(Debug)]
+pub struct Subscription {
+	receiver: Receiver<Change>,
+	dropped: Arc<AtomicUsize>,
+}
+
+impl Subscription {
+	/// Waits for the next change. Fails once the database is closed.
+	pub fn recv(&self) -> ::std::result::Result<Change, RecvError> {
+		self.receiver.recv()
+	}
+
+	/// Returns the next change if there is any.
+	pub fn try_recv(&self) -> ::std::result::Result<Change, TryRecvError> {
+		self.receiver.try_recv()
+	}
+
+	/// Waits for the next change at most `timeout`.
+	pub fn recv_timeout(&self, timeout: Duration) -> ::std::result::Result<Change, RecvTimeoutError> {
+		self.receiver.recv_timeout(timeout)
+	}
+
+	/// Returns an iterator over the buffered changes.
+	pub fn try_iter(&self) -> TryIter<Change> {
+		self.receiver.try_iter()
+	}
+
+	/// Returns the number of changes dropped since the last call, because the buffer was full.
+	pub fn take_dropped(&self) -> usize {
+		self.dropped.swap(0, Ordering::SeqCst)
+	}
+}
+
+/// Sending side of a `Subscription`.
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+	filter: KeyFilter,
+	sender: SyncSender<Change>,
+	dropped: Arc<AtomicUsize>,
+}
+
+impl Subscriber {
+	/// Creates a subscriber buffering up to `capacity` changes and its subscription.
+	pub(crate) fn new(filter: KeyFilter, capacity: usize) -> (Subscriber, Subscription) {
+		let (sender, receiver) = mpsc::sync_channel(capacity);
+		let dropped = Arc::new(AtomicUsize::new(0));
+		let subscriber = Subscriber {
+			filter,
+			sender,
+			dropped: dropped.clone(),
+		};
+
+		(subscriber, Subscription { receiver, dropped })
+	}
+
+	pub(crate) fn matches(&self, key: &[u8]) -> bool {
+		self.filter.matches(key)
+	}
+
+	/// Sends the change unless the buffer is full. Returns false if the subscription was dropped.
+	pub(crate) fn send(&self, change: Change) -> bool {
+		match self.sender.try_send(change) {
+			Ok(()) => true,
+			Err(TrySendError::Full(_)) => {
+				self.dropped.fetch_add(1, Ordering::SeqCst);
+				true
+			},
+			Err(TrySendError::Disconnected(_)) => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ops::Bound;
+	use super::{Change, KeyFilter, Subscriber};
+
+	#[test]
+	fn test_key_filter() {
+		assert!(KeyFilter::Key(b"abc".to_vec()).matches(b"abc"));
+		assert!(!KeyFilter::Key(b"abc".to_vec()).matches(b"abd"));
+		assert!(KeyFilter::Prefix(b"ab".to_vec()).matches(b"abc"));
+		assert!(!KeyFilter::Prefix(b"ab".to_vec()).matches(b"bbc"));
+
+		let range = KeyFilter::Range(Bound::Excluded(b"abc".to_vec()), Bound::Included(b"bcd".to_vec()));
+		assert!(!range.matches(b"abc"));
+		assert!(range.matches(b"abd"));
+		assert!(range.matches(b"bcd"));
+		assert!(!range.matches(b"bce"));
+		assert!(KeyFilter::Range(Bound::Unbounded, Bound::Unbounded).matches(b"abc"));
+	}
+
+	fn change(key: &[u8]) -> Change {
+		Change {
+			key: key.to_vec(),
+			old_value: None,
+			new_value: Some(b"1".to_vec()),
+			seq: 0,
+		}
+	}
+
+	#[test]
+	fn should_count_dropped_changes() {
+		let (subscriber, subscription) = Subscriber::new(KeyFilter::Prefix(Vec::new()), 1);
+		assert!(subscriber.send(change(b"abc")));
+		assert!(subscriber.send(change(b"bcd")));
+		assert!(subscriber.send(change(b"cde")));
+		assert_eq!(subscription.take_dropped(), 2);
+		assert_eq!(subscription.take_dropped(), 0);
+		assert_eq!(subscription.try_iter().collect::<Vec<_>>(), vec![change(b"abc")]);
+
+		drop(subscription);
+		assert!(!subscriber.send(change(b"abc")));
+	}
+}
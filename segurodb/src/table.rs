@@ -0,0 +1,262 @@
+//! Typed view over a `Database`.
+
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+use codec::{KeyCodec, ValueCodec};
+use database::{Database, DatabaseIterator};
+use error::{ErrorKind, Result};
+use transaction::Transaction;
+
+/// A database storing keys of type `K` and values of type `V`.
+///
+/// Keys are encoded with `KeyCodec`, so `K::LEN` has to match the `key_len` of the database.
+#[derive(Debug)]
+pub struct Table<K, V> {
+	db: Database,
+	_types: PhantomData<(K, V)>,
+}
+
+impl<K: KeyCodec, V: ValueCodec> Table<K, V> {
+	/// Wraps the database. Fails if the encoded keys don't match the database key length.
+	pub fn new(db: Database) -> Result<Self> {
+		if db.key_len() != K::LEN {
+			return Err(ErrorKind::InvalidKeyLen(db.key_len(), K::LEN).into());
+		}
+
+		Ok(Table {
+			db,
+			_types: PhantomData,
+		})
+	}
+
+	/// Returns the underlying database.
+	pub fn database(&self) -> &Database {
+		&self.db
+	}
+
+	/// Returns the underlying database.
+	pub fn database_mut(&mut self) -> &mut Database {
+		&mut self.db
+	}
+
+	/// Unwraps the underlying database.
+	pub fn into_inner(self) -> Database {
+		self.db
+	}
+
+	/// Create a new transaction.
+	pub fn create_transaction(&self) -> TableTransaction<K, V> {
+		TableTransaction {
+			tx: self.db.create_transaction(),
+			buf: Vec::new(),
+			_types: PhantomData,
+		}
+	}
+
+	/// Commits changes in the transaction. Returns the sequence number of the journal era
+	/// the transaction was written to.
+	pub fn commit(&mut self, tx: &TableTransaction<K, V>) -> Result<u64> {
+		self.db.commit(&tx.tx)
+	}
+
+	/// Returns the decoded value of the key.
+	pub fn get(&self, key: &K) -> Result<Option<V>> {
+		match self.db.get(encode_key(key))? {
+			Some(value) => V::decode_value(&value.to_vec()).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	/// Returns an iterator over all the key-value pairs of the table ordered by key.
+	pub fn iter(&self) -> Result<TableIterator<K, V>> {
+		self.range(Bound::Unbounded, Bound::Unbounded)
+	}
+
+	/// Returns an iterator over the key-value pairs of the table within the given bounds
+	/// ordered by key.
+	pub fn range(&self, start: Bound<&K>, end: Bound<&K>) -> Result<TableIterator<K, V>> {
+		let start = encode_bound(start);
+		let iter = match start {
+			Bound::Included(ref key) | Bound::Excluded(ref key) => self.db.iter_from(key)?,
+			Bound::Unbounded => self.db.iter()?,
+		};
+
+		Ok(TableIterator {
+			iter,
+			start,
+			end: encode_bound(end),
+			_types: PhantomData,
+		})
+	}
+}
+
+/// A transaction of typed operations.
+pub struct TableTransaction<K, V> {
+	tx: Transaction,
+	buf: Vec<u8>,
+	_types: PhantomData<(K, V)>,
+}
+
+impl<K: KeyCodec, V: ValueCodec> TableTransaction<K, V> {
+	/// Inserts the key-value pair into the transaction.
+	pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+		self.buf.clear();
+		value.encode_value(&mut self.buf);
+		self.tx.insert(encode_key(key), &self.buf)
+	}
+
+	/// Deletes the key in the transaction.
+	pub fn delete(&mut self, key: &K) -> Result<()> {
+		self.tx.delete(encode_key(key))
+	}
+
+	/// Returns the underlying transaction.
+	pub fn transaction(&self) -> &Transaction {
+		&self.tx
+	}
+}
+
+/// An iterator over the decoded key-value pairs of a `Table`.
+pub struct TableIterator<'a, K, V> {
+	iter: DatabaseIterator<'a>,
+	start: Bound<Vec<u8>>,
+	end: Bound<Vec<u8>>,
+	_types: PhantomData<(K, V)>,
+}
+
+impl<'a, K: KeyCodec, V: ValueCodec> Iterator for TableIterator<'a, K, V> {
+	type Item = Result<(K, V)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let (key, value) = match self.iter.next()? {
+				Ok(pair) => pair,
+				Err(err) => return Some(Err(err)),
+			};
+
+			let after_start = match self.start {
+				Bound::Included(ref start) => key >= &start[..],
+				Bound::Excluded(ref start) => key > &start[..],
+				Bound::Unbounded => true,
+			};
+
+			if !after_start {
+				continue;
+			}
+
+			let before_end = match self.end {
+				Bound::Included(ref end) => key <= &end[..],
+				Bound::Excluded(ref end) => key < &end[..],
+				Bound::Unbounded => true,
+			};
+
+			if !before_end {
+				return None;
+			}
+
+			return Some(K::decode_key(key).and_then(|key| {
+				V::decode_value(&value.to_vec()).map(|value| (key, value))
+			}));
+		}
+	}
+}
+
+fn encode_key<K: KeyCodec>(key: &K) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(K::LEN);
+	key.encode_key(&mut buf);
+	buf
+}
+
+fn encode_bound<K: KeyCodec>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+	match bound {
+		Bound::Included(key) => Bound::Included(encode_key(key)),
+		Bound::Excluded(key) => Bound::Excluded(encode_key(key)),
+		Bound::Unbounded => Bound::Unbounded,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::ops::Bound;
+	use self::tempdir::TempDir;
+	use database::Database;
+	use error::ErrorKind;
+	use options::{Options, ValuesLen};
+	use super::Table;
+
+	fn create_table(dir: &TempDir) -> Table<(u32, i16), u64> {
+		let db = Database::create(dir.path(), Options {
+			journal_eras: 0,
+			key_len: 6,
+			value_len: ValuesLen::Constant(8),
+			..Default::default()
+		}).unwrap();
+
+		Table::new(db).unwrap()
+	}
+
+	#[test]
+	fn should_reject_key_of_invalid_length() {
+		let temp = TempDir::new("should_reject_key_of_invalid_length").unwrap();
+		let db = Database::create(temp.path(), Options {
+			key_len: 4,
+			..Default::default()
+		}).unwrap();
+
+		assert_eq!(*Table::<u64, u64>::new(db).unwrap_err().kind(), ErrorKind::InvalidKeyLen(4, 8));
+	}
+
+	#[test]
+	fn test_table_operations() {
+		let temp = TempDir::new("test_table_operations").unwrap();
+		let mut table = create_table(&temp);
+
+		let mut tx = table.create_transaction();
+		tx.insert(&(1, -1), &10).unwrap();
+		tx.insert(&(1, 1), &11).unwrap();
+		tx.insert(&(2, 0), &20).unwrap();
+		table.commit(&tx).unwrap();
+
+		assert_eq!(table.get(&(1, -1)).unwrap(), Some(10));
+		assert_eq!(table.get(&(3, 0)).unwrap(), None);
+
+		let mut tx = table.create_transaction();
+		tx.delete(&(1, 1)).unwrap();
+		table.commit(&tx).unwrap();
+		table.database_mut().flush_journal(None).unwrap();
+
+		assert_eq!(table.get(&(1, 1)).unwrap(), None);
+		assert_eq!(table.get(&(2, 0)).unwrap(), Some(20));
+	}
+
+	#[test]
+	fn test_table_range() {
+		let temp = TempDir::new("test_table_range").unwrap();
+		let mut table = create_table(&temp);
+
+		let mut tx = table.create_transaction();
+		for i in 0..4 {
+			tx.insert(&(i, -2), &(i as u64)).unwrap();
+			tx.insert(&(i, 2), &(i as u64 + 10)).unwrap();
+		}
+		table.commit(&tx).unwrap();
+
+		let all = table.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+		assert_eq!(all.len(), 8);
+		assert_eq!(all[0], ((0, -2), 0));
+		assert_eq!(all[1], ((0, 2), 10));
+
+		let range = table.range(Bound::Included(&(1, 2)), Bound::Excluded(&(3, -2))).unwrap()
+			.map(|pair| pair.unwrap().0)
+			.collect::<Vec<_>>();
+		assert_eq!(range, vec![(1, 2), (2, -2), (2, 2)]);
+
+		let range = table.range(Bound::Excluded(&(2, 2)), Bound::Unbounded).unwrap()
+			.map(|pair| pair.unwrap().0)
+			.collect::<Vec<_>>();
+		assert_eq!(range, vec![(3, -2), (3, 2)]);
+	}
+}
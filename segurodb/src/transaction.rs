@@ -1,12 +1,32 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
-use error::{ErrorKind, Result};
+use database::{Database, DatabaseIterator, Value};
+use error::{Corruption, ErrorKind, Result};
+use expiry::{self, EXPIRY_SIZE, NEVER};
+use options::ValueLenLimit;
+#[cfg(test)]
+use options::MAX_VALUE_LEN;
+
+/// Name of the keyspace of `Database` itself.
+pub(crate) const DEFAULT_KEYSPACE: &'static [u8] = b"";
 
 /// Database operations
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Operation<'a> {
+	/// Insert of the value.
 	Insert(&'a [u8], &'a [u8]),
+	/// Delete of the key.
 	Delete(&'a [u8]),
+	/// Insert, which requires the key to be absent.
+	InsertIfAbsent(&'a [u8], &'a [u8]),
+	/// Insert, which requires the current value of the key to be equal to the second value.
+	UpdateIfEquals(&'a [u8], &'a [u8], &'a [u8]),
+	/// Delete, which requires the key to be present.
+	DeleteIfPresent(&'a [u8]),
+	/// Operand combined with the current value by the merge operator.
+	Merge(&'a [u8], &'a [u8]),
 }
 
 impl<'a> PartialOrd for Operation<'a> {
@@ -24,17 +44,57 @@ impl<'a> Ord for Operation<'a> {
 impl<'a> Operation<'a> {
 	const INSERT: u8 = 0;
 	const DELETE: u8 = 1;
+	const INSERT_IF_ABSENT: u8 = 2;
+	const UPDATE_IF_EQUALS: u8 = 3;
+	const DELETE_IF_PRESENT: u8 = 4;
+	const MERGE: u8 = 5;
+	/// Precedes operations in named keyspaces.
+	const KEYSPACE: u8 = 6;
 
+	/// Returns the key of the operation.
 	pub fn key(&self) -> &'a [u8] {
 		match *self {
-			Operation::Insert(key, _) | Operation::Delete(key) => key,
+			Operation::Insert(key, _) |
+			Operation::Delete(key) |
+			Operation::InsertIfAbsent(key, _) |
+			Operation::UpdateIfEquals(key, _, _) |
+			Operation::DeleteIfPresent(key) |
+			Operation::Merge(key, _) => key,
+		}
+	}
+
+	/// Returns the operation without its precondition.
+	pub fn effect(self) -> Operation<'a> {
+		match self {
+			Operation::Merge(..) => self,
+			Operation::Insert(key, value) |
+			Operation::InsertIfAbsent(key, value) |
+			Operation::UpdateIfEquals(key, _, value) => Operation::Insert(key, value),
+			Operation::Delete(key) |
+			Operation::DeleteIfPresent(key) => Operation::Delete(key),
 		}
 	}
 
+	/// Returns the operation with its value stripped of the expiry time stored with `Options::ttl`.
+	/// Fails if the value is shorter than the expiry time.
+	pub(crate) fn without_expiry(self) -> ::std::result::Result<Operation<'a>, Corruption> {
+		let strip = |value: &'a [u8]| match value.len().checked_sub(EXPIRY_SIZE) {
+			Some(_) => Ok(&value[EXPIRY_SIZE..]),
+			None => Err(Corruption::new(0, format!("Value is shorter than its expiry time of {} bytes", EXPIRY_SIZE))),
+		};
+
+		Ok(match self {
+			Operation::Insert(key, value) => Operation::Insert(key, strip(value)?),
+			Operation::InsertIfAbsent(key, value) => Operation::InsertIfAbsent(key, strip(value)?),
+			Operation::UpdateIfEquals(key, expected, value) => Operation::UpdateIfEquals(key, expected, strip(value)?),
+			Operation::Delete(_) | Operation::DeleteIfPresent(_) | Operation::Merge(..) => self,
+		})
+	}
+
 	/// Each operation is stored with a type and size before the transaction.
 	///
 	/// ```text
-	///  1 byte   4/8 bytes
+	///  1 byte   4/8/12 bytes
 	///   /         /
 	/// | type |  size(s) | data |
 	/// ```
@@ -42,44 +102,137 @@ impl<'a> Operation<'a> {
 		match *self {
 			Operation::Insert(key, value) => {
 				buf.push(Operation::INSERT);
-				buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
-				buf.write_u32::<LittleEndian>(value.len() as u32).unwrap();
-				buf.extend_from_slice(key);
-				buf.extend_from_slice(value);
+				write_insert(buf, key, value);
 			},
 			Operation::Delete(key) => {
 				buf.push(Operation::DELETE);
+				write_delete(buf, key);
+			},
+			Operation::InsertIfAbsent(key, value) => {
+				buf.push(Operation::INSERT_IF_ABSENT);
+				write_insert(buf, key, value);
+			},
+			Operation::UpdateIfEquals(key, expected, value) => {
+				buf.push(Operation::UPDATE_IF_EQUALS);
 				buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+				buf.write_u32::<LittleEndian>(expected.len() as u32).unwrap();
+				buf.write_u32::<LittleEndian>(value.len() as u32).unwrap();
 				buf.extend_from_slice(key);
+				buf.extend_from_slice(expected);
+				buf.extend_from_slice(value);
+			},
+			Operation::DeleteIfPresent(key) => {
+				buf.push(Operation::DELETE_IF_PRESENT);
+				write_delete(buf, key);
+			},
+			Operation::Merge(key, operand) => {
+				buf.push(Operation::MERGE);
+				write_insert(buf, key, operand);
 			},
 		}
 	}
 
-	fn read_from_buf(buf: &[u8]) -> Option<(Operation, usize)> {
-		if buf.is_empty() {
-			return None;
-		}
+	fn read_from_buf(buf: &[u8]) -> ::std::result::Result<(Operation, usize), Corruption> {
+		let tag = *buf.first().ok_or_else(|| Corruption::new(0, "Missing operation"))?;
 
-		match buf[0] {
+		match tag {
 			Operation::INSERT => {
-				let key_len = LittleEndian::read_u32(&buf[1..5]) as usize;
-				let value_len = LittleEndian::read_u32(&buf[5..9]) as usize;
-				let key_end = 9 + key_len;
-				let value_end = key_end + value_len;
-				let o = Operation::Insert(&buf[9..key_end], &buf[key_end..value_end]);
-				Some((o, value_end))
+				let (key, value, end) = read_insert(buf)?;
+				Ok((Operation::Insert(key, value), end))
 			},
 			Operation::DELETE => {
-				let key_len = LittleEndian::read_u32(&buf[1..5]) as usize;
-				let key_end = 5 + key_len;
-				let o = Operation::Delete(&buf[5..key_end]);
-				Some((o, key_end))
+				let (key, end) = read_delete(buf)?;
+				Ok((Operation::Delete(key), end))
+			},
+			Operation::INSERT_IF_ABSENT => {
+				let (key, value, end) = read_insert(buf)?;
+				Ok((Operation::InsertIfAbsent(key, value), end))
+			},
+			Operation::UPDATE_IF_EQUALS => {
+				let key_len = read_len(buf, 1)?;
+				let expected_len = read_len(buf, 5)?;
+				let value_len = read_len(buf, 9)?;
+				let key_end = 13 + key_len;
+				let expected_end = key_end + expected_len;
+				let key = read_slice(buf, 13, key_len)?;
+				let expected = read_slice(buf, key_end, expected_len)?;
+				let value = read_slice(buf, expected_end, value_len)?;
+				Ok((Operation::UpdateIfEquals(key, expected, value), expected_end + value_len))
+			},
+			Operation::DELETE_IF_PRESENT => {
+				let (key, end) = read_delete(buf)?;
+				Ok((Operation::DeleteIfPresent(key), end))
 			},
-			_ => None,
+			Operation::MERGE => {
+				let (key, operand, end) = read_insert(buf)?;
+				Ok((Operation::Merge(key, operand), end))
+			},
+			tag => Err(Corruption::new(0, format!("Unknown operation {}", tag))),
 		}
 	}
 }
 
+fn write_insert(buf: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+	buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+	buf.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+	buf.extend_from_slice(key);
+	buf.extend_from_slice(value);
+}
+
+fn write_delete(buf: &mut Vec<u8>, key: &[u8]) {
+	buf.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+	buf.extend_from_slice(key);
+}
+
+fn read_len(buf: &[u8], offset: usize) -> ::std::result::Result<usize, Corruption> {
+	buf.get(offset..offset + 4)
+		.map(|len| LittleEndian::read_u32(len) as usize)
+		.ok_or_else(|| Corruption::new(offset, "Truncated length"))
+}
+
+fn read_slice(buf: &[u8], offset: usize, len: usize) -> ::std::result::Result<&[u8], Corruption> {
+	buf.get(offset..offset + len)
+		.ok_or_else(|| Corruption::new(offset, format!("Truncated data of {} bytes", len)))
+}
+
+fn read_insert(buf: &[u8]) -> ::std::result::Result<(&[u8], &[u8], usize), Corruption> {
+	let key_len = read_len(buf, 1)?;
+	let value_len = read_len(buf, 5)?;
+	let key_end = 9 + key_len;
+	let key = read_slice(buf, 9, key_len)?;
+	let value = read_slice(buf, key_end, value_len)?;
+	Ok((key, value, key_end + value_len))
+}
+
+fn read_delete(buf: &[u8]) -> ::std::result::Result<(&[u8], usize), Corruption> {
+	let key_len = read_len(buf, 1)?;
+	let key = read_slice(buf, 5, key_len)?;
+	Ok((key, 5 + key_len))
+}
+
+/// Reads the operation at the beginning of the data. Returns the name of its keyspace,
+/// the operation and its length.
+fn read_operation(data: &[u8]) -> ::std::result::Result<(&[u8], Operation, usize), Corruption> {
+	let (keyspace, start) = if data.first() == Some(&Operation::KEYSPACE) {
+		let name_len = read_len(data, 1)?;
+		(read_slice(data, 5, name_len)?, 5 + name_len)
+	} else {
+		(DEFAULT_KEYSPACE, 0)
+	};
+
+	let (operation, len) = Operation::read_from_buf(&data[start..]).map_err(|corruption| corruption.shifted(start))?;
+	Ok((keyspace, operation, start + len))
+}
+
+/// Returns the value as it is stored, preceded by its expiry time if the keyspace has `ttl` enabled.
+fn stored_value(ttl: bool, value: &[u8], expires_at: u64) -> Cow<[u8]> {
+	if ttl {
+		Cow::Owned(expiry::encode(value, expires_at))
+	} else {
+		Cow::Borrowed(value)
+	}
+}
+
 /// Database operations.
 pub struct Transaction {
 	/// key length, it's used to determine whether an insert
@@ -87,44 +240,235 @@ pub struct Transaction {
 	/// use `Options` or `InternalOptions` here, but right now
 	/// we only care about key size, so it's enough info.
 	key_len: usize,
+	/// Lengths of values accepted by the database.
+	value_len: ValueLenLimit,
+	/// Whether values are stored with their expiry time.
+	ttl: bool,
+	/// Key and value lengths of named keyspaces and whether their values are stored with expiry time.
+	keyspaces: HashMap<Vec<u8>, (usize, ValueLenLimit, bool)>,
 	operations: Vec<u8>,
 }
 
 impl Transaction {
+	/// Creates a transaction accepting values of any supported length.
+	/// Use `db.create_transaction()` outside of unit tests.
+	#[cfg(test)]
+	pub(crate) fn new(key_len: usize) -> Transaction {
+		Transaction::with_value_len(key_len, ValueLenLimit::Max(MAX_VALUE_LEN))
+	}
+
 	/// This should only be called in `Database` and some unit tests.
 	/// Use `db.create_transaction()` in any other cases.
-	pub(crate) fn new(key_len: usize) -> Transaction {
+	pub(crate) fn with_value_len(key_len: usize, value_len: ValueLenLimit) -> Transaction {
 		Transaction {
 			key_len: key_len,
+			value_len: value_len,
+			ttl: false,
+			keyspaces: HashMap::new(),
 			operations: Vec::new(),
 		}
 	}
 
+	/// Stores values of the database with their expiry time.
+	pub(crate) fn enable_ttl(&mut self) {
+		self.ttl = true;
+	}
+
+	/// Allows operations in the named keyspace.
+	pub(crate) fn add_keyspace(&mut self, name: &str, key_len: usize, value_len: ValueLenLimit, ttl: bool) {
+		self.keyspaces.insert(name.as_bytes().to_vec(), (key_len, value_len, ttl));
+	}
+
 	/// Append new insert operation to the list of transactions.
 	#[inline]
 	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
-		let key = key.as_ref();
-		if key.len() != self.key_len {
-			Err(ErrorKind::InvalidKeyLen(self.key_len, key.len()).into())
-		} else {
-			self.push(Operation::Insert(key, value.as_ref()));
-			Ok(())
+		self.insert_into(DEFAULT_KEYSPACE, key.as_ref(), value.as_ref(), None)
+	}
+
+	/// Append new insert operation to the named keyspace.
+	///
+	/// Operations in all keyspaces are committed atomically with the transaction.
+	#[inline]
+	pub fn insert_in<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, keyspace: &str, key: K, value: V) -> Result<()> {
+		self.insert_into(keyspace.as_bytes(), key.as_ref(), value.as_ref(), None)
+	}
+
+	/// Append new insert operation of a value, which expires at `expires_at` seconds since the Unix epoch.
+	///
+	/// Expired values are hidden immediately and removed from the disk when the journal is flushed
+	/// or the database is compacted. Requires `Options::ttl`.
+	#[inline]
+	pub fn insert_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V, expires_at: u64) -> Result<()> {
+		self.insert_into(DEFAULT_KEYSPACE, key.as_ref(), value.as_ref(), Some(expires_at))
+	}
+
+	/// Append new insert operation of an expiring value to the named keyspace.
+	/// Requires `ttl` in the `Options` of the keyspace.
+	#[inline]
+	pub fn insert_with_ttl_in<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, keyspace: &str, key: K, value: V, expires_at: u64) -> Result<()> {
+		self.insert_into(keyspace.as_bytes(), key.as_ref(), value.as_ref(), Some(expires_at))
+	}
+
+	fn insert_into(&mut self, name: &[u8], key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+		let (key, value) = self.check_key_value(name, key, value)?;
+		let ttl = self.limits(name)?.2;
+		if expires_at.is_some() && !ttl {
+			return Err(ErrorKind::TtlDisabled.into());
 		}
+
+		let value = stored_value(ttl, value, expires_at.unwrap_or(NEVER));
+		self.push(name, Operation::Insert(key, &value));
+		Ok(())
 	}
 
 	/// Append new delete operation to the list of transactions.
 	#[inline]
 	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
-		let key = key.as_ref();
-		if key.len() != self.key_len {
-			Err(ErrorKind::InvalidKeyLen(self.key_len, key.len()).into())
-		} else {
-			self.push(Operation::Delete(key));
-			Ok(())
+		self.delete_from(DEFAULT_KEYSPACE, key.as_ref())
+	}
+
+	/// Append new delete operation to the named keyspace.
+	#[inline]
+	pub fn delete_in<K: AsRef<[u8]>>(&mut self, keyspace: &str, key: K) -> Result<()> {
+		self.delete_from(keyspace.as_bytes(), key.as_ref())
+	}
+
+	fn delete_from(&mut self, name: &[u8], key: &[u8]) -> Result<()> {
+		let key = self.check_key(name, key)?;
+		self.push(name, Operation::Delete(key));
+		Ok(())
+	}
+
+	/// Append new insert operation, which fails the transaction if the key is already present.
+	#[inline]
+	pub fn insert_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		self.insert_if_absent_into(DEFAULT_KEYSPACE, key.as_ref(), value.as_ref())
+	}
+
+	/// Append new insert operation to the named keyspace, which fails the transaction
+	/// if the key is already present.
+	#[inline]
+	pub fn insert_if_absent_in<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, keyspace: &str, key: K, value: V) -> Result<()> {
+		self.insert_if_absent_into(keyspace.as_bytes(), key.as_ref(), value.as_ref())
+	}
+
+	fn insert_if_absent_into(&mut self, name: &[u8], key: &[u8], value: &[u8]) -> Result<()> {
+		let (key, value) = self.check_key_value(name, key, value)?;
+		let value = stored_value(self.limits(name)?.2, value, NEVER);
+		self.push(name, Operation::InsertIfAbsent(key, &value));
+		Ok(())
+	}
+
+	/// Append new insert operation, which fails the transaction unless the current value
+	/// of the key is equal to `expected`.
+	#[inline]
+	pub fn update_if_equals<K: AsRef<[u8]>, E: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: E, value: V) -> Result<()> {
+		self.update_if_equals_into(DEFAULT_KEYSPACE, key.as_ref(), expected.as_ref(), value.as_ref())
+	}
+
+	/// Append new insert operation to the named keyspace, which fails the transaction
+	/// unless the current value of the key is equal to `expected`.
+	#[inline]
+	pub fn update_if_equals_in<K: AsRef<[u8]>, E: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, keyspace: &str, key: K, expected: E, value: V) -> Result<()> {
+		self.update_if_equals_into(keyspace.as_bytes(), key.as_ref(), expected.as_ref(), value.as_ref())
+	}
+
+	fn update_if_equals_into(&mut self, name: &[u8], key: &[u8], expected: &[u8], value: &[u8]) -> Result<()> {
+		let (key, value) = self.check_key_value(name, key, value)?;
+		let value = stored_value(self.limits(name)?.2, value, NEVER);
+		self.push(name, Operation::UpdateIfEquals(key, expected, &value));
+		Ok(())
+	}
+
+	/// Append new delete operation, which fails the transaction if the key is not present.
+	#[inline]
+	pub fn delete_if_present<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		self.delete_if_present_from(DEFAULT_KEYSPACE, key.as_ref())
+	}
+
+	/// Append new delete operation to the named keyspace, which fails the transaction
+	/// if the key is not present.
+	#[inline]
+	pub fn delete_if_present_in<K: AsRef<[u8]>>(&mut self, keyspace: &str, key: K) -> Result<()> {
+		self.delete_if_present_from(keyspace.as_bytes(), key.as_ref())
+	}
+
+	fn delete_if_present_from(&mut self, name: &[u8], key: &[u8]) -> Result<()> {
+		let key = self.check_key(name, key)?;
+		self.push(name, Operation::DeleteIfPresent(key));
+		Ok(())
+	}
+
+	/// Append new merge operation. The operand is combined with the value of the key
+	/// by the merge operator registered in `Options`.
+	#[inline]
+	pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, operand: V) -> Result<()> {
+		self.merge_into(DEFAULT_KEYSPACE, key.as_ref(), operand.as_ref())
+	}
+
+	/// Append new merge operation to the named keyspace. The operand is combined with the value
+	/// of the key by the merge operator registered in the `Options` of the keyspace.
+	#[inline]
+	pub fn merge_in<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, keyspace: &str, key: K, operand: V) -> Result<()> {
+		self.merge_into(keyspace.as_bytes(), key.as_ref(), operand.as_ref())
+	}
+
+	fn merge_into(&mut self, name: &[u8], key: &[u8], operand: &[u8]) -> Result<()> {
+		let key = self.check_key(name, key)?;
+		self.push(name, Operation::Merge(key, operand));
+		Ok(())
+	}
+
+	/// Lookup a value associated with given `key` as if the transaction was already committed
+	/// to the `db`. Later operations on the same key win.
+	///
+	/// Preconditions of conditional operations are not checked.
+	pub fn get<'a, K: AsRef<[u8]>>(&'a self, db: &'a Database, key: K) -> Result<Option<Value<'a>>> {
+		self.get_from(db, DEFAULT_KEYSPACE, key.as_ref())
+	}
+
+	/// Lookup a value associated with given `key` in the named keyspace as if the transaction
+	/// was already committed to the `db`.
+	pub fn get_in<'a, K: AsRef<[u8]>>(&'a self, db: &'a Database, keyspace: &str, key: K) -> Result<Option<Value<'a>>> {
+		self.get_from(db, keyspace.as_bytes(), key.as_ref())
+	}
+
+	fn get_from<'a>(&'a self, db: &'a Database, name: &[u8], key: &[u8]) -> Result<Option<Value<'a>>> {
+		let key = self.check_key(name, key)?;
+		let mut pending = HashMap::new();
+		let operations = self.operations().with_keyspaces()
+			.filter(|&(keyspace, ref operation)| keyspace == name && operation.key() == key);
+		for (keyspace, operation) in operations {
+			db.apply_pending(keyspace, operation, &mut pending)?;
 		}
+
+		db.get_pending(name, key, &pending)
 	}
 
-	/// Returns double-ended iterator over all operations in a transaction.
+	/// Returns an iterator over all the key-value pairs ordered by key as if the transaction
+	/// was already committed to the `db`. Later operations on the same key win.
+	///
+	/// Preconditions of conditional operations are not checked.
+	pub fn iter<'a>(&'a self, db: &'a Database) -> Result<DatabaseIterator<'a>> {
+		let mut pending = HashMap::new();
+		let operations = self.operations().with_keyspaces()
+			.filter(|&(keyspace, _)| keyspace == DEFAULT_KEYSPACE);
+		for (keyspace, operation) in operations {
+			db.apply_pending(keyspace, operation, &mut pending)?;
+		}
+
+		let mut keys: Vec<_> = pending.keys().map(|&(_, key)| key).collect();
+		keys.sort();
+
+		let overlay = keys.into_iter()
+			.map(|key| db.get_pending(DEFAULT_KEYSPACE, key, &pending).map(|value| (key, value)))
+			.collect::<Result<Vec<_>>>()?;
+
+		db.iter_with_overlay(overlay)
+	}
+
+	/// Returns double-ended iterator over all operations in a transaction,
+	/// including the operations in named keyspaces.
 	pub fn operations(&self) -> OperationsIterator {
 		OperationsIterator {
 			data: &self.operations,
@@ -135,9 +479,66 @@ impl Transaction {
 		&self.operations
 	}
 
+	/// Appends all operations of serialized transaction.
+	pub(crate) fn extend_raw(&mut self, raw: &[u8]) {
+		self.operations.extend_from_slice(raw);
+	}
+
+	/// Returns the key length, the value lengths and whether values are stored with expiry time
+	/// in the keyspace `name`.
+	fn limits(&self, name: &[u8]) -> Result<(usize, ValueLenLimit, bool)> {
+		if name == DEFAULT_KEYSPACE {
+			return Ok((self.key_len, self.value_len, self.ttl));
+		}
+
+		match self.keyspaces.get(name) {
+			Some(&limits) => Ok(limits),
+			None => Err(ErrorKind::KeyspaceNotFound(String::from_utf8_lossy(name).into_owned()).into()),
+		}
+	}
+
 	#[inline]
-	fn push<'a>(&mut self, operation: Operation<'a>) {
-		operation.write_to_buf(&mut self.operations);
+	fn check_key<'a>(&self, name: &[u8], key: &'a [u8]) -> Result<&'a [u8]> {
+		let key_len = self.limits(name)?.0;
+		if key.len() != key_len {
+			Err(ErrorKind::InvalidKeyLen(key_len, key.len()).into())
+		} else {
+			Ok(key)
+		}
+	}
+
+	#[inline]
+	fn check_key_value<'a>(&self, name: &[u8], key: &'a [u8], value: &'a [u8]) -> Result<(&'a [u8], &'a [u8])> {
+		let key = self.check_key(name, key)?;
+		self.limits(name)?.1.check(value.len())?;
+		Ok((key, value))
+	}
+
+	/// Appends the operation to the keyspace `name` without validating it.
+	#[inline]
+	fn push<'a>(&mut self, name: &[u8], operation: Operation<'a>) {
+		if name == DEFAULT_KEYSPACE {
+			operation.write_to_buf(&mut self.operations);
+		} else {
+			self.push_in(name, operation);
+		}
+	}
+
+	/// Appends the operation to the named keyspace without validating it.
+	///
+	/// Each operation is preceded by the name of the keyspace.
+	///
+	/// ```text
+	///  1 byte   4 bytes
+	///   /         /
+	/// | 6 | name len | name | operation |
+	/// ```
+	pub(crate) fn push_in<'a>(&mut self, keyspace: &[u8], operation: Operation<'a>) {
+		let buf = &mut self.operations;
+		buf.push(Operation::KEYSPACE);
+		buf.write_u32::<LittleEndian>(keyspace.len() as u32).unwrap();
+		buf.extend_from_slice(keyspace);
+		operation.write_to_buf(buf);
 	}
 }
 
@@ -148,32 +549,74 @@ pub struct OperationsIterator<'a> {
 }
 
 impl<'a> OperationsIterator<'a> {
-	/// Unsafety is that data may not contain valid operations
+	/// Unsafety is that data may not contain valid operations, unless it was checked with `validate`
 	pub unsafe fn new(data: &'a [u8]) -> Self {
 		OperationsIterator {
 			data,
 		}
 	}
+
+	/// Returns an iterator over operations paired with the names of their keyspaces.
+	pub(crate) fn with_keyspaces(self) -> KeyspaceOperationsIterator<'a> {
+		KeyspaceOperationsIterator {
+			inner: self,
+		}
+	}
+
+	/// Checks that the data contains only valid operations.
+	pub(crate) fn validate(mut data: &[u8]) -> ::std::result::Result<(), Corruption> {
+		let mut position = 0;
+		while !data.is_empty() {
+			let (_, _, len) = read_operation(data).map_err(|corruption| corruption.shifted(position))?;
+			data = &data[len..];
+			position += len;
+		}
+
+		Ok(())
+	}
+
+	fn next_with_keyspace(&mut self) -> Option<(&'a [u8], Operation<'a>)> {
+		if self.data.is_empty() {
+			return None;
+		}
+
+		let (keyspace, operation, len) = read_operation(self.data)
+			.expect("operations are written by transactions or validated when read from the disk; qed");
+		self.data = &self.data[len..];
+		Some((keyspace, operation))
+	}
 }
 
 impl<'a> Iterator for OperationsIterator<'a> {
 	type Item = Operation<'a>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		if self.data.is_empty() {
-			return None;
-		}
+		self.next_with_keyspace().map(|(_, operation)| operation)
+	}
+}
 
-		let (operation, consumed_bytes) = Operation::read_from_buf(self.data)
-			.expect("Data is not empty and contains only supported operations; qed");
-		self.data = &self.data[consumed_bytes..];
-		Some(operation)
+/// Iterator over serialized transaction operations and the names of their keyspaces.
+/// Operations of `Database` itself belong to the keyspace with an empty name.
+pub(crate) struct KeyspaceOperationsIterator<'a> {
+	inner: OperationsIterator<'a>,
+}
+
+impl<'a> Iterator for KeyspaceOperationsIterator<'a> {
+	type Item = (&'a [u8], Operation<'a>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.inner.next_with_keyspace()
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::{Transaction, Operation};
+	extern crate tempdir;
+
+	use super::{Transaction, Operation, OperationsIterator};
+	use database::Database;
+	use error::{ErrorKind, Result};
+	use options::{Options, ValueLenLimit, ValuesLen};
 	use quickcheck::TestResult;
 
 	#[test]
@@ -189,12 +632,128 @@ mod tests {
 		assert_eq!(operations.next(), None);
 	}
 
+	#[test]
+	fn test_transaction_conditional_operations() {
+		let mut t = Transaction::new(3);
+		t.insert_if_absent(b"key", b"value").unwrap();
+		t.update_if_equals(b"key", b"value", b"value2").unwrap();
+		t.delete_if_present(b"key").unwrap();
+		assert!(t.insert_if_absent(b"key1", b"value").is_err());
+		assert!(t.update_if_equals(b"key1", b"value", b"value2").is_err());
+		assert!(t.delete_if_present(b"key1").is_err());
+
+		let mut operations = t.operations();
+
+		assert_eq!(operations.next(), Some(Operation::InsertIfAbsent(b"key", b"value")));
+		assert_eq!(operations.next(), Some(Operation::UpdateIfEquals(b"key", b"value", b"value2")));
+		assert_eq!(operations.next(), Some(Operation::DeleteIfPresent(b"key")));
+		assert_eq!(operations.next(), None);
+	}
+
+	#[test]
+	fn test_transaction_merge() {
+		let mut t = Transaction::new(3);
+		t.merge(b"key", b"operand").unwrap();
+		assert!(t.merge(b"key1", b"operand").is_err());
+
+		let mut operations = t.operations();
+
+		assert_eq!(operations.next(), Some(Operation::Merge(b"key", b"operand")));
+		assert_eq!(operations.next(), None);
+	}
+
+	#[test]
+	fn test_transaction_keyspaces() {
+		let mut t = Transaction::new(3);
+		t.add_keyspace("positions", 2, ValueLenLimit::Exact(1), false);
+		t.insert(b"key", b"value").unwrap();
+		t.insert_in("positions", b"ab", b"1").unwrap();
+		t.delete_if_present_in("positions", b"cd").unwrap();
+		assert_eq!(*t.insert_in("positions", b"key", b"1").unwrap_err().kind(), ErrorKind::InvalidKeyLen(2, 3));
+		assert_eq!(*t.insert_in("positions", b"ab", b"12").unwrap_err().kind(), ErrorKind::InvalidValueLen("1".into(), 2));
+		assert_eq!(*t.insert_with_ttl_in("positions", b"ab", b"1", 1).unwrap_err().kind(), ErrorKind::TtlDisabled);
+		t.delete(b"key").unwrap();
+		assert_eq!(*t.delete_in("orders", b"ab").unwrap_err().kind(), ErrorKind::KeyspaceNotFound("orders".into()));
+
+		let mut operations = t.operations().with_keyspaces();
+
+		assert_eq!(operations.next(), Some((b"" as &[u8], Operation::Insert(b"key", b"value"))));
+		assert_eq!(operations.next(), Some((b"positions" as &[u8], Operation::Insert(b"ab", b"1"))));
+		assert_eq!(operations.next(), Some((b"positions" as &[u8], Operation::DeleteIfPresent(b"cd"))));
+		assert_eq!(operations.next(), Some((b"" as &[u8], Operation::Delete(b"key"))));
+		assert_eq!(operations.next(), None);
+		assert_eq!(t.operations().count(), 4);
+	}
+
+	#[test]
+	fn test_transaction_read_your_writes() {
+		let temp = tempdir::TempDir::new("test_transaction_read_your_writes").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("cde", "001").unwrap();
+		tx.insert("efg", "001").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "002").unwrap();
+		tx.insert("bcd", "002").unwrap();
+		tx.delete("cde").unwrap();
+		tx.insert("abc", "003").unwrap();
+		tx.delete("bcd").unwrap();
+		tx.insert_if_absent("xyz", "003").unwrap();
+
+		assert_eq!(tx.get(&db, "abc").unwrap().unwrap(), b"003");
+		assert!(tx.get(&db, "bcd").unwrap().is_none());
+		assert!(tx.get(&db, "cde").unwrap().is_none());
+		assert_eq!(tx.get(&db, "efg").unwrap().unwrap(), b"001");
+		assert_eq!(tx.get(&db, "xyz").unwrap().unwrap(), b"003");
+		assert_eq!(*tx.get(&db, "ab").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 2));
+
+		let pairs = tx.iter(&db).unwrap()
+			.map(|item| item.map(|(key, value)| (key.to_vec(), value.to_vec())))
+			.collect::<Result<Vec<_>>>()
+			.unwrap();
+		assert_eq!(pairs, vec![
+			(b"abc".to_vec(), b"003".to_vec()),
+			(b"efg".to_vec(), b"001".to_vec()),
+			(b"xyz".to_vec(), b"003".to_vec()),
+		]);
+
+		// the database itself is not affected
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+		assert_eq!(db.iter().unwrap().count(), 3);
+	}
+
 	#[test]
 	fn test_transaction_invalid_key_len_for_insert() {
 		let mut t = Transaction::new(4);
 		assert!(t.insert(b"key", b"value").is_err());
 	}
 
+	#[test]
+	fn test_transaction_invalid_value_len() {
+		let mut t = Transaction::with_value_len(3, ValueLenLimit::Exact(5));
+		t.insert(b"key", b"value").unwrap();
+		assert_eq!(*t.insert(b"key", b"val").unwrap_err().kind(), ErrorKind::InvalidValueLen("5".into(), 3));
+		assert_eq!(*t.insert_if_absent(b"key", b"values").unwrap_err().kind(), ErrorKind::InvalidValueLen("5".into(), 6));
+		assert_eq!(*t.update_if_equals(b"key", b"value", b"").unwrap_err().kind(), ErrorKind::InvalidValueLen("5".into(), 0));
+
+		let mut t = Transaction::with_value_len(3, ValueLenLimit::Max(5));
+		t.insert(b"key", b"").unwrap();
+		t.insert(b"key", b"value").unwrap();
+		assert_eq!(*t.insert(b"key", b"values").unwrap_err().kind(), ErrorKind::InvalidValueLen("at most 5".into(), 6));
+
+		assert_eq!(t.operations().count(), 2);
+	}
+
 	#[test]
 	fn test_transaction_invalid_key_len_for_delete() {
 		let mut t = Transaction::new(4);
@@ -225,6 +784,18 @@ mod tests {
 		}
 	}
 
+	quickcheck! {
+		fn quickcheck_update_if_equals_operation_roundtrips_to_and_from_buf(key: Vec<u8>, expected: Vec<u8>, value: Vec<u8>) -> TestResult {
+			let mut buf: Vec<u8> = Vec::new();
+			let op = Operation::UpdateIfEquals(key.as_slice(), expected.as_slice(), value.as_slice());
+			op.write_to_buf(&mut buf);
+			let (op_read, consumed_bytes) = Operation::read_from_buf(&buf).unwrap();
+			TestResult::from_bool(
+				op == op_read && consumed_bytes == 1 + 4 + 4 + 4 + key.len() + expected.len() + value.len()
+			)
+		}
+	}
+
 	quickcheck! {
 		fn quickcheck_iterate_transaction_operations(key: Vec<u8>, value: Vec<u8>) -> TestResult {
 			let mut tx = Transaction::new(key.len());
@@ -241,4 +812,48 @@ mod tests {
 			)
 		}
 	}
+
+	#[test]
+	fn should_reject_truncated_operations() {
+		let mut tx = Transaction::new(3);
+		tx.insert(b"abc", b"value").unwrap();
+		tx.update_if_equals(b"abc", b"value", b"other").unwrap();
+		tx.delete(b"abc").unwrap();
+		let mut raw = tx.raw().to_vec();
+		assert_eq!(OperationsIterator::validate(&raw), Ok(()));
+
+		// the first operation ends after 1 + 4 + 4 + 3 + 5 bytes
+		for len in 1..17 {
+			assert!(OperationsIterator::validate(&raw[..len]).is_err());
+		}
+		assert_eq!(OperationsIterator::validate(&raw[..18]).unwrap_err().offset, 18);
+
+		raw[0] = 42;
+		assert_eq!(OperationsIterator::validate(&raw).unwrap_err().msg, "Unknown operation 42");
+	}
+
+	quickcheck! {
+		fn quickcheck_validating_garbled_operations_never_panics(data: Vec<u8>) -> bool {
+			if OperationsIterator::validate(&data).is_ok() {
+				let _ = unsafe { OperationsIterator::new(&data) }.count();
+			}
+			true
+		}
+	}
+
+	quickcheck! {
+		fn quickcheck_validating_garbled_transaction_never_panics(key: Vec<u8>, value: Vec<u8>, at: usize, byte: u8) -> TestResult {
+			let mut tx = Transaction::new(key.len());
+			tx.insert(key.clone(), value.clone()).unwrap();
+			tx.delete(key.clone()).unwrap();
+			let mut raw = tx.raw().to_vec();
+			let at = at % raw.len();
+			raw[at] = byte;
+
+			if OperationsIterator::validate(&raw).is_ok() {
+				let _ = unsafe { OperationsIterator::new(&raw) }.count();
+			}
+			TestResult::passed()
+		}
+	}
 }
@@ -0,0 +1,844 @@
+//! Files of a database, stored either on the disk or in heap buffers.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::cmp;
+
+use memmap::{Mmap, Protection};
+use parking_lot::{Mutex, RwLock};
+
+use durability::Durability;
+use error::Result;
+use storage::{PositionedFile, PreadFile, Storage, StorageFile};
+
+/// File system the files of a database are stored in.
+#[derive(Debug, Clone)]
+pub(crate) enum Vfs {
+	/// Files and directories of the operating system.
+	Disk,
+	/// Heap buffers of a database created with `Database::in_memory`. Nothing is synced,
+	/// the files are gone once the database is dropped.
+	///
+	/// Writes, syncs, renames and removals can be failed by tests to simulate crashes,
+	/// which lose whatever was not synced.
+	Memory(Arc<MemoryFs>),
+}
+
+impl Default for Vfs {
+	fn default() -> Self {
+		Vfs::Disk
+	}
+}
+
+impl Vfs {
+	/// Returns a new empty file system in memory.
+	pub fn memory() -> Self {
+		Vfs::Memory(Arc::new(MemoryFs::default()))
+	}
+
+	pub fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => fs::create_dir_all(path),
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				entries.operation("write", path)?;
+				entries.create_dir_all(path);
+				Ok(())
+			},
+		}
+	}
+
+	pub fn is_dir(&self, path: &Path) -> bool {
+		match *self {
+			Vfs::Disk => path.is_dir(),
+			Vfs::Memory(ref memory) => memory.entries.lock().dirs.contains(path),
+		}
+	}
+
+	pub fn exists(&self, path: &Path) -> bool {
+		match *self {
+			Vfs::Disk => path.exists(),
+			Vfs::Memory(ref memory) => {
+				let entries = memory.entries.lock();
+				entries.dirs.contains(path) || entries.files.contains_key(path)
+			},
+		}
+	}
+
+	/// Returns the paths of the files and directories in the directory.
+	pub fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+		match *self {
+			Vfs::Disk => fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect(),
+			Vfs::Memory(ref memory) => {
+				let entries = memory.entries.lock();
+				if !entries.dirs.contains(path) {
+					return Err(not_found(path));
+				}
+
+				Ok(entries.dirs.iter()
+					.chain(entries.files.keys())
+					.filter(|entry| entry.parent() == Some(path))
+					.cloned()
+					.collect())
+			},
+		}
+	}
+
+	pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => fs::remove_file(path),
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				entries.operation("remove", path)?;
+				entries.files.remove(path).ok_or_else(|| not_found(path))?;
+				#[cfg(test)]
+				entries.synced.remove(path);
+				Ok(())
+			},
+		}
+	}
+
+	pub fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => fs::remove_dir_all(path),
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				entries.operation("remove", path)?;
+				if !entries.dirs.contains(path) {
+					return Err(not_found(path));
+				}
+
+				entries.dirs.retain(|dir| !dir.starts_with(path));
+				entries.files.retain(|file, _| !file.starts_with(path));
+				#[cfg(test)]
+				entries.synced.remove(path);
+				Ok(())
+			},
+		}
+	}
+
+	/// Moves a file or a directory, replacing the file at `to` if there is any.
+	pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => fs::rename(from, to),
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				entries.operation("rename", from)?;
+				entries.rename(from, to)
+			},
+		}
+	}
+
+	/// Reads the whole file.
+	pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+		match *self {
+			Vfs::Disk => fs::read(path),
+			Vfs::Memory(ref memory) => memory.entries.lock().file(path).map(|file| file.to_vec()),
+		}
+	}
+
+	/// Writes the data to the file, failing if `create_new` is set and the file exists.
+	pub fn write(&self, path: &Path, data: &[u8], create_new: bool, durability: Durability) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => {
+				let mut file = fs::OpenOptions::new()
+					.write(true)
+					.create(true)
+					.truncate(true)
+					.create_new(create_new)
+					.open(path)?;
+				file.write_all(data)?;
+				file.flush()?;
+				durability.sync_file(&file)
+			},
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				entries.operation("write", path)?;
+				entries.insert(path, MemoryFile::Stream(Arc::new(data.to_vec())), create_new)?;
+				entries.sync_file(path, durability)
+			},
+		}
+	}
+
+	/// Creates a new file of `len` zero bytes, which is opened with `open_storage`.
+	pub fn create_sized(&self, path: &Path, len: u64, durability: Durability) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => {
+				let mut file = fs::OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.open(path)?;
+				file.set_len(len)?;
+				file.flush()?;
+				durability.sync_file(&file)
+			},
+			Vfs::Memory(ref memory) => {
+				let buffer = Arc::new(RwLock::new(vec![0; len as usize]));
+				let mut entries = memory.entries.lock();
+				entries.operation("write", path)?;
+				entries.insert(path, MemoryFile::Fixed(buffer), true)?;
+				entries.sync_file(path, durability)
+			},
+		}
+	}
+
+	/// Opens a file created with `create_sized` or `write` for reading and writing.
+	/// See `Storage::open` for `cache_size`.
+	pub fn open_storage(&self, path: &Path, storage: Storage, cache_size: usize) -> Result<Box<StorageFile>> {
+		match *self {
+			Vfs::Disk => storage.open(path, cache_size),
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				let buffer = match entries.files.get_mut(path) {
+					Some(file) => file.to_fixed(),
+					None => return Err(not_found(path).into()),
+				};
+				let len = buffer.read().len();
+				let file = MemoryStorageFile { memory: memory.clone(), path: path.to_owned(), buffer };
+				Ok(Box::new(PreadFile::new(path, file, len, cache_size)))
+			},
+		}
+	}
+
+	/// Returns the contents of the file, which are not changed by later writes to the file.
+	pub fn map(&self, path: &Path) -> io::Result<Mapping> {
+		match *self {
+			Vfs::Disk => Mmap::open_path(path, Protection::Read).map(Mapping::Disk),
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				match entries.files.get_mut(path) {
+					Some(file) => Ok(Mapping::Memory(file.to_stream())),
+					None => Err(not_found(path)),
+				}
+			},
+		}
+	}
+
+	/// Creates a new file with a single zero byte, which is overwritten by the first write.
+	pub fn create_log(&self, path: &Path, durability: Durability) -> io::Result<LogFile> {
+		match *self {
+			Vfs::Disk => {
+				let mut file = fs::OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.open(path)?;
+				file.set_len(1)?;
+				file.flush()?;
+				durability.sync_file(&file)?;
+				Ok(LogFile::Disk(file))
+			},
+			Vfs::Memory(ref memory) => {
+				let mut entries = memory.entries.lock();
+				entries.operation("write", path)?;
+				entries.insert(path, MemoryFile::Stream(Arc::new(vec![0])), true)?;
+				entries.sync_file(path, durability)?;
+				Ok(LogFile::Memory { memory: memory.clone(), path: path.to_owned(), position: 0 })
+			},
+		}
+	}
+
+	/// Opens an existing file for appending.
+	pub fn open_log(&self, path: &Path) -> io::Result<LogFile> {
+		match *self {
+			Vfs::Disk => {
+				let mut file = fs::OpenOptions::new()
+					.append(true)
+					.open(path)?;
+				// the position of appended data is only known once the file is written otherwise
+				file.seek(SeekFrom::End(0))?;
+				Ok(LogFile::Disk(file))
+			},
+			Vfs::Memory(ref memory) => {
+				let position = memory.entries.lock().file(path)?.len() as u64;
+				Ok(LogFile::Memory { memory: memory.clone(), path: path.to_owned(), position })
+			},
+		}
+	}
+
+	/// Syncs the entries of the directory if the durability requires it.
+	pub fn sync_dir(&self, path: &Path, durability: Durability) -> io::Result<()> {
+		match *self {
+			Vfs::Disk => durability.sync_dir(path),
+			Vfs::Memory(ref memory) => match durability {
+				Durability::Full => memory.entries.lock().sync_dir(path),
+				Durability::None | Durability::Data => Ok(()),
+			},
+		}
+	}
+}
+
+fn not_found(path: &Path) -> io::Error {
+	io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display()))
+}
+
+/// Contents of a file returned by `Vfs::map`.
+#[derive(Debug)]
+pub(crate) enum Mapping {
+	Disk(Mmap),
+	Memory(Arc<Vec<u8>>),
+}
+
+impl Mapping {
+	pub fn as_slice(&self) -> &[u8] {
+		match *self {
+			Mapping::Disk(ref mmap) => unsafe { mmap.as_slice() },
+			Mapping::Memory(ref data) => data,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.as_slice().len()
+	}
+}
+
+/// File opened with `Vfs::create_log` or `Vfs::open_log`.
+#[derive(Debug)]
+pub(crate) enum LogFile {
+	Disk(File),
+	Memory {
+		memory: Arc<MemoryFs>,
+		path: PathBuf,
+		position: u64,
+	},
+}
+
+impl LogFile {
+	/// Syncs the written data to the disk.
+	pub fn sync(&self, durability: Durability) -> io::Result<()> {
+		match *self {
+			LogFile::Disk(ref file) => durability.sync_file(file),
+			LogFile::Memory { ref memory, ref path, .. } => memory.entries.lock().sync_file(path, durability),
+		}
+	}
+}
+
+impl Write for LogFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match *self {
+			LogFile::Disk(ref mut file) => file.write(buf),
+			LogFile::Memory { ref memory, ref path, ref mut position } => {
+				let mut entries = memory.entries.lock();
+				entries.operation("write", path)?;
+				let data = match entries.files.get_mut(path) {
+					Some(file) => file.stream_mut(),
+					None => return Err(not_found(path)),
+				};
+
+				let start = *position as usize;
+				let overwritten = cmp::min(buf.len(), data.len().saturating_sub(start));
+				data[start..start + overwritten].copy_from_slice(&buf[..overwritten]);
+				data.extend_from_slice(&buf[overwritten..]);
+				*position += buf.len() as u64;
+				Ok(buf.len())
+			},
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			LogFile::Disk(ref mut file) => file.flush(),
+			LogFile::Memory { .. } => Ok(()),
+		}
+	}
+}
+
+impl Seek for LogFile {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		match *self {
+			LogFile::Disk(ref mut file) => file.seek(pos),
+			LogFile::Memory { ref memory, ref path, ref mut position } => {
+				let len = memory.entries.lock().file(path)?.len() as i64;
+				let new_position = match pos {
+					SeekFrom::Start(offset) => offset as i64,
+					SeekFrom::End(offset) => len + offset,
+					SeekFrom::Current(offset) => *position as i64 + offset,
+				};
+				if new_position < 0 {
+					return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"));
+				}
+
+				*position = new_position as u64;
+				Ok(*position)
+			},
+		}
+	}
+}
+
+/// Directories and files of `Vfs::Memory`.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryFs {
+	entries: Mutex<MemoryEntries>,
+}
+
+#[cfg(test)]
+impl MemoryFs {
+	/// Returns the number of writes, syncs, renames and removals performed so far.
+	pub fn operations(&self) -> usize {
+		self.entries.lock().faults.operations
+	}
+
+	/// Fails the operation with the given number, counted like `operations`.
+	pub fn inject(&self, operation: usize, fault: Fault) {
+		self.entries.lock().faults.injected = Some((operation, fault));
+	}
+
+	/// Lets the operations succeed again after a simulated crash, like a restarted process would.
+	/// Only the synced contents of the files and the synced entries of the directories are kept.
+	pub fn restart(&self) {
+		let mut entries = self.entries.lock();
+		entries.faults.injected = None;
+		entries.faults.crashed = false;
+		entries.restore_synced();
+	}
+}
+
+/// Fault injected into the operations of `Vfs::Memory`.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Fault {
+	/// The operation fails without any effect, the following operations succeed.
+	Fail,
+	/// The operation and all the following ones fail without any effect until
+	/// `MemoryFs::restart` is called, which drops the data that was not synced.
+	Crash,
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Faults {
+	operations: usize,
+	injected: Option<(usize, Fault)>,
+	crashed: bool,
+}
+
+/// State of `Vfs::Memory` as of the last syncs, which is all that is left after a crash.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Synced {
+	/// Identities of the current files and directories, which are moved along with them by renames.
+	ids: BTreeMap<PathBuf, u64>,
+	next_id: u64,
+	/// Identities of the directories.
+	dirs: BTreeSet<u64>,
+	/// Synced names and identities of the entries of the directories.
+	entries: BTreeMap<u64, BTreeMap<PathBuf, u64>>,
+	/// Synced contents of the files.
+	contents: BTreeMap<u64, Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Synced {
+	/// Gives a new identity to the created file or directory. Overwritten files and existing
+	/// directories keep theirs.
+	fn create(&mut self, path: &Path, dir: bool) {
+		if self.ids.contains_key(path) {
+			return;
+		}
+
+		self.next_id += 1;
+		self.ids.insert(path.to_owned(), self.next_id);
+		if dir {
+			self.dirs.insert(self.next_id);
+		}
+	}
+
+	fn remove(&mut self, path: &Path) {
+		self.ids.retain(|entry, _| !entry.starts_with(path));
+	}
+
+	fn rename(&mut self, from: &Path, to: &Path) {
+		self.remove(to);
+		let moved = self.ids.iter()
+			.filter(|&(entry, _)| entry.starts_with(from))
+			.map(|(entry, &id)| (entry.clone(), id))
+			.collect::<Vec<_>>();
+		for (entry, id) in moved {
+			self.ids.remove(&entry);
+			self.ids.insert(to.join(entry.strip_prefix(from).expect("only paths within the renamed one are moved; qed")), id);
+		}
+	}
+
+	fn sync_file(&mut self, path: &Path, file: Option<&MemoryFile>) {
+		if let (Some(&id), Some(file)) = (self.ids.get(path), file) {
+			self.contents.insert(id, file.to_vec());
+		}
+	}
+
+	fn sync_dir(&mut self, path: &Path) {
+		let id = match self.ids.get(path) {
+			Some(&id) => id,
+			None => return,
+		};
+
+		let entries = self.ids.iter()
+			.filter(|&(entry, _)| entry.parent() == Some(path))
+			.map(|(entry, &id)| (PathBuf::from(entry.file_name().expect("entries within a directory have names; qed")), id))
+			.collect();
+		self.entries.insert(id, entries);
+	}
+}
+
+#[derive(Debug, Default)]
+struct MemoryEntries {
+	dirs: BTreeSet<PathBuf>,
+	files: BTreeMap<PathBuf, MemoryFile>,
+	#[cfg(test)]
+	faults: Faults,
+	#[cfg(test)]
+	synced: Synced,
+}
+
+impl MemoryEntries {
+	/// Counts the operation on the path, failing it if a fault was injected.
+	#[cfg(test)]
+	fn operation(&mut self, name: &str, path: &Path) -> io::Result<()> {
+		let faults = &mut self.faults;
+		let operation = faults.operations;
+		faults.operations += 1;
+
+		match faults.injected {
+			Some((injected, Fault::Fail)) if injected == operation =>
+				return Err(io::Error::new(io::ErrorKind::Other, format!("Injected failure of {} #{} of {}", name, operation, path.display()))),
+			Some((injected, Fault::Crash)) if injected == operation => faults.crashed = true,
+			_ => {},
+		}
+
+		if faults.crashed {
+			return Err(io::Error::new(io::ErrorKind::Other, format!("Simulated crash before {} #{} of {}", name, operation, path.display())));
+		}
+
+		Ok(())
+	}
+
+	#[cfg(not(test))]
+	fn operation(&mut self, _name: &str, _path: &Path) -> io::Result<()> {
+		Ok(())
+	}
+
+	/// Drops the files and directories, their contents and entries which were not synced.
+	/// Entries of the root are never synced, so they are kept.
+	#[cfg(test)]
+	fn restore_synced(&mut self) {
+		let mut pending = self.synced.ids.iter()
+			.filter(|&(entry, _)| entry.parent().map_or(true, |parent| parent.as_os_str().is_empty()))
+			.map(|(entry, &id)| (entry.clone(), id))
+			.collect::<Vec<_>>();
+		let mut ids = BTreeMap::new();
+		self.dirs.clear();
+		self.files.clear();
+
+		while let Some((path, id)) = pending.pop() {
+			if self.synced.dirs.contains(&id) {
+				if let Some(entries) = self.synced.entries.get(&id) {
+					pending.extend(entries.iter().map(|(name, &id)| (path.join(name), id)));
+				}
+				self.dirs.insert(path.clone());
+			} else {
+				let data = self.synced.contents.get(&id).cloned().unwrap_or_default();
+				self.files.insert(path.clone(), MemoryFile::Stream(Arc::new(data)));
+			}
+			ids.insert(path, id);
+		}
+		self.synced.ids = ids;
+	}
+
+	/// Syncs the contents of the file if the durability requires it.
+	fn sync_file(&mut self, path: &Path, durability: Durability) -> io::Result<()> {
+		match durability {
+			Durability::None => Ok(()),
+			Durability::Data | Durability::Full => {
+				self.operation("sync", path)?;
+				#[cfg(test)]
+				self.synced.sync_file(path, self.files.get(path));
+				Ok(())
+			},
+		}
+	}
+
+	fn sync_dir(&mut self, path: &Path) -> io::Result<()> {
+		self.operation("sync", path)?;
+		#[cfg(test)]
+		self.synced.sync_dir(path);
+		Ok(())
+	}
+
+	fn create_dir_all(&mut self, path: &Path) {
+		for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+			self.dirs.insert(dir.to_owned());
+			#[cfg(test)]
+			self.synced.create(dir, true);
+		}
+	}
+
+	fn file(&self, path: &Path) -> io::Result<&MemoryFile> {
+		self.files.get(path).ok_or_else(|| not_found(path))
+	}
+
+	fn insert(&mut self, path: &Path, file: MemoryFile, create_new: bool) -> io::Result<()> {
+		match path.parent() {
+			Some(parent) if !parent.as_os_str().is_empty() && !self.dirs.contains(parent) => return Err(not_found(parent)),
+			_ => {},
+		}
+
+		if self.dirs.contains(path) || (create_new && self.files.contains_key(path)) {
+			return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+		}
+
+		self.files.insert(path.to_owned(), file);
+		#[cfg(test)]
+		self.synced.create(path, false);
+		Ok(())
+	}
+
+	fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+		if let Some(file) = self.files.remove(from) {
+			self.insert(to, file, false)?;
+			#[cfg(test)]
+			self.synced.rename(from, to);
+			return Ok(());
+		}
+
+		if !self.dirs.contains(from) {
+			return Err(not_found(from));
+		}
+
+		let moved = |path: &PathBuf| to.join(path.strip_prefix(from).expect("only paths within the directory are moved; qed"));
+		let dirs = self.dirs.iter().filter(|dir| dir.starts_with(from)).cloned().collect::<Vec<_>>();
+		let files = self.files.keys().filter(|file| file.starts_with(from)).cloned().collect::<Vec<_>>();
+		for dir in dirs {
+			self.dirs.remove(&dir);
+			self.dirs.insert(moved(&dir));
+		}
+		for file in files {
+			let contents = self.files.remove(&file).expect("file was just listed; qed");
+			self.files.insert(moved(&file), contents);
+		}
+		#[cfg(test)]
+		self.synced.rename(from, to);
+		Ok(())
+	}
+}
+
+#[derive(Debug)]
+enum MemoryFile {
+	/// File written as a whole or appended to.
+	Stream(Arc<Vec<u8>>),
+	/// File of a fixed size, which is written in place.
+	Fixed(Arc<RwLock<Vec<u8>>>),
+}
+
+impl MemoryFile {
+	fn len(&self) -> usize {
+		match *self {
+			MemoryFile::Stream(ref data) => data.len(),
+			MemoryFile::Fixed(ref buffer) => buffer.read().len(),
+		}
+	}
+
+	fn to_vec(&self) -> Vec<u8> {
+		match *self {
+			MemoryFile::Stream(ref data) => data.to_vec(),
+			MemoryFile::Fixed(ref buffer) => buffer.read().clone(),
+		}
+	}
+
+	/// Returns the current contents, which are copied by the next write.
+	fn to_stream(&mut self) -> Arc<Vec<u8>> {
+		if let MemoryFile::Fixed(_) = *self {
+			*self = MemoryFile::Stream(Arc::new(self.to_vec()));
+		}
+
+		match *self {
+			MemoryFile::Stream(ref data) => data.clone(),
+			MemoryFile::Fixed(_) => unreachable!("fixed files were just converted; qed"),
+		}
+	}
+
+	/// Returns the contents for appending, copying them if they are still mapped.
+	fn stream_mut(&mut self) -> &mut Vec<u8> {
+		self.to_stream();
+		match *self {
+			MemoryFile::Stream(ref mut data) => Arc::make_mut(data),
+			MemoryFile::Fixed(_) => unreachable!("fixed files were just converted; qed"),
+		}
+	}
+
+	/// Returns the buffer shared by the storage files opened for the file.
+	fn to_fixed(&mut self) -> Arc<RwLock<Vec<u8>>> {
+		if let MemoryFile::Stream(_) = *self {
+			*self = MemoryFile::Fixed(Arc::new(RwLock::new(self.to_vec())));
+		}
+
+		match *self {
+			MemoryFile::Fixed(ref buffer) => buffer.clone(),
+			MemoryFile::Stream(_) => unreachable!("stream files were just converted; qed"),
+		}
+	}
+}
+
+/// Fixed size file read through the page cache of a `PreadFile`.
+#[derive(Debug)]
+struct MemoryStorageFile {
+	memory: Arc<MemoryFs>,
+	path: PathBuf,
+	buffer: Arc<RwLock<Vec<u8>>>,
+}
+
+impl PositionedFile for MemoryStorageFile {
+	fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+		let data = self.buffer.read();
+		let offset = offset as usize;
+		if offset + buf.len() > data.len() {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+		}
+		buf.copy_from_slice(&data[offset..offset + buf.len()]);
+		Ok(())
+	}
+
+	fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+		// the entries are locked before the buffer, like when the synced contents are copied
+		let mut entries = self.memory.entries.lock();
+		entries.operation("write", &self.path)?;
+		let offset = offset as usize;
+		self.buffer.write()[offset..offset + buf.len()].copy_from_slice(buf);
+		Ok(())
+	}
+
+	fn sync(&self, durability: Durability) -> io::Result<()> {
+		self.memory.entries.lock().sync_file(&self.path, durability)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{ErrorKind, Seek, SeekFrom, Write};
+	use std::path::{Path, PathBuf};
+	use std::sync::Arc;
+
+	use super::{Fault, MemoryFs, Vfs};
+	use durability::Durability;
+	use storage::Storage;
+
+	#[test]
+	fn test_memory_dirs() {
+		let vfs = Vfs::memory();
+		assert!(!vfs.exists(Path::new("db")));
+		assert_eq!(vfs.write(Path::new("db/a"), b"a", true, Durability::None).unwrap_err().kind(), ErrorKind::NotFound);
+
+		vfs.create_dir_all(Path::new("db/tmp/sub")).unwrap();
+		vfs.write(Path::new("db/a"), b"a", true, Durability::None).unwrap();
+		vfs.write(Path::new("db/tmp/b"), b"b", true, Durability::None).unwrap();
+		assert_eq!(vfs.write(Path::new("db/a"), b"c", true, Durability::None).unwrap_err().kind(), ErrorKind::AlreadyExists);
+		assert_eq!(vfs.read_dir(Path::new("db")).unwrap(), vec![PathBuf::from("db/tmp"), PathBuf::from("db/a")]);
+
+		vfs.rename(Path::new("db/tmp"), Path::new("db/index")).unwrap();
+		assert!(vfs.is_dir(Path::new("db/index/sub")));
+		assert_eq!(vfs.read(Path::new("db/index/b")).unwrap(), b"b");
+		assert!(!vfs.exists(Path::new("db/tmp")));
+
+		vfs.rename(Path::new("db/a"), Path::new("db/index/b")).unwrap();
+		assert_eq!(vfs.read(Path::new("db/index/b")).unwrap(), b"a");
+
+		vfs.remove_dir_all(Path::new("db/index")).unwrap();
+		assert_eq!(vfs.read_dir(Path::new("db")).unwrap(), Vec::<PathBuf>::new());
+		assert_eq!(vfs.remove_file(Path::new("db/a")).unwrap_err().kind(), ErrorKind::NotFound);
+	}
+
+	#[test]
+	fn test_memory_files() {
+		let vfs = Vfs::memory();
+		vfs.create_dir_all(Path::new("db")).unwrap();
+
+		let mut log = vfs.create_log(Path::new("db/log"), Durability::None).unwrap();
+		log.write_all(b"abc").unwrap();
+		let mapping = vfs.map(Path::new("db/log")).unwrap();
+
+		// mapped contents are not changed by later writes
+		let mut log = vfs.open_log(Path::new("db/log")).unwrap();
+		assert_eq!(log.seek(SeekFrom::Current(0)).unwrap(), 3);
+		log.write_all(b"de").unwrap();
+		assert_eq!(mapping.as_slice(), b"abc");
+		assert_eq!(vfs.map(Path::new("db/log")).unwrap().as_slice(), b"abcde");
+
+		vfs.create_sized(Path::new("db/data"), 4, Durability::None).unwrap();
+		let mut file = vfs.open_storage(Path::new("db/data"), Storage::Mmap, 0).unwrap();
+		file.write(1, b"xy").unwrap();
+		assert_eq!(file.read_all().unwrap(), b"\0xy\0");
+		assert_eq!(vfs.open_storage(Path::new("db/data"), Storage::Pread, 0).unwrap().read(1, 2).unwrap(), b"xy");
+	}
+	#[test]
+	fn should_inject_faults() {
+		let memory = Arc::new(MemoryFs::default());
+		let vfs = Vfs::Memory(memory.clone());
+		vfs.create_dir_all(Path::new("db")).unwrap();
+		// syncs are only counted if the durability requires them
+		vfs.write(Path::new("db/a"), b"a", true, Durability::None).unwrap();
+		vfs.write(Path::new("db/b"), b"b", true, Durability::Full).unwrap();
+		vfs.sync_dir(Path::new("db"), Durability::Data).unwrap();
+		assert_eq!(memory.operations(), 4);
+
+		// failed operations have no effect
+		memory.inject(4, Fault::Fail);
+		assert_eq!(vfs.rename(Path::new("db/a"), Path::new("db/c")).unwrap_err().kind(), ErrorKind::Other);
+		vfs.remove_file(Path::new("db/b")).unwrap();
+		assert_eq!(vfs.read_dir(Path::new("db")).unwrap(), vec![PathBuf::from("db/a")]);
+
+		// crashes fail the following operations as well
+		memory.inject(memory.operations() + 1, Fault::Crash);
+		let mut log = vfs.create_log(Path::new("db/log"), Durability::None).unwrap();
+		assert!(log.write_all(b"abc").is_err());
+		assert!(vfs.remove_file(Path::new("db/a")).is_err());
+		assert!(log.sync(Durability::Full).is_err());
+		assert_eq!(vfs.read(Path::new("db/a")).unwrap(), b"a");
+
+		memory.restart();
+		vfs.write(Path::new("db/a"), b"b", false, Durability::None).unwrap();
+		assert_eq!(vfs.read(Path::new("db/a")).unwrap(), b"b");
+	}
+
+	#[test]
+	fn should_drop_unsynced_data_on_restart() {
+		let memory = Arc::new(MemoryFs::default());
+		let vfs = Vfs::Memory(memory.clone());
+		vfs.create_dir_all(Path::new("db/sub")).unwrap();
+		vfs.write(Path::new("db/a"), b"a", true, Durability::Full).unwrap();
+		vfs.write(Path::new("db/sub/b"), b"b", true, Durability::Full).unwrap();
+		vfs.sync_dir(Path::new("db"), Durability::Full).unwrap();
+		let mut log = vfs.create_log(Path::new("db/log"), Durability::Full).unwrap();
+		log.write_all(b"abc").unwrap();
+		log.sync(Durability::Full).unwrap();
+		vfs.create_sized(Path::new("db/data"), 2, Durability::Full).unwrap();
+		vfs.sync_dir(Path::new("db"), Durability::Full).unwrap();
+
+		// unsynced writes, removals and renames
+		log.write_all(b"de").unwrap();
+		vfs.open_storage(Path::new("db/data"), Storage::Mmap, 0).unwrap().write(0, b"x").unwrap();
+		vfs.remove_file(Path::new("db/a")).unwrap();
+		vfs.rename(Path::new("db/log"), Path::new("db/c")).unwrap();
+		vfs.write(Path::new("db/d"), b"d", true, Durability::Full).unwrap();
+		memory.restart();
+
+		let entries = vec!["db/sub", "db/a", "db/data", "db/log"].into_iter().map(PathBuf::from).collect::<Vec<_>>();
+		assert_eq!(vfs.read_dir(Path::new("db")).unwrap(), entries);
+		// the entries of the subdirectory were never synced
+		assert_eq!(vfs.read_dir(Path::new("db/sub")).unwrap(), Vec::<PathBuf>::new());
+		assert_eq!(vfs.read(Path::new("db/a")).unwrap(), b"a");
+		assert_eq!(vfs.read(Path::new("db/log")).unwrap(), b"abc");
+		assert_eq!(vfs.read(Path::new("db/data")).unwrap(), b"\0\0");
+
+		// synced renames and writes are kept
+		vfs.rename(Path::new("db/log"), Path::new("db/sub/log")).unwrap();
+		vfs.open_storage(Path::new("db/data"), Storage::Mmap, 0).unwrap().write(1, b"y").unwrap();
+		vfs.open_storage(Path::new("db/data"), Storage::Pread, 0).unwrap().sync(Durability::Data).unwrap();
+		vfs.sync_dir(Path::new("db"), Durability::Full).unwrap();
+		vfs.sync_dir(Path::new("db/sub"), Durability::Full).unwrap();
+		memory.restart();
+
+		assert_eq!(vfs.read(Path::new("db/sub/log")).unwrap(), b"abc");
+		assert!(!vfs.exists(Path::new("db/log")));
+		assert_eq!(vfs.read(Path::new("db/data")).unwrap(), b"\0y");
+	}
+}
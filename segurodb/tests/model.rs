@@ -0,0 +1,230 @@
+//! Randomized sequences of operations checked against a `BTreeMap` holding the expected records.
+
+extern crate tempdir;
+extern crate segurodb;
+#[macro_use]
+extern crate quickcheck;
+
+use std::ascii;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use quickcheck::{Arbitrary, Gen};
+use tempdir::TempDir;
+use segurodb::{Database, Options, Storage, Transaction, ValuesLen};
+
+const KEY_LEN: usize = 3;
+/// Keys are built of few distinct bytes, so they share prefixes for any `key_index_bits`.
+const KEY_BYTES: &'static [u8] = b"abc";
+const VALUE_BYTES: &'static [u8] = b"0123456789";
+const CONSTANT_VALUE_LEN: usize = 3;
+const MAX_VARIABLE_VALUE_LEN: usize = 6;
+
+/// Bytes printed like a string literal, so failing cases read like the actions of `db_test!`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Bytes(Vec<u8>);
+
+impl fmt::Debug for Bytes {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let escaped = self.0.iter().cloned().flat_map(ascii::escape_default).map(char::from).collect::<String>();
+		write!(f, "\"{}\"", escaped)
+	}
+}
+
+fn arbitrary_bytes<G: Gen>(g: &mut G, alphabet: &[u8], len: usize) -> Bytes {
+	Bytes((0..len).map(|_| *g.choose(alphabet).expect("alphabets are not empty; qed")).collect())
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+	Insert(Bytes, Bytes),
+	Delete(Bytes),
+	Commit,
+	FlushJournal(Option<usize>),
+	Compact,
+	Reopen,
+}
+
+use Action::*;
+
+impl Arbitrary for Action {
+	fn arbitrary<G: Gen>(g: &mut G) -> Self {
+		let key = arbitrary_bytes(g, KEY_BYTES, KEY_LEN);
+		match g.gen_range(0, 12) {
+			0 | 1 | 2 | 3 | 4 => {
+				// the value length is fixed by the options of the scenario
+				let value = arbitrary_bytes(g, VALUE_BYTES, MAX_VARIABLE_VALUE_LEN);
+				Insert(key, value)
+			},
+			5 | 6 => Delete(key),
+			7 | 8 => Commit,
+			9 => FlushJournal(if g.gen() { Some(g.gen_range(0, 4)) } else { None }),
+			10 => Compact,
+			_ => Reopen,
+		}
+	}
+}
+
+/// Options of the database varied by the scenarios.
+#[derive(Debug, Clone)]
+struct ModelOptions {
+	journal_eras: usize,
+	key_index_bits: u8,
+	variable_values: bool,
+	max_prefix_collisions: usize,
+	storage: Storage,
+}
+
+impl ModelOptions {
+	fn options(&self) -> Options {
+		Options {
+			journal_eras: self.journal_eras,
+			key_len: KEY_LEN,
+			key_index_bits: self.key_index_bits,
+			value_len: match self.variable_values {
+				true => ValuesLen::Variable { expected: CONSTANT_VALUE_LEN },
+				false => ValuesLen::Constant(CONSTANT_VALUE_LEN),
+			},
+			max_prefix_collisions: self.max_prefix_collisions,
+			storage: self.storage,
+			..Default::default()
+		}
+	}
+
+	fn value(&self, value: &Bytes) -> Vec<u8> {
+		let len = match self.variable_values {
+			// the length is derived from the value, so it's shrunk along with it
+			true => value.0.iter().map(|byte| *byte as usize).sum::<usize>() % (MAX_VARIABLE_VALUE_LEN + 1),
+			false => CONSTANT_VALUE_LEN,
+		};
+		value.0[..len].to_vec()
+	}
+
+	/// Number of records which fit in the data file. It doesn't grow yet, so scenarios stop before exceeding it.
+	fn capacity(&self) -> usize {
+		// the data file has 4 fields per prefix and the keys start in the first half of the prefixes
+		let fields = (4usize << self.key_index_bits) - (1usize << self.key_index_bits) / 2;
+		// variable values take up to 2 fields
+		match self.variable_values {
+			true => fields / 2,
+			false => fields,
+		}
+	}
+}
+
+impl Arbitrary for ModelOptions {
+	fn arbitrary<G: Gen>(g: &mut G) -> Self {
+		ModelOptions {
+			journal_eras: g.gen_range(0, 3),
+			key_index_bits: g.gen_range(1, 13),
+			variable_values: g.gen(),
+			max_prefix_collisions: g.gen_range(1, 5),
+			storage: if g.gen() { Storage::Mmap } else { Storage::Pread },
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+struct Scenario {
+	options: ModelOptions,
+	actions: Vec<Action>,
+}
+
+impl Arbitrary for Scenario {
+	fn arbitrary<G: Gen>(g: &mut G) -> Self {
+		Scenario {
+			options: ModelOptions::arbitrary(g),
+			actions: Arbitrary::arbitrary(g),
+		}
+	}
+
+	fn shrink(&self) -> Box<Iterator<Item = Self>> {
+		let options = self.options.clone();
+		Box::new(self.actions.shrink().map(move |actions| Scenario { options: options.clone(), actions }))
+	}
+}
+
+/// Asserts that the database holds exactly the records of the model.
+fn check(db: &Database, model: &BTreeMap<Vec<u8>, Vec<u8>>, after: &Action) {
+	let records = db.iter().unwrap()
+		.map(|pair| {
+			let (key, value) = pair.unwrap();
+			(key.to_vec(), value.to_vec())
+		})
+		.collect::<BTreeMap<_, _>>();
+	assert_eq!(&records, model, "iter disagrees with the model after {:?}", after);
+
+	for &a in KEY_BYTES {
+		let keys = db.iter_from(&[a]).unwrap().map(|pair| pair.unwrap().0.to_vec()).collect::<Vec<_>>();
+		let expected = model.range(vec![a]..).map(|(key, _)| key.clone()).collect::<Vec<_>>();
+		assert_eq!(keys, expected, "iter_from({:?}) disagrees with the model after {:?}", Bytes(vec![a]), after);
+	}
+
+	for &a in KEY_BYTES {
+		for &b in KEY_BYTES {
+			for &c in KEY_BYTES {
+				let key = [a, b, c];
+				let value = db.get(&key).unwrap().map(|value| value.to_vec());
+				assert_eq!(value.as_ref(), model.get(&key[..]), "get({:?}) disagrees with the model after {:?}", Bytes(key.to_vec()), after);
+			}
+		}
+	}
+}
+
+/// Runs the actions against a database and a model of its records, checking that they agree after each action.
+fn run_scenario(scenario: &Scenario) {
+	let temp = TempDir::new("model").unwrap();
+	let options = &scenario.options;
+	let mut db = Database::create(temp.path(), options.options()).unwrap();
+	let mut tx: Transaction = db.create_transaction();
+	let mut model = BTreeMap::new();
+	// operations of the transaction applied to the model once it's committed
+	let mut pending = Vec::new();
+
+	for action in &scenario.actions {
+		match *action {
+			Insert(ref key, ref value) => {
+				let value = options.value(value);
+				tx.insert(&key.0, &value).unwrap();
+				pending.push((key.0.clone(), Some(value)));
+			},
+			Delete(ref key) => {
+				tx.delete(&key.0).unwrap();
+				pending.push((key.0.clone(), None));
+			},
+			Commit => {
+				for (key, value) in pending.drain(..) {
+					match value {
+						Some(value) => model.insert(key, value),
+						None => model.remove(&key),
+					};
+				}
+				if model.len() > options.capacity() {
+					return;
+				}
+				db.commit(&tx).unwrap();
+				tx = db.create_transaction();
+			},
+			FlushJournal(max) => db.flush_journal(max).unwrap(),
+			Compact => {
+				db.compact().unwrap();
+			},
+			Reopen => {
+				drop(db);
+				db = Database::open(temp.path(), options.options()).unwrap();
+				// the transaction was never committed
+				tx = db.create_transaction();
+				pending.clear();
+			},
+		}
+
+		check(&db, &model, action);
+	}
+}
+
+quickcheck! {
+	fn quickcheck_database_agrees_with_model(scenario: Scenario) -> bool {
+		run_scenario(&scenario);
+		true
+	}
+}
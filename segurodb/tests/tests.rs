@@ -3,7 +3,7 @@ extern crate segurodb;
 
 use std::fs;
 use tempdir::TempDir;
-use segurodb::{Database, Options, ValuesLen};
+use segurodb::{Database, Options, Storage, ValuesLen};
 
 #[derive(Debug)]
 enum Action {
@@ -13,6 +13,7 @@ enum Action {
 	AssertCompact(&'static [u32]),
 	AssertEqual(&'static str, &'static str),
 	AssertNone(&'static str),
+	AssertKeys(&'static [&'static str]),
 }
 
 use Action::*;
@@ -43,24 +44,44 @@ fn run_actions(db: &mut Database, actions: &[Action]) {
 			AssertNone(key) => {
 				assert_eq!(db.get(key).unwrap(), None);
 			},
+			AssertKeys(expected_keys) => {
+				let keys = db.iter().unwrap().map(|pair| pair.unwrap().0.to_vec()).collect::<Vec<_>>();
+				let expected_keys = expected_keys.iter().map(|key| key.as_bytes().to_vec()).collect::<Vec<_>>();
+				assert_eq!(keys, expected_keys);
+			},
 		}
 	}
 }
 
+fn options(storage: Storage) -> Options {
+	Options {
+		journal_eras: 0,
+		key_len: 3,
+		value_len: ValuesLen::Constant(3),
+		storage,
+		..Default::default()
+	}
+}
+
+/// Runs the actions against a database with each of the storages.
 macro_rules! db_test {
 	($name: tt, $($actions: expr),*) => {
-		#[test]
-		fn $name() {
-			let temp = TempDir::new(stringify!($name)).unwrap();
-
-			let mut db = Database::create(temp.path(), Options {
-				journal_eras: 0,
-				key_len: 3,
-				value_len: ValuesLen::Constant(3),
-				..Default::default()
-			}).unwrap();
-
-			run_actions(&mut db, &[$($actions),*]);
+		mod $name {
+			use super::*;
+
+			#[test]
+			fn mmap() {
+				let temp = TempDir::new(stringify!($name)).unwrap();
+				let mut db = Database::create(temp.path(), options(Storage::Mmap)).unwrap();
+				run_actions(&mut db, &[$($actions),*]);
+			}
+
+			#[test]
+			fn pread() {
+				let temp = TempDir::new(stringify!($name)).unwrap();
+				let mut db = Database::create(temp.path(), options(Storage::Pread)).unwrap();
+				run_actions(&mut db, &[$($actions),*]);
+			}
 		}
 	}
 }
@@ -363,6 +384,34 @@ db_test!(
 	AssertEqual("jag", "016")
 );
 
+db_test!(
+	db_compact_restore,
+	Insert("aaa", "001"),
+	Insert("aab", "002"),
+	Insert("aac", "003"),
+	Insert("aad", "004"),
+	Insert("aae", "005"),
+	Insert("aaf", "006"),
+	Insert("zzz", "007"),
+	CommitAndFlush,
+	AssertCompact(&[97]),
+	Delete("aab"),
+	Delete("aac"),
+	Delete("aad"),
+	Delete("aae"),
+	CommitAndFlush,
+	AssertCompact(&[]),
+	AssertEqual("aaa", "001"),
+	AssertNone("aab"),
+	AssertNone("aae"),
+	AssertEqual("aaf", "006"),
+	AssertEqual("zzz", "007"),
+	Insert("aag", "008"),
+	CommitAndFlush,
+	AssertEqual("aag", "008"),
+	AssertEqual("aaa", "001")
+);
+
 db_test!(
 	db_flush_bug,
 	Insert("aaa", "001"),
@@ -383,23 +432,79 @@ db_test!(
 	AssertEqual("ddd", "008")
 );
 
+db_test!(
+	db_flush_bug_overwrite_after_delete,
+	Insert("abc", "001"),
+	Insert("bcd", "002"),
+	Insert("cde", "003"),
+	CommitAndFlush,
+	Delete("bcd"),
+	Insert("cde", "004"),
+	CommitAndFlush,
+	AssertEqual("abc", "001"),
+	AssertNone("bcd"),
+	AssertEqual("cde", "004"),
+	AssertKeys(&["abc", "cde"])
+);
+
+db_test!(
+	db_flush_bug_insert_after_shifted_deletes,
+	Insert("aaa", "001"),
+	Insert("aab", "002"),
+	Insert("bbb", "003"),
+	CommitAndFlush,
+	Delete("aaa"),
+	Delete("bbb"),
+	Insert("ccc", "004"),
+	CommitAndFlush,
+	AssertEqual("aab", "002"),
+	AssertNone("bbb"),
+	AssertEqual("ccc", "004"),
+	AssertKeys(&["aab", "ccc"])
+);
+
+db_test!(
+	db_flush_bug_delete_after_shifted_delete,
+	Insert("baa", "001"),
+	Insert("bab", "002"),
+	Insert("eee", "003"),
+	CommitAndFlush,
+	Delete("baa"),
+	Delete("eee"),
+	CommitAndFlush,
+	AssertEqual("bab", "002"),
+	AssertNone("eee"),
+	AssertKeys(&["bab"])
+);
+
+db_test!(
+	db_flush_bug_delete_before_empty_space,
+	Insert("aaa", "001"),
+	Insert("ccc", "002"),
+	CommitAndFlush,
+	Delete("aaa"),
+	CommitAndFlush,
+	AssertEqual("ccc", "002"),
+	AssertKeys(&["ccc"]),
+	Insert("aab", "003"),
+	CommitAndFlush,
+	AssertKeys(&["aab", "ccc"])
+);
+
 #[test]
 fn test_flush_recovery() {
-	let temp = TempDir::new("flush_recovery").unwrap();
-	// this flush file should apply insertions of 2 records
-	// abc -> xyz
-	// cde -> 123
-	fs::copy("tests/flushes/flush_00.flush", temp.path().join("db.flush")).unwrap();
+	for storage in &[Storage::Mmap, Storage::Pread] {
+		let temp = TempDir::new("flush_recovery").unwrap();
+		// this flush file should apply insertions of 2 records
+		// abc -> xyz
+		// cde -> 123
+		fs::copy("tests/flushes/flush_00.flush", temp.path().join("db.flush")).unwrap();
 
-	let mut db = Database::create(temp.path(), Options {
-		journal_eras: 0,
-		key_len: 3,
-		value_len: ValuesLen::Constant(3),
-		..Default::default()
-	}).unwrap();
+		let mut db = Database::create(temp.path(), options(*storage)).unwrap();
 
-	run_actions(&mut db, &[
-		AssertEqual("abc", "xyz"),
-		AssertEqual("cde", "123"),
-	]);
+		run_actions(&mut db, &[
+			AssertEqual("abc", "xyz"),
+			AssertEqual("cde", "123"),
+		]);
+	}
 }